@@ -151,8 +151,15 @@
             };
           };
 
+          measuredOnlyStubCrane = stubCrane.override {
+            extraArgs = {
+              cargoExtraArgs = "--no-default-features --features measured-only";
+            };
+          };
+
           stub = stubCrane.package;
           fatStub = fatStubCrane.package;
+          measuredOnlyStub = measuredOnlyStubCrane.package;
 
           # TODO: when we will have more backends
           # let's generalize this properly.
@@ -181,12 +188,13 @@
             # tell lanzatool where to find our UEFI binaries.
             makeWrapper ${tool}/bin/lzbt-systemd $out/bin/lzbt \
               --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]} \
-              --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
+              --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi \
+              --set LANZABOOTE_FAT_STUB ${fatStub}/bin/lanzaboote_stub.efi
           '';
         in
         {
           packages = {
-            inherit stub fatStub;
+            inherit stub fatStub measuredOnlyStub;
             tool = wrappedTool;
             lzbt = wrappedTool;
           };
@@ -199,6 +207,7 @@
             toolClippy = toolCrane.clippy;
             stubClippy = stubCrane.clippy;
             fatStubClippy = fatStubCrane.clippy;
+            measuredOnlyStubClippy = measuredOnlyStubCrane.clippy;
             toolFmt = toolCrane.rustfmt;
             stubFmt = stubCrane.rustfmt;
           } // (import ./nix/tests {
@@ -232,6 +241,12 @@
             ];
 
             TEST_SYSTEMD = pkgs.systemd;
+          } // lib.optionalAttrs pkgs.stdenv.hostPlatform.isx86_64 {
+            # For `cargo test --features qemu-tests`, which boots the stub
+            # in QEMU. It also needs `qemu-system-x86_64` in PATH.
+            TEST_LANZABOOTE_STUB = "${config.packages.stub}/bin/lanzaboote_stub.efi";
+            TEST_KERNEL = "${pkgs.linux}/${pkgs.stdenv.hostPlatform.linux-kernel.target}";
+            TEST_OVMF = "${pkgs.OVMF.fd}/FV/OVMF.fd";
           };
         } // lib.optionalAttrs (inputs.pre-commit-hooks-nix ? flakeModule) {
           pre-commit = {
@@ -36,6 +36,22 @@ in
       '';
     };
 
+    rollbackCounterBase = mkOption {
+      type = types.nullOr types.ints.unsigned;
+      default = null;
+      example = 1000;
+      description = ''
+        Embed an anti-rollback counter into every boot entry. The stub measures
+        it into PCR 12, so that TPM sealing policies can refuse to unseal
+        secrets for generations older than the one they were sealed against.
+
+        The counter is this base plus the generation number. Raise the base
+        when generation numbers are reset to keep the counter monotonic.
+
+        `null` disables the counter.
+      '';
+    };
+
     pkiBundle = mkOption {
       type = types.nullOr types.path;
       description = "PKI bundle containing db, PK, KEK";
@@ -101,6 +117,148 @@ in
       '';
     };
 
+    fullOsRelease = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Embed the os-release of each generation into its boot entry instead of
+        a minimal one. The fields that Lanzaboote relies on (`ID`,
+        `PRETTY_NAME` and `VERSION_ID`) are still overridden.
+      '';
+    };
+
+    stubLogLevel = mkOption {
+      type = types.nullOr (types.enum [ "off" "error" "warn" "info" ]);
+      default = null;
+      example = "info";
+      description = ''
+        Level up to which the stub mirrors its log to the serial port, if
+        there is one. This helps to debug boots of headless machines that
+        fail before the kernel sets up its own console.
+
+        `null` mirrors the same messages that are printed to the console.
+      '';
+    };
+
+    sbat = mkOption {
+      type = types.nullOr types.lines;
+      default = null;
+      example = ''
+        sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md
+        lanzaboote,1,Lanzaboote,lanzaboote,0.4.1,https://github.com/nix-community/lanzaboote
+        lanzaboote.example,1,Example,lanzaboote,0.4.1,https://example.com
+      '';
+      description = ''
+        SBAT metadata in CSV format that replaces the one the stub was built
+        with. Shim uses it to refuse booting revoked generations of a
+        component.
+
+        `null` keeps the SBAT metadata of the stub.
+      '';
+    };
+
+    splash = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = literalExpression "./splash.bmp";
+      description = ''
+        BMP image that the stub draws centered on the screen instead of its
+        logo. It must be uncompressed with 24 or 32 bits per pixel and at most
+        1 MiB large, because every boot entry carries its own copy.
+
+        `null` prints the logo.
+      '';
+    };
+
+    fatStubs = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Embed the kernel and initrd into every boot entry, producing
+        self-contained unified kernel images, instead of installing them to
+        `EFI/nixos` once and referencing them. This takes considerably more
+        space on the ESP, but works on firmware that has trouble reading
+        additional files.
+      '';
+    };
+
+    installDeviceTrees = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Install the device trees of every generation (the `dtbs` directory of
+        the system toplevel) to `EFI/nixos/dtbs` and record their location in
+        the boot entry. This is useful on ARM boards whose firmware does not
+        provide a device tree.
+      '';
+    };
+
+    forbidCmdlineEditing = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Make the boot entries ignore kernel command lines passed by the
+        bootloader, e.g. edited at the boot menu, even if Secure Boot is not
+        active. With Secure Boot, they are always ignored.
+      '';
+    };
+
+    bootCounting = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      example = 3;
+      description = ''
+        Number of boot attempts after which systemd-boot marks the entries of a
+        newly installed generation as bad and falls back to the previous
+        generation. A boot counts as successful when `boot-complete.target` is
+        reached. `null` disables boot counting.
+      '';
+    };
+
+    initrdCompression = mkOption {
+      type = types.nullOr (types.enum [ "gzip" "zstd" "xz" ]);
+      default = null;
+      example = "zstd";
+      description = ''
+        Compress the initrds with this algorithm before installing them to the
+        ESP, recompressing initrds that are compressed differently. Early cpio
+        archives, e.g. CPU microcode, stay uncompressed. `null` installs the
+        initrds as they are.
+      '';
+    };
+
+    installEfiFallback = mkOption {
+      type = types.bool;
+      default = true;
+      description = ''
+        Whether to install systemd-boot to the removable media path
+        `EFI/BOOT` as well. Disable this to keep another bootloader there in
+        multiboot setups that boot via their own EFI boot entry.
+      '';
+    };
+
+    defaultToLatest = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether to point `default` in `loader.conf` at the boot entry of the
+        latest generation on every installation, instead of using
+        `settings.default`. The other settings are kept.
+      '';
+    };
+
+    espSubdir = mkOption {
+      type = types.strMatching "[A-Za-z0-9_-]+";
+      default = "nixos";
+      description = ''
+        Name of the directory in `EFI` on the ESP that holds the kernels and
+        initrds. The boot entries in `EFI/Linux` are prefixed with it, and
+        garbage collection only touches the files of this installation. Use
+        distinct names, e.g. `nixos-hostA`, if several NixOS installations
+        share an ESP. Names containing `-generation` or `-uki` are rejected.
+      '';
+    };
+
     sortKey = mkOption {
       default = "lanza";
       type = lib.types.str;
@@ -138,11 +296,31 @@ in
           --public-key ${cfg.publicKeyFile} \
           --private-key ${cfg.privateKeyFile} \
           --configuration-limit ${toString configurationLimit} \
+          ${optionalString (cfg.rollbackCounterBase != null) "--rollback-counter-base ${toString cfg.rollbackCounterBase}"} \
+          ${optionalString cfg.fullOsRelease "--full-os-release"} \
+          ${optionalString (cfg.stubLogLevel != null) "--stub-log-level ${cfg.stubLogLevel}"} \
+          ${optionalString (cfg.sbat != null) "--sbat ${pkgs.writeText "sbat.csv" cfg.sbat}"} \
+          ${optionalString (cfg.splash != null) "--splash ${cfg.splash}"} \
+          ${optionalString cfg.fatStubs "--fat"} \
+          ${optionalString cfg.installDeviceTrees "--install-dtb-dir"} \
+          ${optionalString cfg.forbidCmdlineEditing "--forbid-cmdline-editing"} \
+          ${optionalString (cfg.bootCounting != null) "--boot-counting ${toString cfg.bootCounting}"} \
+          ${optionalString (cfg.initrdCompression != null) "--compression ${cfg.initrdCompression}"} \
+          ${optionalString (!cfg.installEfiFallback) "--no-fallback"} \
+          ${optionalString cfg.defaultToLatest "--loader-default-latest"} \
+          --esp-subdir ${cfg.espSubdir} \
           ${config.boot.loader.efi.efiSysMountPoint} \
           /nix/var/nix/profiles/system-*-link
       '';
     };
 
+    # systemd-bless-boot removes the boot counter after a successful boot.
+    systemd.additionalUpstreamSystemUnits = lib.mkIf (cfg.bootCounting != null) [
+      "boot-complete.target"
+      "systemd-bless-boot.service"
+      "systemd-boot-check-no-failures.service"
+    ];
+
     systemd.services.fwupd = lib.mkIf config.services.fwupd.enable {
       # Tell fwupd to load its efi files from /run
       environment.FWUPD_EFIAPPDIR = "/run/fwupd-efi";
@@ -31,6 +31,8 @@ in
 
   systemd-pcrlock = runTest ./lanzaboote/systemd-pcrlock.nix;
   systemd-measured-uki = runTest ./lanzaboote/systemd-measured-uki.nix;
+  rollback-counter = runTest ./lanzaboote/rollback-counter.nix;
+  cmdline-editing = runTest ./lanzaboote/cmdline-editing.nix;
 
   # Stub
   systemd-stub = runTest ./stub/systemd-stub.nix;
@@ -0,0 +1,51 @@
+# Boot the stub through a type 1 entry that passes an additional kernel
+# parameter as load options. Without Secure Boot, the stub uses the passed
+# command line unless editing it is forbidden. With Secure Boot, the passed
+# command line is always ignored, so that it cannot bypass the measured and
+# signed one.
+
+{
+  name = "lanzaboote-cmdline-editing";
+
+  nodes = {
+    editingAllowed = { lib, ... }: {
+      imports = [ ./common/lanzaboote.nix ];
+      virtualisation.useSecureBoot = lib.mkForce false;
+    };
+
+    editingForbidden = { lib, ... }: {
+      imports = [ ./common/lanzaboote.nix ];
+      virtualisation.useSecureBoot = lib.mkForce false;
+      boot.lanzaboote.forbidCmdlineEditing = true;
+    };
+
+    secureBoot = {
+      imports = [ ./common/lanzaboote.nix ];
+    };
+  };
+
+  testScript = ''
+    def boot_with_injected_parameter(machine):
+      machine.start()
+      stub = machine.succeed("cd /boot && ls EFI/Linux/nixos-generation-1-*.efi").strip()
+      cmdline = machine.succeed("cat /proc/cmdline").strip()
+      machine.succeed(f"printf '%s\\n' 'title Injected' 'efi /{stub}' 'options {cmdline} lanzaboote.injected' > /boot/loader/entries/injected.conf")
+      machine.succeed("bootctl set-oneshot injected.conf")
+      machine.succeed("sync")
+      machine.shutdown()
+      machine.start()
+      return machine.succeed("cat /proc/cmdline")
+
+    start_all()
+
+    with subtest("Without Secure Boot, the passed command line is used"):
+      assert "lanzaboote.injected" in boot_with_injected_parameter(editingAllowed)
+
+    with subtest("If editing is forbidden, the passed command line is ignored"):
+      assert "lanzaboote.injected" not in boot_with_injected_parameter(editingForbidden)
+
+    with subtest("With Secure Boot, the passed command line is ignored"):
+      assert "lanzaboote.injected" not in boot_with_injected_parameter(secureBoot)
+      secureBoot.wait_for_console_text("because Secure Boot is active")
+  '';
+}
@@ -13,6 +13,7 @@
     # TODO: the other variables are not yet supported.
     expected_variables = [
       "StubPcrKernelImage"
+      "LanzabooteMeasuredSections"
     ]
 
     # Debug all systemd loader specification GUID EFI variables loaded by the current environment.
@@ -23,5 +24,8 @@
 
     # "Static" parts of the UKI is measured in PCR11
     assert_variable_string("StubPcrKernelImage", "11")
+    # The measured sections are listed in the order they are measured in.
+    assert_variable_string_contains("LanzabooteMeasuredSections", ".osrel")
+    assert_variable_string_contains("LanzabooteMeasuredSections", ".cmdline")
   '';
 }
@@ -0,0 +1,35 @@
+{ pkgs, ... }:
+
+let
+  rollbackCounterBase = 1000;
+in
+{
+  name = "lanzaboote-rollback-counter";
+
+  nodes.machine = {
+    imports = [ ./common/lanzaboote.nix ];
+
+    virtualisation.tpm.enable = true;
+
+    boot.lanzaboote = { inherit rollbackCounterBase; };
+  };
+
+  testScript = ''
+    import hashlib
+    import json
+
+    machine.start()
+
+    with subtest("Check if the rollback counter is embedded"):
+      counter = machine.succeed("${pkgs.binutils-unwrapped}/bin/objcopy -O binary --only-section=.rollback /boot/EFI/Linux/nixos-generation-1-*.efi /dev/stdout")
+      assert counter == "${toString (rollbackCounterBase + 1)}", f"Unexpected rollback counter: {counter}"
+
+    with subtest("Check if the rollback counter is measured"):
+      (status, log_json) = machine.execute("${pkgs.systemd}/lib/systemd/systemd-pcrlock log --json=short")
+      log_data = json.loads(log_json)
+
+      entries = [entry for entry in log_data["log"] if entry["pcr"] == 12 and entry["description"] == "String: Rollback counter"]
+      assert len(entries) == 1, "Failed to find the rollback counter measurement"
+      assert entries[0]["sha256"] == hashlib.sha256(counter.encode()).hexdigest(), "The measurement does not match the rollback counter"
+  '';
+}
@@ -1,35 +1,105 @@
 use std::path::PathBuf;
 
-use anyhow::{bail, Result};
+use anyhow::{Context, Result};
 
 /// Supported system
 #[non_exhaustive]
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Architecture {
     X86,
+    Ia32,
     AArch64,
+    Arm,
+    RiscV64,
 }
 
+/// NixOS system doubles and the architectures they map to.
+const NIXOS_SYSTEMS: [(&str, Architecture); 5] = [
+    ("x86_64-linux", Architecture::X86),
+    ("i686-linux", Architecture::Ia32),
+    ("aarch64-linux", Architecture::AArch64),
+    ("armv7l-linux", Architecture::Arm),
+    ("riscv64-linux", Architecture::RiscV64),
+];
+
 impl Architecture {
+    /// The architecture suffix that UEFI and systemd use in file names, e.g. `x64`.
     pub fn efi_representation(&self) -> &str {
         match self {
             Self::X86 => "x64",
+            Self::Ia32 => "ia32",
             Self::AArch64 => "aa64",
+            Self::Arm => "arm",
+            Self::RiscV64 => "riscv64",
         }
     }
 
     pub fn efi_fallback_filename(&self) -> PathBuf {
         format!("BOOT{}.EFI", self.efi_representation().to_ascii_uppercase()).into()
     }
+
+    /// The machine type in the COFF header of PE binaries for this architecture.
+    pub fn pe_machine(&self) -> u16 {
+        match self {
+            Self::X86 => goblin::pe::header::COFF_MACHINE_X86_64,
+            Self::Ia32 => goblin::pe::header::COFF_MACHINE_X86,
+            Self::AArch64 => goblin::pe::header::COFF_MACHINE_ARM64,
+            Self::Arm => goblin::pe::header::COFF_MACHINE_ARMNT,
+            Self::RiscV64 => goblin::pe::header::COFF_MACHINE_RISCV64,
+        }
+    }
 }
 
 impl Architecture {
+    /// The architecture of PE binaries with the machine type `machine`, if it is supported.
+    pub fn from_pe_machine(machine: u16) -> Option<Self> {
+        NIXOS_SYSTEMS
+            .iter()
+            .map(|(_, architecture)| *architecture)
+            .find(|architecture| architecture.pe_machine() == machine)
+    }
+
     /// Converts from a NixOS system double to a supported system
     pub fn from_nixos_system(system_double: &str) -> Result<Self> {
-        Ok(match system_double {
-            "x86_64-linux" => Self::X86,
-            "aarch64-linux" => Self::AArch64,
-            _ => bail!(format!("Unsupported NixOS system: {}.", system_double)),
-        })
+        NIXOS_SYSTEMS
+            .iter()
+            .find(|(system, _)| *system == system_double)
+            .map(|(_, architecture)| *architecture)
+            .with_context(|| {
+                format!(
+                    "Unsupported NixOS system: {system_double}. Supported systems are: {}.",
+                    NIXOS_SYSTEMS.map(|(system, _)| system).join(", ")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_nixos_systems() -> Result<()> {
+        for (system, efi_representation, efi_fallback_filename) in [
+            ("x86_64-linux", "x64", "BOOTX64.EFI"),
+            ("i686-linux", "ia32", "BOOTIA32.EFI"),
+            ("aarch64-linux", "aa64", "BOOTAA64.EFI"),
+            ("armv7l-linux", "arm", "BOOTARM.EFI"),
+            ("riscv64-linux", "riscv64", "BOOTRISCV64.EFI"),
+        ] {
+            let architecture = Architecture::from_nixos_system(system)?;
+            assert_eq!(architecture.efi_representation(), efi_representation);
+            assert_eq!(
+                architecture.efi_fallback_filename(),
+                PathBuf::from(efi_fallback_filename)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn list_supported_systems_for_unsupported_system() {
+        let error = Architecture::from_nixos_system("mips64el-linux").unwrap_err();
+        assert!(error.to_string().contains("riscv64-linux"));
     }
 }
@@ -2,10 +2,17 @@ use std::path::{Path, PathBuf};
 
 use crate::architecture::Architecture;
 
+/// The default name of the directory in `EFI` that holds the NixOS files.
+pub const DEFAULT_ESP_SUBDIR: &str = "nixos";
+
 /// Generic ESP paths which can be specific to a bootloader
 pub trait EspPaths<const N: usize> {
     /// Build an ESP path structure out of the ESP root directory
-    fn new(esp: impl AsRef<Path>, arch: Architecture) -> Self;
+    ///
+    /// `esp_subdir` is the name of the directory in `EFI` that holds the NixOS files. It also
+    /// namespaces the NixOS files in the Linux path, so that several installations can share an
+    /// ESP.
+    fn new(esp: impl AsRef<Path>, esp_subdir: &str, arch: Architecture) -> Self;
 
     /// Return the used file paths to store as garbage collection roots.
     fn iter(&self) -> std::array::IntoIter<&PathBuf, N>;
@@ -15,4 +22,7 @@ pub trait EspPaths<const N: usize> {
 
     /// Returns the path containing Linux EFI binaries
     fn linux_path(&self) -> &Path;
+
+    /// Returns the prefix of the names of the NixOS EFI binaries in the Linux path
+    fn stub_prefix(&self) -> &str;
 }
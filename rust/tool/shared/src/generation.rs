@@ -20,15 +20,33 @@ pub struct ExtendedBootJson {
     pub lanzaboote_extension: LanzabooteExtension,
 }
 
+impl ExtendedBootJson {
+    /// The initrds of this generation in the order in which they are concatenated.
+    ///
+    /// If the Lanzaboote extension lists initrds, they replace the single initrd of the bootspec.
+    pub fn initrds(&self) -> Vec<PathBuf> {
+        if self.lanzaboote_extension.initrds.is_empty() {
+            self.bootspec.bootspec.initrd.iter().cloned().collect()
+        } else {
+            self.lanzaboote_extension.initrds.clone()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct LanzabooteExtension {
     pub sort_key: String,
+    /// An ordered list of initrds, e.g. a separate early microcode initrd followed by the main
+    /// initrd.
+    #[serde(default)]
+    pub initrds: Vec<PathBuf>,
 }
 
 impl Default for LanzabooteExtension {
     fn default() -> Self {
         Self {
             sort_key: String::from("lanzaboote"),
+            initrds: Vec::new(),
         }
     }
 }
@@ -58,9 +76,14 @@ impl Generation {
         let bootspec_path = link.path.join("boot.json");
         let boot_json: BootJson = fs::read(bootspec_path)
             .context("Failed to read bootspec file")
-            .and_then(|raw| serde_json::from_slice(&raw).context("Failed to read bootspec JSON"))
-            .or_else(|_err| BootJson::synthesize_latest(&link.path)
-                    .context("Failed to read a bootspec (missing bootspec?) and failed to synthesize a valid replacement bootspec."))?;
+            .and_then(|raw| {
+                let mut document: serde_json::Value =
+                    serde_json::from_slice(&raw).context("Failed to read bootspec JSON")?;
+                normalize_specialisations_key(&mut document);
+                serde_json::from_value(document).context("Failed to read bootspec JSON")
+            })
+            .or_else(|err| BootJson::synthesize_latest(&link.path)
+                    .with_context(|| format!("Failed to read a bootspec ({err:#}) and failed to synthesize a valid replacement bootspec")))?;
 
         let bootspec: BootSpec = boot_json.generation.try_into()?;
         let lanzaboote_extension = boot_json
@@ -133,6 +156,36 @@ impl fmt::Display for Generation {
     }
 }
 
+/// Key of the specialisations in a bootspec document.
+const SPECIALISATIONS_KEY: &str = "org.nixos.specialisation.v1";
+
+/// Plural spelling of [`SPECIALISATIONS_KEY`], as used by the bootspec documentation.
+///
+/// The bootspec crate only knows the singular spelling and would silently ignore the
+/// specialisations of documents that use this one.
+const SPECIALISATIONS_KEY_ALIAS: &str = "org.nixos.specialisations.v1";
+
+/// Rename [`SPECIALISATIONS_KEY_ALIAS`] to [`SPECIALISATIONS_KEY`] in a bootspec document and the
+/// documents of its specialisations.
+///
+/// If a document uses both spellings, the singular one wins.
+fn normalize_specialisations_key(document: &mut serde_json::Value) {
+    let Some(document) = document.as_object_mut() else {
+        return;
+    };
+    if let Some(specialisations) = document.remove(SPECIALISATIONS_KEY_ALIAS) {
+        document
+            .entry(SPECIALISATIONS_KEY)
+            .or_insert(specialisations);
+    }
+    if let Some(serde_json::Value::Object(specialisations)) = document.get_mut(SPECIALISATIONS_KEY)
+    {
+        specialisations
+            .values_mut()
+            .for_each(normalize_specialisations_key);
+    }
+}
+
 fn read_build_time(path: &Path) -> Result<Date> {
     let build_time =
         time::OffsetDateTime::from_unix_timestamp(fs::symlink_metadata(path)?.mtime())?.date();
@@ -158,17 +211,60 @@ impl GenerationLink {
             build_time: read_build_time(path.as_ref()).ok(),
         })
     }
+
+    /// A link to a toplevel that is not registered in a profile, e.g. a system closure built from
+    /// a flake, with a synthetic `version`.
+    ///
+    /// The build time is unknown, because store paths do not carry one.
+    pub fn from_toplevel(toplevel: impl AsRef<Path>, version: u64) -> Self {
+        Self {
+            version,
+            path: PathBuf::from(toplevel.as_ref()),
+            build_time: None,
+        }
+    }
+}
+
+/// Discover the generation links of the system profile in a profiles directory, e.g.
+/// `/nix/var/nix/profiles`.
+///
+/// Only entries named `system-<version>-link` are returned, sorted by version.
+pub fn discover_generation_links(profiles_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut links = Vec::new();
+    for entry in fs::read_dir(profiles_dir)
+        .with_context(|| format!("Failed to read the profiles directory {profiles_dir:?}"))?
+    {
+        let path = entry?.path();
+        // Links of other profiles whose names start with `system-`, e.g. `system-foo-1-link`,
+        // are not generations of the system profile.
+        let is_system_link = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("system-")?.strip_suffix("-link"))
+            .is_some_and(|version| {
+                !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit())
+            });
+        if let (true, Ok(version)) = (is_system_link, parse_version(&path)) {
+            links.push((version, path));
+        }
+    }
+    links.sort();
+    Ok(links.into_iter().map(|(_, path)| path).collect())
 }
 
 /// Parse version number from a path.
 ///
-/// Expects a path in the format of "system-{version}-link".
+/// Expects a path in the format of "{profile}-{version}-link", e.g. "system-{version}-link". The
+/// profile name can be anything, including further dashes.
 fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
     let generation_version = path
         .as_ref()
         .file_name()
         .and_then(|x| x.to_str())
-        .and_then(|x| x.split('-').nth(1))
+        .and_then(|x| x.strip_suffix("-link"))
+        .and_then(|x| x.rsplit_once('-'))
+        .map(|(_, version)| version)
+        .filter(|x| x.bytes().all(|b| b.is_ascii_digit()))
         .and_then(|x| x.parse::<u64>().ok())
         .with_context(|| format!("Failed to extract version from: {:?}", path.as_ref()))?;
 
@@ -179,10 +275,103 @@ fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
 mod tests {
     use super::*;
 
+    /// The specialisations of the generation with the bootspec fixture `name`.
+    fn fixture_specialisations(name: &str) -> Result<Vec<String>> {
+        let link = GenerationLink {
+            version: 1,
+            path: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/bootspec")
+                .join(name),
+            build_time: None,
+        };
+        let generation = Generation::from_link(&link)?;
+        let mut names = Vec::new();
+        for (name, specialisation) in &generation.spec.bootspec.specialisations {
+            names.push(name.0.clone());
+            for nested in specialisation.specialisations.keys() {
+                names.push(format!("{}/{}", name.0, nested.0));
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn discover_only_system_generation_links() -> Result<()> {
+        let profiles = tempfile::tempdir()?;
+        for name in [
+            "system-10-link",
+            "system-2-link",
+            "system-foo-1-link",
+            "system-link",
+            "system--link",
+            "system-3-link.tmp",
+            "user-4-link",
+            "system",
+        ] {
+            fs::write(profiles.path().join(name), "")?;
+        }
+
+        assert_eq!(
+            discover_generation_links(profiles.path())?,
+            [
+                profiles.path().join("system-2-link"),
+                profiles.path().join("system-10-link")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accept_both_spellings_of_specialisations() -> Result<()> {
+        let expected = ["gaming", "gaming/vr", "work"];
+        assert_eq!(fixture_specialisations("specialisation")?, expected);
+        assert_eq!(fixture_specialisations("specialisations")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn read_generation_from_toplevel() -> Result<()> {
+        let toplevel =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/bootspec/specialisation");
+        let generation = Generation::from_link(&GenerationLink::from_toplevel(&toplevel, 7))?;
+        assert_eq!(generation.version, 7);
+        assert_eq!(generation.build_time, None);
+        Ok(())
+    }
+
+    #[test]
+    fn discover_system_generation_links() -> Result<()> {
+        let profiles = tempfile::tempdir()?;
+        for name in [
+            "system-10-link",
+            "system-9-link",
+            "system",
+            "system-profiles",
+            "other-1-link",
+            "system-x-link",
+        ] {
+            fs::create_dir(profiles.path().join(name))?;
+        }
+
+        assert_eq!(
+            discover_generation_links(profiles.path())?,
+            [
+                profiles.path().join("system-9-link"),
+                profiles.path().join("system-10-link")
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn parse_version_correctly() {
         let path = Path::new("system-2-link");
         let parsed_version = parse_version(path).unwrap();
         assert_eq!(parsed_version, 2,);
+
+        let path = Path::new("custom-profile-name-12-link");
+        let parsed_version = parse_version(path).unwrap();
+        assert_eq!(parsed_version, 12);
     }
 }
@@ -1,10 +1,17 @@
 use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
 use std::{collections::BTreeMap, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 use crate::generation::Generation;
 
+const WHITESPACE: &str = " \t\n\r";
+const NEWLINE: &str = "\r\n";
+/// Characters that are escaped with a backslash in double-quoted values.
+const SHELL_NEED_ESCAPE: &str = "\"\\`$";
+
 /// An os-release file represented by a BTreeMap.
 ///
 /// This is implemented using a map, so that it can be easily extended in the future (e.g. by
@@ -15,8 +22,118 @@ use crate::generation::Generation;
 /// for testing. Ordered keys allow using snapshot tests.
 pub struct OsRelease(pub BTreeMap<String, String>);
 
+/// A template for a field of the boot entries, e.g. `{label} {version}`.
+///
+/// The placeholders `{version}`, `{label}`, `{date}` and `{specialisation}` are replaced by the
+/// values of the generation. `{{` and `}}` stand for literal braces. Templates are checked when
+/// they are parsed, so that expanding them cannot fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryTemplate(String);
+
+/// The placeholders an [`EntryTemplate`] can contain.
+const ENTRY_TEMPLATE_PLACEHOLDERS: [&str; 4] = ["version", "label", "date", "specialisation"];
+
+impl EntryTemplate {
+    /// Expand the template for a generation.
+    pub fn expand(&self, generation: &Generation) -> String {
+        expand_template(&self.0, |placeholder| match placeholder {
+            "version" => Some(generation.version.to_string()),
+            "label" => Some(generation.spec.bootspec.bootspec.label.clone()),
+            "date" => Some(
+                generation
+                    .build_time
+                    .map_or_else(|| String::from("Unknown"), |date| date.to_string()),
+            ),
+            "specialisation" => Some(
+                generation
+                    .specialisation_name
+                    .as_ref()
+                    .map_or_else(String::new, |name| name.0.clone()),
+            ),
+            _ => None,
+        })
+        .expect("The template was validated when it was parsed")
+    }
+}
+
+impl FromStr for EntryTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        expand_template(template, |placeholder| {
+            ENTRY_TEMPLATE_PLACEHOLDERS
+                .contains(&placeholder)
+                .then(String::new)
+        })?;
+        Ok(Self(template.to_owned()))
+    }
+}
+
+/// Replace the placeholders in `template` with the values returned by `value`.
+///
+/// Fails for unknown placeholders, i.e. if `value` returns `None`, and unmatched braces.
+fn expand_template(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                expanded.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                expanded.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => {
+                            bail!("Unmatched {{ in {template:?}, write {{{{ for a literal brace.")
+                        }
+                    }
+                }
+                match value(&placeholder) {
+                    Some(value) => expanded.push_str(&value),
+                    None => bail!(
+                        "Unknown placeholder {{{placeholder}}} in {template:?}, expected one of {}.",
+                        ENTRY_TEMPLATE_PLACEHOLDERS.map(|p| format!("{{{p}}}")).join(", ")
+                    ),
+                }
+            }
+            '}' => bail!("Unmatched }} in {template:?}, write }}}} for a literal brace."),
+            c => expanded.push(c),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Templates that replace the generated title and sort key of the boot entries.
+#[derive(Debug, Clone, Default)]
+pub struct EntryTemplates {
+    /// Replaces `PRETTY_NAME`, which systemd-boot shows as the title of the entry.
+    pub title: Option<EntryTemplate>,
+    /// Replaces `ID`, which systemd-boot sorts the entries by.
+    pub sort_key: Option<EntryTemplate>,
+}
+
 impl OsRelease {
     pub fn from_generation(generation: &Generation) -> Result<Self> {
+        Self::from_generation_with_templates(generation, &EntryTemplates::default())
+    }
+
+    /// Like [`OsRelease::from_generation`], but with the title and sort key from `templates`
+    /// where they are set.
+    ///
+    /// A title template should produce a unique title for each generation, e.g. by using
+    /// `{version}`, otherwise systemd-boot shows the `VERSION_ID` next to it.
+    pub fn from_generation_with_templates(
+        generation: &Generation,
+        templates: &EntryTemplates,
+    ) -> Result<Self> {
         let mut map = BTreeMap::new();
 
         // Because of a null pointer dereference, `bootctl` segfaults when no ID field is present
@@ -46,8 +163,50 @@ impl OsRelease {
 
         map.insert("VERSION_ID".into(), generation.describe());
 
+        if let Some(title) = &templates.title {
+            map.insert("PRETTY_NAME".into(), title.expand(generation));
+        }
+        if let Some(sort_key) = &templates.sort_key {
+            map.insert("ID".into(), sort_key.expand(generation));
+        }
+
         Ok(Self(map))
     }
+
+    /// Read the os-release of the generation's toplevel and merge the fields from
+    /// `from_generation_with_templates` into it.
+    ///
+    /// This preserves the distribution information (e.g. `NAME` or `HOME_URL`) for consumers of
+    /// the os-release while keeping the fields lanzaboote relies on. If the toplevel does not
+    /// contain an os-release, this falls back to `from_generation`.
+    pub fn from_generation_toplevel(
+        generation: &Generation,
+        templates: &EntryTemplates,
+    ) -> Result<Self> {
+        let required = Self::from_generation_with_templates(generation, templates)?;
+
+        let path = generation
+            .spec
+            .bootspec
+            .bootspec
+            .toplevel
+            .0
+            .join("etc/os-release");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::debug!("No os-release found at {path:?}. Using the minimal os-release.");
+                return Ok(required);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {path:?}")),
+        };
+
+        let mut os_release = Self::from_str(&contents)
+            .with_context(|| format!("Failed to parse os-release at {path:?}"))?;
+        os_release.0.extend(required.0);
+
+        Ok(os_release)
+    }
 }
 
 impl FromStr for OsRelease {
@@ -57,7 +216,16 @@ impl FromStr for OsRelease {
     /// **Beware before reusing this function!**
     ///
     /// This parser might not parse all valid os-release files correctly. It is only designed to
-    /// read the `VERSION` key from the os-release of a systemd-boot binary.
+    /// read the `VERSION` key from the os-release of a systemd-boot binary and the os-release of
+    /// a NixOS toplevel.
+    ///
+    /// The keys and values survive a round trip through [`fmt::Display`], but the following is
+    /// normalized on the way:
+    ///   - comments and lines without `=` are dropped,
+    ///   - the keys are sorted and only the last value of a repeated key is kept,
+    ///   - the quoting of the values changes, see [`fmt::Display`],
+    ///   - escaped newlines (continuation lines) outside of single quotes are joined,
+    ///   - trailing whitespace of unquoted values is stripped.
     fn from_str(value: &str) -> Result<Self> {
         let mut map = BTreeMap::new();
 
@@ -81,9 +249,6 @@ impl FromStr for OsRelease {
         let mut current_value = String::new();
 
         const COMMENTS: &str = "#;";
-        const WHITESPACE: &str = " \t\n\r";
-        const NEWLINE: &str = "\r\n";
-        const SHELL_NEED_ESCAPE: &str = "\"\\`$";
 
         for c in value.chars() {
             match state {
@@ -224,23 +389,64 @@ impl FromStr for OsRelease {
 }
 
 /// Display OsRelease in the format of an os-release file.
+///
+/// Values are written as they are, unless they would be read back differently, e.g. because
+/// they contain a backslash or a newline. These are double-quoted.
 impl fmt::Display for OsRelease {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (key, value) in &self.0 {
-            writeln!(f, "{}={}", key, value)?
+            if needs_quotes(value) {
+                write!(f, "{key}=\"")?;
+                for c in value.chars() {
+                    if SHELL_NEED_ESCAPE.contains(c) {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{c}")?;
+                }
+                writeln!(f, "\"")?
+            } else {
+                writeln!(f, "{}={}", key, value)?
+            }
         }
         Ok(())
     }
 }
 
+/// Whether a value has to be quoted to be read back as it is by [`OsRelease::from_str`].
+fn needs_quotes(value: &str) -> bool {
+    value.starts_with(|c| WHITESPACE.contains(c) || c == '\'' || c == '"')
+        || value.ends_with(|c| WHITESPACE.contains(c))
+        || value.contains(|c| c == '\\' || NEWLINE.contains(c))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::ffi::CStr;
+
+    #[test]
+    fn validate_entry_templates() {
+        assert!(EntryTemplate::from_str("{label} {version} ({date}) {specialisation}").is_ok());
+        assert!(EntryTemplate::from_str("{{literal}} braces").is_ok());
+        assert!(EntryTemplate::from_str("").is_ok());
+        assert!(EntryTemplate::from_str("{hostname}").is_err());
+        assert!(EntryTemplate::from_str("{version").is_err());
+        assert!(EntryTemplate::from_str("version}").is_err());
+    }
+
+    #[test]
+    fn expand_entry_templates() -> Result<()> {
+        let value = |placeholder: &str| (placeholder == "version").then(|| String::from("42"));
+        assert_eq!(
+            expand_template("NixOS {version} {{x}}", value)?,
+            "NixOS 42 {x}"
+        );
+        assert!(expand_template("{label}", value).is_err());
+        Ok(())
+    }
 
     #[test]
     fn parses_correctly_from_str() -> Result<()> {
-        let os_release_cstr = CStr::from_bytes_with_nul(b"ID=systemd-boot\nVERSION=\"252.1\"\n\0")?;
+        let os_release_cstr = c"ID=systemd-boot\nVERSION=\"252.1\"\n";
         let os_release_str = os_release_cstr.to_str()?;
         let os_release = OsRelease::from_str(os_release_str)?;
 
@@ -277,4 +483,66 @@ mod tests {
 
         Ok(())
     }
+    /// The os-release of a NixOS toplevel.
+    const NIXOS_OS_RELEASE: &str = r#"ANSI_COLOR="1;34"
+BUG_REPORT_URL="https://github.com/NixOS/nixpkgs/issues"
+BUILD_ID="24.05.20240605.abcdef0"
+DOCUMENTATION_URL="https://nixos.org/learn.html"
+HOME_URL="https://nixos.org/"
+ID=nixos
+IMAGE_ID=""
+IMAGE_VERSION=""
+LOGO="nix-snowflake"
+NAME=NixOS
+PRETTY_NAME="NixOS 24.05 (Uakari)"
+SUPPORT_URL="https://nixos.org/community.html"
+VERSION="24.05 (Uakari)"
+VERSION_CODENAME=uakari
+VERSION_ID="24.05"
+"#;
+
+    fn assert_round_trip(os_release: &str) -> Result<()> {
+        let parsed = OsRelease::from_str(os_release)?;
+        let reparsed = OsRelease::from_str(&parsed.to_string())?;
+        assert_eq!(reparsed.0, parsed.0);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_real_os_releases() -> Result<()> {
+        assert_round_trip("ID=systemd-boot\nVERSION=\"255.4\"\n")?;
+        assert_round_trip(NIXOS_OS_RELEASE)?;
+
+        let os_release = OsRelease::from_str(NIXOS_OS_RELEASE)?;
+        assert_eq!(os_release.0["PRETTY_NAME"], "NixOS 24.05 (Uakari)");
+        assert_eq!(os_release.0["IMAGE_ID"], "");
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_special_values() -> Result<()> {
+        let teststring = r#"
+            MULTILINE="first
+second"
+            CONTINUATION="first \
+second"
+            UNQUOTED_CONTINUATION=first\
+second
+            BACKSLASH="C:\\nixos"
+            SINGLE_QUOTED='\n $HOME'
+            LEADING_QUOTE=\"1.2
+            LEADING_WHITESPACE="  indented"
+            TRAILING_WHITESPACE="trailing  "
+            SHELL_CHARACTERS="`echo` $HOME"
+        "#;
+        assert_round_trip(teststring)?;
+
+        let os_release = OsRelease::from_str(teststring)?;
+        assert_eq!(os_release.0["MULTILINE"], "first\nsecond");
+        assert_eq!(os_release.0["CONTINUATION"], "first second");
+        assert_eq!(os_release.0["UNQUOTED_CONTINUATION"], "firstsecond");
+        assert_eq!(os_release.0["BACKSLASH"], "C:\\nixos");
+        assert_eq!(os_release.0["SINGLE_QUOTED"], "\\n $HOME");
+        Ok(())
+    }
 }
@@ -1,39 +1,132 @@
 use std::ffi::OsString;
 use std::fs;
+use std::io::Read;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use goblin::pe::PE;
 use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
+use x509_cert::der::Decode;
+use x509_cert::Certificate;
 
+use crate::architecture::Architecture;
 use crate::utils::{file_hash, tmpname, SecureTempDirExt};
 
+/// The default maximum length of a UEFI path embedded into the stub, in UTF-16 code units.
+///
+/// The stub converts the embedded paths to `CString16`s before opening them. Some firmware
+/// implementations fail to open files with longer paths.
+pub const DEFAULT_MAX_UEFI_PATH_LENGTH: usize = 255;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StubParameters {
     pub lanzaboote_store_path: PathBuf,
     pub kernel_cmdline: Vec<String>,
     pub os_release_contents: Vec<u8>,
     pub kernel_store_path: PathBuf,
-    pub initrd_store_path: PathBuf,
+    /// `None` for a generation without initrd, whose image has no `.initrd` and `.initrdh`
+    /// sections.
+    pub initrd_store_path: Option<PathBuf>,
     /// Kernel path rooted at the ESP
     /// i.e. if you refer to /boot/efi/EFI/NixOS/kernel.efi
     /// this gets turned into \\EFI\\NixOS\\kernel.efi as a UTF-16 string
     /// at assembling time.
     pub kernel_path_at_esp: String,
     /// Same as kernel.
+    pub initrd_path_at_esp: Option<String>,
+    /// Monotonic anti-rollback counter, embedded as `.rollback` section and measured by the stub.
+    pub rollback_counter: Option<u64>,
+    /// Kernel and initrd the stub boots if it cannot read its own.
+    pub fallback: Option<FallbackFiles>,
+    /// Level up to which the stub mirrors its log to the serial port, embedded as `.loglevel`
+    /// section.
+    pub log_level: Option<String>,
+    /// SBAT metadata that replaces the `.sbat` section the stub was built with.
+    pub sbat: Option<Vec<u8>>,
+    /// BMP image the stub draws instead of its logo, embedded as `.splash` section.
+    pub splash: Option<Vec<u8>>,
+    /// Embed the kernel and initrd instead of their paths and hashes.
+    pub fat: bool,
+    /// Embed the kernel and initrd of a fat image zstd-compressed, marked by a `.compressed`
+    /// section.
+    #[serde(default)]
+    pub compressed_sections: bool,
+    /// Name of the specialisation, embedded as `.special` section and exported by the stub.
+    pub specialisation: Option<String>,
+    /// Directory with the device trees of the generation rooted at the ESP, embedded as
+    /// `.dtbdir` section.
+    pub dtb_dir_at_esp: Option<String>,
+    /// Restrictions on the kernel command line, embedded as `.cmdflags` section, e.g.
+    /// [`CMDLINE_FORBID_EDITING`].
+    pub cmdline_flags: Option<u32>,
+}
+
+/// Algorithm of the hashes in the `.linuxh`, `.initrdh`, `.linuxh2` and `.initrh2` sections,
+/// embedded as `.hashalg` section. Stubs that do not know the algorithm refuse to verify them.
+const HASH_ALGORITHM: &str = "sha256";
+
+/// Compression of the `.linux` and `.initrd` sections of a fat image, embedded as `.compressed`
+/// section.
+const COMPRESSED_SECTIONS: &str = "zstd";
+
+/// Flag of the `.cmdflags` section: the stub never uses a command line passed by the bootloader,
+/// even if Secure Boot is not active.
+pub const CMDLINE_FORBID_EDITING: u32 = 1 << 0;
+
+/// The kernel and initrd of another generation that is already installed on the ESP.
+///
+/// They are embedded as `.linux2`, `.linuxh2`, `.initrd2` and `.initrh2` sections, in the same
+/// format as the `.linux`, `.linuxh`, `.initrd` and `.initrdh` sections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FallbackFiles {
+    pub kernel_path_at_esp: String,
+    pub kernel_hash: Vec<u8>,
     pub initrd_path_at_esp: String,
+    pub initrd_hash: Vec<u8>,
+}
+
+impl FallbackFiles {
+    /// Use the kernel and initrd of an installed stub as fallback.
+    pub fn from_stub(stub: &[u8]) -> Result<Self> {
+        let section = |name| {
+            read_section_data(stub, name).with_context(|| format!("Missing {name} section."))
+        };
+        Ok(Self {
+            kernel_path_at_esp: std::str::from_utf8(section(".linux")?)?.to_owned(),
+            kernel_hash: section(".linuxh")?.to_vec(),
+            initrd_path_at_esp: std::str::from_utf8(section(".initrd")?)?.to_owned(),
+            initrd_hash: section(".initrdh")?.to_vec(),
+        })
+    }
+
+    /// Read the fallback embedded into a stub, if any.
+    pub fn embedded_in(stub: &[u8]) -> Option<Self> {
+        Some(Self {
+            kernel_path_at_esp: std::str::from_utf8(read_section_data(stub, ".linux2")?)
+                .ok()?
+                .to_owned(),
+            kernel_hash: read_section_data(stub, ".linuxh2")?.to_vec(),
+            initrd_path_at_esp: std::str::from_utf8(read_section_data(stub, ".initrd2")?)
+                .ok()?
+                .to_owned(),
+            initrd_hash: read_section_data(stub, ".initrh2")?.to_vec(),
+        })
+    }
 }
 
 impl StubParameters {
+    /// Parameters for a thin image that references the kernel and initrd on the ESP.
+    ///
+    /// `initrd_path` and `initrd_target` are `None` for a generation without initrd.
     pub fn new(
         lanzaboote_stub: &Path,
         kernel_path: &Path,
-        initrd_path: &Path,
+        initrd_path: Option<&Path>,
         kernel_target: &Path,
-        initrd_target: &Path,
+        initrd_target: Option<&Path>,
         esp: &Path,
     ) -> Result<Self> {
         // Resolve maximally those paths
@@ -43,14 +136,57 @@ impl StubParameters {
         Ok(Self {
             lanzaboote_store_path: lanzaboote_stub.to_path_buf(),
             kernel_store_path: kernel_path.to_path_buf(),
-            initrd_store_path: initrd_path.to_path_buf(),
-            kernel_path_at_esp: esp_relative_uefi_path(esp, kernel_target)?,
-            initrd_path_at_esp: esp_relative_uefi_path(esp, initrd_target)?,
+            initrd_store_path: initrd_path.map(Path::to_path_buf),
+            kernel_path_at_esp: esp_relative_uefi_path(
+                esp,
+                kernel_target,
+                DEFAULT_MAX_UEFI_PATH_LENGTH,
+            )?,
+            initrd_path_at_esp: initrd_target
+                .map(|target| esp_relative_uefi_path(esp, target, DEFAULT_MAX_UEFI_PATH_LENGTH))
+                .transpose()?,
             kernel_cmdline: Vec::new(),
             os_release_contents: Vec::new(),
+            rollback_counter: None,
+            fallback: None,
+            log_level: None,
+            sbat: None,
+            splash: None,
+            fat: false,
+            compressed_sections: false,
+            specialisation: None,
+            dtb_dir_at_esp: None,
+            cmdline_flags: None,
         })
     }
 
+    /// Parameters for a fat image that contains the kernel and initrd itself, like a UKI.
+    ///
+    /// `lanzaboote_stub` must be the fat variant of the stub, which reads the kernel and initrd
+    /// from its `.linux` and `.initrd` sections. `initrd_path` is `None` for a generation without
+    /// initrd.
+    pub fn new_fat(lanzaboote_stub: &Path, kernel_path: &Path, initrd_path: Option<&Path>) -> Self {
+        Self {
+            lanzaboote_store_path: lanzaboote_stub.to_path_buf(),
+            kernel_store_path: kernel_path.to_path_buf(),
+            initrd_store_path: initrd_path.map(Path::to_path_buf),
+            kernel_path_at_esp: String::new(),
+            initrd_path_at_esp: None,
+            kernel_cmdline: Vec::new(),
+            os_release_contents: Vec::new(),
+            rollback_counter: None,
+            fallback: None,
+            log_level: None,
+            sbat: None,
+            splash: None,
+            fat: true,
+            compressed_sections: false,
+            specialisation: None,
+            dtb_dir_at_esp: None,
+            cmdline_flags: None,
+        }
+    }
+
     pub fn with_os_release_contents(mut self, os_release_contents: &[u8]) -> Self {
         self.os_release_contents = os_release_contents.to_vec();
         self
@@ -60,6 +196,53 @@ impl StubParameters {
         self.kernel_cmdline = cmdline.to_vec();
         self
     }
+
+    pub fn with_rollback_counter(mut self, rollback_counter: Option<u64>) -> Self {
+        self.rollback_counter = rollback_counter;
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Option<FallbackFiles>) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    pub fn with_log_level(mut self, log_level: Option<String>) -> Self {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn with_sbat(mut self, sbat: Option<Vec<u8>>) -> Self {
+        self.sbat = sbat;
+        self
+    }
+
+    pub fn with_splash(mut self, splash: Option<Vec<u8>>) -> Self {
+        self.splash = splash;
+        self
+    }
+
+    pub fn with_specialisation(mut self, specialisation: Option<String>) -> Self {
+        self.specialisation = specialisation;
+        self
+    }
+
+    pub fn with_dtb_dir(mut self, dtb_dir_at_esp: Option<String>) -> Self {
+        self.dtb_dir_at_esp = dtb_dir_at_esp;
+        self
+    }
+
+    pub fn with_cmdline_flags(mut self, cmdline_flags: Option<u32>) -> Self {
+        self.cmdline_flags = cmdline_flags;
+        self
+    }
+
+    /// Compress the kernel and initrd of a fat image with zstd. The stub must be built with the
+    /// `compressed-sections` feature to boot the image.
+    pub fn with_compressed_sections(mut self, compressed_sections: bool) -> Self {
+        self.compressed_sections = compressed_sections;
+        self
+    }
 }
 
 /// Performs the evil operation
@@ -86,6 +269,9 @@ pub fn append_initrd_secrets(
 }
 
 /// Assemble a lanzaboote image.
+///
+/// A thin image references the kernel and initrd on the ESP by path and hash. A fat image
+/// contains them instead, see [`StubParameters::new_fat`].
 pub fn lanzaboote_image(
     // Because the returned path of this function is inside the tempdir as well, the tempdir must
     // live longer than the function. This is why it cannot be created inside the function.
@@ -94,46 +280,167 @@ pub fn lanzaboote_image(
 ) -> Result<PathBuf> {
     // objcopy can only copy files into the PE binary. That's why we
     // have to write the contents of some bootspec properties to disk.
+    let mut sections = SectionLayout::new(&stub_parameters.lanzaboote_store_path)?;
+
+    let os_release = tempdir.write_secure_file(&stub_parameters.os_release_contents)?;
+    sections.add(".osrel", os_release)?;
     let kernel_cmdline_file =
         tempdir.write_secure_file(stub_parameters.kernel_cmdline.join(" "))?;
+    sections.add(".cmdline", kernel_cmdline_file)?;
 
-    let kernel_path_file = tempdir.write_secure_file(&stub_parameters.kernel_path_at_esp)?;
-    let kernel_hash_file =
-        tempdir.write_secure_file(file_hash(&stub_parameters.kernel_store_path)?.as_slice())?;
+    if stub_parameters.fat && stub_parameters.compressed_sections {
+        for (name, path) in [
+            (".initrd", stub_parameters.initrd_store_path.as_ref()),
+            (".linux", Some(&stub_parameters.kernel_store_path)),
+        ] {
+            let Some(path) = path else {
+                continue;
+            };
+            let file = fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+            let compressed = zstd::encode_all(file, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .with_context(|| format!("Failed to compress {path:?}"))?;
+            sections.add(name, tempdir.write_secure_file(compressed)?)?;
+        }
+        sections.add(
+            ".compressed",
+            tempdir.write_secure_file(COMPRESSED_SECTIONS)?,
+        )?;
+    } else if stub_parameters.fat {
+        if let Some(initrd) = &stub_parameters.initrd_store_path {
+            sections.add(".initrd", initrd)?;
+        }
+        sections.add(".linux", &stub_parameters.kernel_store_path)?;
+    } else {
+        if let (Some(initrd), Some(initrd_path_at_esp)) = (
+            &stub_parameters.initrd_store_path,
+            &stub_parameters.initrd_path_at_esp,
+        ) {
+            let initrd_path_file = tempdir.write_secure_file(initrd_path_at_esp)?;
+            sections.add(".initrd", initrd_path_file)?;
+            let initrd_hash_file = tempdir.write_secure_file(file_hash(initrd)?.as_slice())?;
+            sections.add(".initrdh", initrd_hash_file)?;
+        }
+        let kernel_path_file = tempdir.write_secure_file(&stub_parameters.kernel_path_at_esp)?;
+        sections.add(".linux", kernel_path_file)?;
+        let kernel_hash_file =
+            tempdir.write_secure_file(file_hash(&stub_parameters.kernel_store_path)?.as_slice())?;
+        sections.add(".linuxh", kernel_hash_file)?;
+        let hash_algorithm_file = tempdir.write_secure_file(HASH_ALGORITHM)?;
+        sections.add(".hashalg", hash_algorithm_file)?;
+    }
 
-    let initrd_path_file = tempdir.write_secure_file(&stub_parameters.initrd_path_at_esp)?;
-    let initrd_hash_file =
-        tempdir.write_secure_file(file_hash(&stub_parameters.initrd_store_path)?.as_slice())?;
+    if let Some(rollback_counter) = stub_parameters.rollback_counter {
+        // The counter is stored as decimal ASCII, so that its measurement is easy to predict.
+        let rollback_counter_file = tempdir.write_secure_file(rollback_counter.to_string())?;
+        sections.add(".rollback", rollback_counter_file)?;
+    }
 
-    let os_release = tempdir.write_secure_file(&stub_parameters.os_release_contents)?;
-    let os_release_offs = stub_offset(&stub_parameters.lanzaboote_store_path)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(&os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrd", initrd_path_file, initrd_path_offs),
-        s(".linux", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".linuxh", kernel_hash_file, kernel_hash_offs),
-    ];
+    if let Some(cmdline_flags) = stub_parameters.cmdline_flags {
+        // Like the rollback counter, the flags are stored as decimal ASCII.
+        let cmdline_flags_file = tempdir.write_secure_file(cmdline_flags.to_string())?;
+        sections.add(".cmdflags", cmdline_flags_file)?;
+    }
+
+    if let Some(specialisation) = &stub_parameters.specialisation {
+        let specialisation_file = tempdir.write_secure_file(specialisation)?;
+        sections.add(".special", specialisation_file)?;
+    }
+
+    if let Some(dtb_dir) = &stub_parameters.dtb_dir_at_esp {
+        let dtb_dir_file = tempdir.write_secure_file(dtb_dir)?;
+        sections.add(".dtbdir", dtb_dir_file)?;
+    }
+
+    if let Some(log_level) = &stub_parameters.log_level {
+        let log_level_file = tempdir.write_secure_file(log_level)?;
+        sections.add(".loglevel", log_level_file)?;
+    }
+
+    if let Some(sbat) = &stub_parameters.sbat {
+        let sbat_file = tempdir.write_secure_file(sbat)?;
+        sections.add(".sbat", sbat_file)?.replace = true;
+    }
+
+    if let Some(splash) = &stub_parameters.splash {
+        let splash_file = tempdir.write_secure_file(splash)?;
+        sections.add(".splash", splash_file)?;
+    }
+
+    if let Some(fallback) = &stub_parameters.fallback {
+        for (name, contents) in [
+            (".linux2", fallback.kernel_path_at_esp.as_bytes()),
+            (".linuxh2", &fallback.kernel_hash),
+            (".initrd2", fallback.initrd_path_at_esp.as_bytes()),
+            (".initrh2", &fallback.initrd_hash),
+        ] {
+            sections.add(name, tempdir.write_secure_file(contents)?)?;
+        }
+    }
 
     let image_path = tempdir.path().join(tmpname());
     wrap_in_pe(
         &stub_parameters.lanzaboote_store_path,
-        sections,
+        sections.sections,
         &image_path,
     )?;
+    log::debug!(
+        "Assembled image of {} bytes, sections end at {:#x}.",
+        file_size(&image_path)?,
+        sections.next_offs
+    );
     Ok(image_path)
 }
 
-/// Take a PE binary stub and attach sections to it.
+/// Replace the section `name` of a PE binary, e.g. the os-release of a UKI.
 ///
-/// The resulting binary is then written to a newly created file at the provided output path.
+/// The new section is appended behind the other sections. Returns the path of the new binary in
+/// `tempdir`.
+pub fn with_replaced_section(
+    tempdir: &TempDir,
+    binary: &Path,
+    name: &'static str,
+    contents: &[u8],
+) -> Result<PathBuf> {
+    let mut sections = SectionLayout::new(binary)?;
+    sections
+        .add(name, tempdir.write_secure_file(contents)?)?
+        .replace = true;
+    let output = tempdir.path().join(tmpname());
+    wrap_in_pe(binary, sections.sections, &output)?;
+    Ok(output)
+}
+
+/// Sections that are appended one after another behind the sections of a stub.
+struct SectionLayout {
+    sections: Vec<Section>,
+    next_offs: u64,
+}
+
+impl SectionLayout {
+    fn new(stub: &Path) -> Result<Self> {
+        let next_offs = stub_offset(stub)?;
+        log::debug!("Sections of {stub:?} end at {next_offs:#x}.");
+        Ok(Self {
+            sections: Vec::new(),
+            next_offs,
+        })
+    }
+
+    /// Append a section with the contents of `file_path`.
+    fn add(&mut self, name: &'static str, file_path: impl AsRef<Path>) -> Result<&mut Section> {
+        let size = file_size(&file_path)?;
+        // Overlapping sections make the stub fail to boot, so the layout helps debugging.
+        log::debug!(
+            "Section {name:<9} at {:#x}..{:#x} ({size} bytes)",
+            self.next_offs,
+            self.next_offs + size
+        );
+        self.sections.push(s(name, file_path, self.next_offs));
+        self.next_offs += size;
+        Ok(self.sections.last_mut().expect("A section was just added"))
+    }
+}
+
 fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()> {
     let mut args: Vec<OsString> = sections.iter().flat_map(Section::to_objcopy).collect();
 
@@ -152,6 +459,10 @@ fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()>
         ));
     }
 
+    let mut pe = fs::read(output).with_context(|| format!("Failed to read {output:?}"))?;
+    clear_timestamp(&mut pe).context("Failed to clear the timestamp of the image.")?;
+    fs::write(output, pe).with_context(|| format!("Failed to write {output:?}"))?;
+
     Ok(())
 }
 
@@ -159,23 +470,31 @@ struct Section {
     name: &'static str,
     file_path: PathBuf,
     offset: u64,
+    /// Remove a section with the same name that the stub already contains.
+    replace: bool,
 }
 
 impl Section {
     /// Create objcopy `-add-section` command line parameters that
-    /// attach the section to a PE file.
+    /// attach the section to a PE file, preceded by `--remove-section`
+    /// if the section replaces an existing one.
     fn to_objcopy(&self) -> Vec<OsString> {
         // There is unfortunately no format! for OsString, so we cannot
         // just format a path.
         let mut map_str: OsString = format!("{}=", self.name).into();
         map_str.push(&self.file_path);
 
-        vec![
+        let mut args = Vec::new();
+        if self.replace {
+            args.extend([OsString::from("--remove-section"), self.name.into()]);
+        }
+        args.extend([
             OsString::from("--add-section"),
             map_str,
             OsString::from("--change-section-vma"),
             format!("{}={:#x}", self.name, self.offset).into(),
-        ]
+        ]);
+        args
     }
 }
 
@@ -184,16 +503,350 @@ fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
         name,
         file_path: file_path.as_ref().into(),
         offset,
+        replace: false,
+    }
+}
+
+/// Check that SBAT metadata is in the CSV format that shim expects.
+///
+/// The first entry must describe the SBAT format version itself and every entry needs the six
+/// fields component name, generation, vendor name, vendor package name, vendor version and URL.
+pub fn validate_sbat(sbat: &[u8]) -> Result<()> {
+    let sbat = std::str::from_utf8(sbat).context("SBAT metadata is not valid UTF-8.")?;
+    if !sbat.starts_with("sbat,") {
+        bail!("SBAT metadata must start with the sbat entry.");
+    }
+    for line in sbat.lines() {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 6 {
+            bail!("SBAT entry does not have 6 fields: {line}");
+        }
+        if fields[1].parse::<u32>().is_err() {
+            bail!("SBAT entry has an invalid generation: {line}");
+        }
     }
+    Ok(())
+}
+
+/// The largest splash image that is embedded into the stubs.
+///
+/// Every stub carries its own copy of the image, so it counts against the space on the ESP once
+/// per generation.
+pub const MAX_SPLASH_SIZE: usize = 1024 * 1024;
+
+/// Check that a splash image is a BMP that the stub can draw.
+///
+/// The stub only decodes uncompressed BMPs with 24 or 32 bits per pixel.
+pub fn validate_splash(splash: &[u8]) -> Result<()> {
+    if splash.len() > MAX_SPLASH_SIZE {
+        bail!(
+            "The splash image has {} bytes, but at most {MAX_SPLASH_SIZE} are allowed.",
+            splash.len()
+        );
+    }
+    if splash.len() < 54 || !splash.starts_with(b"BM") {
+        bail!("The splash image is not a BMP file.");
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([splash[offset], splash[offset + 1]]);
+    let u32_at = |offset: usize| u32::from_le_bytes(splash[offset..offset + 4].try_into().unwrap());
+    let bits_per_pixel = u16_at(28);
+    if ![24, 32].contains(&bits_per_pixel) {
+        bail!("The splash image has {bits_per_pixel} bits per pixel instead of 24 or 32.");
+    }
+    if u32_at(30) != 0 {
+        bail!("The splash image is compressed.");
+    }
+    Ok(())
+}
+
+/// The PE subsystem of EFI applications like EFI stub kernels.
+const IMAGE_SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+
+/// Check that a kernel is an EFI stub kernel for `architecture`.
+///
+/// The firmware refuses to load anything else, but this would only show up at boot.
+pub fn validate_kernel(kernel: &[u8], architecture: Architecture) -> Result<()> {
+    let pe = PE::parse(kernel)
+        .context("The kernel is not a PE binary. Was it built with CONFIG_EFI_STUB?")?;
+    validate_efi_application(&pe, architecture, "kernel")
+}
+
+/// Check that a lanzaboote stub is an EFI application for `architecture` that contains code.
+///
+/// A wrong `LANZABOOTE_STUB` would otherwise result in stubs that do not boot.
+pub fn validate_stub(stub: &[u8], architecture: Architecture) -> Result<()> {
+    let pe = PE::parse(stub).context("The stub is not a PE binary.")?;
+    validate_efi_application(&pe, architecture, "stub")?;
+
+    if !pe
+        .sections
+        .iter()
+        .any(|section| section.name().ok() == Some(".text"))
+    {
+        bail!("The stub has no .text section.");
+    }
+    Ok(())
+}
+
+/// Check the machine type and subsystem of a PE binary, called `name` in the errors.
+fn validate_efi_application(pe: &PE, architecture: Architecture, name: &str) -> Result<()> {
+    let machine = pe.header.coff_header.machine;
+    if machine != architecture.pe_machine() {
+        let detected = match Architecture::from_pe_machine(machine) {
+            Some(detected) => format!("{detected:?}"),
+            None => format!("PE machine type {machine:#06x}"),
+        };
+        bail!(
+            "The {name} is built for {detected} instead of {architecture:?} ({:#06x}).",
+            architecture.pe_machine()
+        );
+    }
+
+    let subsystem = pe
+        .header
+        .optional_header
+        .map(|header| header.windows_fields.subsystem);
+    if subsystem != Some(IMAGE_SUBSYSTEM_EFI_APPLICATION) {
+        bail!("The {name} is not an EFI application.");
+    }
+
+    Ok(())
+}
+
+/// Whether the file at `path` starts with the DOS header of a PE binary.
+pub fn is_pe_file(path: &Path) -> Result<bool> {
+    let mut magic = Vec::with_capacity(2);
+    fs::File::open(path)
+        .with_context(|| format!("Failed to open {path:?}"))?
+        .take(2)
+        .read_to_end(&mut magic)?;
+    Ok(magic == b"MZ")
+}
+
+/// Index of the certificate table among the data directories of the optional header.
+const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+
+/// `wCertificateType` of an Authenticode signature in the certificate table.
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: usize = 2;
+
+/// Find the certificate table of a PE binary.
+///
+/// Returns the offset of its data directory entry and the offset of the table itself, or `None`
+/// if the binary has no signatures.
+fn certificate_table(pe: &[u8]) -> Result<Option<(usize, usize)>> {
+    let u32_at = |offset: usize| u32_at(pe, offset);
+
+    let pe_header = pe_header(pe)?;
+    // The optional header follows the signature and the 20 byte COFF header.
+    let optional_header = pe_header + 24;
+    let data_directories = match pe.get(optional_header..optional_header + 2) {
+        Some([0x0b, 0x01]) => optional_header + 96,
+        Some([0x0b, 0x02]) => optional_header + 112,
+        _ => bail!("Unknown magic of the PE optional header."),
+    };
+    if u32_at(data_directories - 4)? <= IMAGE_DIRECTORY_ENTRY_SECURITY {
+        return Ok(None);
+    }
+
+    let entry = data_directories + 8 * IMAGE_DIRECTORY_ENTRY_SECURITY;
+    let (table_offset, table_size) = (u32_at(entry)?, u32_at(entry + 4)?);
+    if table_size == 0 {
+        return Ok(None);
+    }
+    if table_offset < entry + 8 || table_offset > pe.len() {
+        bail!("The certificate table at offset {table_offset:#x} is outside of the PE binary.");
+    }
+    Ok(Some((entry, table_offset)))
+}
+
+/// Read the little-endian `u32` at `offset` of the headers of a PE binary.
+fn u32_at(pe: &[u8], offset: usize) -> Result<usize> {
+    let bytes = pe
+        .get(offset..offset + 4)
+        .context("The PE headers are truncated.")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+/// Find the PE signature, which the COFF header follows.
+fn pe_header(pe: &[u8]) -> Result<usize> {
+    let pe_header = u32_at(pe, 0x3c)?;
+    if pe.get(pe_header..pe_header + 4) != Some(b"PE\0\0") {
+        bail!("Not a PE binary.");
+    }
+    Ok(pe_header)
+}
+
+/// Clear the timestamp in the COFF header of a PE binary and update its checksum.
+///
+/// objcopy stamps the binaries it writes with the current time, so assembling the same image
+/// twice would give different binaries.
+fn clear_timestamp(pe: &mut [u8]) -> Result<()> {
+    let pe_header = pe_header(pe)?;
+    // The timestamp follows the machine type and the number of sections.
+    let timestamp = pe_header + 8;
+    // The checksum is at the same offset in the optional headers of PE32 and PE32+ binaries.
+    let checksum = pe_header + 24 + 64;
+    if pe.len() < checksum + 4 {
+        bail!("The PE headers are truncated.");
+    }
+
+    pe[timestamp..timestamp + 4].fill(0);
+    // A zero checksum is not verified, so it stays zero.
+    if pe[checksum..checksum + 4] != [0; 4] {
+        let value = pe_checksum(pe, checksum);
+        pe[checksum..checksum + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Compute the checksum of a PE binary like `CheckSumMappedFile`, skipping the checksum field at
+/// `checksum_offset`.
+fn pe_checksum(pe: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u32 = 0;
+    for (index, word) in pe.chunks(2).enumerate() {
+        if (checksum_offset..checksum_offset + 4).contains(&(2 * index)) {
+            continue;
+        }
+        let word = match *word {
+            [low, high] => u16::from_le_bytes([low, high]),
+            [low] => u16::from(low),
+            _ => unreachable!("Chunks have one or two bytes"),
+        };
+        sum += u32::from(word);
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    ((sum & 0xffff) + (sum >> 16)).wrapping_add(pe.len() as u32)
+}
+
+/// Whether a PE binary carries any Authenticode signature, regardless of who signed it.
+pub fn has_signatures(pe: &[u8]) -> Result<bool> {
+    Ok(certificate_table(pe)?.is_some())
+}
+
+/// The subjects of the certificates embedded in the Authenticode signatures of a PE binary.
+///
+/// `sbsign` only embeds the signing certificate, so this tells who signed the binary. The
+/// signatures are not verified, use [`crate::signature::Signer::verify`] for that.
+pub fn signature_subjects(pe: &[u8]) -> Result<Vec<String>> {
+    let Some((entry, table_offset)) = certificate_table(pe)? else {
+        return Ok(Vec::new());
+    };
+    let table_end = table_offset + u32_at(pe, entry + 4)?;
+
+    let mut subjects = Vec::new();
+    let mut offset = table_offset;
+    while offset < table_end {
+        // Each entry is a `WIN_CERTIFICATE`, aligned to 8 bytes.
+        let length = u32_at(pe, offset)?;
+        let certificate_type = u32_at(pe, offset + 4)? >> 16;
+        let data = (length >= 8)
+            .then(|| pe.get(offset + 8..offset + length))
+            .flatten()
+            .context("The certificate table is truncated.")?;
+        if certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            for certificate in
+                pkcs7_certificates(data).context("Failed to parse the PKCS#7 signature.")?
+            {
+                let certificate = Certificate::from_der(certificate)
+                    .context("Failed to parse the certificate of a signature.")?;
+                subjects.push(certificate.tbs_certificate.subject.to_string());
+            }
+        }
+        offset += length.next_multiple_of(8);
+    }
+    Ok(subjects)
+}
+
+/// The DER encoded certificates of a PKCS#7 `ContentInfo` with `SignedData`.
+fn pkcs7_certificates(der: &[u8]) -> Option<Vec<&[u8]>> {
+    let (0x30, content_info, _) = der_tlv(der)? else {
+        return None;
+    };
+    let (0x06, _, content) = der_tlv(content_info)? else {
+        return None;
+    };
+    let (0xa0, content, _) = der_tlv(content)? else {
+        return None;
+    };
+    let (0x30, mut signed_data, _) = der_tlv(content)? else {
+        return None;
+    };
+    // Skip the version, the digest algorithms and the signed content.
+    for expected_tag in [0x02, 0x31, 0x30] {
+        let (tag, _, rest) = der_tlv(signed_data)?;
+        if tag != expected_tag {
+            return None;
+        }
+        signed_data = rest;
+    }
+
+    // The certificates are optional.
+    let mut certificates = match der_tlv(signed_data)? {
+        (0xa0, certificates, _) => certificates,
+        _ => return Some(Vec::new()),
+    };
+    let mut result = Vec::new();
+    while !certificates.is_empty() {
+        let (_, _, rest) = der_tlv(certificates)?;
+        result.push(&certificates[..certificates.len() - rest.len()]);
+        certificates = rest;
+    }
+    Some(result)
+}
+
+/// Split the DER encoded value at the start of `der` into its tag, its contents and the bytes
+/// after it.
+fn der_tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, der) = der.split_first()?;
+    let (&length, der) = der.split_first()?;
+    let (length, der) = if length < 0x80 {
+        (length as usize, der)
+    } else {
+        // The long form, with the number of length bytes in the low bits.
+        let length_bytes = der
+            .get(..usize::from(length & 0x7f))
+            .filter(|b| b.len() <= 4)?;
+        let length = length_bytes
+            .iter()
+            .fold(0, |length, &byte| length << 8 | usize::from(byte));
+        (length, &der[length_bytes.len()..])
+    };
+    let contents = der.get(..length)?;
+    Some((tag, contents, &der[length..]))
+}
+
+/// Remove all Authenticode signatures from a PE binary.
+///
+/// The certificate table is cut off the end of the binary and its data directory entry is
+/// cleared, like `sbattach --remove` does. Signing the result again only leaves the new
+/// signature. Binaries without signatures are returned unchanged.
+pub fn remove_signatures(pe: &[u8]) -> Result<Vec<u8>> {
+    let Some((entry, table_offset)) = certificate_table(pe)? else {
+        return Ok(pe.to_vec());
+    };
+
+    let mut unsigned = pe[..table_offset].to_vec();
+    unsigned[entry..entry + 8].fill(0);
+    Ok(unsigned)
 }
 
 /// Convert a path to an UEFI path relative to the specified ESP.
-fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
+///
+/// Fails if the resulting UEFI path is longer than `max_length` UTF-16 code units.
+pub fn esp_relative_uefi_path(esp: &Path, path: &Path, max_length: usize) -> Result<String> {
     let relative_path = path
         .strip_prefix(esp)
         .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
-    let uefi_path = uefi_path(relative_path)?;
-    Ok(format!("\\{}", &uefi_path))
+    let uefi_path = format!("\\{}", uefi_path(relative_path)?);
+
+    let length = uefi_path.encode_utf16().count();
+    if length > max_length {
+        bail!(
+            "UEFI path {uefi_path} is {length} UTF-16 code units long, exceeding the limit of {max_length}"
+        );
+    }
+
+    Ok(uefi_path)
 }
 
 /// Convert a path to a UEFI string representation.
@@ -244,35 +897,222 @@ fn file_size(path: impl AsRef<Path>) -> Result<u64> {
 
 /// Read the data from a section of a PE binary.
 ///
-/// The binary is supplied as a `u8` slice.
+/// The binary is supplied as a `u8` slice. Returns `None` if the section does not exist or its
+/// data is not within the binary, e.g. because the binary is truncated.
 pub fn read_section_data<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
     let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
 
     pe_binary
         .sections
         .iter()
-        .find(|s| s.name().unwrap() == section_name)
+        .find(|s| s.name().is_ok_and(|name| name == section_name))
+        .filter(|s| s.virtual_size <= s.size_of_raw_data)
         .and_then(|s| {
             let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
-            assert!(s.virtual_size <= s.size_of_raw_data);
-            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
-            Some(&file_data[section_start..section_end])
+            let section_end = section_start.checked_add(s.virtual_size.try_into().ok()?)?;
+            file_data.get(section_start..section_end)
         })
 }
 
+/// Read the kernel or initrd embedded into a fat image, decompressing it if the image has a
+/// `.compressed` section.
+///
+/// Returns `None` if the section does not exist.
+pub fn read_embedded_file(file_data: &[u8], section_name: &str) -> Result<Option<Vec<u8>>> {
+    let Some(data) = read_section_data(file_data, section_name) else {
+        return Ok(None);
+    };
+    match read_section_data(file_data, ".compressed") {
+        None => Ok(Some(data.to_vec())),
+        Some(compression) if compression == COMPRESSED_SECTIONS.as_bytes() => {
+            let decompressed = zstd::decode_all(data)
+                .with_context(|| format!("Failed to decompress the {section_name} section."))?;
+            Ok(Some(decompressed))
+        }
+        Some(compression) => bail!(
+            "Unknown compression {:?} of the {section_name} section.",
+            String::from_utf8_lossy(compression)
+        ),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures;
+
 #[cfg(test)]
 mod tests {
+    use super::fixtures::pe_with_section;
     use super::*;
 
     #[test]
     fn convert_to_valid_uefi_path_relative_to_esp() {
         let esp = Path::new("esp");
         let path = Path::new("esp/lanzaboote/is/great.txt");
-        let converted_path = esp_relative_uefi_path(esp, path).unwrap();
+        let converted_path =
+            esp_relative_uefi_path(esp, path, DEFAULT_MAX_UEFI_PATH_LENGTH).unwrap();
         let expected_path = String::from("\\lanzaboote\\is\\great.txt");
         assert_eq!(converted_path, expected_path);
     }
 
+    #[test]
+    fn reject_too_long_uefi_path() {
+        let esp = Path::new("esp");
+        let path = Path::new("esp/EFI/nixos").join("a".repeat(DEFAULT_MAX_UEFI_PATH_LENGTH));
+        let error = esp_relative_uefi_path(esp, &path, DEFAULT_MAX_UEFI_PATH_LENGTH).unwrap_err();
+        assert!(error.to_string().contains("exceeding the limit"));
+    }
+
+    #[test]
+    fn validate_sbat_csv() {
+        let sbat = b"sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+            lanzaboote,2,Example,lanzaboote,0.4.1,https://example.com\n";
+        validate_sbat(sbat).unwrap();
+
+        assert!(
+            validate_sbat(b"lanzaboote,2,Example,lanzaboote,0.4.1,https://example.com").is_err()
+        );
+        assert!(validate_sbat(b"sbat,1,SBAT Version,sbat,1").is_err());
+        assert!(validate_sbat(b"sbat,one,SBAT Version,sbat,1,https://example.com").is_err());
+    }
+
+    #[test]
+    fn validate_splash_bmp() {
+        let mut splash = vec![0; 54];
+        splash[0..2].copy_from_slice(b"BM");
+        splash[28] = 24;
+        validate_splash(&splash).unwrap();
+
+        splash[28] = 8;
+        assert!(validate_splash(&splash).is_err());
+        assert!(validate_splash(b"\x89PNG").is_err());
+        assert!(validate_splash(&vec![0; MAX_SPLASH_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn reject_kernel_without_efi_stub() {
+        let error = validate_kernel(b"not a kernel", Architecture::X86).unwrap_err();
+        assert!(error.to_string().contains("not a PE binary"));
+    }
+
+    /// Build the headers of a PE32+ binary, followed by a certificate table that holds
+    /// `signature`, if any.
+    fn pe_headers(signature: Option<&[u8]>) -> Vec<u8> {
+        let mut pe = vec![0; 0x40];
+        pe[0..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        pe.extend(b"PE\0\0");
+        pe.extend([0; 20]);
+        let optional_header = pe.len();
+        pe.resize(optional_header + 112 + 16 * 8, 0);
+        pe[optional_header..optional_header + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        pe[optional_header + 108..optional_header + 112].copy_from_slice(&16u32.to_le_bytes());
+        let Some(signature) = signature else {
+            return pe;
+        };
+        let entry = optional_header + 112 + 4 * 8;
+        let table_offset = pe.len() as u32;
+        pe[entry..entry + 4].copy_from_slice(&table_offset.to_le_bytes());
+        pe[entry + 4..entry + 8].copy_from_slice(&(signature.len() as u32).to_le_bytes());
+        pe.extend(signature);
+        pe
+    }
+
+    #[test]
+    fn remove_signatures_from_pe() -> Result<()> {
+        let unsigned = pe_headers(None);
+        assert_eq!(
+            remove_signatures(&pe_headers(Some(b"signature")))?,
+            unsigned
+        );
+        assert_eq!(remove_signatures(&unsigned)?, unsigned);
+        assert!(remove_signatures(b"not a PE binary").is_err());
+
+        assert!(has_signatures(&pe_headers(Some(b"signature")))?);
+        assert!(!has_signatures(&unsigned)?);
+        Ok(())
+    }
+
+    #[test]
+    fn signature_subjects_of_pe() -> Result<()> {
+        assert!(signature_subjects(&pe_headers(None))?.is_empty());
+
+        // A `WIN_CERTIFICATE` with a `SignedData` without certificates.
+        let signed_data = [
+            0x30, 0x0f, 0x06, 0x00, 0xa0, 0x0b, 0x30, 0x09, 0x02, 0x01, 0x01, 0x31, 0x00, 0x30,
+            0x00, 0x31, 0x00,
+        ];
+        let mut signature = ((8 + signed_data.len()) as u32).to_le_bytes().to_vec();
+        signature.extend(0x0002_0200u32.to_le_bytes());
+        signature.extend(signed_data);
+        assert!(signature_subjects(&pe_headers(Some(&signature)))?.is_empty());
+
+        signature[8] = 0x31;
+        assert!(signature_subjects(&pe_headers(Some(&signature))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn clear_timestamp_of_pe() -> Result<()> {
+        let mut pe = pe_headers(Some(b"signature"));
+        // Timestamp and checksum.
+        pe[0x48..0x4c].copy_from_slice(&0x6543_2100u32.to_le_bytes());
+        pe[0x98..0x9c].copy_from_slice(&1u32.to_le_bytes());
+
+        clear_timestamp(&mut pe)?;
+        assert_eq!(pe[0x48..0x4c], [0; 4]);
+        // Computed with an independent implementation of `CheckSumMappedFile`.
+        assert_eq!(pe[0x98..0x9c], 0x63b1u32.to_le_bytes());
+
+        let mut unchecked = pe_headers(None);
+        clear_timestamp(&mut unchecked)?;
+        assert_eq!(unchecked, pe_headers(None));
+        assert!(clear_timestamp(&mut b"not a PE binary".to_vec()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_stub_architecture() -> Result<()> {
+        let stub = |name: &[u8; 8]| {
+            let mut pe = pe_with_section(name, b"code");
+            // Machine type in the COFF header and subsystem in the optional header.
+            pe[0x44..0x46].copy_from_slice(&Architecture::X86.pe_machine().to_le_bytes());
+            pe[0x9c..0x9e].copy_from_slice(&IMAGE_SUBSYSTEM_EFI_APPLICATION.to_le_bytes());
+            pe
+        };
+
+        validate_stub(&stub(b".text\0\0\0"), Architecture::X86)?;
+        let error = validate_stub(&stub(b".text\0\0\0"), Architecture::AArch64).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("built for X86 instead of AArch64"));
+        assert!(validate_stub(&stub(b".data\0\0\0"), Architecture::X86).is_err());
+        assert!(validate_stub(b"not a stub", Architecture::X86).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_uncompressed_embedded_file() -> Result<()> {
+        let pe = pe_with_section(b".linux\0\0", b"kernel");
+        assert_eq!(read_embedded_file(&pe, ".linux")?, Some(b"kernel".to_vec()));
+        assert_eq!(read_embedded_file(&pe, ".initrd")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn read_section_data_of_truncated_pe() {
+        let pe = pe_with_section(b".osrel\0\0", b"ID=nixos\n");
+        assert_eq!(read_section_data(&pe, ".osrel"), Some(&b"ID=nixos\n"[..]));
+
+        // Truncated binaries must never be read out of bounds.
+        for len in 0..pe.len() {
+            assert_eq!(
+                read_section_data(&pe[..len], ".osrel"),
+                None,
+                "length {len}"
+            );
+        }
+    }
+
     #[test]
     fn convert_to_valid_uefi_path() {
         let path = Path::new("lanzaboote/is/great.txt");
@@ -0,0 +1,40 @@
+// The unit tests of linux-bootloader in rust/uefi include this file as well, so it must not depend
+// on anything but `vec!` and `Vec`.
+
+/// Build a PE32+ image with a single section `name` that contains `data`.
+///
+/// The section is at the same offset in the file and in memory, so the file can stand in for the
+/// loaded image.
+pub(crate) fn pe_with_section(name: &[u8; 8], data: &[u8]) -> Vec<u8> {
+    const DATA_OFFSET: u32 = 0x200;
+    let mut pe = vec![0u8; 0x40];
+    pe[0..2].copy_from_slice(b"MZ");
+    pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    pe.extend_from_slice(b"PE\0\0");
+
+    // COFF header with one section and an optional header of 240 bytes.
+    let mut coff = [0u8; 20];
+    coff[0..2].copy_from_slice(&0x8664u16.to_le_bytes());
+    coff[2..4].copy_from_slice(&1u16.to_le_bytes());
+    coff[16..18].copy_from_slice(&240u16.to_le_bytes());
+    pe.extend_from_slice(&coff);
+
+    let mut optional_header = [0u8; 240];
+    optional_header[0..2].copy_from_slice(&0x20bu16.to_le_bytes());
+    optional_header[108..112].copy_from_slice(&16u32.to_le_bytes());
+    pe.extend_from_slice(&optional_header);
+
+    pe.extend_from_slice(name);
+    for field in [
+        data.len() as u32,
+        DATA_OFFSET,
+        data.len() as u32,
+        DATA_OFFSET,
+    ] {
+        pe.extend_from_slice(&field.to_le_bytes());
+    }
+    pe.extend_from_slice(&[0; 16]);
+    pe.resize(DATA_OFFSET as usize, 0);
+    pe.extend_from_slice(data);
+    pe
+}
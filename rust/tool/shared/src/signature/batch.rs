@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use sha2::Sha256;
+
+use super::{Signer, VerificationResult};
+use crate::utils::file_hash;
+
+type Hash = sha2::digest::Output<Sha256>;
+
+/// Verifies the signatures of many PE binaries, e.g. of all files on an ESP.
+///
+/// The binaries are verified by up to `jobs` threads at once, each with
+/// [`Signer::verify_path_detailed`]. The results are cached by the hash of the binaries, so
+/// identical binaries, like systemd-boot at its own path and at the removable media path, are
+/// only verified once.
+pub struct BatchVerifier<'a, S: ?Sized> {
+    signer: &'a S,
+    jobs: usize,
+    cache: Mutex<BTreeMap<Hash, VerificationResult>>,
+}
+
+impl<'a, S: Signer + Sync + ?Sized> BatchVerifier<'a, S> {
+    pub fn new(signer: &'a S, jobs: usize) -> Self {
+        Self {
+            signer,
+            jobs: jobs.max(1),
+            cache: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Verify the signatures of `paths`, returning the results in the same order.
+    pub fn verify_paths(&self, paths: &[PathBuf]) -> Result<Vec<VerificationResult>> {
+        let hashes = paths
+            .iter()
+            .map(|path| file_hash(path))
+            .collect::<Result<Vec<Hash>>>()?;
+
+        // Only one binary of each content that is not cached yet is verified.
+        let mut pending: BTreeMap<&Hash, &Path> = BTreeMap::new();
+        {
+            let cache = self.cache.lock().expect("The cache is not poisoned");
+            for (hash, path) in hashes.iter().zip(paths) {
+                if !cache.contains_key(hash) {
+                    pending.entry(hash).or_insert(path);
+                }
+            }
+        }
+        let pending: Vec<(&Hash, &Path)> = pending.into_iter().collect();
+
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.jobs.min(pending.len()))
+                .map(|_| scope.spawn(|| self.verify_pending(&pending, &next)))
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("A verification thread must not panic"))
+        })?;
+
+        let cache = self.cache.lock().expect("The cache is not poisoned");
+        Ok(hashes.iter().map(|hash| cache[hash]).collect())
+    }
+
+    /// Verify pending binaries until there are none left.
+    fn verify_pending(&self, pending: &[(&Hash, &Path)], next: &AtomicUsize) -> Result<()> {
+        while let Some((hash, path)) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let result = self
+                .signer
+                .verify_path_detailed(path)
+                .with_context(|| format!("Failed to verify {path:?}"))?;
+            self.cache
+                .lock()
+                .expect("The cache is not poisoned")
+                .insert(**hash, result);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::bail;
+
+    use crate::pe::StubParameters;
+
+    /// A signer that trusts binaries with the contents `trusted` and counts its verifications.
+    #[derive(Default)]
+    struct CountingSigner {
+        verifications: AtomicUsize,
+    }
+
+    impl Signer for CountingSigner {
+        fn sign_store_path(&self, _store_path: &Path) -> Result<Vec<u8>> {
+            bail!("not used in this test")
+        }
+
+        fn build_and_sign_stub(&self, _stub: &StubParameters) -> Result<Vec<u8>> {
+            bail!("not used in this test")
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            bail!("not used in this test")
+        }
+
+        fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+            self.verifications.fetch_add(1, Ordering::Relaxed);
+            Ok(pe_binary == b"trusted")
+        }
+    }
+
+    #[test]
+    fn verify_identical_files_once() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for (name, contents) in [
+            ("systemd-bootx64.efi", "trusted"),
+            ("BOOTX64.EFI", "trusted"),
+            ("stub.efi", "untrusted"),
+            ("stub-copy.efi", "untrusted"),
+        ] {
+            let path = tmpdir.path().join(name);
+            std::fs::write(&path, contents)?;
+            paths.push(path);
+        }
+
+        let signer = CountingSigner::default();
+        let verifier = BatchVerifier::new(&signer, 4);
+        assert_eq!(
+            verifier.verify_paths(&paths)?,
+            [
+                VerificationResult::SignedTrusted,
+                VerificationResult::SignedTrusted,
+                VerificationResult::Unsigned,
+                VerificationResult::Unsigned,
+            ]
+        );
+        assert_eq!(signer.verifications.load(Ordering::Relaxed), 2);
+
+        // Verifying the same contents again is answered from the cache.
+        verifier.verify_paths(&paths[1..2])?;
+        assert_eq!(signer.verifications.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+}
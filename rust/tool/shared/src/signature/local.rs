@@ -1,12 +1,20 @@
 use crate::pe::lanzaboote_image;
 use crate::utils::SecureTempDirExt;
 use std::ffi::OsString;
+use std::fmt;
+use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use tempfile::tempdir;
+use x509_cert::der::{DecodePem, Encode};
+use x509_cert::Certificate;
 
 use super::Signer;
 
@@ -20,19 +28,110 @@ use super::Signer;
 ///
 /// In the future, `sbsign` may be removed to perform signature in-memory
 /// without any temporary directory.
+///
+/// The key material is validated when the keypair is constructed, so that a
+/// wrong key is reported before anything is installed. RSA keys and EC keys on
+/// the P-256 and P-384 curves are supported. `sbsign` and `sbverify` leave the
+/// signature algorithm to OpenSSL, which derives it from the key, so all of
+/// them are signed and verified the same way.
 #[derive(Debug, Clone)]
 pub struct LocalKeyPair {
     pub private_key: PathBuf,
     pub public_key: PathBuf,
+    pub key_type: KeyType,
+}
+
+/// The type of a Secure Boot key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// An RSA key with a modulus of the given number of bits.
+    Rsa(usize),
+    /// An EC key on the NIST P-256 curve.
+    EcP256,
+    /// An EC key on the NIST P-384 curve.
+    EcP384,
 }
 
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rsa(bits) => write!(f, "RSA-{bits}"),
+            Self::EcP256 => write!(f, "EC P-256"),
+            Self::EcP384 => write!(f, "EC P-384"),
+        }
+    }
+}
+
+/// Smallest RSA key size that firmware is required to support for Secure Boot.
+const MIN_RSA_KEY_BITS: usize = 2048;
+
 impl LocalKeyPair {
-    pub fn new(public_key: &Path, private_key: &Path) -> Self {
-        Self {
+    /// Load a keypair from a PEM-encoded X.509 certificate and the PEM-encoded private key that
+    /// belongs to it.
+    pub fn new(public_key: &Path, private_key: &Path) -> Result<Self> {
+        let key_type = validate_key_pair(public_key, private_key).with_context(|| {
+            format!("Invalid Secure Boot keypair {public_key:?}, {private_key:?}")
+        })?;
+
+        Ok(Self {
             public_key: public_key.into(),
             private_key: private_key.into(),
+            key_type,
+        })
+    }
+}
+
+/// Check that the certificate is usable for Secure Boot and that the private key belongs to it.
+///
+/// The type of the key is detected from the certificate.
+fn validate_key_pair(public_key: &Path, private_key: &Path) -> Result<KeyType> {
+    let certificate = fs::read_to_string(public_key)
+        .with_context(|| format!("Failed to read the certificate {public_key:?}"))?;
+    let certificate = Certificate::from_pem(&certificate)
+        .with_context(|| format!("{public_key:?} is not a PEM-encoded X.509 certificate"))?;
+    let spki = certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .context("Failed to encode the public key of the certificate")?;
+
+    let key = fs::read_to_string(private_key)
+        .with_context(|| format!("Failed to read the private key {private_key:?}"))?;
+    let invalid_key =
+        |kind: &str| format!("{private_key:?} is not a PEM-encoded unencrypted {kind} private key");
+
+    let (key_type, matches) = if let Ok(certificate_key) = RsaPublicKey::from_public_key_der(&spki)
+    {
+        let bits = certificate_key.size() * 8;
+        if bits < MIN_RSA_KEY_BITS {
+            bail!("The RSA key of {public_key:?} has {bits} bits, but Secure Boot needs at least {MIN_RSA_KEY_BITS}.");
         }
+        let key = RsaPrivateKey::from_pkcs8_pem(&key)
+            .map_err(anyhow::Error::from)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&key).map_err(anyhow::Error::from))
+            .with_context(|| invalid_key("RSA"))?;
+        (KeyType::Rsa(bits), key.to_public_key() == certificate_key)
+    } else if let Ok(certificate_key) = p256::PublicKey::from_public_key_der(&spki) {
+        let key = p256::SecretKey::from_pkcs8_pem(&key)
+            .map_err(anyhow::Error::from)
+            .or_else(|_| p256::SecretKey::from_sec1_pem(&key).map_err(anyhow::Error::from))
+            .with_context(|| invalid_key("P-256"))?;
+        (KeyType::EcP256, key.public_key() == certificate_key)
+    } else if let Ok(certificate_key) = p384::PublicKey::from_public_key_der(&spki) {
+        let key = p384::SecretKey::from_pkcs8_pem(&key)
+            .map_err(anyhow::Error::from)
+            .or_else(|_| p384::SecretKey::from_sec1_pem(&key).map_err(anyhow::Error::from))
+            .with_context(|| invalid_key("P-384"))?;
+        (KeyType::EcP384, key.public_key() == certificate_key)
+    } else {
+        bail!("The certificate {public_key:?} contains neither an RSA key nor an EC key on the P-256 or P-384 curve.");
+    };
+
+    if !matches {
+        bail!("The private key {private_key:?} does not belong to the certificate {public_key:?}.");
     }
+
+    Ok(key_type)
 }
 
 impl Signer for LocalKeyPair {
@@ -61,7 +160,11 @@ impl Signer for LocalKeyPair {
                 .write_all(&output.stderr)
                 .context("Failed to write output of sbsign to stderr.")?;
             log::debug!("sbsign failed with args: `{args:?}`.");
-            return Err(anyhow::anyhow!("Failed to sign {to:?}."));
+            // Older releases of sbsigntools only sign with RSA keys.
+            return Err(anyhow::anyhow!(
+                "Failed to sign {to:?} with the {} key.",
+                self.key_type
+            ));
         }
 
         Ok(())
@@ -85,6 +188,13 @@ impl Signer for LocalKeyPair {
         std::fs::read(&to).context("Failed to read a lanzaboote image")
     }
 
+    fn build_and_sign_stub_to(&self, stub: &crate::pe::StubParameters, to: &Path) -> Result<()> {
+        let working_tree = tempdir()?;
+        let lzbt_image_path =
+            lanzaboote_image(&working_tree, stub).context("Failed to build a lanzaboote image")?;
+        self.sign_and_copy(&lzbt_image_path, to)
+    }
+
     fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
         let working_tree = tempdir().context("Failed to get a temporary working tree")?;
         let from = working_tree
@@ -110,7 +220,10 @@ impl Signer for LocalKeyPair {
             if std::io::stderr().write_all(&output.stderr).is_err() {
                 return Ok(false);
             };
-            log::debug!("sbverify failed with args: `{args:?}`.");
+            log::debug!(
+                "sbverify failed for the {} key with args: `{args:?}`.",
+                self.key_type
+            );
             return Ok(false);
         }
         Ok(true)
@@ -1,7 +1,18 @@
 use anyhow::Result;
 use std::path::Path;
 
-use crate::pe::StubParameters;
+use crate::pe::{self, StubParameters};
+
+/// The outcome of verifying the signatures of a PE binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationResult {
+    /// The binary carries no signature at all.
+    Unsigned,
+    /// The binary is signed, but not with a key the signer trusts.
+    SignedUntrusted,
+    /// The binary is signed with a key the signer trusts.
+    SignedTrusted,
+}
 
 /// This trait abstracts the concept of a signer.
 ///
@@ -34,6 +45,13 @@ pub trait Signer {
     /// representation.
     fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>>;
 
+    /// Assembles and signs a stub like [`Signer::build_and_sign_stub`], but writes it to `to`.
+    /// Signers can override this to avoid holding the whole stub in memory, which matters for
+    /// fat stubs.
+    fn build_and_sign_stub_to(&self, stub: &StubParameters, to: &Path) -> Result<()> {
+        Ok(std::fs::write(to, self.build_and_sign_stub(stub)?)?)
+    }
+
     /// Returns an opaque public key, used for tools to derive content-addressability
     /// of the various files generated and installed in the ESP.
     /// This way, if the key changes, all the bootables will be different.
@@ -53,6 +71,67 @@ pub trait Signer {
     fn verify_path(&self, from: &Path) -> Result<bool> {
         self.verify(&std::fs::read(from).expect("Failed to read the path to verify"))
     }
+
+    /// Verify the signature of a PE binary like [`Signer::verify`], but tell apart unsigned
+    /// binaries from binaries signed with an untrusted key.
+    ///
+    /// The default implementation looks for any signature in the binary if [`Signer::verify`]
+    /// fails. Signers that learn this while verifying can override it.
+    fn verify_detailed(&self, pe_binary: &[u8]) -> Result<VerificationResult> {
+        if self.verify(pe_binary)? {
+            Ok(VerificationResult::SignedTrusted)
+        } else if pe::has_signatures(pe_binary).unwrap_or(false) {
+            Ok(VerificationResult::SignedUntrusted)
+        } else {
+            Ok(VerificationResult::Unsigned)
+        }
+    }
+
+    /// Verify the signature of a PE binary like [`Signer::verify_detailed`], provided by its path.
+    fn verify_path_detailed(&self, from: &Path) -> Result<VerificationResult> {
+        self.verify_detailed(&std::fs::read(from)?)
+    }
+}
+
+impl<S: Signer + ?Sized> Signer for Box<S> {
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        (**self).sign_store_path(store_path)
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        (**self).build_and_sign_stub(stub)
+    }
+
+    fn build_and_sign_stub_to(&self, stub: &StubParameters, to: &Path) -> Result<()> {
+        (**self).build_and_sign_stub_to(stub, to)
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        (**self).get_public_key()
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        (**self).sign_and_copy(from, to)
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        (**self).verify(pe_binary)
+    }
+
+    fn verify_path(&self, from: &Path) -> Result<bool> {
+        (**self).verify_path(from)
+    }
+
+    fn verify_detailed(&self, pe_binary: &[u8]) -> Result<VerificationResult> {
+        (**self).verify_detailed(pe_binary)
+    }
+
+    fn verify_path_detailed(&self, from: &Path) -> Result<VerificationResult> {
+        (**self).verify_path_detailed(from)
+    }
 }
 
+pub mod batch;
 pub mod local;
+pub mod policy;
+pub mod remote;
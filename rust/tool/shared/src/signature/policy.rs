@@ -0,0 +1,417 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use super::{Signer, VerificationResult};
+use crate::pe::{self, StubParameters};
+
+/// A policy decides which signing requests a signing server accepts.
+///
+/// A server should answer requests that its policy rejects with `403 Forbidden`, which
+/// [`RemoteSigningServer`](super::remote::RemoteSigningServer) does not retry.
+pub trait Policy {
+    /// Whether a binary that boots with this kernel command line may be signed.
+    fn trusted_cmdline(&self, cmdline: &[String]) -> bool;
+
+    /// Whether a stub built from these parameters may be signed.
+    fn trusted_stub_parameters(&self, parameters: &StubParameters) -> bool {
+        self.trusted_cmdline(&parameters.kernel_cmdline)
+    }
+}
+
+/// What a [`CmdlinePolicy`] does with parameters that are on neither list.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownParameters {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// A policy that checks each kernel command line parameter of a stub against an allow and a
+/// deny list, e.g. to refuse signing stubs that boot into `init=/bin/sh`.
+///
+/// An entry either matches a parameter exactly (`init=/bin/sh`) or all values of a parameter
+/// (`init` matches `init=/bin/sh` and `init=/sbin/init`). The deny list takes precedence over the
+/// allow list. Parameters on neither list are denied unless `unknown` is `allow`.
+///
+/// Parameters are compared the way the kernel reads them: whitespace inside double quotes does not
+/// separate parameters, quotes around values are dropped and `-` and `_` in keys are the same, so
+/// that `rd-break` is denied by `rd_break`.
+///
+/// Paths are normalised before they are compared with the deny list, so that `init=/bin/sh` also
+/// denies `init=/bin//sh` and `init=/usr/../bin/sh`. Other aliases, e.g. symlinks, are not
+/// detected, so denying a parameter by its key and allowing the exact values that are needed is
+/// safer than denying single values.
+///
+/// It is read from JSON like:
+///
+/// ```json
+/// {
+///   "allow": ["quiet", "loglevel", "init"],
+///   "deny": ["init=/bin/sh", "rd.break"],
+///   "unknown": "deny"
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CmdlinePolicy {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    unknown: UnknownParameters,
+}
+
+impl CmdlinePolicy {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let policy = std::fs::read(path)
+            .with_context(|| format!("Failed to read the cmdline policy {path:?}"))?;
+        serde_json::from_slice(&policy)
+            .with_context(|| format!("Failed to parse the cmdline policy {path:?}"))
+    }
+
+    /// The parameters of `cmdline` that the policy rejects.
+    pub fn denied_parameters<'a>(&self, cmdline: &'a [String]) -> Vec<&'a str> {
+        cmdline
+            .iter()
+            .flat_map(|parameters| split_parameters(parameters))
+            .filter(|parameter| !self.allows(parameter))
+            .collect()
+    }
+
+    fn allows(&self, parameter: &str) -> bool {
+        let parameter = Parameter::parse(parameter);
+        let matches = |entry: &String, eq: fn(&str, &str) -> bool| {
+            Parameter::parse(entry).matches(&parameter, eq)
+        };
+        if self.deny.iter().any(|entry| {
+            matches(entry, |entry, value| {
+                normalise_path(entry) == normalise_path(value)
+            })
+        }) {
+            false
+        } else if self.allow.iter().any(|entry| matches(entry, |a, b| a == b)) {
+            true
+        } else {
+            self.unknown == UnknownParameters::Allow
+        }
+    }
+}
+
+/// A kernel command line parameter as the kernel reads it.
+struct Parameter<'a> {
+    /// The key with `-` replaced by `_`, which the kernel treats the same.
+    key: String,
+    value: Option<&'a str>,
+}
+
+impl<'a> Parameter<'a> {
+    /// Split a parameter into its key and value like the kernel, i.e. drop the quotes around the
+    /// parameter or its value.
+    fn parse(parameter: &'a str) -> Self {
+        let unquote = |s: &'a str| {
+            s.strip_prefix('"')
+                .map_or(s, |s| s.strip_suffix('"').unwrap_or(s))
+        };
+        let (key, value) = match unquote(parameter).split_once('=') {
+            Some((key, value)) => (key, Some(unquote(value))),
+            None => (unquote(parameter), None),
+        };
+        Self {
+            key: key.replace('-', "_"),
+            value,
+        }
+    }
+
+    /// Whether this list entry matches `parameter`. An entry without a value matches all values,
+    /// otherwise the values are compared with `eq`.
+    fn matches(&self, parameter: &Parameter, eq: impl Fn(&str, &str) -> bool) -> bool {
+        self.key == parameter.key
+            && match (self.value, parameter.value) {
+                (None, _) => true,
+                (Some(entry), Some(value)) => eq(entry, value),
+                (Some(_), None) => false,
+            }
+    }
+}
+
+/// Split a kernel command line into parameters like the kernel, i.e. at whitespace outside of
+/// double quotes.
+fn split_parameters(cmdline: &str) -> Vec<&str> {
+    let mut parameters = Vec::new();
+    let mut start = None;
+    let mut in_quote = false;
+    for (i, c) in cmdline.char_indices() {
+        match start {
+            None if c.is_whitespace() => continue,
+            None => start = Some(i),
+            Some(parameter_start) if c.is_whitespace() && !in_quote => {
+                parameters.push(&cmdline[parameter_start..i]);
+                start = None;
+                continue;
+            }
+            Some(_) => {}
+        }
+        if c == '"' {
+            in_quote = !in_quote;
+        }
+    }
+    if let Some(parameter_start) = start {
+        parameters.push(&cmdline[parameter_start..]);
+    }
+    parameters
+}
+
+/// Normalise a value that is an absolute path, i.e. drop empty and `.` components and resolve
+/// `..` lexically. Other values are returned as they are.
+fn normalise_path(value: &str) -> String {
+    if !value.starts_with('/') {
+        return value.to_owned();
+    }
+    let mut components = Vec::new();
+    for component in value.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+impl Policy for CmdlinePolicy {
+    fn trusted_cmdline(&self, cmdline: &[String]) -> bool {
+        let denied = self.denied_parameters(cmdline);
+        if !denied.is_empty() {
+            log::warn!("Refusing to sign a binary with the kernel parameters {denied:?}.");
+        }
+        denied.is_empty()
+    }
+}
+
+/// A signer that only builds and signs stubs that its policy trusts, e.g. for a signing server
+/// or for `lzbt --cmdline-policy`.
+///
+/// Binaries that are signed as they are, e.g. stubs in a bundle or UKIs, are checked by the
+/// kernel command line in their `.cmdline` section. Everything else is passed through to the
+/// inner signer.
+pub struct PolicySigner<S, P> {
+    signer: S,
+    policy: P,
+}
+
+impl<S: Signer, P: Policy> PolicySigner<S, P> {
+    pub fn new(signer: S, policy: P) -> Self {
+        Self { signer, policy }
+    }
+
+    fn check_stub_parameters(&self, stub: &StubParameters) -> Result<()> {
+        if !self.policy.trusted_stub_parameters(stub) {
+            bail!("The signing policy forbids signing this stub.");
+        }
+        Ok(())
+    }
+
+    fn check_binary(&self, binary: &Path) -> Result<()> {
+        let data = fs::read(binary).with_context(|| format!("Failed to read {binary:?}"))?;
+        if let Some(cmdline) = pe::read_section_data(&data, ".cmdline") {
+            let cmdline = String::from_utf8_lossy(cmdline);
+            if !self
+                .policy
+                .trusted_cmdline(&[cmdline.trim_end_matches('\0').to_owned()])
+            {
+                bail!("The signing policy forbids signing {binary:?}.");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Signer, P: Policy> Signer for PolicySigner<S, P> {
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        self.signer.sign_store_path(store_path)
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        self.check_stub_parameters(stub)?;
+        self.signer.build_and_sign_stub(stub)
+    }
+
+    fn build_and_sign_stub_to(&self, stub: &StubParameters, to: &Path) -> Result<()> {
+        self.check_stub_parameters(stub)?;
+        self.signer.build_and_sign_stub_to(stub, to)
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        self.signer.get_public_key()
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.check_binary(from)?;
+        self.signer.sign_and_copy(from, to)
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        self.signer.verify(pe_binary)
+    }
+
+    fn verify_path(&self, from: &Path) -> Result<bool> {
+        self.signer.verify_path(from)
+    }
+
+    fn verify_detailed(&self, pe_binary: &[u8]) -> Result<VerificationResult> {
+        self.signer.verify_detailed(pe_binary)
+    }
+
+    fn verify_path_detailed(&self, from: &Path) -> Result<VerificationResult> {
+        self.signer.verify_path_detailed(from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::fixtures::pe_with_section;
+
+    fn policy(json: &str) -> CmdlinePolicy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn cmdline(parameters: &str) -> Vec<String> {
+        parameters.split(' ').map(str::to_owned).collect()
+    }
+
+    fn stub(parameters: &str) -> StubParameters {
+        StubParameters::new_fat(
+            Path::new("/nix/store/stub.efi"),
+            Path::new("/nix/store/kernel"),
+            Some(Path::new("/nix/store/initrd")),
+        )
+        .with_cmdline(&cmdline(parameters))
+    }
+
+    /// A signer that signs every stub as `signed stub`.
+    struct StubSigner;
+
+    impl Signer for StubSigner {
+        fn sign_store_path(&self, _store_path: &Path) -> Result<Vec<u8>> {
+            bail!("not used in this test")
+        }
+
+        fn build_and_sign_stub(&self, _stub: &StubParameters) -> Result<Vec<u8>> {
+            Ok(b"signed stub".to_vec())
+        }
+
+        fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+            fs::copy(from, to)?;
+            Ok(())
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            bail!("not used in this test")
+        }
+
+        fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+            bail!("not used in this test")
+        }
+    }
+
+    #[test]
+    fn allow_listed_parameters() {
+        let policy = policy(r#"{"allow": ["quiet", "init"], "unknown": "deny"}"#);
+        assert!(policy
+            .denied_parameters(&cmdline("quiet init=/sbin/init"))
+            .is_empty());
+    }
+
+    #[test]
+    fn deny_listed_parameters() {
+        let policy =
+            policy(r#"{"allow": ["init", "rd.break"], "deny": ["init=/bin/sh", "rd.break"]}"#);
+        assert_eq!(
+            policy.denied_parameters(&cmdline("init=/bin/sh init=/sbin/init rd.break=pre-mount")),
+            ["init=/bin/sh", "rd.break=pre-mount"]
+        );
+
+        assert!(policy.trusted_stub_parameters(&stub("init=/sbin/init")));
+        assert!(!policy.trusted_stub_parameters(&stub("init=/bin/sh")));
+    }
+
+    #[test]
+    fn deny_aliases_of_denied_paths() {
+        let policy = policy(r#"{"allow": ["init"], "deny": ["init=/bin/sh"]}"#);
+        assert_eq!(
+            policy.denied_parameters(&cmdline(
+                "init=/bin//sh init=/bin/./sh init=/usr/../bin/sh init=/sbin/init"
+            )),
+            ["init=/bin//sh", "init=/bin/./sh", "init=/usr/../bin/sh"]
+        );
+    }
+
+    #[test]
+    fn treat_dashes_and_underscores_in_keys_the_same() {
+        let policy = policy(r#"{"allow": ["log_buf_len"], "deny": ["rd_shell", "init-fatal"]}"#);
+        assert_eq!(
+            policy.denied_parameters(&cmdline("log-buf-len=1M rd-shell init_fatal=0")),
+            ["rd-shell", "init_fatal=0"]
+        );
+    }
+
+    #[test]
+    fn keep_quoted_values_in_one_parameter() {
+        let policy = policy(r#"{"allow": ["dyndbg", "init"], "deny": ["init=/bin/sh"]}"#);
+        let cmdline = [r#"dyndbg="file init.c +p" init="/bin/sh" "init=/bin//sh""#.to_owned()];
+        assert_eq!(
+            policy.denied_parameters(&cmdline),
+            [r#"init="/bin/sh""#, r#""init=/bin//sh""#]
+        );
+    }
+
+    #[test]
+    fn unknown_parameters() {
+        assert_eq!(policy("{}").denied_parameters(&cmdline("quiet")), ["quiet"]);
+        assert!(policy(r#"{"unknown": "allow"}"#)
+            .denied_parameters(&cmdline("quiet"))
+            .is_empty());
+    }
+
+    #[test]
+    fn refuse_to_sign_denied_stubs() -> Result<()> {
+        let signer = PolicySigner::new(StubSigner, policy(r#"{"allow": ["quiet"]}"#));
+        assert_eq!(signer.build_and_sign_stub(&stub("quiet"))?, b"signed stub");
+
+        let err = signer
+            .build_and_sign_stub(&stub("init=/bin/sh"))
+            .unwrap_err();
+        assert!(err.to_string().contains("policy forbids"), "{err:#}");
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_to_sign_binaries_with_denied_cmdline() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let signer = PolicySigner::new(StubSigner, policy(r#"{"allow": ["quiet"]}"#));
+        let sign = |name: &str, pe: Vec<u8>| {
+            let from = tmpdir.path().join(name);
+            fs::write(&from, pe)?;
+            signer.sign_and_copy(&from, &tmpdir.path().join(format!("{name}.signed")))
+        };
+
+        sign("trusted.efi", pe_with_section(b".cmdline", b"quiet\0"))?;
+        sign("no-cmdline.efi", pe_with_section(b".text\0\0\0", b"code"))?;
+        let err = sign(
+            "denied.efi",
+            pe_with_section(b".cmdline", b"quiet init=/bin/sh"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("policy forbids"), "{err:#}");
+        assert!(!tmpdir.path().join("denied.efi.signed").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{Signer, VerificationResult};
+use crate::pe::StubParameters;
+
+/// A remote signing server is a signer that keeps the private key material on another machine.
+///
+/// The server is expected to provide the following endpoints:
+///
+/// - `GET /publickey` returns the public key.
+/// - `POST /sign/stub` receives the [`StubParameters`] as JSON, assembles the stub from the
+///   referenced store paths and returns it signed.
+/// - `POST /sign/store-path` receives a store path and returns the signed PE binary at it.
+/// - `POST /verify` receives a PE binary and returns a [`VerificationResponse`] as JSON.
+///
+/// As the server assembles the stub itself, all files referenced by the stub parameters must be
+/// available to it, e.g. because they are in a shared Nix store.
+///
+/// Requests that fail because of the connection or a server error (5xx) are retried with an
+/// exponential backoff, see [`RetryPolicy`]. Client errors (4xx) are not retried. The server
+/// answers requests that its [`Policy`](super::policy::Policy) rejects with `403 Forbidden`.
+pub struct RemoteSigningServer {
+    server_url: String,
+    user_agent: String,
+    retry_policy: RetryPolicy,
+    agent: ureq::Agent,
+}
+
+/// Timeouts of the requests to a remote signing server.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            read: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How often and how patiently failed requests are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of attempts, including the first one.
+    pub attempts: u32,
+    /// Backoff before the second attempt. It doubles with every further attempt.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The backoff before the attempt after `attempt` (counting from 1).
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// The answer of a remote signing server to a verification request.
+#[derive(Debug, Deserialize)]
+pub struct VerificationResponse {
+    /// Whether the binary carries a signature at all.
+    pub signed: bool,
+    /// Whether the binary carries a valid signature of the server's key.
+    pub valid_signature: bool,
+}
+
+impl From<VerificationResponse> for VerificationResult {
+    fn from(response: VerificationResponse) -> Self {
+        match response {
+            VerificationResponse { signed: false, .. } => Self::Unsigned,
+            VerificationResponse {
+                valid_signature: false,
+                ..
+            } => Self::SignedUntrusted,
+            _ => Self::SignedTrusted,
+        }
+    }
+}
+
+impl RemoteSigningServer {
+    pub fn new(server_url: &str, user_agent: &str) -> Self {
+        Self {
+            server_url: server_url.trim_end_matches('/').to_owned(),
+            user_agent: user_agent.to_owned(),
+            retry_policy: RetryPolicy::default(),
+            agent: build_agent(user_agent, Timeouts::default()),
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.agent = build_agent(&self.user_agent, timeouts);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{path}", self.server_url)
+    }
+
+    fn request_store_path_signature(&self, store_path: &Path) -> Result<ureq::Response> {
+        let store_path = store_path
+            .to_str()
+            .with_context(|| format!("Store path {store_path:?} is not valid UTF-8"))?;
+        self.request(&format!("request a signature for {store_path}"), |agent| {
+            agent
+                .post(&self.endpoint("sign/store-path"))
+                .send_string(store_path)
+                .map_err(Box::new)
+        })
+    }
+
+    fn request_stub_signature(&self, stub: &StubParameters) -> Result<ureq::Response> {
+        self.request("request a signed stub", |agent| {
+            agent
+                .post(&self.endpoint("sign/stub"))
+                .send_json(stub)
+                .map_err(Box::new)
+        })
+    }
+
+    /// Send a request built by `send`, retrying it according to the retry policy.
+    fn request(
+        &self,
+        description: &str,
+        send: impl Fn(&ureq::Agent) -> Result<ureq::Response, Box<ureq::Error>>,
+    ) -> Result<ureq::Response> {
+        let mut attempt = 1;
+        loop {
+            let err = match send(&self.agent) {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            let retryable = match err.as_ref() {
+                ureq::Error::Status(status, _) => *status >= 500,
+                ureq::Error::Transport(_) => true,
+            };
+            if let ureq::Error::Status(403, _) = err.as_ref() {
+                return Err(err).with_context(|| {
+                    format!("Failed to {description}: the policy of the remote signing server forbids it")
+                });
+            }
+            if !retryable || attempt >= self.retry_policy.attempts {
+                return Err(err).with_context(|| {
+                    format!(
+                        "Failed to {description} (attempt {attempt} of {})",
+                        self.retry_policy.attempts
+                    )
+                });
+            }
+
+            let backoff = self.retry_policy.backoff(attempt);
+            log::warn!("Failed to {description}, retrying in {backoff:?}: {err}");
+            std::thread::sleep(backoff);
+            attempt += 1;
+        }
+    }
+}
+
+fn build_agent(user_agent: &str, timeouts: Timeouts) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .user_agent(user_agent)
+        .timeout_connect(timeouts.connect)
+        .timeout_read(timeouts.read)
+        .timeout_write(timeouts.write)
+        .build()
+}
+
+/// Write the body of a successful response to `to` without holding it in memory.
+fn write_body(response: ureq::Response, to: &Path) -> Result<()> {
+    let mut file = File::create(to).with_context(|| format!("Failed to create the file {to:?}"))?;
+    std::io::copy(&mut response.into_reader(), &mut file).with_context(|| {
+        format!("Failed to write the response of the remote signing server to {to:?}")
+    })?;
+    Ok(())
+}
+
+/// Read the body of a successful response.
+fn read_body(response: ureq::Response) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut body)
+        .context("Failed to read the response of the remote signing server")?;
+    Ok(body)
+}
+
+impl Signer for RemoteSigningServer {
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        let response = self.request("request the public key", |agent| {
+            agent
+                .get(&self.endpoint("publickey"))
+                .call()
+                .map_err(Box::new)
+        })?;
+        read_body(response)
+    }
+
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        read_body(self.request_store_path_signature(store_path)?)
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        write_body(self.request_store_path_signature(from)?, to)
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        read_body(self.request_stub_signature(stub)?)
+    }
+
+    fn build_and_sign_stub_to(&self, stub: &StubParameters, to: &Path) -> Result<()> {
+        write_body(self.request_stub_signature(stub)?, to)
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        Ok(self.verify_detailed(pe_binary)? == VerificationResult::SignedTrusted)
+    }
+
+    fn verify_path(&self, from: &Path) -> Result<bool> {
+        Ok(self.verify_path_detailed(from)? == VerificationResult::SignedTrusted)
+    }
+
+    fn verify_detailed(&self, pe_binary: &[u8]) -> Result<VerificationResult> {
+        let response: VerificationResponse = self
+            .request("request a verification", |agent| {
+                agent
+                    .post(&self.endpoint("verify"))
+                    .send_bytes(pe_binary)
+                    .map_err(Box::new)
+            })?
+            .into_json()
+            .context("Failed to parse the verification response")?;
+        Ok(response.into())
+    }
+
+    fn verify_path_detailed(&self, from: &Path) -> Result<VerificationResult> {
+        let file = File::open(from).with_context(|| format!("Failed to open {from:?}"))?;
+        // Without a known length, the file is sent chunked instead of being read into memory.
+        let response: VerificationResponse = self
+            .request("request a verification", |agent| {
+                // A retry sends the file from the start again.
+                (&file).rewind().map_err(|err| Box::new(err.into()))?;
+                agent
+                    .post(&self.endpoint("verify"))
+                    .send(&file)
+                    .map_err(Box::new)
+            })?
+            .into_json()
+            .context("Failed to parse the verification response")?;
+        Ok(response.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Serve one canned response per connection and return the number of requests served.
+    fn mock_server(responses: Vec<&'static str>) -> (String, JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut served = 0;
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                // Skip the request head. Request bodies are small, so they are ignored.
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                served += 1;
+            }
+            served
+        });
+        (url, handle)
+    }
+
+    const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+    const FORBIDDEN: &str = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+    const NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+    const PUBLIC_KEY: &str = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nkey";
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn write_signed_binary_to_file() -> Result<()> {
+        let (url, server) = mock_server(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nsigned\r\n4\r\n.efi\r\n0\r\n\r\n",
+        ]);
+        let signer = RemoteSigningServer::new(&url, "test").with_retry_policy(fast_retries());
+        let tmpdir = tempfile::tempdir()?;
+        let to = tmpdir.path().join("signed.efi");
+
+        signer.sign_and_copy(Path::new("/nix/store/binary.efi"), &to)?;
+        assert_eq!(std::fs::read(&to)?, b"signed.efi");
+        assert_eq!(server.join().unwrap(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn tell_apart_unsigned_and_untrusted_binaries() -> Result<()> {
+        let response = |body: &'static str| -> &'static str {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .leak()
+        };
+        let (url, server) = mock_server(vec![
+            response(r#"{"signed":false,"valid_signature":false}"#),
+            response(r#"{"signed":true,"valid_signature":false}"#),
+            response(r#"{"signed":true,"valid_signature":true}"#),
+        ]);
+        let signer = RemoteSigningServer::new(&url, "test").with_retry_policy(fast_retries());
+
+        assert_eq!(
+            signer.verify_detailed(b"binary")?,
+            VerificationResult::Unsigned
+        );
+        assert_eq!(
+            signer.verify_detailed(b"binary")?,
+            VerificationResult::SignedUntrusted
+        );
+        assert_eq!(
+            signer.verify_detailed(b"binary")?,
+            VerificationResult::SignedTrusted
+        );
+        assert_eq!(server.join().unwrap(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = RetryPolicy {
+            attempts: 4,
+            initial_backoff: Duration::from_millis(100),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn retry_server_errors() -> Result<()> {
+        let (url, server) = mock_server(vec![UNAVAILABLE, UNAVAILABLE, PUBLIC_KEY]);
+        let signer = RemoteSigningServer::new(&url, "test").with_retry_policy(fast_retries());
+
+        assert_eq!(signer.get_public_key()?, b"key");
+        assert_eq!(server.join().unwrap(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn give_up_after_all_attempts() {
+        let (url, server) = mock_server(vec![UNAVAILABLE, UNAVAILABLE, UNAVAILABLE]);
+        let signer = RemoteSigningServer::new(&url, "test").with_retry_policy(fast_retries());
+
+        assert!(signer.get_public_key().is_err());
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn do_not_retry_client_errors() {
+        let (url, server) = mock_server(vec![NOT_FOUND]);
+        let signer = RemoteSigningServer::new(&url, "test").with_retry_policy(fast_retries());
+
+        assert!(signer.get_public_key().is_err());
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn report_policy_refusals() {
+        let (url, server) = mock_server(vec![FORBIDDEN]);
+        let signer = RemoteSigningServer::new(&url, "test").with_retry_policy(fast_retries());
+        let stub = StubParameters::new_fat(
+            Path::new("/nix/store/stub.efi"),
+            Path::new("/nix/store/kernel"),
+            Some(Path::new("/nix/store/initrd")),
+        );
+
+        let error = signer.build_and_sign_stub(&stub).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("policy of the remote signing server forbids it"));
+        assert_eq!(server.join().unwrap(), 1);
+    }
+}
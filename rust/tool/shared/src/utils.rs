@@ -8,6 +8,7 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
 use tempfile::TempDir;
+use walkdir::WalkDir;
 
 /// The number of random alphanumeric characters in the tempfiles.
 const TEMPFILE_RANDOM_LENGTH: usize = 32;
@@ -75,3 +76,28 @@ pub fn file_hash(file: &Path) -> Result<Hash> {
         format!("Failed to read file to hash: {file:?}")
     })?))
 }
+
+/// Compute the SHA 256 hash of a directory tree.
+///
+/// The hash covers the relative path and the contents of every file, so it changes when a file
+/// is added, removed, renamed or modified.
+pub fn directory_hash(directory: &Path) -> Result<Hash> {
+    let mut hasher = Sha256::new();
+    for entry in WalkDir::new(directory)
+        .follow_links(true)
+        .sort_by_file_name()
+    {
+        let entry = entry.with_context(|| format!("Failed to read directory {directory:?}"))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative_path = entry
+            .path()
+            .strip_prefix(directory)
+            .expect("Walked paths are inside the walked directory");
+        hasher.update(relative_path.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(file_hash(entry.path())?);
+    }
+    Ok(hasher.finalize())
+}
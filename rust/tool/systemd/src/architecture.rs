@@ -12,3 +12,25 @@ impl SystemdArchitectureExt for Architecture {
         format!("systemd-boot{}.efi", self.efi_representation()).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_systemd_boot_filenames() -> anyhow::Result<()> {
+        for (system, systemd_filename) in [
+            ("x86_64-linux", "systemd-bootx64.efi"),
+            ("i686-linux", "systemd-bootia32.efi"),
+            ("aarch64-linux", "systemd-bootaa64.efi"),
+            ("armv7l-linux", "systemd-bootarm.efi"),
+            ("riscv64-linux", "systemd-bootriscv64.efi"),
+        ] {
+            assert_eq!(
+                Architecture::from_nixos_system(system)?.systemd_filename(),
+                PathBuf::from(systemd_filename)
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::syncfs;
+use serde::{Deserialize, Serialize};
+use tempfile::{tempdir, TempDir};
+use walkdir::WalkDir;
+
+use crate::esp::{parse_esp_subdir, SystemdEspPaths, SYSTEMD_BOOT_VERSIONS};
+use crate::install::{collect_garbage, install, newer_systemd_boot, DEFAULT_IO_RETRIES};
+use crate::lock::lock_esp;
+use crate::version::{SystemdVersion, SystemdVersionCache};
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp::{EspPaths, DEFAULT_ESP_SUBDIR};
+use lanzaboote_tool::gc::Roots;
+use lanzaboote_tool::pe::{lanzaboote_image, StubParameters};
+use lanzaboote_tool::signature::Signer;
+
+/// Version of the bundle layout, increased on incompatible changes.
+const BUNDLE_VERSION: u32 = 1;
+
+/// The manifest describing the bundle, see [`Manifest`].
+const MANIFEST: &str = "manifest.json";
+/// The public key the stub names were computed with.
+const PUBLIC_KEY: &str = "public-key";
+/// The directory containing the files to install, laid out like the ESP.
+const ESP: &str = "esp";
+
+/// Bundles carry an installation to a machine that holds the signing keys and back.
+///
+/// A bundle is an uncompressed tar archive with this layout:
+///
+/// - `manifest.json`: this manifest.
+/// - `public-key`: the public key that the stub names are derived from. Only a signer with this
+///   key can sign the bundle.
+/// - `esp/`: all files to install, at their paths relative to the ESP.
+///
+/// The stubs are assembled when the bundle is created, so that signing does not need access to
+/// the Nix store.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// The NixOS system the bundle was created for, e.g. `x86_64-linux`.
+    system: String,
+    /// The name of the directory in `EFI` that holds the NixOS files.
+    #[serde(default = "default_esp_subdir")]
+    esp_subdir: String,
+    /// Whether the PE binaries in the bundle are signed.
+    signed: bool,
+    /// Generations that could not be read. Garbage collection is disabled while there are any.
+    broken_generations: BTreeSet<u64>,
+    files: Vec<BundleFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleFile {
+    /// Path relative to the ESP.
+    path: PathBuf,
+    /// Whether the file is a PE binary that must be signed before it is installed.
+    ///
+    /// Only informational: readers recompute it with [`needs_signature`], so that a manifest
+    /// cannot make the signer sign arbitrary files.
+    sign: bool,
+}
+
+fn default_esp_subdir() -> String {
+    DEFAULT_ESP_SUBDIR.to_owned()
+}
+
+/// A signer that leaves everything unsigned, used to stage the installation of a bundle.
+pub struct UnsignedSigner {
+    public_key: Vec<u8>,
+}
+
+impl UnsignedSigner {
+    pub fn new(public_key: Vec<u8>) -> Self {
+        Self { public_key }
+    }
+}
+
+impl Signer for UnsignedSigner {
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        fs::read(store_path).with_context(|| format!("Failed to read {store_path:?}"))
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        let working_tree = tempdir()?;
+        let image = lanzaboote_image(&working_tree, stub)?;
+        fs::read(&image).context("Failed to read a lanzaboote image")
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        Ok(self.public_key.clone())
+    }
+
+    fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Write the installation staged in `staging_esp` into a bundle.
+pub fn write_bundle(
+    staging_esp: &Path,
+    system: &str,
+    esp_subdir: &str,
+    public_key: &[u8],
+    broken_generations: BTreeSet<u64>,
+    out: &Path,
+) -> Result<()> {
+    let files = esp_files(staging_esp)?
+        .into_iter()
+        .map(|path| BundleFile {
+            sign: needs_signature(&path),
+            path,
+        })
+        .collect();
+    let manifest = Manifest {
+        version: BUNDLE_VERSION,
+        system: system.to_owned(),
+        esp_subdir: esp_subdir.to_owned(),
+        signed: false,
+        broken_generations,
+        files,
+    };
+
+    let tmpdir = tempdir()?;
+    fs::write(tmpdir.path().join(PUBLIC_KEY), public_key)?;
+    write_archive(&manifest, tmpdir.path(), staging_esp, out)
+}
+
+/// Sign all PE binaries of the bundle at `bundle` and write the signed bundle to `out`.
+pub fn sign_bundle(bundle: &Path, signer: &impl Signer, out: &Path) -> Result<()> {
+    let (mut manifest, contents) = read_bundle(bundle)?;
+    if manifest.signed {
+        bail!("The bundle {bundle:?} is already signed.");
+    }
+
+    let public_key = fs::read(contents.path().join(PUBLIC_KEY))
+        .context("Failed to read the public key of the bundle.")?;
+    if signer.get_public_key()? != public_key {
+        bail!("The bundle {bundle:?} was created for a different public key.");
+    }
+
+    let esp = contents.path().join(ESP);
+    for file in manifest.files.iter().filter(|f| needs_signature(&f.path)) {
+        let path = esp.join(&file.path);
+        let signed_path = path.with_extension("signed");
+        signer
+            .sign_and_copy(&path, &signed_path)
+            .with_context(|| format!("Failed to sign {:?}", file.path))?;
+        fs::rename(&signed_path, &path)?;
+    }
+    manifest.signed = true;
+
+    write_archive(&manifest, contents.path(), &esp, out)
+}
+
+/// Install the files of the signed bundle at `bundle` to the ESP and collect garbage.
+///
+/// Like `lzbt install`, this never downgrades systemd-boot: an installed binary that is at least
+/// as new as the one in the bundle is kept.
+pub fn apply_bundle(bundle: &Path, esp: &Path, lock_timeout: Duration) -> Result<()> {
+    let _esp_lock = lock_esp(esp, lock_timeout)?;
+
+    let (manifest, contents) = read_bundle(bundle)?;
+    if !manifest.signed {
+        bail!("The bundle {bundle:?} is not signed. Sign it with `lzbt sign-bundle` first.");
+    }
+
+    let esp_paths = SystemdEspPaths::new(
+        esp,
+        &manifest.esp_subdir,
+        Architecture::from_nixos_system(&manifest.system)?,
+    );
+    let mut roots = Roots::new();
+    roots.extend(esp_paths.iter());
+    let mut versions = SystemdVersionCache::load(&esp_paths.systemd_boot_versions, esp);
+
+    for file in &manifest.files {
+        let from = contents.path().join(ESP).join(&file.path);
+        let to = esp.join(&file.path);
+        if to == esp_paths.systemd_boot || to == esp_paths.efi_fallback {
+            let version = SystemdVersion::from_systemd_boot_binary(&from).with_context(|| {
+                format!("Failed to read systemd-boot version from {:?}.", file.path)
+            })?;
+            if newer_systemd_boot(&version, &to, &mut versions) {
+                tracing::info!("Updating {to:?}...");
+                install(&from, &to, DEFAULT_IO_RETRIES)
+                    .with_context(|| format!("Failed to install {:?}", file.path))?;
+                versions.insert(&to, version)?;
+            }
+        } else {
+            install(&from, &to, DEFAULT_IO_RETRIES)
+                .with_context(|| format!("Failed to install {:?}", file.path))?;
+        }
+        // Parent directories must be roots as well.
+        let ancestors = file
+            .path
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| esp.join(p))
+            .collect::<Vec<_>>();
+        roots.extend(&ancestors);
+    }
+
+    versions.save(&esp_paths.systemd_boot_versions)?;
+
+    let esp_file = File::open(esp).context("Failed to open ESP root directory.")?;
+    syncfs(esp_file.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+
+    collect_garbage(&roots, &esp_paths, &manifest.broken_generations)
+}
+
+/// PE binaries that are loaded by the firmware need to be signed. Kernels and initrds in
+/// `EFI/nixos` are verified by the stub through their hashes instead.
+fn needs_signature(path: &Path) -> bool {
+    ["EFI/Linux", "EFI/systemd", "EFI/BOOT"]
+        .iter()
+        .any(|dir| path.starts_with(dir))
+        && path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("efi"))
+}
+
+/// The paths of all files in `esp` that belong into a bundle, relative to it.
+fn esp_files(esp: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(esp).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Failed to read {esp:?}"))?;
+        let path = entry
+            .path()
+            .strip_prefix(esp)
+            .expect("Walked paths are inside the walked directory");
+        if entry.file_type().is_file() && path != Path::new(SYSTEMD_BOOT_VERSIONS) {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+/// Write a bundle with `manifest`, the public key in `contents` and the files in `esp`.
+fn write_archive(manifest: &Manifest, contents: &Path, esp: &Path, out: &Path) -> Result<()> {
+    fs::write(
+        contents.join(MANIFEST),
+        serde_json::to_vec_pretty(manifest)?,
+    )?;
+
+    let file = File::create(out).with_context(|| format!("Failed to create {out:?}"))?;
+    let mut archive = tar::Builder::new(file);
+    // Bundles of the same installation are identical, regardless of the timestamps, owners and
+    // directory order of the staged files.
+    archive.mode(tar::HeaderMode::Deterministic);
+    archive.append_path_with_name(contents.join(MANIFEST), MANIFEST)?;
+    archive.append_path_with_name(contents.join(PUBLIC_KEY), PUBLIC_KEY)?;
+    for entry in WalkDir::new(esp).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Failed to read {esp:?}"))?;
+        let path = entry
+            .path()
+            .strip_prefix(esp)
+            .expect("Walked paths are inside the walked directory");
+        archive.append_path_with_name(entry.path(), Path::new(ESP).join(path))?;
+    }
+    archive
+        .into_inner()
+        .with_context(|| format!("Failed to write the bundle {out:?}"))?
+        .sync_all()?;
+    Ok(())
+}
+
+/// Unpack a bundle into a temporary directory and read its manifest.
+///
+/// The manifest is untrusted: its paths must stay inside the ESP and the unpacked bundle, and its
+/// `esp_subdir` is checked like `--esp-subdir`.
+fn read_bundle(bundle: &Path) -> Result<(Manifest, TempDir)> {
+    let contents = tempdir()?;
+    let file = File::open(bundle).with_context(|| format!("Failed to open {bundle:?}"))?;
+    tar::Archive::new(file)
+        .unpack(contents.path())
+        .with_context(|| format!("Failed to unpack the bundle {bundle:?}"))?;
+
+    let manifest: Manifest = serde_json::from_slice(
+        &fs::read(contents.path().join(MANIFEST)).context("The bundle has no manifest.")?,
+    )
+    .context("Failed to parse the manifest of the bundle.")?;
+    if manifest.version != BUNDLE_VERSION {
+        bail!(
+            "Unsupported bundle version {}, expected {BUNDLE_VERSION}.",
+            manifest.version
+        );
+    }
+
+    parse_esp_subdir(&manifest.esp_subdir).context("Invalid ESP subdirectory in the bundle.")?;
+
+    let esp = contents.path().join(ESP);
+    for file in &manifest.files {
+        if file.path.as_os_str().is_empty()
+            || !file
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("The bundle contains the invalid path {:?}.", file.path);
+        }
+        // Symlinks in the unpacked bundle could point outside of it.
+        let mut path = esp.clone();
+        for component in file.path.components() {
+            path.push(component);
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_symlink()) {
+                bail!("The bundle contains a symlink at {:?}.", file.path);
+            }
+        }
+        if !path.is_file() {
+            bail!("The bundle is missing {:?}.", file.path);
+        }
+    }
+
+    Ok((manifest, contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_files_to_sign() {
+        assert!(needs_signature(Path::new(
+            "EFI/Linux/nixos-generation-1.efi"
+        )));
+        assert!(needs_signature(Path::new("EFI/BOOT/BOOTX64.EFI")));
+        assert!(needs_signature(Path::new(
+            "EFI/systemd/systemd-bootx64.efi"
+        )));
+        assert!(!needs_signature(Path::new(
+            "EFI/nixos/kernel-6.1.1-abc.efi"
+        )));
+        assert!(!needs_signature(Path::new("loader/loader.conf")));
+    }
+
+    #[test]
+    fn reject_unsigned_bundle() -> Result<()> {
+        let staging = tempdir()?;
+        let esp = tempdir()?;
+        let tmpdir = tempdir()?;
+        fs::create_dir_all(staging.path().join("loader"))?;
+        fs::write(staging.path().join("loader/loader.conf"), "timeout 0\n")?;
+
+        let bundle = tmpdir.path().join("bundle.tar");
+        write_bundle(
+            staging.path(),
+            "x86_64-linux",
+            DEFAULT_ESP_SUBDIR,
+            b"public key",
+            BTreeSet::new(),
+            &bundle,
+        )?;
+
+        let (manifest, contents) = read_bundle(&bundle)?;
+        assert!(!manifest.signed);
+        assert_eq!(
+            fs::read(contents.path().join(PUBLIC_KEY))?,
+            b"public key".to_vec()
+        );
+        assert!(apply_bundle(&bundle, esp.path(), Duration::ZERO).is_err());
+        assert!(!esp.path().join("loader/loader.conf").exists());
+
+        Ok(())
+    }
+
+    /// Write a bundle with a manifest listing `paths` in `esp_subdir`, containing only
+    /// `loader/loader.conf`.
+    fn bundle_with_manifest(out: &Path, esp_subdir: &str, paths: &[&str]) -> Result<()> {
+        let contents = tempdir()?;
+        let esp = contents.path().join(ESP);
+        fs::create_dir_all(esp.join("loader"))?;
+        fs::write(esp.join("loader/loader.conf"), "timeout 0\n")?;
+        fs::write(contents.path().join(PUBLIC_KEY), b"public key")?;
+        let manifest = Manifest {
+            version: BUNDLE_VERSION,
+            system: "x86_64-linux".to_owned(),
+            esp_subdir: esp_subdir.to_owned(),
+            signed: true,
+            broken_generations: BTreeSet::new(),
+            files: paths
+                .iter()
+                .map(|path| BundleFile {
+                    path: PathBuf::from(path),
+                    sign: true,
+                })
+                .collect(),
+        };
+        write_archive(&manifest, contents.path(), &esp, out)
+    }
+
+    #[test]
+    fn reject_paths_outside_of_the_esp() -> Result<()> {
+        let tmpdir = tempdir()?;
+        let bundle = tmpdir.path().join("bundle.tar");
+
+        bundle_with_manifest(&bundle, DEFAULT_ESP_SUBDIR, &["loader/loader.conf"])?;
+        assert!(read_bundle(&bundle).is_ok());
+
+        for path in [
+            "../manifest.json",
+            "loader/../../manifest.json",
+            "/etc/hostname",
+        ] {
+            bundle_with_manifest(&bundle, DEFAULT_ESP_SUBDIR, &[path])?;
+            assert!(read_bundle(&bundle).is_err(), "{path:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_esp_subdir() -> Result<()> {
+        let tmpdir = tempdir()?;
+        let bundle = tmpdir.path().join("bundle.tar");
+
+        for esp_subdir in ["../..", "", "Linux"] {
+            bundle_with_manifest(&bundle, esp_subdir, &["loader/loader.conf"])?;
+            assert!(read_bundle(&bundle).is_err(), "{esp_subdir:?}");
+        }
+        Ok(())
+    }
+}
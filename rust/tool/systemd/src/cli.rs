@@ -1,9 +1,40 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
 
-use crate::install;
+use crate::bundle::{self, UnsignedSigner};
+use crate::doctor::BrokenGenerations;
+use crate::efivars;
+use crate::esp::{
+    check_esp, gpt_partition, parse_esp_subdir, SystemdEspPaths, SYSTEMD_BOOT_VERSIONS,
+};
+use crate::gc::GarbageCollector;
+use crate::initrd::Compression;
+use crate::install::{self, KernelParams};
+use crate::layout::EspLayout;
+use crate::loader_conf;
+use crate::lock::DEFAULT_LOCK_TIMEOUT;
+use crate::preview::StubPreview;
+use crate::recovery;
+use crate::resign::Resigner;
+use crate::status::EspStatus;
+use crate::uki::UkiInstaller;
+use crate::verify::EspAudit;
+use lanzaboote_tool::certificate;
+use lanzaboote_tool::esp::{EspPaths, DEFAULT_ESP_SUBDIR};
+use lanzaboote_tool::generation::{discover_generation_links, Generation, GenerationLink};
+use lanzaboote_tool::os_release::{EntryTemplate, EntryTemplates};
+use lanzaboote_tool::pe;
+use lanzaboote_tool::signature::policy::{CmdlinePolicy, PolicySigner};
+use lanzaboote_tool::signature::remote::{RemoteSigningServer, Timeouts};
+use lanzaboote_tool::signature::Signer;
 use lanzaboote_tool::{architecture::Architecture, signature::local::LocalKeyPair};
 
 /// The default log level.
@@ -19,13 +50,56 @@ pub struct Cli {
     /// Verbose mode (-v, -vv, etc.)
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
+    /// Format of the log on stderr
+    #[arg(long, value_enum, default_value_t = LogFormat::Human)]
+    log_format: LogFormat,
     #[clap(subcommand)]
     commands: Commands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Plain messages, prefixed with the generation they belong to
+    Human,
+    /// One JSON object per message, including the generation it belongs to
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    Install(InstallCommand),
+    Install(Box<InstallCommand>),
+    /// Remove files of generations that are not in the list of generation links from the ESP
+    Gc(GcCommand),
+    /// Select the boot entry of a generation as the systemd-boot default
+    SetDefault(SetDefaultCommand),
+    /// Preview which stubs an installation with the given key would reuse or regenerate
+    WillRegenerate(WillRegenerateCommand),
+    /// Print the stubs, kernels and initrds an installation of the generations would write
+    PrintEspLayout(PrintEspLayoutCommand),
+    /// Build a bootable FAT image containing the latest generation, e.g. for a recovery USB stick
+    MakeRecoveryImage(MakeRecoveryImageCommand),
+    /// Stage an installation into a bundle that is signed on another machine
+    Bundle(BundleCommand),
+    /// Sign all PE binaries of a bundle
+    SignBundle(SignBundleCommand),
+    /// Install a signed bundle to the ESP
+    ApplyBundle(ApplyBundleCommand),
+    /// Re-sign the stubs and systemd-boot on the ESP with a new key
+    Resign(ResignCommand),
+    /// List the generations that cannot be read and disable the garbage collection
+    Doctor(DoctorCommand),
+    /// Create or update the firmware boot entry of systemd-boot and boot it first
+    EfibootmgrEntry(EfibootmgrEntryCommand),
+    /// Summarize the installed systemd-boot, generations and space used on the ESP
+    Status(StatusCommand),
+    /// Build and sign the stub of a single generation without installing it to an ESP
+    SignStub(Box<SignStubCommand>),
+    /// Verify the signatures of systemd-boot and the stubs on the ESP
+    Verify(VerifyCommand),
+    /// Sign a UKI built outside of NixOS, e.g. with ukify, and install it to `EFI/Linux`
+    InstallUki(InstallUkiCommand),
+    /// Export the signing certificate for enrollment into the Secure Boot `db`
+    ExportCert(ExportCertCommand),
 }
 
 #[derive(Parser)]
@@ -42,68 +116,1255 @@ struct InstallCommand {
     #[arg(long)]
     systemd_boot_loader_config: PathBuf,
 
-    /// sbsign Public Key
+    #[command(flatten)]
+    signer: SignerArgs,
+
+    /// Configuration limit
+    #[arg(long, default_value_t = 1)]
+    configuration_limit: usize,
+
+    /// Embed an anti-rollback counter (this base plus the generation number) into the stubs
     #[arg(long)]
+    rollback_counter_base: Option<u64>,
+
+    /// Embed the os-release of the generation's toplevel into the stubs
+    #[arg(long)]
+    full_os_release: bool,
+
+    /// Title of the boot entries instead of "<label> (Generation <version>, <date>)", with the
+    /// placeholders {version}, {label}, {date} and {specialisation}
+    #[arg(long)]
+    entry_title_template: Option<EntryTemplate>,
+
+    /// Sort key of the boot entries, with the same placeholders as --entry-title-template
+    #[arg(long)]
+    entry_sort_key_template: Option<EntryTemplate>,
+
+    /// Seconds to wait for another installation to release the ESP (0 fails immediately)
+    #[arg(long, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+
+    /// How often to retry copying a file to the ESP after a transient IO error, e.g. on a flaky
+    /// USB drive
+    #[arg(long, default_value_t = install::DEFAULT_IO_RETRIES)]
+    io_retries: u32,
+
+    /// Level up to which the stubs mirror their log to the serial port
+    #[arg(long, value_parser = ["off", "error", "warn", "info"])]
+    stub_log_level: Option<String>,
+
+    /// CSV file with SBAT metadata that replaces the one the stub was built with
+    #[arg(long)]
+    sbat: Option<PathBuf>,
+
+    /// BMP image the stubs draw instead of their logo, uncompressed with 24 or 32 bits per pixel
+    #[arg(long)]
+    splash: Option<PathBuf>,
+
+    /// Embed the kernel and initrd into the stubs, producing self-contained UKIs
+    #[arg(long)]
+    fat: bool,
+
+    /// Compress the kernel and initrd embedded into fat stubs with zstd. The fat stub must be
+    /// built with the `compressed-sections` feature
+    #[arg(long, requires = "fat")]
+    compress_sections: bool,
+
+    /// Install the device trees from the `dtbs` directory of each generation to the ESP
+    #[arg(long)]
+    install_dtb_dir: bool,
+
+    /// Make the stubs ignore command lines passed by the bootloader, even without Secure Boot
+    #[arg(long)]
+    forbid_cmdline_editing: bool,
+
+    /// Let systemd-boot fall back to the previous generation after this many failed boots of a
+    /// newly installed latest generation
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    boot_counting: Option<u32>,
+
+    /// (Re)compress the initrds before installing them, e.g. to save space on the ESP
+    #[arg(long, value_enum)]
+    compression: Option<Compression>,
+
+    /// Put an initrd in front of the initrds of every generation, e.g. CPU microcode, can be given
+    /// multiple times
+    #[arg(long, value_name = "PATH")]
+    initrd_prepend: Vec<PathBuf>,
+
+    /// Append a file to the initrds as initrd secret, for generations without an initrd secrets
+    /// script
+    #[arg(long = "initrd-secret", value_name = "PATH=SOURCE", value_parser = parse_initrd_secret)]
+    initrd_secrets: Vec<(PathBuf, PathBuf)>,
+
+    /// Fail instead of warning if the ESP is not a FAT file system on an EFI System Partition
+    #[arg(long)]
+    strict: bool,
+
+    /// Install into the raw FAT image file at the ESP path instead of a mountpoint, creating it if
+    /// it does not exist
+    #[arg(long, conflicts_with = "strict")]
+    esp_image: bool,
+
+    /// Do not install systemd-boot to the removable media path `EFI/BOOT`, e.g. to keep another
+    /// bootloader there
+    #[arg(long)]
+    no_fallback: bool,
+
+    #[command(flatten)]
+    kernel_params: KernelParamsArgs,
+
+    /// Only install the generation with this version, leaving the others and their files as they
+    /// are
+    #[arg(long = "only-generation", value_name = "VERSION")]
+    only_generations: Vec<u64>,
+
+    /// Skip generations other than the latest that fail to install instead of aborting
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Rewrite all files on the ESP, even those that are already installed, e.g. to repair it
+    #[arg(long)]
+    force: bool,
+
+    /// Install a toplevel that has no generation link (e.g. a system closure built from a flake)
+    /// as the generation after the newest link, can be given multiple times
+    #[arg(long = "toplevel", value_name = "PATH")]
+    toplevels: Vec<PathBuf>,
+
+    /// Set `default` in loader.conf to the boot entry of the latest generation
+    #[arg(long)]
+    loader_default_latest: bool,
+
+    /// Set `timeout` in loader.conf, in seconds or one of `menu-force`, `menu-hidden` and
+    /// `menu-disabled`
+    #[arg(long, value_parser = parse_loader_timeout)]
+    loader_timeout: Option<String>,
+
+    /// Set `console-mode` in loader.conf
+    #[arg(long)]
+    loader_console_mode: Option<String>,
+
+    /// Fail instead of warning if the systemd-boot loader config has unknown keys or invalid
+    /// values
+    #[arg(long)]
+    strict_loader_config: bool,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// Also install the `system-*-link` generation links in this directory (e.g.
+    /// /nix/var/nix/profiles)
+    #[arg(long)]
+    profiles_dir: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SignerKind {
+    /// Sign with a key pair on the local disk using sbsign
+    Local,
+    /// Sign with a remote signing server
+    Remote,
+}
+
+/// The kernel parameters of the installation, which the stub names depend on.
+#[derive(Args)]
+struct KernelParamsArgs {
+    /// Remove a kernel parameter from the command lines, either `key=value` or `key` for any value
+    #[arg(long = "remove-kernel-param", value_name = "PARAM")]
+    remove: Vec<String>,
+
+    /// Append a kernel parameter to the command lines, after removing parameters
+    #[arg(long = "append-kernel-param", value_name = "PARAM")]
+    append: Vec<String>,
+
+    /// Only change the kernel parameters of the latest generation
+    #[arg(long = "kernel-params-latest-only")]
+    latest_only: bool,
+}
+
+impl From<KernelParamsArgs> for KernelParams {
+    fn from(args: KernelParamsArgs) -> Self {
+        Self {
+            remove: args.remove,
+            append: args.append,
+            latest_only: args.latest_only,
+        }
+    }
+}
+
+#[derive(Args)]
+struct EspSubdirArgs {
+    /// Name of the directory in `EFI` that holds the NixOS files, e.g. to share the ESP with other
+    /// NixOS installations
+    #[arg(
+        long = "esp-subdir",
+        value_name = "ESP_SUBDIR",
+        default_value = DEFAULT_ESP_SUBDIR,
+        value_parser = parse_esp_subdir
+    )]
+    subdir: String,
+}
+
+#[derive(Args)]
+struct SignerArgs {
+    /// Signer used for the stubs and systemd-boot
+    #[arg(long, value_enum, default_value_t = SignerKind::Local)]
+    signer: SignerKind,
+
+    /// sbsign Public Key
+    #[arg(long, conflicts_with_all = ["signer_url", "signer_user_agent"])]
     public_key: Option<PathBuf>,
 
     /// sbsign Private Key
-    #[arg(long)]
+    #[arg(long, conflicts_with_all = ["signer_url", "signer_user_agent"])]
     private_key: Option<PathBuf>,
 
+    /// URL of the remote signing server
+    #[arg(long)]
+    signer_url: Option<String>,
+
+    /// User agent sent to the remote signing server
+    #[arg(long, requires = "signer_url")]
+    signer_user_agent: Option<String>,
+
+    /// Seconds to wait for the remote signing server to accept a connection
+    #[arg(long, default_value_t = 5, requires = "signer_url")]
+    signer_connect_timeout: u64,
+
+    /// Seconds to wait for the remote signing server to receive or send data
+    #[arg(long, default_value_t = 30, requires = "signer_url")]
+    signer_timeout: u64,
+
+    /// JSON policy with the kernel parameters that stubs may be signed with
+    #[arg(long)]
+    cmdline_policy: Option<PathBuf>,
+}
+
+impl SignerArgs {
+    fn into_signer(self) -> Result<Box<dyn Signer + Send + Sync>> {
+        let signer: Box<dyn Signer + Send + Sync> = match self.signer {
+            SignerKind::Local => {
+                if self.signer_url.is_some() {
+                    bail!("--signer-url requires --signer remote.");
+                }
+                let (Some(public_key), Some(private_key)) = (self.public_key, self.private_key)
+                else {
+                    bail!("--signer local requires --public-key and --private-key.");
+                };
+                Box::new(LocalKeyPair::new(&public_key, &private_key)?)
+            }
+            SignerKind::Remote => {
+                if self.public_key.is_some() || self.private_key.is_some() {
+                    bail!("--public-key and --private-key require --signer local.");
+                }
+                let Some(signer_url) = self.signer_url else {
+                    bail!("--signer remote requires --signer-url.");
+                };
+                let user_agent = self
+                    .signer_user_agent
+                    .unwrap_or_else(|| format!("lzbt/{}", env!("CARGO_PKG_VERSION")));
+                let timeouts = Timeouts {
+                    connect: Duration::from_secs(self.signer_connect_timeout),
+                    read: Duration::from_secs(self.signer_timeout),
+                    write: Duration::from_secs(self.signer_timeout),
+                };
+                Box::new(RemoteSigningServer::new(&signer_url, &user_agent).with_timeouts(timeouts))
+            }
+        };
+
+        match self.cmdline_policy {
+            Some(path) => Ok(Box::new(PolicySigner::new(
+                signer,
+                CmdlinePolicy::from_file(&path)?,
+            ))),
+            None => Ok(signer),
+        }
+    }
+}
+
+#[derive(Parser)]
+struct GcCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// sbsign Public Key the generations were installed with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Seconds to wait for an installation to release the ESP (0 fails immediately)
+    #[arg(long, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+
+    #[command(flatten)]
+    kernel_params: KernelParamsArgs,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
+    esp: PathBuf,
+
+    /// List of generation links to keep (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct SetDefaultCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// sbsign Public Key the generation was installed with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Only boot the generation on the next boot (LoaderEntryOneShot) instead of making it the
+    /// default (LoaderEntryDefault)
+    #[arg(long)]
+    oneshot: bool,
+
+    #[command(flatten)]
+    kernel_params: KernelParamsArgs,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// Generation link (e.g. /nix/var/nix/profiles/system-42-link)
+    generation: PathBuf,
+}
+
+#[derive(Parser)]
+struct EfibootmgrEntryCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Description of the boot entry that the firmware shows
+    #[arg(long, default_value = "Linux Boot Manager")]
+    description: String,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct StatusCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
+    esp: PathBuf,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// Print the status as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser)]
+struct VerifyCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    #[command(flatten)]
+    signer: SignerArgs,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
+    esp: PathBuf,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// Number of binaries verified at once (default: the number of CPUs)
+    #[arg(long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Parser)]
+struct InstallUkiCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    #[command(flatten)]
+    signer: SignerArgs,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
+    esp: PathBuf,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// Title of the boot entry, replacing the `PRETTY_NAME` in the os-release of the UKI
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Seconds to wait for an installation to release the ESP (0 fails immediately)
+    #[arg(long, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+
+    /// The UKI to install
+    uki: PathBuf,
+}
+
+#[derive(Parser)]
+struct ExportCertCommand {
+    /// Certificate used for signing, PEM- or DER-encoded
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Format of the exported certificate
+    #[arg(long, value_enum, default_value_t = CertFormat::Der)]
+    format: CertFormat,
+
+    /// Owner GUID of the entry in the EFI signature list
+    #[arg(
+        long,
+        value_parser = certificate::parse_guid,
+        default_value = "00000000-0000-0000-0000-000000000000"
+    )]
+    owner: [u8; 16],
+
+    /// Where to write the certificate
+    #[arg(long)]
+    out: PathBuf,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CertFormat {
+    /// The DER-encoded X.509 certificate, e.g. for the key enrollment menu of the firmware
+    Der,
+    /// An EFI signature list containing the certificate, e.g. for `efi-updatevar`
+    Esl,
+}
+
+#[derive(Parser)]
+struct SignStubCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    #[command(flatten)]
+    signer: SignerArgs,
+
+    /// Kernel the stub loads
+    #[arg(long)]
+    kernel: PathBuf,
+
+    /// Initrd the stub loads, if any
+    #[arg(long, requires = "initrd_path_at_esp")]
+    initrd: Option<PathBuf>,
+
+    /// Path of the kernel relative to the root of the ESP (e.g. EFI/nixos/kernel.efi)
+    #[arg(long)]
+    kernel_path_at_esp: PathBuf,
+
+    /// Path of the initrd relative to the root of the ESP (e.g. EFI/nixos/initrd.efi)
+    #[arg(long, requires = "initrd")]
+    initrd_path_at_esp: Option<PathBuf>,
+
+    /// Kernel command line parameter, can be given multiple times
+    #[arg(long = "kernel-param")]
+    kernel_params: Vec<String>,
+
+    /// os-release file embedded into the stub
+    #[arg(long)]
+    os_release: Option<PathBuf>,
+
+    /// Path of the signed stub to write
+    #[arg(long)]
+    out: PathBuf,
+}
+
+#[derive(Parser)]
+struct DoctorCommand {
+    /// Directory with the `system-*-link` generation links
+    #[arg(long, default_value = "/nix/var/nix/profiles")]
+    profiles_dir: PathBuf,
+
+    /// Delete the broken generations with `nix-env --delete-generations`
+    #[arg(long)]
+    delete: bool,
+}
+
+#[derive(Parser)]
+struct WillRegenerateCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// sbsign Public Key the next installation will use
+    #[arg(long)]
+    public_key: PathBuf,
+
+    #[command(flatten)]
+    kernel_params: KernelParamsArgs,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct PrintEspLayoutCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// sbsign Public Key the installation will use
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Compression of the initrds the installation will use
+    #[arg(long, value_enum)]
+    compression: Option<Compression>,
+
+    /// Initrds the installation will put in front of the initrds of every generation
+    #[arg(long, value_name = "PATH")]
+    initrd_prepend: Vec<PathBuf>,
+
+    #[command(flatten)]
+    kernel_params: KernelParamsArgs,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct MakeRecoveryImageCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Systemd path
+    #[arg(long)]
+    systemd: PathBuf,
+
+    /// Systemd-boot loader config
+    #[arg(long)]
+    systemd_boot_loader_config: PathBuf,
+
+    #[command(flatten)]
+    signer: SignerArgs,
+
+    /// Path of the image to write
+    #[arg(long)]
+    out: PathBuf,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct BundleCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Systemd path
+    #[arg(long)]
+    systemd: PathBuf,
+
+    /// Systemd-boot loader config
+    #[arg(long)]
+    systemd_boot_loader_config: PathBuf,
+
+    /// sbsign Public Key of the key pair that will sign the bundle
+    #[arg(long)]
+    public_key: PathBuf,
+
     /// Configuration limit
     #[arg(long, default_value_t = 1)]
     configuration_limit: usize,
 
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// Path of the bundle to write
+    #[arg(long)]
+    out: PathBuf,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct SignBundleCommand {
+    #[command(flatten)]
+    signer: SignerArgs,
+
+    /// Path of the signed bundle to write
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Bundle created by `lzbt bundle`
+    bundle: PathBuf,
+}
+
+#[derive(Parser)]
+struct ApplyBundleCommand {
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
     esp: PathBuf,
 
-    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    /// Seconds to wait for another installation to release the ESP (0 fails immediately)
+    #[arg(long, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+
+    /// Fail instead of warning if the ESP is not a FAT file system on an EFI System Partition
+    #[arg(long)]
+    strict: bool,
+
+    /// Bundle signed by `lzbt sign-bundle`
+    bundle: PathBuf,
+}
+
+#[derive(Parser)]
+struct ResignCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// sbsign Public Key the generations were installed with
+    #[arg(long)]
+    old_public_key: PathBuf,
+
+    /// sbsign Public Key to re-sign with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key to re-sign with
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// Seconds to wait for an installation to release the ESP (0 fails immediately)
+    #[arg(long, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+
+    #[command(flatten)]
+    kernel_params: KernelParamsArgs,
+
+    #[command(flatten)]
+    esp_subdir: EspSubdirArgs,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
+    esp: PathBuf,
+
+    /// List of generation links whose stubs are re-signed (e.g. /nix/var/nix/profiles/system-*-link)
     generations: Vec<PathBuf>,
 }
 
 impl Cli {
     pub fn call(self, module: &str) {
-        stderrlog::new()
-            .module(module)
-            .show_level(false)
-            .quiet(self.quiet)
-            .verbosity(DEFAULT_LOG_LEVEL + usize::from(self.verbose))
-            .init()
+        // Only messages of lzbt itself and its shared library are shown, not those of the
+        // libraries they use.
+        let filter = Targets::new()
+            .with_target(module, self.log_level())
+            .with_target("lanzaboote_tool", self.log_level());
+        let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+        let layer = match self.log_format {
+            LogFormat::Human => layer
+                .without_time()
+                .with_level(false)
+                .with_target(false)
+                .with_ansi(std::io::stderr().is_terminal())
+                .boxed(),
+            LogFormat::Json => layer.json().with_span_list(false).boxed(),
+        };
+        tracing_subscriber::registry()
+            .with(layer.with_filter(filter))
+            .try_init()
             .expect("Failed to setup logger.");
 
         if let Err(e) = self.commands.call() {
-            log::error!("{e:#}");
+            tracing::error!("{e:#}");
             std::process::exit(1);
         };
     }
+
+    /// The log level for `--quiet` and the number of `--verbose` flags.
+    fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::OFF;
+        }
+        match DEFAULT_LOG_LEVEL + usize::from(self.verbose) {
+            0 => LevelFilter::ERROR,
+            1 => LevelFilter::WARN,
+            2 => LevelFilter::INFO,
+            3 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    }
 }
 
 impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
-            Commands::Install(args) => install(args),
+            Commands::Install(args) => install(*args),
+            Commands::Gc(args) => gc(args),
+            Commands::SetDefault(args) => set_default(args),
+            Commands::WillRegenerate(args) => will_regenerate(args),
+            Commands::PrintEspLayout(args) => print_esp_layout(args),
+            Commands::MakeRecoveryImage(args) => make_recovery_image(args),
+            Commands::Bundle(args) => bundle(args),
+            Commands::SignBundle(args) => sign_bundle(args),
+            Commands::ApplyBundle(args) => apply_bundle(args),
+            Commands::Resign(args) => resign(args),
+            Commands::Doctor(args) => doctor(args),
+            Commands::EfibootmgrEntry(args) => efibootmgr_entry(args),
+            Commands::Status(args) => status(args),
+            Commands::SignStub(args) => sign_stub(*args),
+            Commands::Verify(args) => verify(args),
+            Commands::InstallUki(args) => install_uki(args),
+            Commands::ExportCert(args) => export_cert(args),
         }
     }
 }
 
 fn install(args: InstallCommand) -> Result<()> {
-    let lanzaboote_stub =
-        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+    let stub_variable = if args.fat {
+        "LANZABOOTE_FAT_STUB"
+    } else {
+        "LANZABOOTE_STUB"
+    };
+    let lanzaboote_stub = std::env::var(stub_variable)
+        .with_context(|| format!("Failed to read {stub_variable} env variable"))?;
+
+    // An ESP image is unpacked into a temporary directory, which is installed into like a
+    // mounted ESP and then packed into the image again.
+    let staging_esp = if args.esp_image {
+        let staging_esp = tempfile::tempdir().context("Failed to create a temporary ESP.")?;
+        if args.esp.exists() {
+            recovery::read_fat_image(&args.esp, staging_esp.path())?;
+        }
+        Some(staging_esp)
+    } else {
+        check_esp_or_warn(&args.esp, args.strict)?;
+        None
+    };
+    let esp = staging_esp
+        .as_ref()
+        .map_or_else(|| args.esp.clone(), |dir| dir.path().to_path_buf());
+
+    let signer = args.signer.into_signer()?;
+
+    let sbat = args
+        .sbat
+        .map(|path| {
+            let sbat = std::fs::read(&path)
+                .with_context(|| format!("Failed to read SBAT metadata {path:?}"))?;
+            pe::validate_sbat(&sbat).with_context(|| format!("Invalid SBAT metadata {path:?}"))?;
+            Ok::<_, anyhow::Error>(sbat)
+        })
+        .transpose()?;
+
+    let splash = args
+        .splash
+        .map(|path| {
+            let splash = std::fs::read(&path)
+                .with_context(|| format!("Failed to read the splash image {path:?}"))?;
+            pe::validate_splash(&splash)
+                .with_context(|| format!("Invalid splash image {path:?}"))?;
+            Ok::<_, anyhow::Error>(splash)
+        })
+        .transpose()?;
+
+    let generations = union_generation_links(args.generations, args.profiles_dir.as_deref())?;
+
+    install::Installer::new(
+        PathBuf::from(lanzaboote_stub),
+        Architecture::from_nixos_system(&args.system)?,
+        args.systemd,
+        args.systemd_boot_loader_config,
+        signer,
+        args.configuration_limit,
+        esp,
+        generations,
+    )
+    .with_rollback_counter_base(args.rollback_counter_base)
+    .with_full_os_release(args.full_os_release)
+    .with_entry_templates(EntryTemplates {
+        title: args.entry_title_template,
+        sort_key: args.entry_sort_key_template,
+    })
+    .with_lock_timeout(Duration::from_secs(args.lock_timeout))
+    .with_io_retries(args.io_retries)
+    .with_stub_log_level(args.stub_log_level)
+    .with_sbat(sbat)
+    .with_splash(splash)
+    .with_fat(args.fat)
+    .with_compressed_sections(args.compress_sections)
+    .with_install_dtb_dir(args.install_dtb_dir)
+    .with_forbid_cmdline_editing(args.forbid_cmdline_editing)
+    .with_boot_counting(args.boot_counting)
+    .with_compression(args.compression)
+    .with_initrd_prepend(args.initrd_prepend)
+    .with_initrd_secret_files(args.initrd_secrets.into_iter().collect())
+    .with_efi_fallback(!args.no_fallback)
+    .with_keep_going(args.keep_going)
+    .with_force(args.force)
+    .with_toplevels(args.toplevels)
+    .with_only_generations(args.only_generations.into_iter().collect())
+    .with_kernel_params(args.kernel_params.into())
+    .with_loader_default_latest(args.loader_default_latest)
+    .with_loader_timeout(args.loader_timeout)
+    .with_loader_console_mode(args.loader_console_mode)
+    .with_strict_loader_config(args.strict_loader_config)
+    .with_esp_subdir(&args.esp_subdir.subdir)
+    .install()?;
+
+    if let Some(staging_esp) = staging_esp {
+        // Replace the image only once the new one is complete.
+        let image_tmp = args.esp.with_extension(".tmp");
+        recovery::write_fat_image(staging_esp.path(), &image_tmp)?;
+        std::fs::rename(&image_tmp, &args.esp)
+            .with_context(|| format!("Failed to move the image {image_tmp:?} to {:?}", args.esp))?;
+        tracing::info!("Successfully wrote the ESP image {:?}.", args.esp);
+    }
 
-    let local_signer = LocalKeyPair::new(
-        &args.public_key.expect("Failed to obtain public key"),
-        &args.private_key.expect("Failed to obtain private key"),
+    Ok(())
+}
+
+fn gc(args: GcCommand) -> Result<()> {
+    let public_key = std::fs::read(&args.public_key)
+        .with_context(|| format!("Failed to read public key {:?}", args.public_key))?;
+
+    GarbageCollector::new(
+        Architecture::from_nixos_system(&args.system)?,
+        args.esp,
+        public_key,
+        args.generations,
+    )
+    .with_lock_timeout(Duration::from_secs(args.lock_timeout))
+    .with_esp_subdir(&args.esp_subdir.subdir)
+    .with_kernel_params(args.kernel_params.into())
+    .collect_garbage()
+}
+
+fn set_default(args: SetDefaultCommand) -> Result<()> {
+    efivars::ensure_root()?;
+
+    let link = GenerationLink::from_path(&args.generation)?;
+    let generation = Generation::from_link(&link)
+        .with_context(|| format!("Failed to build generation from link: {link:?}"))?;
+    let public_key = std::fs::read(&args.public_key)
+        .with_context(|| format!("Failed to read public key {:?}", args.public_key))?;
+
+    let esp_paths = SystemdEspPaths::new(
+        args.esp,
+        &args.esp_subdir.subdir,
+        Architecture::from_nixos_system(&args.system)?,
     );
+    // Whether the generation is the latest one is not known here, so it is looked up by the
+    // command lines of both.
+    let kernel_params = KernelParams::from(args.kernel_params);
+    let stub_names = [true, false]
+        .map(|is_latest| {
+            install::stub_name(
+                &generation,
+                &kernel_params.kernel_cmdline(&generation, is_latest),
+                &public_key,
+                esp_paths.stub_prefix(),
+            )
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    // The entry ID that systemd-boot uses does not include a boot counter.
+    let esp_contents = install::EspContents::read(&esp_paths)?;
+    let Some(stub_name) = stub_names.iter().find(|stub_name| {
+        esp_contents
+            .find_stub(esp_paths.linux_path(), stub_name)
+            .is_some()
+    }) else {
+        bail!(
+            "Generation {generation} is not installed on the ESP: {:?} does not exist.",
+            esp_paths.linux_path().join(&stub_names[0])
+        );
+    };
+
+    let variable = if args.oneshot {
+        "LoaderEntryOneShot"
+    } else {
+        "LoaderEntryDefault"
+    };
+    let entry = stub_name
+        .to_str()
+        .context("Failed to convert the stub name to a string.")?;
+    efivars::write_loader_string_variable(Path::new(efivars::EFIVARFS), variable, entry)?;
 
+    tracing::info!("Set {variable} to {entry}.");
+    Ok(())
+}
+
+fn will_regenerate(args: WillRegenerateCommand) -> Result<()> {
+    let public_key = std::fs::read(&args.public_key)
+        .with_context(|| format!("Failed to read public key {:?}", args.public_key))?;
+    let esp_paths = SystemdEspPaths::new(
+        args.esp,
+        &args.esp_subdir.subdir,
+        Architecture::from_nixos_system(&args.system)?,
+    );
+
+    StubPreview::new(
+        &esp_paths,
+        &args.generations,
+        &public_key,
+        &args.kernel_params.into(),
+    )?
+    .print();
+    Ok(())
+}
+
+fn print_esp_layout(args: PrintEspLayoutCommand) -> Result<()> {
+    let public_key = std::fs::read(&args.public_key)
+        .with_context(|| format!("Failed to read public key {:?}", args.public_key))?;
+    // The paths are printed relative to the root of the ESP.
+    let esp_paths = SystemdEspPaths::new(
+        "/",
+        &args.esp_subdir.subdir,
+        Architecture::from_nixos_system(&args.system)?,
+    );
+
+    EspLayout::new(
+        &esp_paths,
+        &args.generations,
+        &public_key,
+        &args.kernel_params.into(),
+        &args.initrd_prepend,
+        args.compression,
+    )?
+    .print();
+    Ok(())
+}
+
+fn make_recovery_image(args: MakeRecoveryImageCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+
+    // Install into an empty directory that is then copied into the image. A configuration limit
+    // of 1 only installs the latest generation.
+    let esp = tempfile::tempdir().context("Failed to create a temporary ESP.")?;
     install::Installer::new(
         PathBuf::from(lanzaboote_stub),
         Architecture::from_nixos_system(&args.system)?,
         args.systemd,
         args.systemd_boot_loader_config,
-        local_signer,
+        args.signer.into_signer()?,
+        1,
+        esp.path().to_path_buf(),
+        args.generations,
+    )
+    .install()?;
+
+    // The versions cache describes the staging directory, not the image.
+    std::fs::remove_file(esp.path().join(SYSTEMD_BOOT_VERSIONS))
+        .context("Failed to remove the systemd-boot versions cache.")?;
+    recovery::write_fat_image(esp.path(), &args.out)?;
+
+    tracing::info!("Successfully wrote the recovery image to {:?}.", args.out);
+    Ok(())
+}
+
+fn bundle(args: BundleCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+    let public_key = std::fs::read(&args.public_key)
+        .with_context(|| format!("Failed to read public key {:?}", args.public_key))?;
+
+    // Like the recovery image, the installation is staged in an empty directory. The files are
+    // left unsigned and packed into the bundle.
+    let staging_esp = tempfile::tempdir().context("Failed to create a temporary ESP.")?;
+    let mut installer = install::Installer::new(
+        PathBuf::from(lanzaboote_stub),
+        Architecture::from_nixos_system(&args.system)?,
+        args.systemd,
+        args.systemd_boot_loader_config,
+        UnsignedSigner::new(public_key.clone()),
         args.configuration_limit,
+        staging_esp.path().to_path_buf(),
+        args.generations,
+    )
+    .with_esp_subdir(&args.esp_subdir.subdir);
+    installer.install()?;
+
+    bundle::write_bundle(
+        staging_esp.path(),
+        &args.system,
+        &args.esp_subdir.subdir,
+        &public_key,
+        installer.broken_generations().clone(),
+        &args.out,
+    )?;
+
+    tracing::info!("Successfully wrote the bundle to {:?}.", args.out);
+    Ok(())
+}
+
+fn sign_bundle(args: SignBundleCommand) -> Result<()> {
+    let signer = args.signer.into_signer()?;
+    bundle::sign_bundle(&args.bundle, &signer, &args.out)?;
+
+    tracing::info!("Successfully wrote the signed bundle to {:?}.", args.out);
+    Ok(())
+}
+
+fn apply_bundle(args: ApplyBundleCommand) -> Result<()> {
+    check_esp_or_warn(&args.esp, args.strict)?;
+
+    bundle::apply_bundle(
+        &args.bundle,
+        &args.esp,
+        Duration::from_secs(args.lock_timeout),
+    )?;
+
+    tracing::info!("Successfully installed the bundle to {:?}.", args.esp);
+    Ok(())
+}
+
+fn resign(args: ResignCommand) -> Result<()> {
+    let old_public_key = std::fs::read(&args.old_public_key)
+        .with_context(|| format!("Failed to read public key {:?}", args.old_public_key))?;
+    let signer = LocalKeyPair::new(&args.public_key, &args.private_key)?;
+
+    Resigner::new(
+        Architecture::from_nixos_system(&args.system)?,
         args.esp,
+        signer,
+        old_public_key,
         args.generations,
     )
-    .install()
+    .with_lock_timeout(Duration::from_secs(args.lock_timeout))
+    .with_esp_subdir(&args.esp_subdir.subdir)
+    .with_kernel_params(args.kernel_params.into())
+    .resign()
+}
+
+fn doctor(args: DoctorCommand) -> Result<()> {
+    let broken = BrokenGenerations::find(&args.profiles_dir)?;
+    if broken.is_empty() {
+        tracing::info!("All generations in {:?} can be read.", args.profiles_dir);
+        return Ok(());
+    }
+
+    broken.print();
+    if args.delete {
+        broken.delete()?;
+        tracing::info!("Successfully deleted the broken generations.");
+    }
+    Ok(())
+}
+
+fn efibootmgr_entry(args: EfibootmgrEntryCommand) -> Result<()> {
+    efivars::ensure_root()?;
+
+    let esp_paths = SystemdEspPaths::new(
+        &args.esp,
+        DEFAULT_ESP_SUBDIR,
+        Architecture::from_nixos_system(&args.system)?,
+    );
+    if !esp_paths.systemd_boot.exists() {
+        bail!(
+            "systemd-boot is not installed to {:?}. Install it with `lzbt install` first.",
+            esp_paths.systemd_boot
+        );
+    }
+    let loader_path = pe::esp_relative_uefi_path(
+        &args.esp,
+        &esp_paths.systemd_boot,
+        pe::DEFAULT_MAX_UEFI_PATH_LENGTH,
+    )?;
+    let partition = gpt_partition(&args.esp)
+        .with_context(|| format!("Failed to read the partition of the ESP {:?}", args.esp))?
+        .with_context(|| format!("The ESP {:?} is not on a GPT partition.", args.esp))?;
+
+    let number = efivars::write_boot_entry(
+        Path::new(efivars::EFIVARFS),
+        &args.description,
+        &partition,
+        &loader_path,
+    )?;
+
+    tracing::info!("Boot{number:04X} boots {loader_path} and is first in BootOrder.");
+    Ok(())
+}
+
+/// Add the generation links discovered in `profiles_dir` to the explicitly passed ones.
+///
+/// The links are sorted by version like the installation does. An explicitly passed link takes
+/// precedence over a discovered link with the same version.
+fn union_generation_links(
+    generations: Vec<PathBuf>,
+    profiles_dir: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let Some(profiles_dir) = profiles_dir else {
+        return Ok(generations);
+    };
+
+    let mut links = BTreeMap::new();
+    for path in discover_generation_links(profiles_dir)?
+        .into_iter()
+        .chain(generations)
+    {
+        let link = GenerationLink::from_path(&path)?;
+        links.insert(link.version, path);
+    }
+    Ok(links.into_values().collect())
+}
+
+/// Warn if the ESP does not look like one, or fail if `strict` is set.
+fn check_esp_or_warn(esp: &Path, strict: bool) -> Result<()> {
+    if let Err(err) = check_esp(esp) {
+        if strict {
+            return Err(err);
+        }
+        tracing::warn!("{err:#} The firmware may not boot from it.");
+    }
+    Ok(())
+}
+
+fn status(args: StatusCommand) -> Result<()> {
+    let esp_paths = SystemdEspPaths::new(
+        &args.esp,
+        &args.esp_subdir.subdir,
+        Architecture::from_nixos_system(&args.system)?,
+    );
+    let status = EspStatus::new(&esp_paths)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        status.print();
+    }
+    Ok(())
+}
+
+fn sign_stub(args: SignStubCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+    let lanzaboote_stub = PathBuf::from(lanzaboote_stub);
+    let stub = std::fs::read(&lanzaboote_stub)
+        .with_context(|| format!("Failed to read lanzaboote stub {lanzaboote_stub:?}"))?;
+    pe::validate_stub(&stub, Architecture::from_nixos_system(&args.system)?)
+        .with_context(|| format!("Invalid lanzaboote stub {lanzaboote_stub:?}."))?;
+    let signer = args.signer.into_signer()?;
+
+    let os_release = args
+        .os_release
+        .map(|path| {
+            std::fs::read(&path).with_context(|| format!("Failed to read os-release {path:?}"))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    // The ESP paths are given relative to its root, so nothing needs to be mounted.
+    let esp = Path::new("/");
+    let initrd_target = args.initrd_path_at_esp.map(|path| esp.join(path));
+    let parameters = pe::StubParameters::new(
+        &lanzaboote_stub,
+        &args.kernel,
+        args.initrd.as_deref(),
+        &esp.join(&args.kernel_path_at_esp),
+        initrd_target.as_deref(),
+        esp,
+    )?
+    .with_cmdline(&args.kernel_params)
+    .with_os_release_contents(&os_release);
+
+    install::install_signed_stub(&signer, &parameters, &args.out)?;
+
+    tracing::info!("Successfully wrote the signed stub to {:?}.", args.out);
+    Ok(())
+}
+
+fn install_uki(args: InstallUkiCommand) -> Result<()> {
+    let signer = args.signer.into_signer()?;
+    let target = UkiInstaller::new(
+        Architecture::from_nixos_system(&args.system)?,
+        args.esp,
+        signer,
+        args.uki,
+    )
+    .with_esp_subdir(&args.esp_subdir.subdir)
+    .with_title(args.title)
+    .with_lock_timeout(Duration::from_secs(args.lock_timeout))
+    .install()?;
+    println!("{}", target.display());
+    Ok(())
+}
+
+fn export_cert(args: ExportCertCommand) -> Result<()> {
+    let certificate = certificate::read_certificate_der(&args.public_key)?;
+    let contents = match args.format {
+        CertFormat::Der => certificate,
+        CertFormat::Esl => certificate::signature_list(&certificate, &args.owner)?,
+    };
+    std::fs::write(&args.out, contents)
+        .with_context(|| format!("Failed to write the certificate to {:?}", args.out))
+}
+
+fn verify(args: VerifyCommand) -> Result<()> {
+    let esp_paths = SystemdEspPaths::new(
+        &args.esp,
+        &args.esp_subdir.subdir,
+        Architecture::from_nixos_system(&args.system)?,
+    );
+    let signer = args.signer.into_signer()?;
+    let jobs = args.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+
+    let audit = EspAudit::new(&esp_paths, &signer, jobs)?;
+    audit.print();
+    let untrusted = audit.untrusted().count();
+    if untrusted > 0 {
+        bail!("{untrusted} binaries on the ESP are not signed with a trusted key.");
+    }
+    Ok(())
+}
+
+/// Parse an initrd secret, i.e. its absolute path in the initrd and the file it is read from.
+fn parse_initrd_secret(secret: &str) -> Result<(PathBuf, PathBuf)> {
+    let Some((path, source)) = secret.split_once('=') else {
+        bail!("{secret:?} is not of the form PATH=SOURCE.");
+    };
+    if !path.starts_with('/') {
+        bail!("The path {path:?} of the initrd secret is not absolute.");
+    }
+    Ok((PathBuf::from(path), PathBuf::from(source)))
+}
+
+/// Parse the `timeout` of loader.conf, see `loader.conf(5)`.
+fn parse_loader_timeout(timeout: &str) -> Result<String> {
+    if !loader_conf::is_valid_timeout(timeout) {
+        bail!("{timeout:?} is neither a number of seconds nor a menu mode.");
+    }
+    Ok(timeout.to_owned())
 }
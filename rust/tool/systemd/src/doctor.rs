@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::generation::{discover_generation_links, Generation, GenerationLink};
+
+/// The generations of a system profile that cannot be read.
+///
+/// The installation ignores them, but disables the garbage collection while there are any, see
+/// [`load_generations`](crate::install::load_generations).
+pub struct BrokenGenerations {
+    /// The links of the broken generations with the reason they cannot be read, by the profile
+    /// they belong to, e.g. `/nix/var/nix/profiles/system`.
+    profiles: BTreeMap<PathBuf, Vec<(GenerationLink, anyhow::Error)>>,
+}
+
+impl BrokenGenerations {
+    /// Try to read all `system-*-link` generation links in `profiles_dir`.
+    pub fn find(profiles_dir: &Path) -> Result<Self> {
+        let mut profiles: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for path in discover_generation_links(profiles_dir)? {
+            let link = GenerationLink::from_path(&path)?;
+            if let Err(err) = Generation::from_link(&link) {
+                let profile = profile_of(&path)
+                    .with_context(|| format!("Failed to find the profile of {path:?}"))?;
+                profiles.entry(profile).or_default().push((link, err));
+            }
+        }
+
+        Ok(Self { profiles })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    /// The `nix-env` arguments that delete the broken generations, one invocation per profile.
+    fn delete_args(&self) -> Vec<Vec<String>> {
+        self.profiles
+            .iter()
+            .map(|(profile, links)| {
+                let mut args = vec![
+                    "--profile".to_owned(),
+                    profile.display().to_string(),
+                    "--delete-generations".to_owned(),
+                ];
+                args.extend(links.iter().map(|(link, _)| link.version.to_string()));
+                args
+            })
+            .collect()
+    }
+
+    /// Print each broken generation link with the reason it cannot be read, followed by the
+    /// commands that delete them.
+    pub fn print(&self) {
+        for (link, err) in self.profiles.values().flatten() {
+            println!("{}: {err:#}", link.path.display());
+        }
+        for args in self.delete_args() {
+            println!("nix-env {}", args.join(" "));
+        }
+    }
+
+    /// Delete the broken generations with `nix-env`.
+    pub fn delete(&self) -> Result<()> {
+        for (profile, args) in self.profiles.keys().zip(self.delete_args()) {
+            let status = Command::new("nix-env")
+                .args(args)
+                .status()
+                .context("Failed to run nix-env. Most likely, the binary is not on PATH.")?;
+            if !status.success() {
+                bail!("Failed to delete the broken generations of {profile:?}.");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The profile a generation link belongs to, e.g. `/nix/var/nix/profiles/system` for
+/// `/nix/var/nix/profiles/system-42-link`.
+fn profile_of(link: &Path) -> Option<PathBuf> {
+    let name = link.file_name()?.to_str()?.strip_suffix("-link")?;
+    let (profile, version) = name.rsplit_once('-')?;
+    if profile.is_empty() || version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(link.with_file_name(profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_profile_from_link_name() {
+        let profiles = Path::new("/nix/var/nix/profiles");
+        assert_eq!(
+            profile_of(&profiles.join("system-42-link")),
+            Some(profiles.join("system"))
+        );
+        assert_eq!(
+            profile_of(&profiles.join("system-foo-1-link")),
+            Some(profiles.join("system-foo"))
+        );
+        assert_eq!(profile_of(&profiles.join("system-link")), None);
+        assert_eq!(profile_of(&profiles.join("system-foo-link")), None);
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::mem::size_of;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use nix::libc::{c_int, c_long};
+
+use crate::esp::GptPartition;
+
+/// The default mountpoint of efivarfs.
+pub const EFIVARFS: &str = "/sys/firmware/efi/efivars";
+
+/// systemd loader's GUID
+/// != systemd's GUID
+/// https://systemd.io/BOOT_LOADER_INTERFACE/
+///
+/// This is the same vendor GUID the stub uses for its EFI variables.
+const BOOT_LOADER_VENDOR_UUID: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// The vendor GUID of the variables defined by the UEFI specification, e.g. `BootOrder`.
+const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+const EFI_VARIABLE_NON_VOLATILE: u32 = 0x1;
+const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+/// Attribute of a load option: the firmware only boots active load options.
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// The inode flag marking a file as immutable, see `chattr(1)`.
+const FS_IMMUTABLE_FL: c_int = 0x10;
+
+// The kernel declares these ioctls with a `long` argument but actually reads and writes an `int`.
+nix::ioctl_read_bad!(
+    fs_ioc_getflags,
+    nix::request_code_read!(b'f', 1, size_of::<c_long>()),
+    c_int
+);
+nix::ioctl_write_ptr_bad!(
+    fs_ioc_setflags,
+    nix::request_code_write!(b'f', 2, size_of::<c_long>()),
+    c_int
+);
+
+/// Write a systemd-boot loader variable (e.g. `LoaderEntryDefault`) containing a string.
+///
+/// The variable is persisted in NVRAM so that systemd-boot can read it on the next boot.
+pub fn write_loader_string_variable(efivarfs: &Path, name: &str, value: &str) -> Result<()> {
+    let attributes =
+        EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS;
+    write_variable(
+        efivarfs,
+        name,
+        BOOT_LOADER_VENDOR_UUID,
+        &encode_string_variable(attributes, value),
+    )
+}
+
+/// Write an EFI variable, given as its attributes followed by its data.
+fn write_variable(efivarfs: &Path, name: &str, vendor: &str, variable: &[u8]) -> Result<()> {
+    let path = efivarfs.join(format!("{name}-{vendor}"));
+
+    // efivarfs marks existing variables as immutable to protect against accidental deletion.
+    if path.exists() {
+        clear_immutable_flag(&path)
+            .with_context(|| format!("Failed to make EFI variable {path:?} writable"))?;
+    }
+
+    // efivarfs requires the whole variable to be written with a single write(2) call.
+    fs::write(&path, variable).with_context(|| format!("Failed to write EFI variable {path:?}"))
+}
+
+/// Read the data of an EFI variable without its attributes, or `None` if it does not exist.
+fn read_variable(efivarfs: &Path, name: &str, vendor: &str) -> Result<Option<Vec<u8>>> {
+    let path = efivarfs.join(format!("{name}-{vendor}"));
+    match fs::read(&path) {
+        Ok(variable) => Ok(Some(variable.get(4..).unwrap_or_default().to_vec())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read EFI variable {path:?}")),
+    }
+}
+
+/// Create or update the `Boot####` variable that boots `loader_path` on `partition` and put it
+/// first in `BootOrder`.
+///
+/// An existing boot entry is reused if it is identical or has the same description, so running
+/// this again does not change anything. Returns the number of the boot entry.
+pub fn write_boot_entry(
+    efivarfs: &Path,
+    description: &str,
+    partition: &GptPartition,
+    loader_path: &str,
+) -> Result<u16> {
+    let attributes =
+        EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS;
+    let load_option = encode_load_option(description, partition, loader_path);
+
+    let boot_entries = read_boot_entries(efivarfs)?;
+    let number = boot_entry_number(&boot_entries, description, &load_option)?;
+    if boot_entries.get(&number) != Some(&load_option) {
+        write_variable(
+            efivarfs,
+            &format!("Boot{number:04X}"),
+            EFI_GLOBAL_VARIABLE,
+            &encode_variable(attributes, &load_option),
+        )?;
+    }
+
+    let boot_order: Vec<u16> = read_variable(efivarfs, "BootOrder", EFI_GLOBAL_VARIABLE)?
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|number| u16::from_le_bytes([number[0], number[1]]))
+        .collect();
+    let new_boot_order = boot_order_starting_with(&boot_order, number);
+    if new_boot_order != boot_order {
+        let data: Vec<u8> = new_boot_order
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        write_variable(
+            efivarfs,
+            "BootOrder",
+            EFI_GLOBAL_VARIABLE,
+            &encode_variable(attributes, &data),
+        )?;
+    }
+
+    Ok(number)
+}
+
+/// Read all `Boot####` variables, i.e. the load options of the firmware, by their number.
+fn read_boot_entries(efivarfs: &Path) -> Result<BTreeMap<u16, Vec<u8>>> {
+    let mut boot_entries = BTreeMap::new();
+    for entry in
+        fs::read_dir(efivarfs).with_context(|| format!("Failed to read efivarfs {efivarfs:?}"))?
+    {
+        let file_name = entry?.file_name();
+        let Some(number) = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(&format!("-{EFI_GLOBAL_VARIABLE}")))
+            .and_then(|name| name.strip_prefix("Boot"))
+            .filter(|number| number.len() == 4)
+            .and_then(|number| u16::from_str_radix(number, 16).ok())
+        else {
+            continue;
+        };
+        if let Some(load_option) =
+            read_variable(efivarfs, &format!("Boot{number:04X}"), EFI_GLOBAL_VARIABLE)?
+        {
+            boot_entries.insert(number, load_option);
+        }
+    }
+    Ok(boot_entries)
+}
+
+/// Choose the number of the boot entry for `load_option`.
+///
+/// This is an identical boot entry, then one with the same description (e.g. after the ESP was
+/// moved), and otherwise the lowest free number.
+fn boot_entry_number(
+    boot_entries: &BTreeMap<u16, Vec<u8>>,
+    description: &str,
+    load_option: &[u8],
+) -> Result<u16> {
+    let find = |matches: &dyn Fn(&[u8]) -> bool| {
+        boot_entries
+            .iter()
+            .find(|(_, option)| matches(option))
+            .map(|(number, _)| *number)
+    };
+    find(&|option| option == load_option)
+        .or_else(|| find(&|option| load_option_description(option).as_deref() == Some(description)))
+        .or_else(|| (0..=u16::MAX).find(|number| !boot_entries.contains_key(number)))
+        .context("All boot entry numbers are in use.")
+}
+
+/// Move `number` to the front of a boot order, or add it there.
+fn boot_order_starting_with(boot_order: &[u16], number: u16) -> Vec<u16> {
+    std::iter::once(number)
+        .chain(boot_order.iter().copied().filter(|n| *n != number))
+        .collect()
+}
+
+/// Encode an `EFI_LOAD_OPTION` that boots `loader_path` on the GPT partition `partition`.
+fn encode_load_option(description: &str, partition: &GptPartition, loader_path: &str) -> Vec<u8> {
+    let mut file_path_list = Vec::new();
+
+    // Hard drive media device path with a GPT partition signature.
+    file_path_list.extend([0x04, 0x01]);
+    file_path_list.extend(42u16.to_le_bytes());
+    file_path_list.extend(partition.number.to_le_bytes());
+    file_path_list.extend(partition.start_lba.to_le_bytes());
+    file_path_list.extend(partition.size_lba.to_le_bytes());
+    file_path_list.extend(partition.unique_guid);
+    file_path_list.extend([0x02, 0x02]);
+
+    // File path media device path.
+    let path = utf16_nul_terminated(loader_path);
+    file_path_list.extend([0x04, 0x04]);
+    file_path_list.extend((4 + path.len() as u16).to_le_bytes());
+    file_path_list.extend(path);
+
+    // End of the device path.
+    file_path_list.extend([0x7f, 0xff, 0x04, 0x00]);
+
+    let mut load_option = LOAD_OPTION_ACTIVE.to_le_bytes().to_vec();
+    load_option.extend((file_path_list.len() as u16).to_le_bytes());
+    load_option.extend(utf16_nul_terminated(description));
+    load_option.extend(file_path_list);
+    load_option
+}
+
+/// The description of an `EFI_LOAD_OPTION`, which follows the attributes and the length of the
+/// device path.
+fn load_option_description(load_option: &[u8]) -> Option<String> {
+    let description: Vec<u16> = load_option
+        .get(6..)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16(&description).ok()
+}
+
+fn utf16_nul_terminated(value: &str) -> Vec<u8> {
+    value
+        .encode_utf16()
+        .chain([0])
+        .flat_map(|c| c.to_le_bytes())
+        .collect()
+}
+
+/// Encode an EFI variable in the format expected by efivarfs, i.e. the little-endian attributes
+/// followed by the data.
+fn encode_variable(attributes: u32, data: &[u8]) -> Vec<u8> {
+    attributes
+        .to_le_bytes()
+        .into_iter()
+        .chain(data.iter().copied())
+        .collect()
+}
+
+/// Encode a string EFI variable in the format expected by efivarfs.
+///
+/// The data consists of the little-endian attributes followed by the value as a NUL-terminated
+/// UTF-16LE string.
+fn encode_string_variable(attributes: u32, value: &str) -> Vec<u8> {
+    encode_variable(attributes, &utf16_nul_terminated(value))
+}
+
+fn clear_immutable_flag(path: &Path) -> Result<()> {
+    let file = File::open(path)?;
+    let mut flags: c_int = 0;
+    // SAFETY: The file descriptor is valid and `flags` outlives the calls.
+    unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) }?;
+    if flags & FS_IMMUTABLE_FL != 0 {
+        flags &= !FS_IMMUTABLE_FL;
+        unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }?;
+    }
+    Ok(())
+}
+
+/// Ensure that the current process is allowed to write EFI variables.
+pub fn ensure_root() -> Result<()> {
+    if !nix::unistd::geteuid().is_root() {
+        bail!("Writing EFI variables requires root privileges.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_string_variable_correctly() {
+        assert_eq!(
+            encode_string_variable(0x7, "ab"),
+            vec![0x7, 0, 0, 0, b'a', 0, b'b', 0, 0, 0]
+        );
+    }
+
+    fn esp_partition() -> GptPartition {
+        GptPartition {
+            number: 1,
+            type_guid: [0; 16],
+            unique_guid: [0xaa; 16],
+            start_lba: 2048,
+            size_lba: 1024 * 1024,
+        }
+    }
+
+    #[test]
+    fn encode_load_option_correctly() {
+        let load_option = encode_load_option("L", &esp_partition(), "\\a.efi");
+
+        let mut expected = vec![0x1, 0, 0, 0];
+        // The device path: 42 + (4 + 14) + 4 bytes.
+        expected.extend(64u16.to_le_bytes());
+        expected.extend([b'L', 0, 0, 0]);
+        expected.extend([0x04, 0x01, 42, 0, 1, 0, 0, 0]);
+        expected.extend(2048u64.to_le_bytes());
+        expected.extend((1024u64 * 1024).to_le_bytes());
+        expected.extend([0xaa; 16]);
+        expected.extend([0x02, 0x02]);
+        expected.extend([0x04, 0x04, 18, 0]);
+        expected.extend(utf16_nul_terminated("\\a.efi"));
+        expected.extend([0x7f, 0xff, 0x04, 0x00]);
+        assert_eq!(load_option, expected);
+
+        assert_eq!(load_option_description(&load_option).as_deref(), Some("L"));
+    }
+
+    #[test]
+    fn reuse_boot_entries() -> Result<()> {
+        let load_option = encode_load_option("Linux Boot Manager", &esp_partition(), "\\a.efi");
+        let moved_load_option =
+            encode_load_option("Linux Boot Manager", &esp_partition(), "\\b.efi");
+        let other_load_option = encode_load_option("Windows", &esp_partition(), "\\a.efi");
+
+        let mut boot_entries = BTreeMap::from([(0, other_load_option), (1, moved_load_option)]);
+        assert_eq!(
+            boot_entry_number(&boot_entries, "Linux Boot Manager", &load_option)?,
+            1
+        );
+        boot_entries.insert(3, load_option.clone());
+        assert_eq!(
+            boot_entry_number(&boot_entries, "Linux Boot Manager", &load_option)?,
+            3
+        );
+        assert_eq!(boot_entry_number(&boot_entries, "Other", b"")?, 2);
+
+        assert_eq!(boot_order_starting_with(&[0, 1, 2], 1), [1, 0, 2]);
+        assert_eq!(boot_order_starting_with(&[0], 3), [3, 0]);
+        Ok(())
+    }
+}
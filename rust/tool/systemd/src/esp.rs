@@ -1,9 +1,34 @@
+use std::fs::{self, File};
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Context, Result};
+use nix::sys::stat::{major, minor};
+use nix::sys::statfs::{statfs, MSDOS_SUPER_MAGIC};
+
 use crate::architecture::SystemdArchitectureExt;
 use lanzaboote_tool::architecture::Architecture;
 use lanzaboote_tool::esp::EspPaths;
 
+/// The cache of the systemd-boot versions on the ESP, see
+/// [`SystemdVersionCache`](crate::version::SystemdVersionCache).
+///
+/// It is installation state, not part of the installed artifacts, so bundles and recovery images
+/// leave it out.
+pub const SYSTEMD_BOOT_VERSIONS: &str = "loader/lanzaboote-systemd-boot-versions.json";
+
+/// Whether `entry` is a PE binary, i.e. a regular file ending in `.efi`.
+///
+/// This skips the drop-in directories next to the stubs, e.g.
+/// `nixos-generation-1-<hash>.efi.extra`, which share their prefix.
+pub fn is_efi_binary(entry: &fs::DirEntry) -> Result<bool> {
+    Ok(entry.file_type()?.is_file()
+        && entry
+            .path()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("efi")))
+}
+
 /// Paths to the boot files that are not specific to a generation.
 /// Systemd variant
 pub struct SystemdEspPaths {
@@ -17,18 +42,25 @@ pub struct SystemdEspPaths {
     pub systemd_boot: PathBuf,
     pub loader: PathBuf,
     pub systemd_boot_loader_config: PathBuf,
+    pub systemd_boot_versions: PathBuf,
+    /// Prefix of the stub names in `EFI/Linux`, derived from the name of the NixOS directory.
+    pub stub_prefix: String,
+    /// Prefix of the names of UKIs built outside of NixOS in `EFI/Linux`, see
+    /// [`UkiInstaller`](crate::uki::UkiInstaller).
+    pub uki_prefix: String,
 }
 
-impl EspPaths<10> for SystemdEspPaths {
-    fn new(esp: impl AsRef<Path>, architecture: Architecture) -> Self {
+impl EspPaths<11> for SystemdEspPaths {
+    fn new(esp: impl AsRef<Path>, esp_subdir: &str, architecture: Architecture) -> Self {
         let esp = esp.as_ref();
         let efi = esp.join("EFI");
-        let efi_nixos = efi.join("nixos");
+        let efi_nixos = efi.join(esp_subdir);
         let efi_linux = efi.join("Linux");
         let efi_systemd = efi.join("systemd");
         let efi_efi_fallback_dir = efi.join("BOOT");
         let loader = esp.join("loader");
         let systemd_boot_loader_config = loader.join("loader.conf");
+        let systemd_boot_versions = esp.join(SYSTEMD_BOOT_VERSIONS);
 
         Self {
             esp: esp.to_path_buf(),
@@ -41,6 +73,9 @@ impl EspPaths<10> for SystemdEspPaths {
             systemd_boot: efi_systemd.join(architecture.systemd_filename()),
             loader,
             systemd_boot_loader_config,
+            systemd_boot_versions,
+            stub_prefix: format!("{esp_subdir}-generation-"),
+            uki_prefix: format!("{esp_subdir}-uki-"),
         }
     }
 
@@ -52,7 +87,11 @@ impl EspPaths<10> for SystemdEspPaths {
         &self.linux
     }
 
-    fn iter(&self) -> std::array::IntoIter<&PathBuf, 10> {
+    fn stub_prefix(&self) -> &str {
+        &self.stub_prefix
+    }
+
+    fn iter(&self) -> std::array::IntoIter<&PathBuf, 11> {
         [
             &self.esp,
             &self.efi,
@@ -64,7 +103,172 @@ impl EspPaths<10> for SystemdEspPaths {
             &self.systemd_boot,
             &self.loader,
             &self.systemd_boot_loader_config,
+            &self.systemd_boot_versions,
         ]
         .into_iter()
     }
 }
+
+/// Parse the name of the directory in `EFI` that holds the NixOS files.
+///
+/// It must be a single path component that does not clash with the directories of the firmware
+/// and systemd-boot.
+///
+/// The stub names in `EFI/Linux` are prefixed with `<esp_subdir>-generation-` and
+/// `<esp_subdir>-uki-`. A name like `nixos-generation` would thus make the stubs of its
+/// installation look like those of the `nixos` installation, which would collect them as garbage.
+pub fn parse_esp_subdir(esp_subdir: &str) -> Result<String> {
+    if esp_subdir.is_empty()
+        || !esp_subdir
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        bail!("{esp_subdir:?} must only consist of ASCII letters, digits, dashes and underscores.");
+    }
+    if ["BOOT", "Linux", "systemd"]
+        .iter()
+        .any(|reserved| esp_subdir.eq_ignore_ascii_case(reserved))
+    {
+        bail!("EFI/{esp_subdir} is reserved for other boot files.");
+    }
+    if esp_subdir.split('-').skip(1).any(|part| {
+        ["generation", "uki"]
+            .iter()
+            .any(|namespace| part.eq_ignore_ascii_case(namespace))
+    }) {
+        bail!(
+            "{esp_subdir:?} must not contain `-generation` or `-uki`, because its stub names would \
+            collide with those of another installation."
+        );
+    }
+    Ok(esp_subdir.to_owned())
+}
+
+/// Type GUID of an EFI System Partition (C12A7328-F81F-11D2-BA4B-00A0C93EC93B) as it is stored in
+/// a GPT partition entry.
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// Check that `esp` is a FAT file system on a partition marked as EFI System Partition.
+///
+/// The firmware only looks for boot loaders there, so a misconfigured ESP only shows up as an
+/// unbootable system otherwise. The partition type is only checked on GPT disks whose partition
+/// table can be read.
+pub fn check_esp(esp: &Path) -> Result<()> {
+    let fs_stat =
+        statfs(esp).with_context(|| format!("Failed to get the file system type of {esp:?}"))?;
+    if fs_stat.filesystem_type() != MSDOS_SUPER_MAGIC {
+        bail!("The ESP {esp:?} is not a FAT (vfat/msdos) file system.");
+    }
+
+    match gpt_partition(esp) {
+        Ok(Some(partition)) if partition.type_guid != ESP_TYPE_GUID => {
+            bail!("The ESP {esp:?} is not on a partition with the EFI System Partition type GUID.")
+        }
+        Ok(_) => {}
+        Err(err) => {
+            tracing::debug!("Failed to read the partition type of the ESP {esp:?}: {err:#}")
+        }
+    }
+
+    Ok(())
+}
+
+/// A partition of a GPT disk, as described by its partition entry.
+///
+/// The GUIDs are stored in their on-disk byte order, which is also the one of UEFI device paths.
+pub struct GptPartition {
+    /// The number of the partition, i.e. the index of its entry starting from 1.
+    pub number: u32,
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    /// The first logical block of the partition.
+    pub start_lba: u64,
+    /// The number of logical blocks of the partition.
+    pub size_lba: u64,
+}
+
+/// Read the GPT partition entry of the partition that holds `path`.
+///
+/// Returns `None` if `path` is not on a partition of a GPT disk, e.g. on a loop device.
+pub fn gpt_partition(path: &Path) -> Result<Option<GptPartition>> {
+    let dev = fs::metadata(path)?.dev();
+    let sysfs = fs::canonicalize(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)))?;
+    let Ok(partition) = fs::read_to_string(sysfs.join("partition")) else {
+        return Ok(None);
+    };
+    let partition: u64 = partition.trim().parse()?;
+
+    let disk = sysfs
+        .parent()
+        .and_then(Path::file_name)
+        .context("Failed to find the disk of the partition.")?;
+    let block_size: u64 = fs::read_to_string(sysfs.with_file_name("queue/logical_block_size"))?
+        .trim()
+        .parse()?;
+    let disk = File::open(Path::new("/dev").join(disk))?;
+
+    // The GPT header is in the second logical block.
+    let mut header = [0; 92];
+    disk.read_exact_at(&mut header, block_size)?;
+    if &header[..8] != b"EFI PART" {
+        return Ok(None);
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into()?);
+    let entry_count = u32::from_le_bytes(header[80..84].try_into()?);
+    let entry_size = u32::from_le_bytes(header[84..88].try_into()?);
+    if partition == 0 || partition > u64::from(entry_count) {
+        bail!("Partition {partition} is not in the partition table.");
+    }
+
+    // Partitions of GPT disks are numbered after their entry. An entry starts with the type GUID,
+    // the unique GUID and the first and last logical block of the partition.
+    let mut entry = [0; 48];
+    disk.read_exact_at(
+        &mut entry,
+        entries_lba * block_size + (partition - 1) * u64::from(entry_size),
+    )?;
+    let start_lba = u64::from_le_bytes(entry[32..40].try_into()?);
+    let end_lba = u64::from_le_bytes(entry[40..48].try_into()?);
+    Ok(Some(GptPartition {
+        number: u32::try_from(partition)?,
+        type_guid: entry[..16].try_into()?,
+        unique_guid: entry[16..32].try_into()?,
+        start_lba,
+        size_lba: end_lba
+            .checked_sub(start_lba)
+            .context("The partition ends before it starts.")?
+            + 1,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_colliding_esp_subdirs() {
+        for esp_subdir in [
+            "nixos",
+            "nixos-hostA",
+            "nixos_generation",
+            "nixos-generationA",
+            "nixos_uki",
+            "nixos-ukiA",
+        ] {
+            assert!(parse_esp_subdir(esp_subdir).is_ok(), "{esp_subdir:?}");
+        }
+        for esp_subdir in [
+            "nixos-generation",
+            "nixos-uki",
+            "nixos-generation-1",
+            "nixos-GENERATION-hostA",
+            "nixos-UKI-hostA",
+            "Linux",
+            "../nixos",
+        ] {
+            assert!(parse_esp_subdir(esp_subdir).is_err(), "{esp_subdir:?}");
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::esp::SystemdEspPaths;
+use crate::install::{
+    collect_garbage, load_generations, read_installed_generation, EspContents, KernelParams,
+};
+use crate::lock::{lock_esp, DEFAULT_LOCK_TIMEOUT};
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp::{EspPaths, DEFAULT_ESP_SUBDIR};
+use lanzaboote_tool::gc::Roots;
+use lanzaboote_tool::generation::GenerationLink;
+
+/// Collects garbage on the ESP without installing anything.
+///
+/// The roots are the same as during an installation: the files that are not specific to a
+/// generation and the files of all installed generations among `generation_links`.
+pub struct GarbageCollector {
+    esp_paths: SystemdEspPaths,
+    arch: Architecture,
+    public_key: Vec<u8>,
+    generation_links: Vec<PathBuf>,
+    kernel_params: KernelParams,
+    lock_timeout: Duration,
+}
+
+impl GarbageCollector {
+    pub fn new(
+        arch: Architecture,
+        esp: PathBuf,
+        public_key: Vec<u8>,
+        generation_links: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            esp_paths: SystemdEspPaths::new(esp, DEFAULT_ESP_SUBDIR, arch),
+            arch,
+            public_key,
+            generation_links,
+            kernel_params: KernelParams::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        }
+    }
+
+    /// Collect the garbage of the installation in `EFI/<esp_subdir>`, see
+    /// [`Installer::with_esp_subdir`](crate::install::Installer::with_esp_subdir).
+    pub fn with_esp_subdir(mut self, esp_subdir: &str) -> Self {
+        self.esp_paths = SystemdEspPaths::new(&self.esp_paths.esp, esp_subdir, self.arch);
+        self
+    }
+
+    /// Look up the stubs by the kernel command lines of an installation with these kernel
+    /// parameters, see [`Installer::with_kernel_params`](crate::install::Installer::with_kernel_params).
+    pub fn with_kernel_params(mut self, kernel_params: KernelParams) -> Self {
+        self.kernel_params = kernel_params;
+        self
+    }
+
+    /// Wait for at most `timeout` if an installation holds the lock on the ESP.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    pub fn collect_garbage(&self) -> Result<()> {
+        let _esp_lock = lock_esp(&self.esp_paths.esp, self.lock_timeout)?;
+
+        let links = self
+            .generation_links
+            .iter()
+            .map(GenerationLink::from_path)
+            .collect::<Result<Vec<GenerationLink>>>()?;
+
+        let mut broken_gens = BTreeSet::new();
+        let generations = load_generations(&links, &mut broken_gens)?;
+        let latest_version = generations
+            .iter()
+            .map(|generation| generation.version)
+            .max();
+
+        let mut roots = Roots::new();
+        roots.extend(self.esp_paths.iter());
+
+        let esp_contents = EspContents::read(&self.esp_paths)?;
+        let mut installed = 0;
+        for generation in generations {
+            let is_latest = Some(generation.version) == latest_version;
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec));
+            for generation in std::iter::once(generation.clone()).chain(specialisations) {
+                match read_installed_generation(
+                    &self.esp_paths,
+                    &esp_contents,
+                    &self.public_key,
+                    &generation,
+                    &self.kernel_params.kernel_cmdline(&generation, is_latest),
+                ) {
+                    Ok((_, files)) => {
+                        roots.extend(&files);
+                        installed += 1;
+                    }
+                    Err(e) => tracing::warn!(
+                        "Generation {} is not installed: {e:#}",
+                        generation.version_tag()
+                    ),
+                }
+            }
+        }
+
+        if installed == 0 {
+            // This most likely means that the wrong public key was passed.
+            bail!("None of the generations is installed on the ESP! Aborting to avoid removing all boot entries.");
+        }
+
+        collect_garbage(&roots, &self.esp_paths, &broken_gens)
+    }
+}
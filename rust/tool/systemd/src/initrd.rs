@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+/// Size of the header of an entry in a cpio archive in the "newc" format.
+const CPIO_HEADER_LEN: usize = 110;
+
+/// Compression algorithms the kernel can unpack an initrd with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Detect the compression of `data` by its magic number.
+    fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::Gzip)
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::Zstd)
+        } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::Xz)
+        } else {
+            None
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Self::Zstd => Ok(zstd::encode_all(data, 10)?),
+            Self::Xz => {
+                // The kernel only verifies CRC32 checksums of xz streams.
+                let stream = xz2::stream::Stream::new_easy_encoder(6, xz2::stream::Check::Crc32)?;
+                let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Self::Gzip => flate2::read::MultiGzDecoder::new(data).read_to_end(&mut decompressed)?,
+            Self::Zstd => zstd::Decoder::new(data)?.read_to_end(&mut decompressed)?,
+            Self::Xz => {
+                xz2::read::XzDecoder::new_multi_decoder(data).read_to_end(&mut decompressed)?
+            }
+        };
+        Ok(decompressed)
+    }
+}
+
+/// Compress an initrd, decompressing it first if it is compressed with another algorithm.
+///
+/// Early cpio archives at the start of the initrd stay uncompressed, since the kernel only looks
+/// for CPU microcode in uncompressed archives.
+pub fn compress_initrd(initrd: Vec<u8>, compression: Compression) -> Result<Vec<u8>> {
+    let (early, main) = initrd.split_at(early_cpio_len(&initrd));
+    if main.is_empty() {
+        return Ok(initrd);
+    }
+
+    let main = match Compression::detect(main) {
+        Some(current) if current == compression => return Ok(initrd),
+        Some(current) => current
+            .decompress(main)
+            .with_context(|| format!("Failed to decompress the {current:?} compressed initrd."))?,
+        None => main.to_vec(),
+    };
+
+    let mut compressed = early.to_vec();
+    compressed.extend(compression.compress(&main)?);
+    Ok(compressed)
+}
+
+/// The compression of an initrd, ignoring any early cpio archives at its start.
+pub fn initrd_compression(initrd: &[u8]) -> Option<Compression> {
+    Compression::detect(&initrd[early_cpio_len(initrd)..])
+}
+
+/// The length of the early cpio archives at the start of an initrd, including their padding.
+///
+/// Early archives are uncompressed and only contain files below `kernel/`, e.g. CPU microcode.
+fn early_cpio_len(initrd: &[u8]) -> usize {
+    let mut offset = 0;
+    while let Some(len) = early_cpio_archive_len(&initrd[offset..]) {
+        offset += len;
+        // The kernel skips the zero padding between concatenated archives.
+        while initrd.get(offset) == Some(&0) {
+            offset += 1;
+        }
+    }
+    offset
+}
+
+/// The length of the early cpio archive at the start of `data`, if there is one.
+fn early_cpio_archive_len(data: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    loop {
+        let header = data.get(offset..offset + CPIO_HEADER_LEN)?;
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return None;
+        }
+        let field = |index: usize| {
+            let start = 6 + 8 * index;
+            std::str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|field| usize::from_str_radix(field, 16).ok())
+        };
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        let name_start = offset + CPIO_HEADER_LEN;
+        let name = data.get(name_start..name_start + name_size)?;
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        offset = (name_start + name_size).next_multiple_of(4);
+        offset = (offset + file_size).next_multiple_of(4);
+
+        if name == b"TRAILER!!!" {
+            return Some(offset.min(data.len()));
+        }
+        if !(name == b"." || name == b"kernel" || name.starts_with(b"kernel/")) {
+            return None;
+        }
+    }
+}
+
+/// Append the initrd secrets to the initrd at `initrd_path` without running a script.
+///
+/// `secrets` maps the absolute paths of the secrets in the initrd to the files they are read
+/// from. Like the `append-initrd-secrets` script of NixOS, this appends an uncompressed cpio
+/// archive with the secrets and their parent directories to the initrd. The kernel unpacks it
+/// over the archives before it.
+///
+/// The archive is reproducible: the entries are sorted, owned by root and have no modification
+/// time. The secrets keep the permissions of their source files.
+pub fn append_initrd_secret_files(
+    initrd_path: &Path,
+    secrets: &BTreeMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    // `None` marks the parent directories of the secrets.
+    let mut entries = BTreeMap::new();
+    for (target, source) in secrets {
+        let relative = target.strip_prefix("/").with_context(|| {
+            format!("The path of the initrd secret {target:?} is not absolute.")
+        })?;
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            bail!("The path of the initrd secret {target:?} is not normalized.");
+        }
+        for parent in relative.ancestors().skip(1) {
+            if parent != Path::new("") {
+                entries.insert(parent.to_owned(), None);
+            }
+        }
+        entries.insert(relative.to_owned(), Some(source));
+    }
+
+    let mut archive = Vec::new();
+    for (ino, (name, source)) in (1..).zip(&entries) {
+        let (mode, data) = match source {
+            None => (0o040755, Vec::new()),
+            Some(source) => {
+                let permissions = fs::metadata(source)
+                    .with_context(|| format!("Failed to read the initrd secret {source:?}."))?
+                    .permissions();
+                let data = fs::read(source)
+                    .with_context(|| format!("Failed to read the initrd secret {source:?}."))?;
+                (0o100000 | (permissions.mode() & 0o7777), data)
+            }
+        };
+        let name = name
+            .to_str()
+            .with_context(|| format!("The path of the initrd secret {name:?} is not UTF-8."))?;
+        append_cpio_entry(&mut archive, ino, name, mode, &data);
+    }
+    append_cpio_entry(&mut archive, 0, "TRAILER!!!", 0, &[]);
+
+    let mut initrd = OpenOptions::new()
+        .append(true)
+        .open(initrd_path)
+        .with_context(|| format!("Failed to open the initrd {initrd_path:?}."))?;
+    // Archives appended to the initrd have to start at a 4-byte boundary.
+    let len = initrd.metadata()?.len();
+    let padding = (len.next_multiple_of(4) - len) as usize;
+    initrd
+        .write_all(&[&[0; 3][..padding], &archive].concat())
+        .with_context(|| format!("Failed to append the initrd secrets to {initrd_path:?}."))
+}
+
+/// Append an entry in the "newc" format to a cpio archive.
+fn append_cpio_entry(archive: &mut Vec<u8>, ino: u32, name: &str, mode: u32, data: &[u8]) {
+    let nlink = match mode & 0o170000 {
+        0o040000 => 2,
+        0 => 0,
+        _ => 1,
+    };
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize, check
+    let fields = [
+        ino,
+        mode,
+        0,
+        0,
+        nlink,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+
+    archive.extend(b"070701");
+    for field in fields {
+        archive.extend(format!("{field:08x}").as_bytes());
+    }
+    archive.extend(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an uncompressed cpio archive of empty files.
+    fn cpio(names: &[&str]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for name in names.iter().chain(&["TRAILER!!!"]) {
+            archive.extend(b"070701");
+            for index in 0..13 {
+                let field = if index == 11 { name.len() + 1 } else { 0 };
+                archive.extend(format!("{field:08x}").as_bytes());
+            }
+            archive.extend(name.as_bytes());
+            archive.push(0);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+        }
+        archive
+    }
+
+    #[test]
+    fn keep_early_cpio_uncompressed() -> Result<()> {
+        let early = cpio(&["kernel", "kernel/x86/microcode/GenuineIntel.bin"]);
+        let main = cpio(&["init"]);
+        let initrd = [early.clone(), main.clone()].concat();
+
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Xz] {
+            let compressed = compress_initrd(initrd.clone(), compression)?;
+            assert!(compressed.starts_with(&early));
+            assert_eq!(initrd_compression(&compressed), Some(compression));
+            assert_eq!(compression.decompress(&compressed[early.len()..])?, main);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recompress_initrd() -> Result<()> {
+        let main = cpio(&["init"]);
+        let gzip = compress_initrd(main.clone(), Compression::Gzip)?;
+        assert_eq!(compress_initrd(gzip.clone(), Compression::Gzip)?, gzip);
+
+        let xz = compress_initrd(gzip, Compression::Xz)?;
+        assert_eq!(initrd_compression(&xz), Some(Compression::Xz));
+        assert_eq!(Compression::Xz.decompress(&xz)?, main);
+        Ok(())
+    }
+
+    #[test]
+    fn append_secret_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let initrd = tmpdir.path().join("initrd");
+        fs::write(&initrd, b"initrd")?;
+        let secret = tmpdir.path().join("secret");
+        fs::write(&secret, b"hunter2")?;
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o400))?;
+
+        let secrets = BTreeMap::from([(PathBuf::from("/etc/ssh/host_key"), secret)]);
+        append_initrd_secret_files(&initrd, &secrets)?;
+
+        let mut expected = b"initrd\0\0".to_vec();
+        append_cpio_entry(&mut expected, 1, "etc", 0o040755, b"");
+        append_cpio_entry(&mut expected, 2, "etc/ssh", 0o040755, b"");
+        append_cpio_entry(&mut expected, 3, "etc/ssh/host_key", 0o100400, b"hunter2");
+        append_cpio_entry(&mut expected, 0, "TRAILER!!!", 0, b"");
+        assert_eq!(fs::read(&initrd)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_relative_secret_paths() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let initrd = tmpdir.path().join("initrd");
+        fs::write(&initrd, b"initrd")?;
+
+        for target in ["etc/secret", "/etc/../secret"] {
+            let secrets = BTreeMap::from([(PathBuf::from(target), initrd.clone())]);
+            assert!(append_initrd_secret_files(&initrd, &secrets).is_err());
+        }
+        assert_eq!(fs::read(&initrd)?, b"initrd");
+        Ok(())
+    }
+
+    #[test]
+    fn detect_uncompressed_initrd() {
+        assert_eq!(initrd_compression(&cpio(&["init"])), None);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::esp::SystemdEspPaths;
+use crate::initrd::Compression;
+use crate::install::{assemble_initrd, kernel_version, nixos_ca_name, stub_name, KernelParams};
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::utils::file_hash;
+
+/// The files an installation of a set of generations would write to the ESP.
+///
+/// The names are computed with the same functions as the installation, so the layout is exactly
+/// what [`Installer`](crate::install::Installer) produces for thin stubs. Nothing is written.
+#[derive(Debug, Default, PartialEq)]
+pub struct EspLayout {
+    /// The stubs in `EFI/Linux` with the kernel and initrd in `EFI/nixos` they boot.
+    pub stubs: BTreeMap<PathBuf, (PathBuf, InitrdFile)>,
+}
+
+/// The initrd of a generation in `EFI/nixos`.
+#[derive(Debug, PartialEq)]
+pub enum InitrdFile {
+    Path(PathBuf),
+    /// The path cannot be known in advance because initrd secrets are appended to the initrd
+    /// during the installation.
+    DependsOnSecrets,
+    /// The generation boots without initrd.
+    Missing,
+}
+
+impl EspLayout {
+    /// Compute the layout of the generations, with stub names for `public_key`.
+    ///
+    /// `kernel_params`, `initrd_prepend` and `compression` must match the ones of the
+    /// installation, because the stub names depend on the kernel command lines and the initrds
+    /// are hashed after assembling them.
+    pub fn new(
+        esp_paths: &SystemdEspPaths,
+        generation_links: &[PathBuf],
+        public_key: &[u8],
+        kernel_params: &KernelParams,
+        initrd_prepend: &[PathBuf],
+        compression: Option<Compression>,
+    ) -> Result<Self> {
+        let mut layout = Self::default();
+
+        let links = generation_links
+            .iter()
+            .map(GenerationLink::from_path)
+            .collect::<Result<Vec<_>>>()?;
+        let latest_version = links.iter().map(|link| link.version).max();
+        for link in links {
+            let is_latest = Some(link.version) == latest_version;
+            let generation = Generation::from_link(&link)
+                .with_context(|| format!("Failed to build generation from link: {link:?}"))?;
+
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec));
+
+            for generation in std::iter::once(generation.clone()).chain(specialisations) {
+                let stub = esp_paths.linux.join(stub_name(
+                    &generation,
+                    &kernel_params.kernel_cmdline(&generation, is_latest),
+                    public_key,
+                    &esp_paths.stub_prefix,
+                )?);
+                let files = nixos_files(esp_paths, &generation, initrd_prepend, compression)
+                    .with_context(|| {
+                        format!("Failed to compute the files of generation {generation}")
+                    })?;
+                layout.stubs.insert(stub, files);
+            }
+        }
+
+        Ok(layout)
+    }
+
+    /// Print the stubs with their kernel and initrd, followed by the files in `EFI/nixos`.
+    ///
+    /// Generations that share a kernel or initrd refer to the same file, which is only listed
+    /// once.
+    pub fn print(&self) {
+        let mut nixos_files = BTreeSet::new();
+
+        for (stub, (kernel, initrd)) in &self.stubs {
+            println!("{}", stub.display());
+            println!("  kernel {}", kernel.display());
+            match initrd {
+                InitrdFile::Path(initrd) => {
+                    println!("  initrd {}", initrd.display());
+                    nixos_files.insert(initrd);
+                }
+                InitrdFile::DependsOnSecrets => {
+                    println!("  initrd (depends on the initrd secrets)")
+                }
+                InitrdFile::Missing => println!("  no initrd"),
+            }
+            nixos_files.insert(kernel);
+        }
+        for file in nixos_files {
+            println!("{}", file.display());
+        }
+    }
+}
+
+/// The kernel and initrd of a generation in `EFI/nixos`.
+///
+/// This assembles the initrd like [`Installer`](crate::install::Installer) does, i.e. an
+/// initrd consisting of a single file is hashed as it is, otherwise the concatenation of all
+/// initrds.
+fn nixos_files(
+    esp_paths: &SystemdEspPaths,
+    generation: &Generation,
+    initrd_prepend: &[PathBuf],
+    compression: Option<Compression>,
+) -> Result<(PathBuf, InitrdFile)> {
+    let bootspec = &generation.spec.bootspec.bootspec;
+    let kernel_version = kernel_version(&bootspec.kernel)?;
+
+    let kernel_hash = file_hash(&bootspec.kernel)?;
+    let kernel = esp_paths.nixos.join(nixos_ca_name(
+        &format!("kernel-{kernel_version}"),
+        &kernel_hash,
+    ));
+
+    let initrds = generation.spec.initrds();
+    if initrds.is_empty() && initrd_prepend.is_empty() {
+        return Ok((kernel, InitrdFile::Missing));
+    }
+    if bootspec.initrd_secrets.is_some() {
+        return Ok((kernel, InitrdFile::DependsOnSecrets));
+    }
+    let initrd_hash = match initrds.as_slice() {
+        [initrd] if compression.is_none() && initrd_prepend.is_empty() => file_hash(initrd)?,
+        initrds => Sha256::digest(assemble_initrd(initrd_prepend, initrds, compression)?),
+    };
+    let initrd = esp_paths.nixos.join(nixos_ca_name(
+        &format!("initrd-{kernel_version}"),
+        &initrd_hash,
+    ));
+
+    Ok((kernel, InitrdFile::Path(initrd)))
+}
@@ -1 +1,56 @@
+//! Install NixOS generations as signed lanzaboote stubs for systemd-boot.
+//!
+//! `lzbt` is a thin wrapper around [`install::Installer`], which other tools can use to drive an
+//! installation themselves. The signer is injected, so any [`Signer`] works, e.g. a key pair on
+//! disk or a remote signing server.
+//!
+//! ```no_run
+//! use std::path::{Path, PathBuf};
+//!
+//! use lanzaboote_tool::architecture::Architecture;
+//! use lanzaboote_tool::signature::local::LocalKeyPair;
+//! use lzbt_systemd::install::Installer;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let signer = LocalKeyPair::new(
+//!     Path::new("/var/lib/sbctl/keys/db/db.pem"),
+//!     Path::new("/var/lib/sbctl/keys/db/db.key"),
+//! )?;
+//!
+//! Installer::new(
+//!     PathBuf::from("/nix/store/...-lanzaboote-stub/bin/lanzaboote_stub.efi"),
+//!     Architecture::from_nixos_system("x86_64-linux")?,
+//!     PathBuf::from("/nix/store/...-systemd"),
+//!     PathBuf::from("/nix/store/...-loader.conf"),
+//!     signer,
+//!     // Keep the 10 latest generations.
+//!     10,
+//!     PathBuf::from("/boot"),
+//!     vec![PathBuf::from("/nix/var/nix/profiles/system-1-link")],
+//! )
+//! .install()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Signer`]: lanzaboote_tool::signature::Signer
+
 pub mod architecture;
+mod bundle;
+pub mod cli;
+mod doctor;
+mod efivars;
+pub mod esp;
+mod gc;
+pub mod initrd;
+pub mod install;
+mod layout;
+mod loader_conf;
+mod lock;
+mod preview;
+pub mod recovery;
+mod resign;
+mod status;
+mod uki;
+mod verify;
+mod version;
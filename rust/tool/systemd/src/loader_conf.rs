@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A systemd-boot `loader.conf`, see `loader.conf(5)`.
+///
+/// Unlike [`OsRelease`](lanzaboote_tool::os_release::OsRelease), the lines are kept as they are,
+/// including comments and their order, so that patching a few keys leaves the rest of a
+/// user-provided configuration untouched.
+pub struct LoaderConf(Vec<String>);
+
+/// The keys of `loader.conf(5)` whose values are not checked.
+const OTHER_KEYS: &[&str] = &[
+    "auto-entries",
+    "auto-firmware",
+    "auto-poweroff",
+    "auto-reboot",
+    "beep",
+    "log-level",
+    "reboot-for-bitlocker",
+    "reboot-on-error",
+    "secure-boot-enroll",
+];
+
+impl LoaderConf {
+    /// Check the keys and values against `loader.conf(5)` and describe each problem.
+    ///
+    /// The values of `timeout`, `default`, `console-mode` and `editor` are checked, because a
+    /// wrong one silently changes the menu. Unknown keys are reported to catch typos, since
+    /// systemd-boot ignores them.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (index, line) in self.0.iter().enumerate() {
+            let Some(key) = line_key(line) else {
+                continue;
+            };
+            let value = line.trim()[key.len()..].trim();
+            let valid = match key {
+                "timeout" => is_valid_timeout(value),
+                "default" => !value.is_empty(),
+                "console-mode" => {
+                    value.parse::<u32>().is_ok() || ["auto", "max", "keep"].contains(&value)
+                }
+                "editor" => parse_boolean(value).is_some(),
+                key if OTHER_KEYS.contains(&key) => true,
+                key => {
+                    problems.push(format!("line {}: unknown key {key:?}", index + 1));
+                    continue;
+                }
+            };
+            if !valid {
+                problems.push(format!(
+                    "line {}: invalid value {value:?} for {key:?}",
+                    index + 1
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Set `key` to `value`.
+    ///
+    /// The first line with this key is replaced and all further lines with it are removed,
+    /// because systemd-boot would let them override the value. If there is no such line, the
+    /// key is appended.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let mut found = false;
+        self.0.retain_mut(|line| {
+            if line_key(line) != Some(key) {
+                return true;
+            }
+            if found {
+                return false;
+            }
+            found = true;
+            *line = format!("{key} {value}");
+            true
+        });
+        if !found {
+            self.0.push(format!("{key} {value}"));
+        }
+    }
+}
+
+/// Whether `timeout` is a valid value of the `timeout` key, i.e. a number of seconds or a menu
+/// mode.
+pub fn is_valid_timeout(timeout: &str) -> bool {
+    timeout.parse::<u32>().is_ok()
+        || ["menu-force", "menu-hidden", "menu-disabled"].contains(&timeout)
+}
+
+/// Parse a boolean like systemd-boot does.
+fn parse_boolean(value: &str) -> Option<bool> {
+    match value {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Some(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// The key of a line in `loader.conf`, i.e. the first word. Comments and empty lines have none.
+fn line_key(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    line.split_whitespace().next()
+}
+
+impl FromStr for LoaderConf {
+    type Err = anyhow::Error;
+
+    /// Parse the string representation of a `loader.conf`.
+    ///
+    /// Each line is a key and a value separated by whitespace. Lines starting with `#` are
+    /// comments.
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        Ok(Self(value.lines().map(ToOwned::to_owned).collect()))
+    }
+}
+
+impl fmt::Display for LoaderConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.0 {
+            writeln!(f, "{line}")?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_update_set_keys() -> anyhow::Result<()> {
+        let mut loader_conf = LoaderConf::from_str(
+            "# Managed by NixOS\ntimeout 5\neditor no\n\ndefault nixos-*\ndefault @saved\n",
+        )?;
+        loader_conf.set("default", "nixos-generation-2-abc.efi");
+        loader_conf.set("console-mode", "max");
+        assert_eq!(
+            loader_conf.to_string(),
+            "# Managed by NixOS\ntimeout 5\neditor no\n\ndefault nixos-generation-2-abc.efi\nconsole-mode max\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_unknown_keys_and_invalid_values() -> anyhow::Result<()> {
+        let loader_conf = LoaderConf::from_str(
+            "# timout 5\ntimout 5\ntimeout 5\ntimeout soon\neditor maybe\neditor no\nconsole-mode max\nconsole-mode huge\ndefault\nbeep yes\n",
+        )?;
+        assert_eq!(
+            loader_conf.validate(),
+            [
+                "line 2: unknown key \"timout\"",
+                "line 4: invalid value \"soon\" for \"timeout\"",
+                "line 5: invalid value \"maybe\" for \"editor\"",
+                "line 8: invalid value \"huge\" for \"console-mode\"",
+                "line 9: invalid value \"\" for \"default\"",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+/// How long to wait between two attempts to acquire a held lock.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for another installation to release the ESP by default.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Acquire an exclusive advisory lock on the ESP.
+///
+/// The lock is taken on the ESP mountpoint directory itself, so that no lock file is left behind
+/// on the ESP. It is released when the returned guard is dropped.
+///
+/// If another process holds the lock, this waits for at most `timeout` before failing. A timeout
+/// of zero fails immediately.
+pub fn lock_esp(esp: &Path, timeout: Duration) -> Result<Flock<File>> {
+    let deadline = Instant::now() + timeout;
+    let mut file = File::open(esp).with_context(|| format!("Failed to open the ESP {esp:?}"))?;
+    let mut waiting = false;
+
+    loop {
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => return Ok(lock),
+            Err((f, Errno::EWOULDBLOCK)) if Instant::now() < deadline => {
+                if !waiting {
+                    tracing::info!(
+                        "Waiting for another installation to release the ESP {esp:?}..."
+                    );
+                    waiting = true;
+                }
+                file = f;
+                thread::sleep(RETRY_INTERVAL);
+            }
+            Err((_, Errno::EWOULDBLOCK)) => bail!(
+                "The ESP {esp:?} is locked by another installation. Gave up after {timeout:?}."
+            ),
+            Err((_, e)) => {
+                return Err(e).with_context(|| format!("Failed to lock the ESP {esp:?}"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn second_lock_times_out() -> Result<()> {
+        let esp = tempdir()?;
+
+        let lock = lock_esp(esp.path(), Duration::ZERO)?;
+        let error = lock_esp(esp.path(), Duration::from_millis(200))
+            .expect_err("Acquired a lock that is already held");
+        assert!(error.to_string().contains("locked by another installation"));
+
+        drop(lock);
+        lock_esp(esp.path(), Duration::ZERO)?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,6 @@
-mod architecture;
-mod cli;
-mod esp;
-mod install;
-mod version;
-
 use clap::Parser;
 
-use cli::Cli;
+use lzbt_systemd::cli::Cli;
 
 fn main() {
     Cli::parse().call(module_path!())
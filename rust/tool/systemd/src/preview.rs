@@ -0,0 +1,103 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::esp::SystemdEspPaths;
+use crate::install::{strip_boot_counter, stub_name, EspContents, KernelParams};
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+
+/// What an installation would do with the stubs on the ESP.
+#[derive(Debug, Default, PartialEq)]
+pub struct StubPreview {
+    /// Stubs that already exist under their prospective name and are kept as they are.
+    pub reused: Vec<PathBuf>,
+    /// Stubs that do not exist under their prospective name and are built again.
+    pub regenerated: Vec<PathBuf>,
+    /// Existing NixOS stubs that are no longer referenced and will be garbage collected.
+    pub obsolete: Vec<PathBuf>,
+}
+
+impl StubPreview {
+    /// Compare the prospective stub names of the generations with the stubs in `EFI/Linux`.
+    ///
+    /// The stub names are computed with `public_key` and `kernel_params`, so this previews the
+    /// effect of a key rotation or of changed kernel parameters before running the installation.
+    pub fn new(
+        esp_paths: &SystemdEspPaths,
+        generation_links: &[PathBuf],
+        public_key: &[u8],
+        kernel_params: &KernelParams,
+    ) -> Result<Self> {
+        let linux_path: &Path = &esp_paths.linux;
+        let mut preview = Self::default();
+        let mut prospective = BTreeSet::new();
+        let esp_contents = EspContents::read(esp_paths)?;
+
+        let links = generation_links
+            .iter()
+            .map(GenerationLink::from_path)
+            .collect::<Result<Vec<_>>>()?;
+        let latest_version = links.iter().map(|link| link.version).max();
+        for link in links {
+            let is_latest = Some(link.version) == latest_version;
+            let generation = Generation::from_link(&link)
+                .with_context(|| format!("Failed to build generation from link: {link:?}"))?;
+
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec));
+
+            for generation in std::iter::once(generation.clone()).chain(specialisations) {
+                let name = stub_name(
+                    &generation,
+                    &kernel_params.kernel_cmdline(&generation, is_latest),
+                    public_key,
+                    &esp_paths.stub_prefix,
+                )?;
+                if esp_contents.find_stub(linux_path, &name).is_some() {
+                    preview.reused.push(name.clone());
+                } else {
+                    preview.regenerated.push(name.clone());
+                }
+                prospective.insert(name);
+            }
+        }
+
+        if linux_path.exists() {
+            for entry in fs::read_dir(linux_path)
+                .with_context(|| format!("Failed to read directory {linux_path:?}"))?
+            {
+                let name = PathBuf::from(entry?.file_name());
+                let is_nixos_stub = name
+                    .to_str()
+                    .is_some_and(|n| n.starts_with(&esp_paths.stub_prefix));
+                // Stubs with a boot counter are compared by the name without it.
+                let uncounted = strip_boot_counter(name.as_os_str());
+                if is_nixos_stub && !prospective.contains(uncounted.as_ref().unwrap_or(&name)) {
+                    preview.obsolete.push(name);
+                }
+            }
+            preview.obsolete.sort();
+        }
+
+        Ok(preview)
+    }
+
+    /// Print the preview in a line-based format.
+    pub fn print(&self) {
+        for name in &self.reused {
+            println!("reuse {}", name.display());
+        }
+        for name in &self.regenerated {
+            println!("regenerate {}", name.display());
+        }
+        for name in &self.obsolete {
+            println!("remove {}", name.display());
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use fatfs::{
+    Date, DateTime, Dir, FileSystem, FormatVolumeOptions, FsOptions, ReadWriteSeek, Time,
+    TimeProvider,
+};
+
+/// Space reserved in the image for the FAT metadata and directory entries.
+const IMAGE_OVERHEAD: u64 = 16 * 1024 * 1024;
+
+/// The volume ID of the images. It is usually random, but the images should be reproducible.
+const VOLUME_ID: u32 = 0x4c5a4254;
+
+/// Timestamps all files in the images with the earliest date FAT can represent, so that the
+/// images do not depend on when they were built.
+#[derive(Debug)]
+struct EpochTimeProvider;
+
+static EPOCH_TIME_PROVIDER: EpochTimeProvider = EpochTimeProvider;
+
+impl TimeProvider for EpochTimeProvider {
+    fn get_current_date(&self) -> Date {
+        Date {
+            year: 1980,
+            month: 1,
+            day: 1,
+        }
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        DateTime {
+            date: self.get_current_date(),
+            time: Time {
+                hour: 0,
+                min: 0,
+                sec: 0,
+                millis: 0,
+            },
+        }
+    }
+}
+
+/// Write the contents of an ESP into a raw FAT image.
+///
+/// The image is not partitioned. It can be written directly to a USB stick, which firmware boots
+/// like an ESP because it contains the removable media fallback path (`EFI/BOOT`).
+///
+/// The image only depends on the contents of the ESP, i.e. it has a fixed volume ID and all
+/// timestamps are zero.
+pub fn write_fat_image(esp: &Path, image: &Path) -> Result<()> {
+    let image_size = image_size(esp)?;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)
+        .with_context(|| format!("Failed to create the image {image:?}"))?;
+    file.set_len(image_size)
+        .with_context(|| format!("Failed to resize the image {image:?}"))?;
+
+    fatfs::format_volume(
+        &mut file,
+        FormatVolumeOptions::new()
+            .volume_label(*b"LANZABOOTE ")
+            .volume_id(VOLUME_ID),
+    )
+    .context("Failed to format the image.")?;
+
+    let filesystem = FileSystem::new(
+        &mut file,
+        FsOptions::new().time_provider(&EPOCH_TIME_PROVIDER),
+    )
+    .context("Failed to open the formatted image.")?;
+    copy_dir(esp, &filesystem.root_dir())
+        .with_context(|| format!("Failed to copy {esp:?} into the image"))?;
+    filesystem.unmount().context("Failed to write the image.")?;
+
+    Ok(())
+}
+
+/// Copy the contents of a raw FAT image into the directory `esp`, i.e. the reverse of
+/// [`write_fat_image`].
+pub fn read_fat_image(image: &Path, esp: &Path) -> Result<()> {
+    let file = File::open(image).with_context(|| format!("Failed to open the image {image:?}"))?;
+    let filesystem = FileSystem::new(file, FsOptions::new())
+        .with_context(|| format!("Failed to open the FAT file system in {image:?}"))?;
+    copy_dir_out(&filesystem.root_dir(), esp)
+        .with_context(|| format!("Failed to copy the image {image:?} to {esp:?}"))?;
+    Ok(())
+}
+
+/// Compute the size of an image that has enough space for all files in `dir`.
+///
+/// The size is rounded up to a whole MiB.
+fn image_size(dir: &Path) -> Result<u64> {
+    const MIB: u64 = 1024 * 1024;
+    let contents_size = dir_size(dir)?;
+    Ok((contents_size + contents_size / 10 + IMAGE_OVERHEAD).div_ceil(MIB) * MIB)
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Recursively copy the directory `from` on the host into the directory `to` in the image.
+///
+/// The entries are copied sorted by name, so that their order in the image does not depend on the
+/// order of the directory on the host.
+fn copy_dir<T: ReadWriteSeek>(from: &Path, to: &Dir<T>) -> Result<()> {
+    let mut entries = fs::read_dir(from)
+        .with_context(|| format!("Failed to read {from:?}"))?
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .with_context(|| format!("File name is not valid UTF-8: {name:?}"))?;
+
+        if entry.file_type()?.is_dir() {
+            let dir = to.create_dir(name)?;
+            copy_dir(&entry.path(), &dir)?;
+        } else {
+            let mut source = File::open(entry.path())?;
+            let mut target = to.create_file(name)?;
+            target.truncate()?;
+            io::copy(&mut source, &mut target)
+                .with_context(|| format!("Failed to copy {:?}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively copy the directory `from` in the image into the directory `to` on the host.
+fn copy_dir_out<T: ReadWriteSeek>(from: &Dir<T>, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).with_context(|| format!("Failed to create {to:?}"))?;
+    for entry in from.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = to.join(&name);
+        if entry.is_dir() {
+            copy_dir_out(&entry.to_dir(), &path)?;
+        } else {
+            let mut target =
+                File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+            io::copy(&mut entry.to_file(), &mut target)
+                .with_context(|| format!("Failed to copy {name} out of the image"))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn copy_esp_into_image() -> Result<()> {
+        let esp = tempdir()?;
+        let tmpdir = tempdir()?;
+        fs::create_dir_all(esp.path().join("EFI/BOOT"))?;
+        fs::write(esp.path().join("EFI/BOOT/BOOTX64.EFI"), b"systemd-boot")?;
+        fs::create_dir_all(esp.path().join("loader"))?;
+        fs::write(esp.path().join("loader/loader.conf"), b"timeout 0\n")?;
+
+        let image = tmpdir.path().join("recovery.img");
+        write_fat_image(esp.path(), &image)?;
+
+        let filesystem = FileSystem::new(File::open(&image)?, FsOptions::new())?;
+        let mut contents = String::new();
+        filesystem
+            .root_dir()
+            .open_file("EFI/BOOT/BOOTX64.EFI")?
+            .read_to_string(&mut contents)?;
+        assert_eq!(contents, "systemd-boot");
+
+        let mut names = filesystem
+            .root_dir()
+            .iter()
+            .map(|e| Ok(e?.file_name()))
+            .collect::<Result<Vec<String>>>()?;
+        names.sort();
+        assert_eq!(names, ["EFI", "loader"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reproducible_image_round_trip() -> Result<()> {
+        let esp = tempdir()?;
+        let tmpdir = tempdir()?;
+        fs::create_dir_all(esp.path().join("EFI/Linux"))?;
+        fs::write(esp.path().join("EFI/Linux/nixos-generation-1.efi"), b"stub")?;
+
+        let image0 = tmpdir.path().join("esp0.img");
+        let image1 = tmpdir.path().join("esp1.img");
+        write_fat_image(esp.path(), &image0)?;
+        write_fat_image(esp.path(), &image1)?;
+        assert_eq!(fs::read(&image0)?, fs::read(&image1)?);
+
+        let unpacked = tmpdir.path().join("esp");
+        read_fat_image(&image0, &unpacked)?;
+        assert_eq!(
+            fs::read(unpacked.join("EFI/Linux/nixos-generation-1.efi"))?,
+            b"stub"
+        );
+
+        Ok(())
+    }
+}
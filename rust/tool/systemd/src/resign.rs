@@ -0,0 +1,259 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::syncfs;
+use tempfile::tempdir;
+
+use crate::esp::SystemdEspPaths;
+use crate::install::{load_generations, stub_name, EspContents, KernelParams};
+use crate::lock::{lock_esp, DEFAULT_LOCK_TIMEOUT};
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp::{EspPaths, DEFAULT_ESP_SUBDIR};
+use lanzaboote_tool::generation::GenerationLink;
+use lanzaboote_tool::pe;
+use lanzaboote_tool::signature::{Signer, VerificationResult};
+
+/// Re-signs the PE binaries on the ESP with a new key, without building them again.
+///
+/// The stubs of the generations among `generation_links` are renamed to the names derived from
+/// the new key, so that the next installation reuses them. systemd-boot and the EFI fallback are
+/// re-signed in place. The kernels and initrds in `EFI/nixos` are not signed, the stubs verify
+/// them through their hashes instead.
+pub struct Resigner<S: Signer> {
+    esp_paths: SystemdEspPaths,
+    arch: Architecture,
+    signer: S,
+    old_public_key: Vec<u8>,
+    generation_links: Vec<PathBuf>,
+    kernel_params: KernelParams,
+    lock_timeout: Duration,
+}
+
+impl<S: Signer> Resigner<S> {
+    pub fn new(
+        arch: Architecture,
+        esp: PathBuf,
+        signer: S,
+        old_public_key: Vec<u8>,
+        generation_links: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            esp_paths: SystemdEspPaths::new(esp, DEFAULT_ESP_SUBDIR, arch),
+            arch,
+            signer,
+            old_public_key,
+            generation_links,
+            kernel_params: KernelParams::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        }
+    }
+
+    /// Re-sign the installation in `EFI/<esp_subdir>`, see
+    /// [`Installer::with_esp_subdir`](crate::install::Installer::with_esp_subdir).
+    pub fn with_esp_subdir(mut self, esp_subdir: &str) -> Self {
+        self.esp_paths = SystemdEspPaths::new(&self.esp_paths.esp, esp_subdir, self.arch);
+        self
+    }
+
+    /// Look up the stubs by the kernel command lines of an installation with these kernel
+    /// parameters, see [`Installer::with_kernel_params`](crate::install::Installer::with_kernel_params).
+    pub fn with_kernel_params(mut self, kernel_params: KernelParams) -> Self {
+        self.kernel_params = kernel_params;
+        self
+    }
+
+    /// Wait for at most `timeout` if an installation holds the lock on the ESP.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    pub fn resign(&self) -> Result<()> {
+        let _esp_lock = lock_esp(&self.esp_paths.esp, self.lock_timeout)?;
+        let public_key = self.signer.get_public_key()?;
+
+        let mut resigned = self.resign_stubs(&public_key)?;
+        resigned.extend(self.resign_systemd_boot()?);
+
+        let boot = File::open(&self.esp_paths.esp).context("Failed to open ESP root directory.")?;
+        syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+
+        for path in &resigned {
+            match self.signer.verify_path_detailed(path)? {
+                VerificationResult::SignedTrusted => {}
+                VerificationResult::SignedUntrusted => {
+                    bail!("{path:?} is not signed with the new key.")
+                }
+                VerificationResult::Unsigned => bail!("{path:?} is not signed at all."),
+            }
+        }
+        tracing::info!("Re-signed and verified {} files.", resigned.len());
+        Ok(())
+    }
+
+    /// Re-sign the stubs of all generations and rename them to their names for the new key.
+    fn resign_stubs(&self, public_key: &[u8]) -> Result<Vec<PathBuf>> {
+        let links = self
+            .generation_links
+            .iter()
+            .map(GenerationLink::from_path)
+            .collect::<Result<Vec<GenerationLink>>>()?;
+        let generations = load_generations(&links, &mut BTreeSet::new())?;
+        let latest_version = generations
+            .iter()
+            .map(|generation| generation.version)
+            .max();
+        let esp_contents = EspContents::read(&self.esp_paths)?;
+
+        let mut resigned = Vec::new();
+        for generation in generations {
+            let is_latest = Some(generation.version) == latest_version;
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec));
+            for generation in std::iter::once(generation.clone()).chain(specialisations) {
+                let prefix = &self.esp_paths.stub_prefix;
+                let cmdline = self.kernel_params.kernel_cmdline(&generation, is_latest);
+                let old_name = stub_name(&generation, &cmdline, &self.old_public_key, prefix)?;
+                let new_name = stub_name(&generation, &cmdline, public_key, prefix)?;
+
+                let Some(old_stub) = esp_contents.find_stub(&self.esp_paths.linux, &old_name)
+                else {
+                    if let Some(new_stub) = esp_contents.find_stub(&self.esp_paths.linux, &new_name)
+                    {
+                        // A previous run was interrupted after re-signing this stub.
+                        resigned.push(new_stub);
+                    } else {
+                        tracing::warn!(
+                            "Generation {} is not installed with the old key.",
+                            generation.version_tag()
+                        );
+                    }
+                    continue;
+                };
+
+                let new_stub = self
+                    .esp_paths
+                    .linux
+                    .join(renamed_stub(&old_stub, &old_name, &new_name)?);
+                tracing::info!(
+                    "Re-signing the stub of generation {}...",
+                    generation.version_tag()
+                );
+                resign_file(&self.signer, &old_stub, &new_stub)?;
+                resigned.push(new_stub);
+            }
+        }
+        Ok(resigned)
+    }
+
+    /// Re-sign systemd-boot and the EFI fallback, if the fallback is a copy of systemd-boot.
+    ///
+    /// Another bootloader in the fallback location is left as it is.
+    fn resign_systemd_boot(&self) -> Result<Vec<PathBuf>> {
+        let systemd_boot = &self.esp_paths.systemd_boot;
+        if !systemd_boot.exists() {
+            tracing::warn!("systemd-boot is not installed at {systemd_boot:?}.");
+            return Ok(Vec::new());
+        }
+        let unsigned_systemd_boot = read_unsigned(systemd_boot)?;
+
+        let mut resigned = Vec::new();
+        let fallback = &self.esp_paths.efi_fallback;
+        if fallback.exists() && read_unsigned(fallback)? == unsigned_systemd_boot {
+            tracing::info!("Re-signing {fallback:?}...");
+            resign_file(&self.signer, fallback, fallback)?;
+            resigned.push(fallback.clone());
+        }
+        tracing::info!("Re-signing {systemd_boot:?}...");
+        resign_file(&self.signer, systemd_boot, systemd_boot)?;
+        resigned.push(systemd_boot.clone());
+        Ok(resigned)
+    }
+}
+
+/// Read a PE binary and remove its signatures.
+fn read_unsigned(path: &Path) -> Result<Vec<u8>> {
+    let binary = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    pe::remove_signatures(&binary)
+        .with_context(|| format!("Failed to remove the signatures of {path:?}"))
+}
+
+/// Replace the signatures of the PE binary at `from` with a signature by `signer` and move it to
+/// `to`.
+///
+/// The signed binary is first written next to `to` and then renamed, so that `to` is never left
+/// half written.
+fn resign_file(signer: &impl Signer, from: &Path, to: &Path) -> Result<()> {
+    let working_tree = tempdir()?;
+    let unsigned = working_tree.path().join("unsigned.efi");
+    fs::write(&unsigned, read_unsigned(from)?)
+        .with_context(|| format!("Failed to write the unsigned copy of {from:?}"))?;
+
+    let to_tmp = to.with_extension(".tmp");
+    signer
+        .sign_and_copy(&unsigned, &to_tmp)
+        .with_context(|| format!("Failed to sign {to:?}"))?;
+    fs::rename(&to_tmp, to).with_context(|| {
+        format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
+    })?;
+    if from != to {
+        fs::remove_file(from).with_context(|| format!("Failed to remove {from:?}"))?;
+    }
+    Ok(())
+}
+
+/// The name of the installed stub `stub` after renaming it from `old_name` to `new_name`.
+///
+/// A boot counter in the installed name is kept, so that systemd-boot continues counting.
+fn renamed_stub(stub: &Path, old_name: &Path, new_name: &Path) -> Result<PathBuf> {
+    let stub_file_name = stub
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid stub name {stub:?}"))?;
+    let to_str = |name: &Path| {
+        name.to_str()
+            .and_then(|name| name.strip_suffix(".efi"))
+            .map(ToOwned::to_owned)
+            .with_context(|| format!("Invalid stub name {name:?}"))
+    };
+    let counter = stub_file_name
+        .strip_prefix(&to_str(old_name)?)
+        .with_context(|| format!("{stub:?} is not named {old_name:?}"))?;
+    Ok(PathBuf::from(format!("{}{counter}", to_str(new_name)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_boot_counter_when_renaming_stub() -> Result<()> {
+        let old_name = Path::new("nixos-generation-1-old.efi");
+        let new_name = Path::new("nixos-generation-1-new.efi");
+        assert_eq!(
+            renamed_stub(
+                Path::new("/esp/EFI/Linux").join(old_name).as_path(),
+                old_name,
+                new_name
+            )?,
+            new_name
+        );
+        assert_eq!(
+            renamed_stub(
+                Path::new("/esp/EFI/Linux/nixos-generation-1-old+2-1.efi"),
+                old_name,
+                new_name
+            )?,
+            PathBuf::from("nixos-generation-1-new+2-1.efi")
+        );
+        Ok(())
+    }
+}
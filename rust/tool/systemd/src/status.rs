@@ -0,0 +1,154 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::esp::{is_efi_binary, SystemdEspPaths};
+use crate::version::SystemdVersion;
+use lanzaboote_tool::pe;
+
+/// The boot state of an ESP as installed by lanzaboote, similar to `bootctl status`.
+///
+/// Reading the status does not change anything on the ESP.
+#[derive(Debug, Serialize)]
+pub struct EspStatus {
+    /// systemd-boot in `EFI/systemd`, if it is installed.
+    pub systemd_boot: Option<BootBinary>,
+    /// The binary at the removable media path `EFI/BOOT`, if there is one.
+    pub efi_fallback: Option<BootBinary>,
+    /// The stubs in `EFI/Linux`, sorted by generation.
+    pub generations: Vec<InstalledStub>,
+    /// Bytes used by the stubs and the files in `EFI/nixos`.
+    pub used_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BootBinary {
+    pub path: PathBuf,
+    /// The systemd version, `None` if the binary is not systemd-boot.
+    pub version: Option<String>,
+    pub signed: bool,
+    /// The subjects of the certificates in its signatures, see [`pe::signature_subjects`].
+    pub signers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstalledStub {
+    pub path: PathBuf,
+    pub generation: u64,
+    pub specialisation: Option<String>,
+}
+
+impl EspStatus {
+    pub fn new(esp_paths: &SystemdEspPaths) -> Result<Self> {
+        let mut generations = Vec::new();
+        let mut used_bytes = 0;
+
+        if esp_paths.linux.exists() {
+            for entry in fs::read_dir(&esp_paths.linux)
+                .with_context(|| format!("Failed to read {:?}", esp_paths.linux))?
+            {
+                let entry = entry?;
+                if !is_efi_binary(&entry)? {
+                    continue;
+                }
+                let path = entry.path();
+                let Some(generation) = stub_generation(&path, &esp_paths.stub_prefix) else {
+                    continue;
+                };
+                let stub = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+                used_bytes += stub.len() as u64;
+                let specialisation = pe::read_section_data(&stub, ".special")
+                    .map(|name| String::from_utf8_lossy(name).into_owned());
+                generations.push(InstalledStub {
+                    path,
+                    generation,
+                    specialisation,
+                });
+            }
+        }
+        generations.sort_by(|a, b| {
+            (a.generation, &a.specialisation).cmp(&(b.generation, &b.specialisation))
+        });
+
+        if esp_paths.nixos.exists() {
+            for entry in walkdir::WalkDir::new(&esp_paths.nixos) {
+                let entry =
+                    entry.with_context(|| format!("Failed to read {:?}", esp_paths.nixos))?;
+                if entry.file_type().is_file() {
+                    used_bytes += entry.metadata()?.len();
+                }
+            }
+        }
+
+        Ok(Self {
+            systemd_boot: BootBinary::read(&esp_paths.systemd_boot)?,
+            efi_fallback: BootBinary::read(&esp_paths.efi_fallback)?,
+            generations,
+            used_bytes,
+        })
+    }
+
+    pub fn print(&self) {
+        for (name, binary) in [
+            ("systemd-boot", &self.systemd_boot),
+            ("fallback", &self.efi_fallback),
+        ] {
+            let Some(binary) = binary else {
+                println!("{name}: not installed");
+                continue;
+            };
+            println!("{name}: {}", binary.path.display());
+            if let Some(version) = &binary.version {
+                println!("  version {version}");
+            }
+            match (binary.signed, binary.signers.as_slice()) {
+                (false, _) => println!("  unsigned"),
+                (true, []) => println!("  signed"),
+                (true, signers) => println!("  signed by {}", signers.join("; ")),
+            }
+        }
+
+        let mut versions: Vec<u64> = self.generations.iter().map(|s| s.generation).collect();
+        versions.dedup();
+        println!("generations: {}", versions.len());
+        for stub in &self.generations {
+            match &stub.specialisation {
+                Some(specialisation) => println!(
+                    "  {} ({specialisation}) {}",
+                    stub.generation,
+                    stub.path.display()
+                ),
+                None => println!("  {} {}", stub.generation, stub.path.display()),
+            }
+        }
+        println!("used: {} bytes", self.used_bytes);
+    }
+}
+
+impl BootBinary {
+    /// Read a boot binary, `None` if it does not exist.
+    fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let binary = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(Some(Self {
+            path: path.to_owned(),
+            version: SystemdVersion::from_systemd_boot_binary(path)
+                .ok()
+                .map(|version| version.to_string()),
+            signed: pe::has_signatures(&binary)?,
+            signers: pe::signature_subjects(&binary)
+                .with_context(|| format!("Failed to read the signatures of {path:?}"))?,
+        }))
+    }
+}
+
+/// The generation of a NixOS stub, parsed from its name. `None` for other files.
+fn stub_generation(path: &Path, stub_prefix: &str) -> Option<u64> {
+    let name = path.file_name()?.to_str()?.strip_prefix(stub_prefix)?;
+    let (generation, _) = name.split_once('-')?;
+    generation.parse().ok()
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base32ct::{Base32Unpadded, Encoding};
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+use crate::esp::SystemdEspPaths;
+use crate::install::install_signed;
+use crate::lock::{lock_esp, DEFAULT_LOCK_TIMEOUT};
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp::{EspPaths, DEFAULT_ESP_SUBDIR};
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::pe;
+use lanzaboote_tool::signature::Signer;
+
+/// Signs and installs a UKI that was built outside of NixOS, e.g. with `ukify`, to `EFI/Linux`.
+///
+/// The UKI is installed under a content-addressed name with the prefix
+/// [`SystemdEspPaths::uki_prefix`]. The garbage collection only deletes the stubs of the NixOS
+/// generations, so the UKI stays installed until it is deleted by hand.
+pub struct UkiInstaller<S: Signer> {
+    esp_paths: SystemdEspPaths,
+    arch: Architecture,
+    signer: S,
+    uki: PathBuf,
+    /// Replaces the `PRETTY_NAME` of the UKI, which systemd-boot shows as title.
+    title: Option<String>,
+    lock_timeout: Duration,
+}
+
+impl<S: Signer> UkiInstaller<S> {
+    pub fn new(arch: Architecture, esp: PathBuf, signer: S, uki: PathBuf) -> Self {
+        Self {
+            esp_paths: SystemdEspPaths::new(esp, DEFAULT_ESP_SUBDIR, arch),
+            arch,
+            signer,
+            uki,
+            title: None,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        }
+    }
+
+    /// Name the UKI after the NixOS installation in `EFI/<esp_subdir>`, see
+    /// [`Installer::with_esp_subdir`](crate::install::Installer::with_esp_subdir).
+    pub fn with_esp_subdir(mut self, esp_subdir: &str) -> Self {
+        self.esp_paths = SystemdEspPaths::new(&self.esp_paths.esp, esp_subdir, self.arch);
+        self
+    }
+
+    /// Replace the title of the boot entry, i.e. the `PRETTY_NAME` in the os-release of the UKI.
+    pub fn with_title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+
+    /// Wait for at most `timeout` if an installation holds the lock on the ESP.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Install the UKI and return its path on the ESP.
+    pub fn install(&self) -> Result<PathBuf> {
+        let uki = fs::read(&self.uki).with_context(|| format!("Failed to read {:?}", self.uki))?;
+        let kernel = pe::read_section_data(&uki, ".linux")
+            .with_context(|| format!("{:?} is not a UKI: it has no .linux section.", self.uki))?;
+        pe::validate_kernel(kernel, self.arch)
+            .with_context(|| format!("Invalid kernel in the UKI {:?}.", self.uki))?;
+        let os_release = pe::read_section_data(&uki, ".osrel")
+            .with_context(|| format!("{:?} is not a UKI: it has no .osrel section.", self.uki))?;
+
+        let tempdir = tempdir().context("Failed to create temporary directory.")?;
+        let source = match &self.title {
+            Some(title) => {
+                let mut os_release = OsRelease::from_str(
+                    std::str::from_utf8(os_release).context("The os-release is not UTF-8.")?,
+                )?;
+                os_release
+                    .0
+                    .insert("PRETTY_NAME".to_owned(), title.to_owned());
+                pe::with_replaced_section(
+                    &tempdir,
+                    &self.uki,
+                    ".osrel",
+                    os_release.to_string().as_bytes(),
+                )?
+            }
+            None => self.uki.clone(),
+        };
+
+        let public_key = self.signer.get_public_key()?;
+        let hash = Sha256::new()
+            .chain_update(fs::read(&source)?)
+            .chain_update(&public_key)
+            .finalize();
+        let target = self.esp_paths.linux.join(format!(
+            "{}{}.efi",
+            self.esp_paths.uki_prefix,
+            Base32Unpadded::encode_string(&hash)
+        ));
+
+        let _esp_lock = lock_esp(&self.esp_paths.esp, self.lock_timeout)?;
+        if target.exists() {
+            tracing::info!("{target:?} is already installed.");
+            return Ok(target);
+        }
+        install_signed(&self.signer, &source, &target)?;
+        tracing::info!("Installed {:?} to {target:?}.", self.uki);
+        Ok(target)
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::esp::{is_efi_binary, SystemdEspPaths};
+use lanzaboote_tool::signature::batch::BatchVerifier;
+use lanzaboote_tool::signature::{Signer, VerificationResult};
+
+/// The signatures of the binaries on an ESP that the firmware or systemd-boot boot.
+///
+/// These are systemd-boot, the binary at the removable media path, the stubs of NixOS and the
+/// UKIs installed with `lzbt install-uki` in `EFI/Linux`. The kernels and initrds in `EFI/nixos`
+/// are not signed, the stubs check their hashes instead.
+pub struct EspAudit {
+    pub binaries: Vec<(PathBuf, VerificationResult)>,
+}
+
+impl EspAudit {
+    /// Verify the binaries with `signer`, using up to `jobs` threads.
+    pub fn new(
+        esp_paths: &SystemdEspPaths,
+        signer: &(impl Signer + Sync + ?Sized),
+        jobs: usize,
+    ) -> Result<Self> {
+        let mut paths: Vec<PathBuf> = [&esp_paths.systemd_boot, &esp_paths.efi_fallback]
+            .into_iter()
+            .filter(|path| path.exists())
+            .cloned()
+            .collect();
+
+        if esp_paths.linux.exists() {
+            let mut stubs = Vec::new();
+            for entry in fs::read_dir(&esp_paths.linux)
+                .with_context(|| format!("Failed to read {:?}", esp_paths.linux))?
+            {
+                let entry = entry?;
+                let path = entry.path();
+                let is_installed_by_lanzaboote = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.starts_with(&esp_paths.stub_prefix)
+                            || name.starts_with(&esp_paths.uki_prefix)
+                    });
+                if is_installed_by_lanzaboote && is_efi_binary(&entry)? {
+                    stubs.push(path);
+                }
+            }
+            stubs.sort();
+            paths.extend(stubs);
+        }
+
+        let results = BatchVerifier::new(signer, jobs).verify_paths(&paths)?;
+        Ok(Self {
+            binaries: paths.into_iter().zip(results).collect(),
+        })
+    }
+
+    /// The binaries that are not signed with a trusted key.
+    pub fn untrusted(&self) -> impl Iterator<Item = &PathBuf> {
+        self.binaries
+            .iter()
+            .filter(|(_, result)| *result != VerificationResult::SignedTrusted)
+            .map(|(path, _)| path)
+    }
+
+    pub fn print(&self) {
+        for (path, result) in &self.binaries {
+            let result = match result {
+                VerificationResult::SignedTrusted => "trusted",
+                VerificationResult::SignedUntrusted => "untrusted",
+                VerificationResult::Unsigned => "unsigned",
+            };
+            println!("{}: {result}", path.display());
+        }
+    }
+}
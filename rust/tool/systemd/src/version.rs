@@ -1,9 +1,13 @@
+use std::collections::BTreeMap;
 use std::ffi::CStr;
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 
 use lanzaboote_tool::os_release::OsRelease;
 use lanzaboote_tool::pe;
@@ -15,7 +19,7 @@ use lanzaboote_tool::pe;
 /// A notible quirk here is our handling of release candidate
 /// versions. We treat 255-rc2 as 255.-1.2, which should give us the
 /// correct ordering.
-#[derive(PartialEq, PartialOrd, Eq, Debug)]
+#[derive(PartialEq, PartialOrd, Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct SystemdVersion {
     major: u32,
 
@@ -28,6 +32,9 @@ pub struct SystemdVersion {
 impl SystemdVersion {
     /// Read the systemd version from the `.osrel` section of a systemd-boot binary.
     pub fn from_systemd_boot_binary(path: &Path) -> Result<Self> {
+        #[cfg(test)]
+        tests::SYSTEMD_BOOT_PARSES.with(|count| count.set(count.get() + 1));
+
         let file_data = fs::read(path).with_context(|| format!("Failed to read file {path:?}"))?;
         let section_data = pe::read_section_data(&file_data, ".osrel")
             .with_context(|| format!("PE section '.osrel' is empty: {path:?}"))?;
@@ -52,6 +59,16 @@ impl SystemdVersion {
     }
 }
 
+impl fmt::Display for SystemdVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.minor {
+            -1 => write!(f, "{}-rc{}", self.major, self.patch),
+            0 => write!(f, "{}", self.major),
+            minor => write!(f, "{}.{minor}", self.major),
+        }
+    }
+}
+
 impl FromStr for SystemdVersion {
     type Err = anyhow::Error;
 
@@ -81,6 +98,101 @@ impl FromStr for SystemdVersion {
     }
 }
 
+/// Versions of the systemd-boot binaries on the ESP.
+///
+/// Each version is recorded together with the size and modification time of the binary it was
+/// read from. As long as both are unchanged, the binary is not read again, which is slow on some
+/// ESPs.
+///
+/// The binaries are recorded by their paths relative to the ESP, so that the cache does not
+/// depend on where the ESP is mounted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SystemdVersionCache {
+    entries: BTreeMap<PathBuf, CachedVersion>,
+    #[serde(skip)]
+    esp: PathBuf,
+    #[serde(skip)]
+    changed: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CachedVersion {
+    len: u64,
+    modified: SystemTime,
+    version: SystemdVersion,
+}
+
+impl CachedVersion {
+    fn new(binary: &Path, version: SystemdVersion) -> Result<Self> {
+        let metadata =
+            fs::metadata(binary).with_context(|| format!("Failed to stat file {binary:?}"))?;
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            version,
+        })
+    }
+}
+
+impl SystemdVersionCache {
+    /// Load the cache of the ESP at `esp` from `path`.
+    ///
+    /// A missing or unreadable cache is treated as empty.
+    pub fn load(path: &Path, esp: &Path) -> Self {
+        let cache: Self = fs::read(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        Self {
+            esp: esp.to_path_buf(),
+            ..cache
+        }
+    }
+
+    /// Write the cache to `path` if it changed since it was loaded.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if self.changed {
+            fs::write(path, serde_json::to_vec(self)?)
+                .with_context(|| format!("Failed to write systemd-boot versions to {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Return the version of `binary`, calling `read` only if the binary changed since its
+    /// version was recorded.
+    pub fn get(
+        &mut self,
+        binary: &Path,
+        read: impl FnOnce(&Path) -> Result<SystemdVersion>,
+    ) -> Result<SystemdVersion> {
+        if let Some(cached) = self.entries.get(self.key(binary)) {
+            if CachedVersion::new(binary, cached.version.clone())
+                .ok()
+                .as_ref()
+                == Some(cached)
+            {
+                return Ok(cached.version.clone());
+            }
+        }
+
+        let version = read(binary)?;
+        self.insert(binary, version.clone())?;
+        Ok(version)
+    }
+
+    /// Record the version of a binary that was just written.
+    pub fn insert(&mut self, binary: &Path, version: SystemdVersion) -> Result<()> {
+        let cached = CachedVersion::new(binary, version)?;
+        self.entries.insert(self.key(binary).to_path_buf(), cached);
+        self.changed = true;
+        Ok(())
+    }
+
+    fn key<'a>(&self, binary: &'a Path) -> &'a Path {
+        binary.strip_prefix(&self.esp).unwrap_or(binary)
+    }
+}
+
 #[cfg(test)]
 impl From<(u32, i32, u32)> for SystemdVersion {
     fn from(value: (u32, i32, u32)) -> Self {
@@ -93,9 +205,16 @@ impl From<(u32, i32, u32)> for SystemdVersion {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use std::cell::Cell;
+
     use super::*;
 
+    thread_local! {
+        /// How often [`SystemdVersion::from_systemd_boot_binary`] parsed a binary on this thread.
+        pub(crate) static SYSTEMD_BOOT_PARSES: Cell<usize> = const { Cell::new(0) };
+    }
+
     #[test]
     fn parse_version_correctly() {
         assert_eq!(parse_version("253"), (253, 0, 0).into());
@@ -104,6 +223,13 @@ mod tests {
         assert_eq!(parse_version("251-rc7"), (251, -1, 7).into());
     }
 
+    #[test]
+    fn print_version_like_systemd() {
+        for version in ["253", "252.4", "251-rc7"] {
+            assert_eq!(parse_version(version).to_string(), version);
+        }
+    }
+
     #[test]
     fn compare_version_correctly() {
         assert!(parse_version("253") > parse_version("252"));
@@ -120,6 +246,35 @@ mod tests {
         parse_version_error("-1.3.123");
     }
 
+    #[test]
+    fn read_cached_binary_only_after_change() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let binary = tmpdir.path().join("systemd-bootx64.efi");
+        let cache_path = tmpdir.path().join("versions.json");
+        fs::write(&binary, "systemd-boot 255")?;
+
+        let reads = Cell::new(0);
+        let read = |_: &Path| {
+            reads.set(reads.get() + 1);
+            Ok(parse_version("255"))
+        };
+
+        let mut cache = SystemdVersionCache::load(&cache_path, tmpdir.path());
+        assert_eq!(cache.get(&binary, read)?, parse_version("255"));
+        cache.save(&cache_path)?;
+        assert!(!fs::read_to_string(&cache_path)?.contains(&*tmpdir.path().to_string_lossy()));
+
+        let mut cache = SystemdVersionCache::load(&cache_path, tmpdir.path());
+        assert_eq!(cache.get(&binary, read)?, parse_version("255"));
+        assert_eq!(reads.get(), 1);
+
+        fs::write(&binary, "systemd-boot 256.1")?;
+        cache.get(&binary, read)?;
+        assert_eq!(reads.get(), 2);
+
+        Ok(())
+    }
+
     fn parse_version(input: &str) -> SystemdVersion {
         SystemdVersion::from_str(input).unwrap()
     }
@@ -0,0 +1,145 @@
+//! Boot a real lanzaboote stub in QEMU.
+//!
+//! Unlike the other tests, these do not use the systemd stub as stand-in, so they catch
+//! regressions in the section layout of the stubs and in the PE loader of the stub. They only run
+//! with the `qemu-tests` feature, see `Cargo.toml` for what they need.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+use crate::common::{self, SYSTEM};
+
+/// Printed by the kernel when it runs `/init` from the initrd, i.e. once it booted with the
+/// initrd the stub passed to it.
+const INIT_MARKER: &str = "Run /init as init process";
+
+#[test]
+fn boot_thin_stub() -> Result<()> {
+    let stub = env_path("TEST_LANZABOOTE_STUB")?;
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    fs::copy(env_path("TEST_KERNEL")?, store_path.join("kernel"))?;
+    fs::write(store_path.join("initrd"), initrd_with_init())?;
+
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    set_kernel_params(&generation_link)?;
+
+    let output = common::lanzaboote_install_with_stub(&stub, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+
+    let serial = common::boot_in_qemu(esp.path(), INIT_MARKER, Duration::from_secs(120))?;
+    assert!(
+        serial.contains(INIT_MARKER),
+        "The kernel did not run the init of the initrd."
+    );
+
+    Ok(())
+}
+
+/// Printed by the kernel when it has no initrd and no root file system to mount.
+const NO_ROOT_MARKER: &str = "VFS: Unable to mount root fs";
+
+#[test]
+fn boot_thin_stub_without_initrd() -> Result<()> {
+    let stub = env_path("TEST_LANZABOOTE_STUB")?;
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    fs::copy(env_path("TEST_KERNEL")?, store_path.join("kernel"))?;
+
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    set_kernel_params(&generation_link)?;
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]["initrd"] = serde_json::Value::Null;
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output = common::lanzaboote_install_with_stub(&stub, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+
+    // The stub skips the initrd loader, so the kernel looks for a root file system right away.
+    let serial = common::boot_in_qemu(esp.path(), NO_ROOT_MARKER, Duration::from_secs(120))?;
+    assert!(
+        serial.contains(NO_ROOT_MARKER),
+        "The kernel did not boot without initrd."
+    );
+    assert!(!serial.contains(INIT_MARKER));
+
+    Ok(())
+}
+
+fn env_path(variable: &str) -> Result<PathBuf> {
+    std::env::var_os(variable)
+        .map(PathBuf::from)
+        .with_context(|| format!("{variable} environment variable is not set."))
+}
+
+/// Make the kernel log to the serial console and reboot, i.e. stop QEMU, when it panics.
+fn set_kernel_params(generation_link: &Path) -> Result<()> {
+    let console = match SYSTEM {
+        "aarch64-linux" => "console=ttyAMA0",
+        _ => "console=ttyS0",
+    };
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]["kernelParams"] = serde_json::json!([console, "panic=-1"]);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    Ok(())
+}
+
+/// An initrd in the `newc` cpio format that only contains an executable `/init`.
+///
+/// `/init` is a script for an interpreter that does not exist, so the kernel panics right after
+/// trying to run it.
+fn initrd_with_init() -> Vec<u8> {
+    let mut cpio = Vec::new();
+    append_cpio_entry(&mut cpio, "init", 0o100755, b"#!/bin/sh\n");
+    append_cpio_entry(&mut cpio, "TRAILER!!!", 0, b"");
+    cpio
+}
+
+fn append_cpio_entry(cpio: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let pad = |cpio: &mut Vec<u8>| cpio.resize(cpio.len().next_multiple_of(4), 0);
+    let name_size = name.len() as u32 + 1;
+    let nlink = u32::from(mode != 0);
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize, check
+    let fields = [
+        1,
+        mode,
+        0,
+        0,
+        nlink,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name_size,
+        0,
+    ];
+
+    cpio.extend(b"070701");
+    for field in fields {
+        cpio.extend(format!("{field:08x}").as_bytes());
+    }
+    cpio.extend(name.as_bytes());
+    cpio.push(0);
+    pad(cpio);
+    cpio.extend(data);
+    pad(cpio);
+}
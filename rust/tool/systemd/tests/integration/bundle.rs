@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use tempfile::tempdir;
+use walkdir::WalkDir;
+
+use crate::common::{self, verify_signature};
+use lanzaboote_tool::architecture::Architecture;
+use lzbt_systemd::architecture::SystemdArchitectureExt;
+use lzbt_systemd::esp::SYSTEMD_BOOT_VERSIONS;
+
+/// Signing a bundle offline and applying it produces the same ESP as a regular installation.
+#[test]
+fn bundle_round_trip() -> Result<()> {
+    let bundle_esp = tempdir()?;
+    let install_esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), v))
+        .collect::<Result<_>>()?;
+
+    let bundle = tmpdir.path().join("bundle.tar");
+    let signed_bundle = tmpdir.path().join("signed.tar");
+
+    let output0 = common::lanzaboote_bundle(&bundle, generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // An unsigned bundle is never installed.
+    let output1 = common::lanzaboote_apply_bundle(&bundle, bundle_esp.path())?;
+    assert!(!output1.status.success());
+
+    let output2 = common::lanzaboote_sign_bundle(&bundle, &signed_bundle)?;
+    assert!(output2.status.success());
+    let output3 = common::lanzaboote_apply_bundle(&signed_bundle, bundle_esp.path())?;
+    assert!(output3.status.success());
+
+    let output4 = common::lanzaboote_install(0, install_esp.path(), generation_links)?;
+    assert!(output4.status.success());
+
+    assert_eq!(esp_files(bundle_esp.path()), esp_files(install_esp.path()));
+
+    for version in [1, 2] {
+        let stub = common::image_path(&bundle_esp, version, &toplevel)?;
+        assert!(verify_signature(&stub)?);
+    }
+
+    Ok(())
+}
+
+/// Bundling the same generations twice produces identical bundles, i.e. the unsigned stubs and
+/// the archive do not depend on when they were built.
+#[test]
+fn reproducible_bundles() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), v))
+        .collect::<Result<_>>()?;
+
+    let bundle0 = tmpdir.path().join("bundle0.tar");
+    let bundle1 = tmpdir.path().join("bundle1.tar");
+    let output0 = common::lanzaboote_bundle(&bundle0, generation_links.clone())?;
+    assert!(output0.status.success());
+    // objcopy stamps the stubs with the time in seconds.
+    thread::sleep(Duration::from_secs(2));
+    let output1 = common::lanzaboote_bundle(&bundle1, generation_links)?;
+    assert!(output1.status.success());
+
+    assert_eq!(common::hash_file(&bundle0), common::hash_file(&bundle1));
+
+    Ok(())
+}
+
+/// Applying a bundle keeps an installed systemd-boot that is at least as new as the bundled one.
+#[test]
+fn keep_systemd_boot_when_applying_bundles() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let bundle = tmpdir.path().join("bundle.tar");
+    let signed_bundle = tmpdir.path().join("signed.tar");
+    let output0 = common::lanzaboote_bundle(&bundle, vec![&generation_link])?;
+    assert!(output0.status.success());
+    let output1 = common::lanzaboote_sign_bundle(&bundle, &signed_bundle)?;
+    assert!(output1.status.success());
+
+    let output2 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output2.status.success());
+
+    let arch = Architecture::from_nixos_system(common::SYSTEM)?;
+    let systemd_boot = esp.path().join("EFI/systemd").join(arch.systemd_filename());
+    let mtime0 = common::mtime(&systemd_boot);
+
+    let output3 = common::lanzaboote_apply_bundle(&signed_bundle, esp.path())?;
+    assert!(output3.status.success());
+
+    assert_eq!(
+        mtime0,
+        common::mtime(&systemd_boot),
+        "systemd-boot binary was replaced by the one from the bundle."
+    );
+
+    Ok(())
+}
+
+fn esp_files(esp: &Path) -> Vec<PathBuf> {
+    let mut files = WalkDir::new(esp)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.path().strip_prefix(esp).unwrap().to_path_buf())
+        // The versions cache is installation state that bundles leave out.
+        .filter(|path| path != Path::new(SYSTEMD_BOOT_VERSIONS))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
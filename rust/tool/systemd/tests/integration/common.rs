@@ -15,6 +15,9 @@ use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
 use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::signature::{local::LocalKeyPair, Signer};
 
 /// Returns the host platform system
 /// in the system double format for
@@ -30,6 +33,24 @@ pub static SYSTEM: &str = "i686-linux";
 #[cfg(target_arch = "x86_64")]
 pub static SYSTEM: &str = "x86_64-linux";
 
+#[cfg(target_arch = "arm")]
+pub static SYSTEM: &str = "armv7l-linux";
+
+#[cfg(target_arch = "riscv64")]
+pub static SYSTEM: &str = "riscv64-linux";
+
+/// The kernel parameters of the mock generations.
+pub const KERNEL_PARAMS: [&str; 8] = [
+    "amd_iommu=on",
+    "amd_iommu=pt",
+    "iommu=pt",
+    "kvm.ignore_msrs=1",
+    "kvm.report_ignored_msrs=0",
+    "udev.log_priority=3",
+    "systemd.unified_cgroup_hierarchy=1",
+    "loglevel=4",
+];
+
 /// Create a mock generation link.
 ///
 /// Works like `setup_generation_link_from_toplevel` but already sets up toplevel.
@@ -50,6 +71,25 @@ pub fn setup_generation_link_from_toplevel(
     toplevel: &Path,
     profiles_directory: &Path,
     version: u64,
+) -> Result<PathBuf> {
+    setup_generation_link_with_extension(
+        toplevel,
+        profiles_directory,
+        version,
+        json!({
+            "sort_key": "lanzaboote",
+        }),
+    )
+}
+
+/// Create a mock generation link with a custom `org.nix-community.lanzaboote` bootspec extension.
+///
+/// Works like `setup_generation_link_from_toplevel` otherwise.
+pub fn setup_generation_link_with_extension(
+    toplevel: &Path,
+    profiles_directory: &Path,
+    version: u64,
+    lanzaboote_extension: serde_json::Value,
 ) -> Result<PathBuf> {
     let bootspec = json!({
         "org.nixos.bootspec.v1": {
@@ -57,23 +97,12 @@ pub fn setup_generation_link_from_toplevel(
           // Normally, these are in the Nix store.
           "initrd": toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd"),
           "kernel": toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"),
-          "kernelParams": [
-            "amd_iommu=on",
-            "amd_iommu=pt",
-            "iommu=pt",
-            "kvm.ignore_msrs=1",
-            "kvm.report_ignored_msrs=0",
-            "udev.log_priority=3",
-            "systemd.unified_cgroup_hierarchy=1",
-            "loglevel=4"
-          ],
+          "kernelParams": KERNEL_PARAMS,
           "label": "LanzaOS",
           "toplevel": toplevel,
           "system": SYSTEM,
         },
-        "org.nix-community.lanzaboote": {
-            "sort_key": "lanzaboote",
-        }
+        "org.nix-community.lanzaboote": lanzaboote_extension,
     });
 
     let generation_link_path = profiles_directory.join(format!("system-{}-link", version));
@@ -116,8 +145,9 @@ pub fn setup_toplevel(tmpdir: &Path) -> Result<PathBuf> {
 
     // To simplify the test setup, we use the systemd stub for all PE binaries used by lanzatool.
     // Lanzatool doesn't care whether its actually a kernel or initrd but only whether it can
-    // manipulate the PE binary with objcopy and/or sign it with sbsigntool. For testing lanzatool
-    // in isolation this should suffice.
+    // manipulate the PE binary with objcopy and/or sign it with sbsigntool. The stub also passes
+    // the check that the kernel is an EFI application for the architecture, and a PE binary as
+    // initrd is only warned about. For testing lanzatool in isolation this should suffice.
     fs::copy(&test_systemd_stub, initrd_path)?;
     fs::copy(&test_systemd_stub, kernel_path)?;
     fs::write(nixos_version_path, b"23.05")?;
@@ -139,6 +169,39 @@ pub fn lanzaboote_install(
     config_limit: u64,
     esp_mountpoint: &Path,
     generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_args(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        Vec::<&str>::new(),
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments.
+pub fn lanzaboote_install_with_args(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_loader_config(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        "timeout 0\nconsole-mode 1\n",
+        extra_args,
+    )
+}
+
+/// Call the `lanzaboote install` command with a systemd-boot loader config and additional
+/// arguments.
+pub fn lanzaboote_install_with_loader_config(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    loader_config: &str,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
 ) -> Result<Output> {
     // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
     // the comment in setup_toplevel for details.
@@ -151,12 +214,14 @@ pub fn lanzaboote_install(
     );
 
     let test_loader_config_path = tempfile::NamedTempFile::new()?;
-    let test_loader_config = r"timeout 0\nconsole-mode 1\n";
-    fs::write(test_loader_config_path.path(), test_loader_config)?;
+    fs::write(test_loader_config_path.path(), loader_config)?;
 
+    // The systemd stub also reads the kernel and initrd from its own sections, like the fat
+    // lanzaboote stub.
     let mut cmd = Command::cargo_bin("lzbt-systemd")?;
     let output = cmd
-        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .env("LANZABOOTE_STUB", &test_systemd_stub)
+        .env("LANZABOOTE_FAT_STUB", &test_systemd_stub)
         .arg("-vv")
         .arg("install")
         .arg("--system")
@@ -171,6 +236,7 @@ pub fn lanzaboote_install(
         .arg("tests/fixtures/uefi-keys/db.key")
         .arg("--configuration-limit")
         .arg(config_limit.to_string())
+        .args(extra_args)
         .arg(esp_mountpoint)
         .args(generation_links)
         .output()?;
@@ -189,6 +255,471 @@ pub fn lanzaboote_install(
     Ok(output)
 }
 
+/// Call the `lanzaboote gc` command.
+pub fn lanzaboote_gc(
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("-vv")
+        .arg("gc")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote make-recovery-image` command.
+pub fn lanzaboote_make_recovery_image(
+    image: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
+    // the comment in setup_toplevel for details.
+    let architecture = Architecture::from_nixos_system(SYSTEM)?;
+    let test_systemd = systemd_location_from_env()?;
+    let systemd_stub_filename = systemd_stub_filename(&architecture);
+    let test_systemd_stub = format!(
+        "{test_systemd}/lib/systemd/boot/efi/{systemd_stub_filename}",
+        systemd_stub_filename = systemd_stub_filename.display()
+    );
+
+    let test_loader_config_path = tempfile::NamedTempFile::new()?;
+    fs::write(test_loader_config_path.path(), "timeout 0\n")?;
+
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .arg("-vv")
+        .arg("make-recovery-image")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--systemd")
+        .arg(test_systemd)
+        .arg("--systemd-boot-loader-config")
+        .arg(test_loader_config_path.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--out")
+        .arg(image)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote bundle` command.
+pub fn lanzaboote_bundle(
+    bundle: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
+    // the comment in setup_toplevel for details.
+    let architecture = Architecture::from_nixos_system(SYSTEM)?;
+    let test_systemd = systemd_location_from_env()?;
+    let systemd_stub_filename = systemd_stub_filename(&architecture);
+    let test_systemd_stub = format!(
+        "{test_systemd}/lib/systemd/boot/efi/{systemd_stub_filename}",
+        systemd_stub_filename = systemd_stub_filename.display()
+    );
+
+    let test_loader_config_path = tempfile::NamedTempFile::new()?;
+    fs::write(test_loader_config_path.path(), "timeout 0\n")?;
+
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .arg("-vv")
+        .arg("bundle")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--systemd")
+        .arg(test_systemd)
+        .arg("--systemd-boot-loader-config")
+        .arg(test_loader_config_path.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--configuration-limit")
+        .arg("0")
+        .arg("--out")
+        .arg(bundle)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote sign-bundle` command.
+pub fn lanzaboote_sign_bundle(bundle: &Path, out: &Path) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("-vv")
+        .arg("sign-bundle")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--out")
+        .arg(out)
+        .arg(bundle)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote apply-bundle` command.
+pub fn lanzaboote_apply_bundle(bundle: &Path, esp_mountpoint: &Path) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("-vv")
+        .arg("apply-bundle")
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .arg(bundle)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote will-regenerate` command.
+pub fn lanzaboote_will_regenerate(
+    esp_mountpoint: &Path,
+    public_key: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_will_regenerate_with_args(
+        esp_mountpoint,
+        public_key,
+        generation_links,
+        Vec::<&OsStr>::new(),
+    )
+}
+
+/// Call the `lanzaboote will-regenerate` command with additional arguments.
+pub fn lanzaboote_will_regenerate_with_args(
+    esp_mountpoint: &Path,
+    public_key: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("will-regenerate")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--public-key")
+        .arg(public_key)
+        .args(extra_args)
+        .arg(esp_mountpoint)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote print-esp-layout` command.
+pub fn lanzaboote_print_esp_layout(
+    public_key: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("print-esp-layout")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--public-key")
+        .arg(public_key)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote doctor` command.
+pub fn lanzaboote_doctor(profiles_dir: &Path) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("doctor")
+        .arg("--profiles-dir")
+        .arg(profiles_dir)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote status --json` command.
+pub fn lanzaboote_status(esp_mountpoint: &Path) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("status")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .arg("--json")
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote verify` command.
+pub fn lanzaboote_verify(esp_mountpoint: &Path) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("verify")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+pub fn lanzaboote_install_uki(
+    esp_mountpoint: &Path,
+    uki: &Path,
+    extra_args: &[&str],
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("install-uki")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .args(extra_args)
+        .arg(uki)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote export-cert` command.
+pub fn lanzaboote_export_cert(public_key: &Path, format: &str, out: &Path) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("export-cert")
+        .arg("--public-key")
+        .arg(public_key)
+        .arg("--format")
+        .arg(format)
+        .arg("--out")
+        .arg(out)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote sign-stub` command with the kernel and initrd at fixed ESP paths.
+pub fn lanzaboote_sign_stub(
+    kernel: &Path,
+    initrd: Option<&Path>,
+    out: &Path,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    cmd.env("LANZABOOTE_STUB", systemd_stub()?)
+        .arg("-vv")
+        .arg("sign-stub")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--kernel")
+        .arg(kernel)
+        .arg("--kernel-path-at-esp")
+        .arg("EFI/nixos/kernel.efi")
+        .arg("--out")
+        .arg(out);
+    if let Some(initrd) = initrd {
+        cmd.arg("--initrd")
+            .arg(initrd)
+            .arg("--initrd-path-at-esp")
+            .arg("EFI/nixos/initrd.efi");
+    }
+    let output = cmd.args(extra_args).output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote resign` command to rotate from the test key to the P-256 test key.
+pub fn lanzaboote_resign(
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("-vv")
+        .arg("resign")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--old-public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys-p256/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys-p256/db.key")
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Call the `lanzaboote install` command with a real stub instead of the systemd stub.
+pub fn lanzaboote_install_with_stub(
+    stub: &Path,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let test_loader_config_path = tempfile::NamedTempFile::new()?;
+    fs::write(test_loader_config_path.path(), "timeout 0\n")?;
+
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .env("LANZABOOTE_STUB", stub)
+        .arg("-vv")
+        .arg("install")
+        .arg("--system")
+        .arg(SYSTEM)
+        .arg("--systemd")
+        .arg(systemd_location_from_env()?)
+        .arg("--systemd-boot-loader-config")
+        .arg(test_loader_config_path.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--configuration-limit")
+        .arg("1")
+        .arg(esp_mountpoint)
+        .args(generation_links)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Boot an ESP in QEMU with the UEFI firmware from `TEST_OVMF` and return the output on the
+/// serial console.
+///
+/// The ESP is copied into a FAT image that QEMU attaches as disk, so the firmware boots the
+/// removable media path `EFI/BOOT`. QEMU is stopped as soon as `marker` appears on the serial
+/// console or after `timeout`, whichever comes first.
+#[cfg(feature = "qemu-tests")]
+pub fn boot_in_qemu(esp: &Path, marker: &str, timeout: std::time::Duration) -> Result<String> {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    let firmware = std::env::var("TEST_OVMF")
+        .context("TEST_OVMF environment variable is not set. TEST_OVMF has to point to the UEFI firmware for QEMU, e.g. OVMF.fd.")?;
+    let (qemu, machine): (_, &[&str]) = match SYSTEM {
+        "x86_64-linux" => ("qemu-system-x86_64", &["-machine", "q35"]),
+        "aarch64-linux" => ("qemu-system-aarch64", &["-machine", "virt", "-cpu", "max"]),
+        system => anyhow::bail!("Booting in QEMU is not supported on {system}."),
+    };
+
+    let image_dir = tempfile::tempdir()?;
+    let image = image_dir.path().join("esp.img");
+    lzbt_systemd::recovery::write_fat_image(esp, &image)?;
+
+    let mut child = Command::new(qemu)
+        .args(machine)
+        .args(["-m", "512", "-nographic", "-no-reboot", "-bios", &firmware])
+        .arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start {qemu}"))?;
+
+    // Read the serial console in a thread, so that waiting for the output can time out.
+    let mut stdout = child
+        .stdout
+        .take()
+        .context("Failed to capture the serial console.")?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok(n @ 1..) = stdout.read(&mut buffer) {
+            if sender.send(buffer[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let mut output = Vec::new();
+    while !String::from_utf8_lossy(&output).contains(marker) {
+        let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+            break;
+        };
+        match receiver.recv_timeout(left) {
+            Ok(chunk) => output.extend(chunk),
+            // QEMU exited or the timeout expired.
+            Err(_) => break,
+        }
+    }
+    child.kill().ok();
+    child.wait()?;
+
+    let output = String::from_utf8_lossy(&output).into_owned();
+    print!("{output}");
+    Ok(output)
+}
+
 /// Read location of systemd installation from an environment variable.
 fn systemd_location_from_env() -> Result<String> {
     let error_msg = "TEST_SYSTEMD environment variable is not set. TEST_SYSTEMD has to point to a systemd installation.
@@ -236,26 +767,94 @@ pub fn count_files(path: &Path) -> Result<usize> {
     Ok(fs::read_dir(path)?.count())
 }
 
+/// The signer the installations in the tests use.
+pub fn test_signer() -> Result<LocalKeyPair> {
+    LocalKeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    )
+}
+
+/// The path of the stub of generation `version` installed with the kernel parameters from its
+/// bootspec.
 pub fn image_path(esp: &TempDir, version: u64, toplevel: &Path) -> Result<PathBuf> {
+    let kernel_cmdline = format!("init=init-v{version} {}", KERNEL_PARAMS.join(" "));
+    Ok(esp
+        .path()
+        .join("EFI/Linux")
+        .join(expected_stub_name(version, toplevel, &kernel_cmdline)?))
+}
+
+/// The name of the stub of generation `version` with the kernel command line `kernel_cmdline`.
+///
+/// This spells out how stubs are named instead of asking lzbt, so that the tests notice when the
+/// names stop depending on their inputs.
+pub fn expected_stub_name(version: u64, toplevel: &Path, kernel_cmdline: &str) -> Result<String> {
+    // The os-release depends on the generation, so it is derived from a generation set up like
+    // the one that was installed.
+    let profiles = tempfile::tempdir()?;
+    let link = setup_generation_link_from_toplevel(toplevel, profiles.path(), version)?;
+    let generation = Generation::from_link(&GenerationLink::from_path(&link)?)?;
+    let os_release = OsRelease::from_generation(&generation)?.to_string();
+    let public_key = test_signer()?.get_public_key()?;
+
     let stub_inputs = [
-        // Generation numbers can be reused if the latest generation was deleted.
-        // To detect this, the stub path depends on the actual toplevel used.
         ("toplevel", toplevel.as_os_str().as_bytes()),
-        // If the key is rotated, the signed stubs must be re-generated.
-        // So we make their path depend on the public key used for signature.
-        (
-            "public_key",
-            &std::fs::read("tests/fixtures/uefi-keys/db.pem")?,
-        ),
+        ("kernel_cmdline", kernel_cmdline.as_bytes()),
+        ("os_release", os_release.as_bytes()),
+        ("public_key", public_key.as_slice()),
     ];
     let stub_input_hash = Base32Unpadded::encode_string(&Sha256::digest(
         serde_json::to_string(&stub_inputs).unwrap(),
     ));
-    Ok(esp.path().join(format!(
-        "EFI/Linux/nixos-generation-{version}-{stub_input_hash}.efi"
-    )))
+    Ok(format!("nixos-generation-{version}-{stub_input_hash}.efi"))
+}
+
+/// Find the only stub of the base generation `version` on the ESP, whatever its hash.
+pub fn installed_stub(esp: &Path, version: u64) -> Result<PathBuf> {
+    let prefix = format!("nixos-generation-{version}-");
+    let mut stubs = fs::read_dir(esp.join("EFI/Linux"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    stubs.retain(|stub| {
+        stub.file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| {
+                name.starts_with(&prefix)
+                    && name.ends_with(".efi")
+                    && !name.contains("-specialisation-")
+            })
+    });
+    match stubs.as_slice() {
+        [stub] => Ok(stub.clone()),
+        _ => anyhow::bail!("Expected one stub of generation {version}, found {stubs:?}"),
+    }
+}
+
+/// The systemd stub of the test systemd, a PE binary that can be signed.
+pub fn systemd_stub() -> Result<PathBuf> {
+    let architecture = Architecture::from_nixos_system(SYSTEM)?;
+    Ok(PathBuf::from(systemd_location_from_env()?)
+        .join("lib/systemd/boot/efi")
+        .join(systemd_stub_filename(&architecture)))
 }
 
 fn systemd_stub_filename(architecture: &Architecture) -> PathBuf {
     format!("linux{}.efi.stub", architecture.efi_representation()).into()
 }
+
+/// Read the data from a section of a PE binary.
+pub fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+
+    pe_binary
+        .sections
+        .iter()
+        .find(|s| s.name().unwrap() == section_name)
+        .and_then(|s| {
+            let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
+            assert!(s.virtual_size <= s.size_of_raw_data);
+            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
+            Some(&file_data[section_start..section_end])
+        })
+}
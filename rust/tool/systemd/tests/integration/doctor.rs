@@ -0,0 +1,40 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn list_broken_generations() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let output0 = common::lanzaboote_doctor(profiles.path())?;
+    assert!(output0.status.success());
+    assert!(output0.stdout.is_empty());
+
+    common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    fs::write(generation_link2.join("boot.json"), "{ not json")?;
+
+    // Only the broken generation is listed, followed by the command that deletes it.
+    let output1 = common::lanzaboote_doctor(profiles.path())?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&format!("{}: ", generation_link2.display())));
+    assert!(lines[0].contains("Failed to read bootspec JSON"));
+    assert_eq!(
+        lines[1],
+        format!(
+            "nix-env --profile {} --delete-generations 2",
+            profiles.path().join("system").display()
+        )
+    );
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn export_der_and_signature_list() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let der = tmpdir.path().join("db.der");
+    let esl = tmpdir.path().join("db.esl");
+
+    let output =
+        common::lanzaboote_export_cert(Path::new("tests/fixtures/uefi-keys/db.pem"), "der", &der)?;
+    assert!(output.status.success());
+    let der_contents = fs::read(&der)?;
+    // A DER-encoded certificate is an ASN.1 SEQUENCE.
+    assert_eq!(der_contents[0], 0x30);
+
+    // Exporting the DER-encoded certificate again does not change it.
+    let output = common::lanzaboote_export_cert(&der, "esl", &esl)?;
+    assert!(output.status.success());
+    let esl_contents = fs::read(&esl)?;
+    assert_eq!(
+        esl_contents[16..20],
+        u32::try_from(esl_contents.len())?.to_le_bytes()
+    );
+    // The signature list ends with the only entry: the owner GUID and the certificate.
+    assert!(esl_contents.ends_with(&der_contents));
+    assert_eq!(esl_contents.len(), 16 + 3 * 4 + 16 + der_contents.len());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_to_export_a_private_key() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let out = tmpdir.path().join("db.der");
+
+    let output =
+        common::lanzaboote_export_cert(Path::new("tests/fixtures/uefi-keys/db.key"), "der", &out)?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("private key"));
+    assert!(!out.exists());
+
+    Ok(())
+}
@@ -1,10 +1,10 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use tempfile::tempdir;
+use anyhow::{Context, Result};
+use tempfile::{tempdir, TempDir};
 
-use crate::common::{self, count_files};
+use crate::common::{self, count_files, pe_section, setup_generation_link_from_toplevel};
 
 #[test]
 fn keep_only_configured_number_of_generations() -> Result<()> {
@@ -122,3 +122,125 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn standalone_gc_keeps_only_passed_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let stub_count = || count_files(&esp_mountpoint.path().join("EFI/Linux")).unwrap();
+    let kernel_and_initrd_count = || count_files(&esp_mountpoint.path().join("EFI/nixos")).unwrap();
+
+    // Install all 3 generations.
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // Create a garbage kernel, which should be deleted.
+    fs::write(
+        esp_mountpoint.path().join("EFI/nixos/kernel-garbage.efi"),
+        "garbage",
+    )?;
+
+    // Only keep the latest generation without installing anything.
+    let output1 = common::lanzaboote_gc(esp_mountpoint.path(), &generation_links[2..])?;
+    assert!(output1.status.success());
+    assert_eq!(stub_count(), 1, "Wrong number of stubs after gc.");
+    assert_eq!(
+        kernel_and_initrd_count(),
+        2,
+        "Wrong number of kernels & initrds after gc."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn standalone_gc_refuses_without_installed_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let stub_count = || count_files(&esp_mountpoint.path().join("EFI/Linux")).unwrap();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), [&generation_link])?;
+    assert!(output0.status.success());
+
+    // Generation 2 is not installed, so collecting garbage would remove every boot entry.
+    let other_generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+    let output1 = common::lanzaboote_gc(esp_mountpoint.path(), [&other_generation_link])?;
+    assert!(!output1.status.success());
+    assert_eq!(stub_count(), 1, "Stubs were removed by a failed gc.");
+
+    Ok(())
+}
+
+#[test]
+fn keep_device_trees_of_installed_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let profiles = tempdir()?;
+    let tmpdirs = [tempdir()?, tempdir()?];
+    let mut toplevels = Vec::new();
+    let mut generation_links = Vec::new();
+    for (version, tmpdir) in (1..).zip(&tmpdirs) {
+        let toplevel = common::setup_toplevel(tmpdir.path())?;
+        fs::create_dir_all(toplevel.join("dtbs/vendor"))?;
+        fs::write(
+            toplevel.join("dtbs/vendor/board.dtb"),
+            format!("device tree {version}"),
+        )?;
+        generation_links.push(setup_generation_link_from_toplevel(
+            &toplevel,
+            profiles.path(),
+            version,
+        )?);
+        toplevels.push(toplevel);
+    }
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        &generation_links,
+        ["--install-dtb-dir"],
+    )?;
+    assert!(output0.status.success());
+    let old_dtb = installed_dtb(&esp, 1, &toplevels[0])?;
+    assert_eq!(fs::read(&old_dtb)?, b"device tree 1");
+
+    // Installing again collects garbage, which must not touch the device trees of generation 1.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        &generation_links,
+        ["--install-dtb-dir"],
+    )?;
+    assert!(output1.status.success());
+    assert_eq!(fs::read(&old_dtb)?, b"device tree 1");
+
+    let output2 = common::lanzaboote_gc(esp.path(), &generation_links)?;
+    assert!(output2.status.success());
+    assert_eq!(fs::read(&old_dtb)?, b"device tree 1");
+    assert_eq!(
+        fs::read(installed_dtb(&esp, 2, &toplevels[1])?)?,
+        b"device tree 2"
+    );
+
+    Ok(())
+}
+
+/// The installed copy of `vendor/board.dtb` referenced by the stub of a generation.
+fn installed_dtb(esp: &TempDir, version: u64, toplevel: &Path) -> Result<PathBuf> {
+    let stub_data = fs::read(common::image_path(esp, version, toplevel)?)?;
+    let dtb_dir = pe_section(&stub_data, ".dtbdir").context("Missing device tree directory.")?;
+    let dtb_dir = std::str::from_utf8(dtb_dir)?;
+    Ok(esp
+        .path()
+        .join(dtb_dir.trim_start_matches('\\').replace('\\', "/"))
+        .join("vendor/board.dtb"))
+}
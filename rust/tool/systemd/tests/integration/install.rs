@@ -1,11 +1,25 @@
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
 use base32ct::{Base32Unpadded, Encoding};
+use lanzaboote_tool::pe;
+use nix::fcntl::{Flock, FlockArg};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use tempfile::tempdir;
+use walkdir::WalkDir;
 
 use crate::common::{
-    self, count_files, hash_file, remove_signature, setup_generation_link_from_toplevel,
-    verify_signature,
+    self, count_files, hash_file, pe_section, remove_signature,
+    setup_generation_link_from_toplevel, setup_generation_link_with_extension, verify_signature,
 };
+use lzbt_systemd::esp::SYSTEMD_BOOT_VERSIONS;
 
 /// Install two generations that point at the same toplevel.
 /// This should install two lanzaboote images and one kernel and one initrd.
@@ -121,3 +135,1182 @@ fn content_addressing_works() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn install_toplevel_without_generation_link() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel1 = common::setup_toplevel(tmpdir.path())?;
+    let toplevel2 = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel1, profiles.path(), 3)?;
+    // A toplevel carries its own bootspec. Borrow the one of a generation link.
+    let bootspec_link = setup_generation_link_from_toplevel(&toplevel2, profiles.path(), 4)?;
+    fs::copy(bootspec_link.join("boot.json"), toplevel2.join("boot.json"))?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        ["--toplevel".as_ref(), toplevel2.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    let mut versions = Vec::new();
+    for entry in fs::read_dir(esp.path().join("EFI/Linux"))? {
+        let stub = fs::read(entry?.path())?;
+        let os_release = pe_section(&stub, ".osrel").context("Missing os-release.")?;
+        versions.push(String::from_utf8(os_release.to_vec())?);
+    }
+    versions.sort();
+    // The toplevel is installed as the generation after the newest generation link.
+    assert_eq!(versions.len(), 2);
+    assert!(versions[0].contains("VERSION_ID=Generation 3,"));
+    assert!(versions[1].contains("VERSION_ID=Generation 4,"));
+
+    Ok(())
+}
+
+#[test]
+fn force_reinstall_repairs_corrupted_files() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_links = vec![generation_link];
+
+    let kernel_hash_source =
+        hash_file(&toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"));
+    let kernel_path = esp.path().join(format!(
+        "EFI/nixos/kernel-6.1.1-{}.efi",
+        Base32Unpadded::encode_string(&kernel_hash_source)
+    ));
+
+    let output0 = common::lanzaboote_install(1, esp.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // The content-addressed kernel is not hashed again, so a plain reinstallation keeps it.
+    fs::write(&kernel_path, b"corrupted")?;
+    let output1 = common::lanzaboote_install(1, esp.path(), generation_links.clone())?;
+    assert!(output1.status.success());
+    assert_ne!(kernel_hash_source, hash_file(&kernel_path));
+
+    let output2 =
+        common::lanzaboote_install_with_args(1, esp.path(), generation_links, ["--force"])?;
+    assert!(output2.status.success());
+    assert_eq!(kernel_hash_source, hash_file(&kernel_path));
+    assert!(String::from_utf8(output2.stderr)?.contains("Forcibly reinstalling"));
+
+    Ok(())
+}
+
+#[test]
+fn resume_interrupted_installation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel1 = common::setup_toplevel(tmpdir.path())?;
+    let toplevel2 = common::setup_toplevel(tmpdir.path())?;
+
+    let image1 = common::image_path(&esp, 1, &toplevel1)?;
+    let image2 = common::image_path(&esp, 2, &toplevel2)?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel1, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel2, profiles.path(), 2)?;
+    let generation_links = vec![generation_link1, generation_link2];
+
+    // Make the installation of the second generation fail halfway through.
+    let kernel2 = toplevel2.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+    fs::remove_file(&kernel2)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), generation_links.clone())?;
+    assert!(!output0.status.success());
+    assert!(image1.exists());
+    assert!(!image2.exists());
+    let image1_mtime = common::mtime(&image1);
+
+    // Repair the second generation and resume the installation.
+    fs::copy(
+        toplevel1.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"),
+        &kernel2,
+    )?;
+
+    let output1 = common::lanzaboote_install(0, esp.path(), generation_links)?;
+    assert!(output1.status.success());
+    assert!(image2.exists());
+    // The already installed generation is not touched again.
+    assert_eq!(image1_mtime, common::mtime(&image1));
+
+    Ok(())
+}
+
+#[test]
+fn concatenate_multiple_initrds() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let early_initrd = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/early-initrd");
+    let initrd = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd");
+    fs::write(&early_initrd, b"microcode")?;
+
+    let generation_link = setup_generation_link_with_extension(
+        &toplevel,
+        profiles.path(),
+        1,
+        json!({
+            "sort_key": "lanzaboote",
+            "initrds": [early_initrd, initrd],
+        }),
+    )?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(common::image_path(&esp, 1, &toplevel)?)?;
+    let initrd_path = pe_section(&stub_data, ".initrd").context("Missing initrd path.")?;
+    let installed_initrd = esp
+        .path()
+        .join(std::str::from_utf8(&initrd_path[1..])?.replace('\\', "/"));
+
+    // The early initrd is padded to a 4-byte boundary before the main initrd is appended.
+    let mut expected_initrd = b"microcode\0\0\0".to_vec();
+    expected_initrd.extend(fs::read(&initrd)?);
+    let installed_initrd_contents = fs::read(installed_initrd)?;
+    assert!(installed_initrd_contents.starts_with(&expected_initrd));
+
+    // The stub accepts the installed initrd because its hash matches the embedded one.
+    let initrd_hash = pe_section(&stub_data, ".initrdh").context("Missing initrd hash.")?;
+    assert_eq!(
+        initrd_hash,
+        Sha256::digest(&installed_initrd_contents).as_slice()
+    );
+    assert_eq!(pe_section(&stub_data, ".hashalg"), Some(&b"sha256"[..]));
+
+    Ok(())
+}
+
+#[test]
+fn prepend_microcode_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let microcode = tmpdir.path().join("microcode.cpio");
+    fs::write(&microcode, b"microcode")?;
+    let initrd = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        [OsStr::new("--initrd-prepend"), microcode.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    let installed_initrd = || -> Result<Vec<u8>> {
+        let stub_data = fs::read(common::image_path(&esp, 1, &toplevel)?)?;
+        let initrd_path = pe_section(&stub_data, ".initrd").context("Missing initrd path.")?;
+        let contents = fs::read(
+            esp.path()
+                .join(std::str::from_utf8(&initrd_path[1..])?.replace('\\', "/")),
+        )?;
+        let initrd_hash = pe_section(&stub_data, ".initrdh").context("Missing initrd hash.")?;
+        assert_eq!(initrd_hash, Sha256::digest(&contents).as_slice());
+        Ok(contents)
+    };
+
+    // The prepended initrd comes first, padded to a 4-byte boundary, then the main initrd.
+    let mut expected_initrd = b"microcode\0\0\0".to_vec();
+    expected_initrd.extend(fs::read(&initrd)?);
+    assert!(installed_initrd()?.starts_with(&expected_initrd));
+
+    // Without --initrd-prepend, the stale initrd is replaced by the main initrd alone.
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+    assert_eq!(installed_initrd()?, fs::read(&initrd)?);
+
+    // A changed prepended initrd replaces the installed initrd as well.
+    fs::write(&microcode, b"new microcode")?;
+    let output2 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        [OsStr::new("--initrd-prepend"), microcode.as_os_str()],
+    )?;
+    assert!(output2.status.success());
+    assert!(installed_initrd()?.starts_with(b"new microcode\0\0\0"));
+
+    Ok(())
+}
+
+#[test]
+fn install_generation_without_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]["initrd"] = serde_json::Value::Null;
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+
+    // Only the kernel is installed and the stub does not reference an initrd.
+    assert_eq!(count_files(&esp.path().join("EFI/nixos"))?, 1);
+    let stub = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .next()
+        .context("Missing stub.")??
+        .path();
+    let stub_data = fs::read(&stub)?;
+    assert!(pe_section(&stub_data, ".linux").is_some());
+    assert_eq!(pe_section(&stub_data, ".initrd"), None);
+    assert_eq!(pe_section(&stub_data, ".initrdh"), None);
+
+    // The installed generation is recognized as up to date.
+    let mtime = common::mtime(&stub);
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+    assert_eq!(common::mtime(&stub), mtime);
+
+    Ok(())
+}
+
+#[test]
+fn embed_rollback_counter() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let image = common::image_path(&esp, 2, &toplevel)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--rollback-counter-base", "1000"],
+    )?;
+    assert!(output0.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".rollback"), Some(&b"1002"[..]));
+
+    // Changing the base replaces the already installed stub.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--rollback-counter-base", "2000"],
+    )?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".rollback"), Some(&b"2002"[..]));
+
+    Ok(())
+}
+
+#[test]
+fn embed_cmdline_flags() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".cmdflags"), None);
+
+    // Forbidding command line editing replaces the already installed stub.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--forbid-cmdline-editing"],
+    )?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".cmdflags"), Some(&b"1"[..]));
+
+    Ok(())
+}
+
+/// An installation fails while another installation holds the lock on the ESP.
+#[test]
+fn refuse_concurrent_installation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let lock = Flock::lock(fs::File::open(esp.path())?, FlockArg::LockExclusiveNonblock)
+        .map_err(|(_, e)| e)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--lock-timeout", "0"],
+    )?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("locked by another installation"));
+    assert_eq!(count_files(esp.path())?, 0);
+
+    drop(lock);
+
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
+/// The stub of a generation falls back to the kernel and initrd of the previous generation.
+#[test]
+fn embed_fallback_of_previous_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel1 = common::setup_toplevel(tmpdir.path())?;
+    let toplevel2 = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel1, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel2, profiles.path(), 2)?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(output0.status.success());
+
+    let stub1 = fs::read(common::image_path(&esp, 1, &toplevel1)?)?;
+    let stub2 = fs::read(common::image_path(&esp, 2, &toplevel2)?)?;
+    assert_eq!(pe_section(&stub1, ".linux2"), None);
+    for (fallback, primary) in [
+        (".linux2", ".linux"),
+        (".linuxh2", ".linuxh"),
+        (".initrd2", ".initrd"),
+        (".initrh2", ".initrdh"),
+    ] {
+        assert_eq!(
+            pe_section(&stub2, fallback),
+            pe_section(&stub1, primary),
+            "{fallback} does not match {primary} of the previous generation"
+        );
+    }
+
+    // Once the previous generation is gone, the fallback is removed.
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link2])?;
+    assert!(output1.status.success());
+    let stub2 = fs::read(common::image_path(&esp, 2, &toplevel2)?)?;
+    assert_eq!(pe_section(&stub2, ".linux2"), None);
+
+    Ok(())
+}
+
+#[test]
+fn embed_stub_log_level() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--stub-log-level", "info"],
+    )?;
+    assert!(output0.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".loglevel"), Some(&b"info"[..]));
+
+    // Dropping the log level replaces the already installed stub.
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".loglevel"), None);
+
+    Ok(())
+}
+
+#[test]
+fn replace_sbat_metadata() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    // Without custom metadata, the stub keeps the metadata it was built with.
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+    let stub_data = fs::read(&image)?;
+    let builtin_sbat = pe_section(&stub_data, ".sbat").expect("Missing .sbat section");
+    assert!(builtin_sbat.starts_with(b"sbat,1,"));
+
+    let sbat = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+                lanzaboote.example,2,Example,lanzaboote,1,https://example.com\n";
+    let sbat_path = tmpdir.path().join("sbat.csv");
+    fs::write(&sbat_path, sbat)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--sbat", sbat_path.to_str().unwrap()],
+    )?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".sbat"), Some(sbat.as_bytes()));
+
+    Ok(())
+}
+
+/// Fat stubs contain the kernel and initrd, so nothing is installed to `EFI/nixos`.
+#[test]
+fn install_fat_stubs() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let kernel = fs::read(toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"))?;
+
+    let kernel_and_initrd_count = || count_files(&esp.path().join("EFI/nixos")).unwrap();
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), vec![&generation_link], ["--fat"])?;
+    assert!(output0.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".linux"), Some(kernel.as_slice()));
+    assert_eq!(pe_section(&stub_data, ".linuxh"), None);
+    assert_eq!(pe_section(&stub_data, ".hashalg"), None);
+    assert_eq!(kernel_and_initrd_count(), 0);
+
+    // Switching back to thin stubs replaces the fat stub.
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert!(pe_section(&stub_data, ".linuxh").is_some());
+    assert_eq!(kernel_and_initrd_count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn install_compressed_fat_stubs() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let kernel = fs::read(toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"))?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--fat", "--compress-sections"],
+    )?;
+    assert!(output0.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".compressed"), Some(&b"zstd"[..]));
+    assert_ne!(pe_section(&stub_data, ".linux"), Some(kernel.as_slice()));
+    assert_eq!(pe::read_embedded_file(&stub_data, ".linux")?, Some(kernel));
+
+    // Without compression, the stub is rebuilt with the plain kernel and initrd.
+    let output1 =
+        common::lanzaboote_install_with_args(0, esp.path(), vec![&generation_link], ["--fat"])?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".compressed"), None);
+
+    Ok(())
+}
+
+#[test]
+fn embed_specialisation_name() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    // Add a specialisation that boots the same system as the base generation.
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    let specialisation = bootspec.clone();
+    bootspec["org.nixos.specialisation.v1"] = json!({ "debug": specialisation });
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(&image)?;
+    assert_eq!(pe_section(&stub_data, ".special"), None);
+
+    let specialisation_image = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("-specialisation-debug-"))
+        .expect("Specialisation stub was not installed");
+    let stub_data = fs::read(specialisation_image)?;
+    assert_eq!(pe_section(&stub_data, ".special"), Some(&b"debug"[..]));
+
+    Ok(())
+}
+
+#[test]
+fn warn_about_malformed_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    fs::write(generation_link2.join("boot.json"), "{ not json")?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(output0.status.success());
+
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Ignoring generation 2"));
+    assert!(stderr.contains("Failed to read bootspec JSON"));
+    assert!(stderr.contains("Garbage collection is disabled"));
+
+    Ok(())
+}
+
+/// Point the bootspec of a generation at an initrd secrets script that fails.
+fn break_initrd_secrets(generation_link: &Path, tmpdir: &Path) -> Result<()> {
+    let script = tmpdir.join("append-initrd-secrets");
+    fs::write(&script, "#!/bin/sh\nexit 1\n")?;
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]["initrdSecrets"] = json!(script);
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    Ok(())
+}
+
+#[test]
+fn skip_old_generation_with_failing_initrd_secrets() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    break_initrd_secrets(&generation_link1, tmpdir.path())?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(output0.status.success());
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+    assert!(common::image_path(&esp, 2, &toplevel)?.exists());
+
+    // The secrets of the latest generation are still required. Install to a fresh ESP, so that
+    // the latest generation is not already installed.
+    let esp = tempdir()?;
+    break_initrd_secrets(&generation_link2, tmpdir.path())?;
+    let output1 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(!output1.status.success());
+
+    Ok(())
+}
+
+/// The secrets of the newest generation that can be read are required, even if there is a newer,
+/// broken generation.
+#[test]
+fn require_secrets_of_latest_readable_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    break_initrd_secrets(&generation_link1, tmpdir.path())?;
+    fs::write(generation_link2.join("boot.json"), "")?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(!output0.status.success());
+
+    Ok(())
+}
+
+/// A skipped generation that was installed before keeps its files, so that it stays bootable.
+#[test]
+fn keep_installed_files_of_skipped_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(output0.status.success());
+    let stub1 = common::image_path(&esp, 1, &toplevel)?;
+    assert!(stub1.exists());
+
+    // Force the reinstallation, so that the now failing secrets skip the generation.
+    break_initrd_secrets(&generation_link1, tmpdir.path())?;
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link1, &generation_link2],
+        ["--force"],
+    )?;
+    assert!(output1.status.success());
+    assert!(
+        stub1.exists(),
+        "The stub of the skipped generation was deleted."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn skip_specialisations_of_skipped_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    // Only the secrets of the base generation fail, not those of its specialisation.
+    let bootspec_path = generation_link1.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    let specialisation = bootspec.clone();
+    bootspec["org.nixos.specialisation.v1"] = json!({ "debug": specialisation });
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    break_initrd_secrets(&generation_link1, tmpdir.path())?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![&generation_link1, &generation_link2])?;
+    assert!(output0.status.success());
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+    assert!(common::image_path(&esp, 2, &toplevel)?.exists());
+
+    let specialisation_images = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.to_string_lossy().contains("-specialisation-"))
+        .collect::<Vec<_>>();
+    assert!(
+        specialisation_images.is_empty(),
+        "Specialisations of the skipped generation were installed: {specialisation_images:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_device_trees() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let dtbs = toplevel.join("dtbs");
+    fs::create_dir_all(dtbs.join("vendor"))?;
+    fs::write(dtbs.join("vendor/board.dtb"), b"device tree")?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--install-dtb-dir"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(&image)?;
+    let dtb_dir = pe_section(&stub_data, ".dtbdir").context("Missing device tree directory.")?;
+    let dtb_dir = std::str::from_utf8(dtb_dir)?;
+    assert!(dtb_dir.starts_with("\\EFI\\nixos\\dtbs\\"));
+    let installed_dtb = esp
+        .path()
+        .join(dtb_dir[1..].replace('\\', "/"))
+        .join("vendor/board.dtb");
+    assert_eq!(fs::read(&installed_dtb)?, b"device tree");
+
+    // Changed device trees are installed to a new directory and the old one is collected.
+    fs::write(dtbs.join("vendor/board.dtb"), b"new device tree")?;
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--install-dtb-dir"],
+    )?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_ne!(pe_section(&stub_data, ".dtbdir"), Some(dtb_dir.as_bytes()));
+    assert!(!installed_dtb.exists());
+
+    Ok(())
+}
+
+/// Installations with different ESP subdirectories share the ESP without collecting each other's
+/// files.
+#[test]
+fn namespace_installation_with_esp_subdir() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--esp-subdir", "nixos-hostA"],
+    )?;
+    assert!(output1.status.success());
+
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let namespaced_image = esp.path().join("EFI/Linux").join(
+        image
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("Invalid stub name.")?
+            .replacen("nixos-", "nixos-hostA-", 1),
+    );
+    assert!(image.exists());
+    assert!(namespaced_image.exists());
+    assert_eq!(
+        count_files(&esp.path().join("EFI/nixos"))?,
+        count_files(&esp.path().join("EFI/nixos-hostA"))?
+    );
+
+    // Reinstalling the default installation with another generation keeps the files of the
+    // namespaced one.
+    let toplevel2 = common::setup_toplevel(tmpdir.path())?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel2, profiles.path(), 2)?;
+    let output2 = common::lanzaboote_install(1, esp.path(), vec![&generation_link2])?;
+    assert!(output2.status.success());
+    assert!(!image.exists());
+    assert!(namespaced_image.exists());
+    assert!(count_files(&esp.path().join("EFI/nixos-hostA"))? > 0);
+
+    Ok(())
+}
+
+/// Only the stub of the latest generation gets a boot counter, and the counter that systemd-boot
+/// maintains is kept on reinstallation.
+#[test]
+fn add_boot_counter_to_latest_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let generation_links = vec![&generation_link1, &generation_link2];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links.clone(),
+        ["--boot-counting", "3"],
+    )?;
+    assert!(output0.status.success());
+
+    let image1 = common::image_path(&esp, 1, &toplevel)?;
+    let image2 = common::image_path(&esp, 2, &toplevel)?;
+    let stub_name2 = image2
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Invalid stub name.")?;
+    let counted_image2 = image2.with_file_name(stub_name2.replace(".efi", "+3.efi"));
+    let attempted_image2 = image2.with_file_name(stub_name2.replace(".efi", "+2-1.efi"));
+    assert!(image1.exists());
+    assert!(!image2.exists());
+    assert!(counted_image2.exists());
+
+    // Simulate a failed boot attempt.
+    fs::rename(&counted_image2, &attempted_image2)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links,
+        ["--boot-counting", "3"],
+    )?;
+    assert!(output1.status.success());
+    assert!(attempted_image2.exists());
+    assert!(!counted_image2.exists());
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 2);
+
+    Ok(())
+}
+
+#[test]
+fn compress_installed_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--compression", "zstd"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(&image)?;
+    let initrd_path = pe_section(&stub_data, ".initrd").context("Missing initrd path.")?;
+    let installed_initrd = fs::read(
+        esp.path()
+            .join(std::str::from_utf8(&initrd_path[1..])?.replace('\\', "/")),
+    )?;
+    assert!(installed_initrd.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+
+    // The embedded hash covers the compressed initrd that the stub reads.
+    let initrd_hash = pe_section(&stub_data, ".initrdh").context("Missing initrd hash.")?;
+    assert_eq!(initrd_hash, Sha256::digest(&installed_initrd).as_slice());
+
+    // Changing the compression replaces the already installed stub.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--compression", "gzip"],
+    )?;
+    assert!(output1.status.success());
+    let stub_data = fs::read(&image)?;
+    assert_ne!(
+        pe_section(&stub_data, ".initrdh").context("Missing initrd hash.")?,
+        initrd_hash
+    );
+
+    Ok(())
+}
+
+/// The temporary directory of the ESP is not on a FAT file system.
+#[test]
+fn check_esp_file_system() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), vec![&generation_link], ["--strict"])?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("is not a FAT (vfat/msdos) file system"));
+    assert_eq!(count_files(esp.path())?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn reject_kernel_without_efi_stub() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    fs::write(
+        toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"),
+        b"not a kernel",
+    )?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("The kernel is not a PE binary"));
+
+    Ok(())
+}
+
+#[test]
+fn keep_going_after_failing_old_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let broken_toplevel = common::setup_toplevel(tmpdir.path())?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    fs::write(
+        broken_toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"),
+        b"not a kernel",
+    )?;
+
+    let generation_link1 =
+        setup_generation_link_from_toplevel(&broken_toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let generation_links = vec![&generation_link1, &generation_link2];
+
+    let output0 = common::lanzaboote_install(0, esp.path(), generation_links.clone())?;
+    assert!(!output0.status.success());
+
+    let output1 =
+        common::lanzaboote_install_with_args(0, esp.path(), generation_links, ["--keep-going"])?;
+    assert!(output1.status.success());
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(stderr.contains("Skipping generation 1"));
+    assert!(!common::image_path(&esp, 1, &broken_toplevel)?.exists());
+    assert!(common::image_path(&esp, 2, &toplevel)?.exists());
+
+    // The latest generation is still required.
+    let generation_link3 =
+        setup_generation_link_from_toplevel(&broken_toplevel, profiles.path(), 3)?;
+    let output2 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link2, &generation_link3],
+        ["--keep-going"],
+    )?;
+    assert!(!output2.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn change_kernel_params() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let generation_links = vec![&generation_link1, &generation_link2];
+    // Reads the command line embedded in the stub and checks that the stub is named after it.
+    let cmdline = |version| -> Result<String> {
+        let stub = common::installed_stub(esp.path(), version)?;
+        let stub_data = fs::read(&stub)?;
+        let cmdline = pe_section(&stub_data, ".cmdline").context("Missing .cmdline section")?;
+        let cmdline = String::from_utf8(cmdline.to_vec())?;
+        assert_eq!(
+            stub.file_name().and_then(OsStr::to_str),
+            Some(common::expected_stub_name(version, &toplevel, &cmdline)?.as_str())
+        );
+        Ok(cmdline)
+    };
+    let original_cmdline = |version| {
+        format!("init=init-v{version} amd_iommu=on amd_iommu=pt iommu=pt kvm.ignore_msrs=1 kvm.report_ignored_msrs=0 udev.log_priority=3 systemd.unified_cgroup_hierarchy=1 loglevel=4")
+    };
+    let changed_cmdline = |version| {
+        format!("init=init-v{version} iommu=pt kvm.ignore_msrs=1 kvm.report_ignored_msrs=0 udev.log_priority=3 systemd.unified_cgroup_hierarchy=1 console=ttyS0")
+    };
+    let args = [
+        "--remove-kernel-param",
+        "amd_iommu",
+        "--remove-kernel-param",
+        "loglevel=4",
+        "--append-kernel-param",
+        "console=ttyS0",
+    ];
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), generation_links.clone(), args)?;
+    assert!(output0.status.success());
+    assert_eq!(cmdline(1)?, changed_cmdline(1));
+    assert_eq!(cmdline(2)?, changed_cmdline(2));
+    // The stub names depend on the changed command line.
+    assert!(!common::image_path(&esp, 2, &toplevel)?.exists());
+
+    // The already installed stubs with a different command line are replaced.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links.clone(),
+        args.into_iter().chain(["--kernel-params-latest-only"]),
+    )?;
+    assert!(output1.status.success());
+    assert_eq!(cmdline(1)?, original_cmdline(1));
+    assert_eq!(cmdline(2)?, changed_cmdline(2));
+
+    let output2 = common::lanzaboote_install(0, esp.path(), generation_links)?;
+    assert!(output2.status.success());
+    assert_eq!(cmdline(2)?, original_cmdline(2));
+
+    Ok(())
+}
+
+#[test]
+fn discover_generation_links_in_profiles_dir() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    // Explicit links are installed as well.
+    let other_profiles = tempdir()?;
+    let generation_link3 =
+        setup_generation_link_from_toplevel(&toplevel, other_profiles.path(), 3)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link3],
+        [OsStr::new("--profiles-dir"), profiles.path().as_os_str()],
+    )?;
+    assert!(output0.status.success());
+    for version in 1..=3 {
+        assert!(common::image_path(&esp, version, &toplevel)?.exists());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reinstall_only_some_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let generation_links = vec![&generation_link1, &generation_link2];
+    let image1 = common::image_path(&esp, 1, &toplevel)?;
+    let image2 = common::image_path(&esp, 2, &toplevel)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    fs::remove_file(&image1)?;
+    let image2_data = fs::read(&image2)?;
+    // Without garbage collection, files unknown to the installation are kept.
+    let unknown_file = esp.path().join("EFI/nixos/unknown.efi");
+    fs::write(&unknown_file, b"")?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links.clone(),
+        ["--only-generation", "1"],
+    )?;
+    assert!(output1.status.success());
+    assert!(image1.exists());
+    assert_eq!(fs::read(&image2)?, image2_data);
+    assert!(unknown_file.exists());
+
+    let output2 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links,
+        ["--only-generation", "3"],
+    )?;
+    assert!(!output2.status.success());
+    let stderr = String::from_utf8(output2.stderr)?;
+    assert!(stderr.contains("Generation 3 is not among the generations to install"));
+
+    Ok(())
+}
+
+#[test]
+fn reject_invalid_stub() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let stub = tmpdir.path().join("stub.efi");
+    fs::write(&stub, b"not a stub")?;
+
+    let output = common::lanzaboote_install_with_stub(&stub, esp.path(), [generation_link])?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("Invalid lanzaboote stub"));
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
+
+/// Installing the same generations twice produces identical unsigned artifacts, regardless of
+/// when and where the ESP is mounted.
+#[test]
+fn reproducible_installations() -> Result<()> {
+    let esps = [tempdir()?, tempdir()?];
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links = [1, 2]
+        .into_iter()
+        .map(|v| setup_generation_link_from_toplevel(&toplevel, profiles.path(), v))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut hashes = Vec::new();
+    for esp in &esps {
+        let output = common::lanzaboote_install(0, esp.path(), &generation_links)?;
+        assert!(output.status.success());
+        hashes.push(unsigned_artifact_hashes(esp.path())?);
+        // objcopy stamps the stubs with the time in seconds.
+        thread::sleep(Duration::from_secs(2));
+    }
+
+    assert!(!hashes[0].is_empty());
+    assert_eq!(hashes[0], hashes[1]);
+
+    Ok(())
+}
+
+/// The hashes of all files on the ESP with the signatures of the PE binaries removed.
+fn unsigned_artifact_hashes(esp: &Path) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut hashes = BTreeMap::new();
+    for entry in WalkDir::new(esp) {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(esp)?.to_path_buf();
+        // The versions cache is installation state, not an artifact.
+        if !entry.file_type().is_file() || path == Path::new(SYSTEMD_BOOT_VERSIONS) {
+            continue;
+        }
+        if !path.starts_with("EFI/nixos") {
+            remove_signature(entry.path())?;
+        }
+        hashes.insert(path, hash_file(entry.path()).to_vec());
+    }
+    Ok(hashes)
+}
@@ -1,5 +1,19 @@
+#[cfg(feature = "qemu-tests")]
+mod boot;
+mod bundle;
 mod common;
+mod doctor;
+mod export_cert;
 mod gc;
 mod install;
 mod os_release;
+mod print_esp_layout;
+mod recovery_image;
+mod resign;
+mod sign_stub;
+mod signature;
+mod status;
 mod systemd_boot;
+mod uki;
+mod verify;
+mod will_regenerate;
@@ -0,0 +1,44 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn print_the_files_of_an_installation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_print_esp_layout(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        generation_links,
+    )?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+
+    // Two stubs, each with a kernel and an initrd, and the files in `EFI/nixos`. The generations
+    // share their kernel and initrd.
+    let paths: Vec<&str> = stdout
+        .lines()
+        .map(|line| line.rsplit(' ').next().unwrap())
+        .collect();
+    assert_eq!(paths.len(), 8);
+    for path in paths {
+        let path = esp_mountpoint.path().join(path.trim_start_matches('/'));
+        assert!(path.exists(), "{path:?} was not installed.");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use fatfs::{Dir, FileSystem, FsOptions, ReadWriteSeek};
+use tempfile::tempdir;
+use walkdir::WalkDir;
+
+use crate::common;
+use lzbt_systemd::esp::SYSTEMD_BOOT_VERSIONS;
+
+/// The recovery image contains the latest generation only and the same files as a regular
+/// installation of it.
+#[test]
+fn recovery_image_layout() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), v))
+        .collect::<Result<_>>()?;
+
+    let image = tmpdir.path().join("recovery.img");
+    let output0 = common::lanzaboote_make_recovery_image(&image, generation_links.clone())?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_install(1, esp.path(), generation_links)?;
+    assert!(output1.status.success());
+
+    let filesystem = FileSystem::new(File::open(&image)?, FsOptions::new())?;
+    let mut image_files = Vec::new();
+    list_files(&filesystem.root_dir(), String::new(), &mut image_files)?;
+    image_files.sort();
+
+    let mut esp_files = WalkDir::new(esp.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| {
+            e.path()
+                .strip_prefix(esp.path())
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        })
+        // The versions cache is installation state that the image leaves out.
+        .filter(|path| path != SYSTEMD_BOOT_VERSIONS)
+        .collect::<Vec<String>>();
+    esp_files.sort();
+
+    assert_eq!(image_files, esp_files);
+
+    let stub_name = |version| -> Result<String> {
+        let stub = common::image_path(&esp, version, &toplevel)?;
+        Ok(stub
+            .strip_prefix(esp.path())?
+            .to_string_lossy()
+            .into_owned())
+    };
+    assert!(image_files.contains(&stub_name(2)?));
+    assert!(!image_files.contains(&stub_name(1)?));
+
+    Ok(())
+}
+
+/// Installing into an ESP image creates it and updates it on the next installation.
+#[test]
+fn install_into_esp_image() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let image = tmpdir.path().join("esp.img");
+    let output0 = common::lanzaboote_install_with_args(
+        1,
+        &image,
+        [generation_link1.clone()],
+        ["--esp-image"],
+    )?;
+    assert!(output0.status.success());
+    let output1 = common::lanzaboote_install_with_args(
+        2,
+        &image,
+        [generation_link1, generation_link2],
+        ["--esp-image"],
+    )?;
+    assert!(output1.status.success());
+
+    let filesystem = FileSystem::new(File::open(&image)?, FsOptions::new())?;
+    for version in [1, 2] {
+        let stub_path = common::image_path(&tmpdir, version, &toplevel)?;
+        let stub_name = stub_path.strip_prefix(tmpdir.path())?.to_string_lossy();
+        let mut stub = Vec::new();
+        filesystem
+            .root_dir()
+            .open_file(&stub_name)?
+            .read_to_end(&mut stub)?;
+
+        // Read the stub back out of the image to check that it is signed.
+        let unpacked_stub = tmpdir.path().join(format!("stub-{version}.efi"));
+        fs::write(&unpacked_stub, stub)?;
+        assert!(common::verify_signature(&unpacked_stub)?);
+    }
+
+    Ok(())
+}
+
+fn list_files<T: ReadWriteSeek>(
+    dir: &Dir<T>,
+    prefix: String,
+    files: &mut Vec<String>,
+) -> Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = format!("{prefix}{name}");
+        if entry.is_dir() {
+            list_files(&entry.to_dir(), format!("{path}/"), files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
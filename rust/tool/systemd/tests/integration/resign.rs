@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::signature::local::LocalKeyPair;
+use lanzaboote_tool::signature::Signer;
+use lzbt_systemd::architecture::SystemdArchitectureExt;
+use tempfile::tempdir;
+
+use crate::common::{self, count_files, SYSTEM};
+
+#[test]
+fn resign_stubs_and_systemd_boot_with_new_key() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let old_stubs = fs::read_dir(&linux)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    let kernels = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| Ok(common::hash_file(&entry?.path())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output1 = common::lanzaboote_resign(esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output1.status.success());
+
+    let old_signer = common::test_signer()?;
+    let new_signer = LocalKeyPair::new(
+        Path::new("tests/fixtures/uefi-keys-p256/db.pem"),
+        Path::new("tests/fixtures/uefi-keys-p256/db.key"),
+    )?;
+    assert_eq!(count_files(&linux)?, 2);
+    for stub in fs::read_dir(&linux)? {
+        let stub = stub?.path();
+        assert!(!old_stubs.contains(&stub), "{stub:?} was not renamed");
+        assert!(new_signer.verify_path(&stub)?);
+        assert!(
+            !old_signer.verify_path(&stub)?,
+            "{stub:?} kept its old signature"
+        );
+    }
+
+    let architecture = Architecture::from_nixos_system(SYSTEM)?;
+    for systemd_boot in [
+        esp_mountpoint
+            .path()
+            .join("EFI/systemd")
+            .join(architecture.systemd_filename()),
+        esp_mountpoint
+            .path()
+            .join("EFI/BOOT")
+            .join(architecture.efi_fallback_filename()),
+    ] {
+        assert!(new_signer.verify_path(&systemd_boot)?);
+        assert!(!old_signer.verify_path(&systemd_boot)?);
+    }
+
+    // The kernels and initrds are verified by the stubs through their hashes and stay untouched.
+    let resigned_kernels = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| Ok(common::hash_file(&entry?.path())))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(kernels, resigned_kernels);
+
+    // The next installation with the new key reuses the renamed stubs.
+    let output2 = common::lanzaboote_will_regenerate(
+        esp_mountpoint.path(),
+        Path::new("tests/fixtures/uefi-keys-p256/db.pem"),
+        generation_links,
+    )?;
+    assert!(output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert_eq!(
+        stdout.lines().filter(|l| l.starts_with("reuse ")).count(),
+        2
+    );
+
+    Ok(())
+}
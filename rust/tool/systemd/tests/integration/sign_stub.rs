@@ -0,0 +1,75 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn sign_stub_without_esp() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let kernel = tmpdir.path().join("kernel");
+    let initrd = tmpdir.path().join("initrd");
+    let os_release = tmpdir.path().join("os-release");
+    fs::write(&kernel, b"kernel")?;
+    fs::write(&initrd, b"initrd")?;
+    fs::write(&os_release, b"ID=nixos\n")?;
+    let out = tmpdir.path().join("stub.efi");
+
+    let output = common::lanzaboote_sign_stub(
+        &kernel,
+        Some(&initrd),
+        &out,
+        [
+            "--kernel-param".as_ref(),
+            "quiet".as_ref(),
+            "--kernel-param".as_ref(),
+            "init=/init".as_ref(),
+            "--os-release".as_ref(),
+            os_release.as_os_str(),
+        ],
+    )?;
+    assert!(output.status.success());
+
+    assert!(common::verify_signature(&out)?);
+    let stub = fs::read(&out)?;
+    assert_eq!(
+        common::pe_section(&stub, ".linux"),
+        Some(&b"\\EFI\\nixos\\kernel.efi"[..])
+    );
+    assert_eq!(
+        common::pe_section(&stub, ".initrd"),
+        Some(&b"\\EFI\\nixos\\initrd.efi"[..])
+    );
+    assert_eq!(
+        common::pe_section(&stub, ".cmdline"),
+        Some(&b"quiet init=/init"[..])
+    );
+    assert_eq!(
+        common::pe_section(&stub, ".osrel"),
+        Some(&b"ID=nixos\n"[..])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sign_stub_without_initrd() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let kernel = tmpdir.path().join("kernel");
+    fs::write(&kernel, b"kernel")?;
+    let out = tmpdir.path().join("stub.efi");
+
+    let output = common::lanzaboote_sign_stub(&kernel, None, &out, Vec::<&str>::new())?;
+    assert!(output.status.success());
+
+    assert!(common::verify_signature(&out)?);
+    let stub = fs::read(&out)?;
+    assert_eq!(
+        common::pe_section(&stub, ".linux"),
+        Some(&b"\\EFI\\nixos\\kernel.efi"[..])
+    );
+    assert_eq!(common::pe_section(&stub, ".initrd"), None);
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::path::Path;
+
+use anyhow::Result;
+use lanzaboote_tool::signature::local::{KeyType, LocalKeyPair};
+use lanzaboote_tool::signature::Signer;
+use tempfile::tempdir;
+
+use crate::common;
+
+fn key_pair(keys: &str) -> Result<LocalKeyPair> {
+    let keys = Path::new("tests/fixtures").join(keys);
+    LocalKeyPair::new(&keys.join("db.pem"), &keys.join("db.key"))
+}
+
+#[test]
+fn accept_matching_key_pair() -> Result<()> {
+    assert_eq!(common::test_signer()?.key_type, KeyType::Rsa(4096));
+    assert_eq!(key_pair("uefi-keys-p256")?.key_type, KeyType::EcP256);
+    assert_eq!(key_pair("uefi-keys-p384")?.key_type, KeyType::EcP384);
+    Ok(())
+}
+
+#[test]
+fn report_key_type_of_failed_signature() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let error = key_pair("uefi-keys-p256")?
+        .sign_and_copy(
+            Path::new("tests/fixtures/uefi-keys-p256/db.pem"),
+            &tmpdir.path().join("signed.efi"),
+        )
+        .expect_err("A certificate is not a PE binary and cannot be signed");
+    assert!(format!("{error:#}").contains("with the EC P-256 key"));
+    Ok(())
+}
+
+#[test]
+fn reject_mismatched_key_pair() {
+    let error = LocalKeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/mismatched.key"),
+    )
+    .expect_err("A private key of another certificate must be rejected");
+    assert!(format!("{error:#}").contains("does not belong to the certificate"));
+}
+
+#[test]
+fn reject_mismatched_key_type() {
+    assert!(LocalKeyPair::new(
+        Path::new("tests/fixtures/uefi-keys-p256/db.pem"),
+        Path::new("tests/fixtures/uefi-keys-p384/db.key"),
+    )
+    .is_err());
+    assert!(LocalKeyPair::new(
+        Path::new("tests/fixtures/uefi-keys-p384/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    )
+    .is_err());
+}
+
+#[test]
+fn reject_key_as_certificate() {
+    assert!(LocalKeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    )
+    .is_err());
+}
+
+#[test]
+fn sign_and_verify_with_ec_keys() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let stub = common::systemd_stub()?;
+    let rsa = common::test_signer()?;
+
+    for keys in ["uefi-keys-p256", "uefi-keys-p384"] {
+        let signer = key_pair(keys)?;
+        let signed = tmpdir.path().join(format!("{keys}.efi"));
+        signer.sign_and_copy(&stub, &signed)?;
+
+        assert!(signer.verify_path(&signed)?, "{keys} signature is invalid");
+        assert!(!rsa.verify_path(&signed)?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn report_installed_boot_state() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link2 = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+    let toplevel = fs::read_link(&generation_link2)?;
+
+    let output0 = common::lanzaboote_status(esp.path())?;
+    assert!(output0.status.success());
+    let status0: serde_json::Value = serde_json::from_slice(&output0.stdout)?;
+    assert!(status0["systemd_boot"].is_null());
+    assert_eq!(status0["generations"], serde_json::json!([]));
+    assert_eq!(status0["used_bytes"], 0);
+
+    let output1 =
+        common::lanzaboote_install(0, esp.path(), vec![generation_link1, generation_link2])?;
+    assert!(output1.status.success());
+
+    // Drop-in directories share the name of their stub, but are no stubs.
+    let stub = common::image_path(&esp, 2, &toplevel)?;
+    let dropin_dir = stub.with_extension("efi.extra");
+    fs::create_dir(&dropin_dir)?;
+    fs::write(dropin_dir.join("a.cred"), b"credential")?;
+
+    let output2 = common::lanzaboote_status(esp.path())?;
+    assert!(output2.status.success());
+    let status: serde_json::Value = serde_json::from_slice(&output2.stdout)?;
+
+    for binary in [&status["systemd_boot"], &status["efi_fallback"]] {
+        assert_eq!(binary["signed"], true);
+        let signers = binary["signers"].as_array().expect("signers is an array");
+        assert_eq!(signers.len(), 1);
+        assert!(signers[0].as_str().unwrap().contains("CN=Database Key"));
+    }
+
+    let generations: Vec<u64> = status["generations"]
+        .as_array()
+        .expect("generations is an array")
+        .iter()
+        .map(|stub| stub["generation"].as_u64().unwrap())
+        .collect();
+    assert_eq!(generations, [1, 2]);
+    assert!(status["used_bytes"].as_u64().unwrap() > 0);
+
+    Ok(())
+}
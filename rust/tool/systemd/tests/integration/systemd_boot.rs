@@ -112,6 +112,105 @@ fn overwrite_unsigned_systemd_boot_binaries() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn keep_efi_fallback_without_fallback() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // Another bootloader occupies the removable media path.
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+    fs::create_dir_all(systemd_boot_fallback_path.parent().unwrap())?;
+    fs::write(&systemd_boot_fallback_path, b"another bootloader")?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--no-fallback"],
+    )?;
+    assert!(output0.status.success());
+
+    assert!(verify_signature(&systemd_boot_path(&esp))?);
+    assert_eq!(
+        fs::read(&systemd_boot_fallback_path)?,
+        b"another bootloader",
+        "The EFI fallback was modified despite --no-fallback."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn point_loader_conf_default_at_latest_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links = vec![
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?,
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?,
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links,
+        [
+            "--loader-default-latest",
+            "--loader-timeout",
+            "menu-force",
+            "--loader-console-mode",
+            "max",
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let image2 = common::image_path(&esp, 2, &toplevel)?;
+    let loader_conf = fs::read_to_string(esp.path().join("loader/loader.conf"))?;
+    let lines = loader_conf.lines().collect::<Vec<_>>();
+    assert!(lines
+        .contains(&format!("default {}", image2.file_name().unwrap().to_str().unwrap()).as_str()));
+    assert!(lines.contains(&"timeout menu-force"));
+    assert!(lines.contains(&"console-mode max"));
+
+    Ok(())
+}
+
+#[test]
+fn validate_loader_config() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let loader_config = "timout 5\neditor maybe\n";
+
+    let output0 = common::lanzaboote_install_with_loader_config(
+        0,
+        esp.path(),
+        [&generation_link],
+        loader_config,
+        Vec::<&str>::new(),
+    )?;
+    assert!(output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("line 1: unknown key \"timout\""));
+    assert!(stderr.contains("line 2: invalid value \"maybe\" for \"editor\""));
+
+    let output1 = common::lanzaboote_install_with_loader_config(
+        0,
+        esp.path(),
+        [&generation_link],
+        loader_config,
+        ["--strict-loader-config"],
+    )?;
+    assert!(!output1.status.success());
+
+    Ok(())
+}
+
 fn systemd_boot_path(esp: &tempfile::TempDir) -> PathBuf {
     let arch = Architecture::from_nixos_system(SYSTEM).unwrap();
     esp.path()
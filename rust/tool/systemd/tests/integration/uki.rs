@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::{self, StubParameters};
+
+use crate::common::{self, setup_generation_link_from_toplevel, systemd_stub, verify_signature};
+
+#[test]
+fn install_uki_with_title() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+
+    // A UKI as ukify would build it, with the kernel and initrd embedded.
+    let uki_dir = tempdir()?;
+    let uki = pe::lanzaboote_image(
+        &uki_dir,
+        &StubParameters::new_fat(
+            &systemd_stub()?,
+            &store_path.join("kernel"),
+            Some(&store_path.join("initrd")),
+        )
+        .with_os_release_contents(b"ID=fedora\nPRETTY_NAME=\"Fedora Linux\"\n"),
+    )?;
+
+    let output0 = common::lanzaboote_install_uki(esp.path(), &uki, &["--title", "Rescue"])?;
+    assert!(output0.status.success());
+    let installed = PathBuf::from(String::from_utf8(output0.stdout)?.trim());
+    assert_eq!(
+        installed.parent(),
+        Some(esp.path().join("EFI/Linux").as_path())
+    );
+    assert!(installed
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("nixos-uki-")));
+    assert!(verify_signature(&installed)?);
+    let os_release = String::from_utf8(
+        common::pe_section(&fs::read(&installed)?, ".osrel")
+            .expect("The UKI has an os-release")
+            .to_vec(),
+    )?;
+    assert!(os_release.lines().any(|line| line == "PRETTY_NAME=Rescue"));
+
+    // The garbage collection of an installation keeps the UKI.
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output1.status.success());
+    assert!(installed.exists());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_to_install_a_binary_without_kernel() -> Result<()> {
+    let esp = tempdir()?;
+
+    let output0 = common::lanzaboote_install_uki(esp.path(), &systemd_stub()?, &[])?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("is not a UKI"));
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
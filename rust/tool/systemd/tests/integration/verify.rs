@@ -0,0 +1,44 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common::{self, remove_signature, setup_generation_link_from_toplevel};
+
+#[test]
+fn verify_installed_binaries() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), vec![generation_link1, generation_link2])?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_verify(esp.path())?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    // systemd-boot, its copy at the removable media path and the stubs of both generations.
+    assert_eq!(stdout.lines().count(), 4);
+    assert!(stdout.lines().all(|line| line.ends_with(": trusted")));
+
+    // Drop-in directories share the name of their stub, but are not verified.
+    let dropin_dir = common::installed_stub(esp.path(), 1)?.with_extension("efi.extra");
+    fs::create_dir(&dropin_dir)?;
+    fs::write(dropin_dir.join("a.cred"), b"credential")?;
+    let output2 = common::lanzaboote_verify(esp.path())?;
+    assert!(output2.status.success());
+    assert_eq!(String::from_utf8(output2.stdout)?.lines().count(), 4);
+
+    let image = common::image_path(&esp, 2, &toplevel)?;
+    remove_signature(&image)?;
+    let output3 = common::lanzaboote_verify(esp.path())?;
+    assert!(!output3.status.success());
+    let stdout = String::from_utf8(output3.stdout)?;
+    assert!(stdout.contains(&format!("{}: unsigned", image.display())));
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn key_rotation_regenerates_all_stubs() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // With the same key, all stubs are reused.
+    let output1 = common::lanzaboote_will_regenerate(
+        esp_mountpoint.path(),
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        generation_links.clone(),
+    )?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert_eq!(
+        stdout.lines().filter(|l| l.starts_with("reuse ")).count(),
+        2
+    );
+    assert_eq!(stdout.lines().count(), 2);
+
+    // With a new key, all stubs are regenerated and the old ones are removed.
+    let new_public_key = tmpdir.path().join("new-db.pem");
+    fs::write(&new_public_key, "new public key")?;
+    let output2 = common::lanzaboote_will_regenerate(
+        esp_mountpoint.path(),
+        &new_public_key,
+        generation_links,
+    )?;
+    assert!(output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert_eq!(
+        stdout
+            .lines()
+            .filter(|l| l.starts_with("regenerate "))
+            .count(),
+        2
+    );
+    assert_eq!(
+        stdout.lines().filter(|l| l.starts_with("remove ")).count(),
+        2
+    );
+    assert_eq!(stdout.lines().count(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn changed_kernel_params_regenerate_stubs() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| common::setup_generation_link(tmpdir.path(), profiles.path(), v))
+        .collect::<Result<_>>()?;
+    let public_key = Path::new("tests/fixtures/uefi-keys/db.pem");
+    let args = ["--append-kernel-param", "console=ttyS0"];
+    let count = |stdout: &str, action: &str| {
+        stdout
+            .lines()
+            .filter(|l| l.starts_with(&format!("{action} ")))
+            .count()
+    };
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        args,
+    )?;
+    assert!(output0.status.success());
+
+    // With the same kernel parameters, all stubs are reused.
+    let output1 = common::lanzaboote_will_regenerate_with_args(
+        esp_mountpoint.path(),
+        public_key,
+        generation_links.clone(),
+        args,
+    )?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert_eq!(count(&stdout, "reuse"), 2);
+    assert_eq!(stdout.lines().count(), 2);
+
+    // Only the latest generation changes its command line with --kernel-params-latest-only.
+    let output2 = common::lanzaboote_will_regenerate_with_args(
+        esp_mountpoint.path(),
+        public_key,
+        generation_links,
+        args.into_iter().chain(["--kernel-params-latest-only"]),
+    )?;
+    assert!(output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert_eq!(count(&stdout, "reuse"), 1);
+    assert_eq!(count(&stdout, "regenerate"), 1);
+    assert_eq!(count(&stdout, "remove"), 1);
+
+    Ok(())
+}
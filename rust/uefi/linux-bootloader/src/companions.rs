@@ -1,13 +1,28 @@
-use crate::cpio::{pack_cpio, Cpio};
-use alloc::{string::ToString, vec::Vec};
+use crate::{
+    cpio::pack_cpio,
+    pe_section::{pe_file_section, pe_section},
+    shim::shim_verify,
+};
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
 use uefi::{
+    boot::{self, LoadImageSource, OpenProtocolAttributes, OpenProtocolParams},
     cstr16,
     fs::{Path, PathBuf},
-    proto::device_path::{
-        text::{AllowShortcuts, DisplayOnly},
-        DevicePath,
+    guid,
+    proto::{
+        device_path::{
+            build::{self, DevicePathBuilder},
+            text::{AllowShortcuts, DisplayOnly},
+            DevicePath,
+        },
+        loaded_image::LoadedImage,
+        media::file::{Directory, File, FileAttribute, FileHandle, FileMode, RegularFile},
     },
-    CString16,
+    CString16, Guid, Handle,
 };
 
 /// Locate files with ASCII filenames and matching the suffix passed as a parameter.
@@ -19,7 +34,10 @@ pub fn find_files(
 ) -> uefi::Result<Vec<PathBuf>> {
     let mut results = Vec::new();
 
-    for maybe_entry in fs.read_dir(search_path).unwrap() {
+    for maybe_entry in fs
+        .read_dir(search_path)
+        .map_err(|_err| uefi::Status::LOAD_ERROR)?
+    {
         let entry = maybe_entry?;
         if entry.is_regular_file() {
             let fname = entry.file_name();
@@ -64,36 +82,104 @@ pub fn get_default_dropin_directory(
         }))
 }
 
+/// Parse the extra drop-in directories listed in the `.dropins` section of a stub.
+///
+/// The section lists one path per line, rooted at the ESP, e.g. `\loader\credentials`. Forward
+/// slashes are accepted as separators. Empty lines and paths that are not valid UCS-2 are skipped.
+pub fn parse_dropin_directories(section: &str) -> Vec<PathBuf> {
+    section
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            CString16::try_from(line.replace('/', "\\").as_str())
+                .map_err(|_err| log::warn!("Ignoring invalid drop-in directory {line:?}"))
+                .ok()
+        })
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// The extra drop-in directories listed in the `.dropins` section of a stub that exist.
+pub fn extra_dropin_directories(fs: &mut uefi::fs::FileSystem, section: &str) -> Vec<PathBuf> {
+    parse_dropin_directories(section)
+        .into_iter()
+        .filter(|dir| {
+            fs.metadata(dir)
+                .is_ok_and(|metadata| metadata.is_directory())
+        })
+        .collect()
+}
+
 pub enum CompanionInitrdType {
     Credentials,
     GlobalCredentials,
     SystemExtension,
     PcrSignature,
     PcrPublicKey,
+    Addon,
+}
+
+impl CompanionInitrdType {
+    /// A short name of the type, as exported in `LanzabooteCompanions`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Credentials => "credentials",
+            Self::GlobalCredentials => "global-credentials",
+            Self::SystemExtension => "sysext",
+            Self::PcrSignature => "pcrsig",
+            Self::PcrPublicKey => "pcrpkey",
+            Self::Addon => "addon",
+        }
+    }
 }
 
 /// Potential companion initrd assembled on the fly
 /// during discovery workflows, e.g. finding files in drop-in directories.
 pub struct CompanionInitrd {
     pub r#type: CompanionInitrdType,
-    pub cpio: Cpio,
+    /// The initrd passed to the kernel, a CPIO archive that may be compressed.
+    pub contents: Vec<u8>,
+    /// The paths of the files the initrd was assembled from or extracted from.
+    pub files: Vec<String>,
+}
+
+#[cfg(feature = "zstd")]
+impl CompanionInitrd {
+    /// Compress the initrd with zstd.
+    ///
+    /// The kernel unpacks every segment of a concatenated initrd on its own, so compressed
+    /// companion initrds can be mixed with the uncompressed main initrd.
+    pub fn compress(&mut self) {
+        self.contents = ruzstd::encoding::compress_to_vec(
+            self.contents.as_slice(),
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+    }
 }
 
 /// Collect all credentials and return them as CPIO archive.
 ///
 /// There are two variants of credentials:
 ///   - global: `$ESP/loader.credentials/*.cred`
-///   - image-specific: `$path_to_image.extra/*.cred`
+///   - image-specific: `*.cred` in each of `dropin_dirs`, by default only `$path_to_image.extra`
+///
+/// Every drop-in directory is packed into its own CPIO archive, in the order of `dropin_dirs`.
+/// The kernel unpacks them in this order, so a credential in a later directory replaces one
+/// with the same name in an earlier directory.
 ///
 /// The credentials are not measured.
 pub fn discover_credentials(
     fs: &mut uefi::fs::FileSystem,
-    default_dropin_dir: Option<&Path>,
+    dropin_dirs: &[PathBuf],
 ) -> uefi::Result<Vec<CompanionInitrd>> {
     let mut companions = Vec::new();
 
     let default_global_dropin_dir = cstr16!("\\loader\\credentials");
-    if fs.try_exists(default_global_dropin_dir).unwrap() {
+    if fs
+        .try_exists(default_global_dropin_dir)
+        .map_err(|_err| uefi::Status::LOAD_ERROR)?
+    {
         let metadata = fs.metadata(default_global_dropin_dir).map_err(|_err| {
             log::warn!("Failed to obtain metadata on `\\loader\\credentials` path (which is supposed to exist)");
             uefi::Error::new(uefi::Status::VOLUME_CORRUPTED, ())
@@ -105,52 +191,434 @@ pub fn discover_credentials(
             if !global_credentials.is_empty() {
                 companions.push(CompanionInitrd {
                     r#type: CompanionInitrdType::GlobalCredentials,
-                    cpio: pack_cpio(
+                    files: global_credentials.iter().map(ToString::to_string).collect(),
+                    contents: pack_cpio(
                         fs,
                         global_credentials,
                         ".extra/global_credentials",
                         0o500,
                         0o400,
-                    )
-                    .map_err(|_err| uefi::Status::LOAD_ERROR)?,
+                    )?,
                 });
             }
         }
     }
 
-    if let Some(default_dropin_dir) = default_dropin_dir {
-        let local_credentials: Vec<PathBuf> = find_files(fs, default_dropin_dir, ".cred")?;
+    for dropin_dir in dropin_dirs {
+        let local_credentials: Vec<PathBuf> = find_files(fs, dropin_dir, ".cred")?;
 
         if !local_credentials.is_empty() {
             companions.push(CompanionInitrd {
                 r#type: CompanionInitrdType::Credentials,
-                cpio: pack_cpio(fs, local_credentials, ".extra/credentials", 0o500, 0o400)
-                    .map_err(|_err| uefi::Status::LOAD_ERROR)?,
+                files: local_credentials.iter().map(ToString::to_string).collect(),
+                contents: pack_cpio(fs, local_credentials, ".extra/credentials", 0o500, 0o400)?,
             });
         }
     }
 
     Ok(companions)
 }
+/// Partition type GUIDs of the verity signature partitions of the root and `/usr` file systems
+/// for the architecture of the stub, see the Discoverable Partitions Specification.
+#[cfg(target_arch = "x86_64")]
+const VERITY_SIGNATURE_PARTITION_TYPES: [Guid; 2] = [
+    guid!("41092b05-9fc8-4523-994f-2def0408b176"),
+    guid!("e7bb33fb-06cf-4e81-8273-e543b413e2e2"),
+];
+#[cfg(target_arch = "x86")]
+const VERITY_SIGNATURE_PARTITION_TYPES: [Guid; 2] = [
+    guid!("5996fc05-109c-48de-808b-23fa0830b676"),
+    guid!("974a71c0-de41-43c3-be5d-5c5ccd1ad2c0"),
+];
+#[cfg(target_arch = "aarch64")]
+const VERITY_SIGNATURE_PARTITION_TYPES: [Guid; 2] = [
+    guid!("6db69de6-29f4-4758-a7a5-962190f00ce3"),
+    guid!("c23ce4ff-44bd-4b00-b2d4-b41b3419e02a"),
+];
+
+/// Limit on the size of the partition entries we read from a system extension image. The GPT
+/// usually has 128 entries of 128 bytes, i.e. 16 KiB.
+const MAX_PARTITION_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// Check whether a disk image has a GPT with a verity signature partition for our architecture.
+///
+/// This only checks for the presence of the partition, not the signature in it. The signature is
+/// a PKCS#7 signature of the verity root hash, which the kernel checks against its keyring when
+/// `systemd-sysext` sets up the image, so an image with a bogus signature is refused later on.
+///
+/// `read_at` reads exactly `len` bytes at `offset` of the image, or returns `None`, so that only
+/// the GPT header and the partition entries are read.
+fn has_verity_signature_partition(mut read_at: impl FnMut(u64, usize) -> Option<Vec<u8>>) -> bool {
+    let read_u32 = |data: &[u8], offset: usize| {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    };
+
+    // Disk images use sectors of 512 bytes or 4 KiB, and the GPT header is in the second sector.
+    for sector_size in [512, 4096] {
+        let Some(header) = read_at(sector_size, 92) else {
+            continue;
+        };
+        if &header[..8] != b"EFI PART" {
+            continue;
+        }
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+        let Ok(entries_count) = usize::try_from(read_u32(&header, 80)) else {
+            continue;
+        };
+        let Ok(entry_size) = usize::try_from(read_u32(&header, 84)) else {
+            continue;
+        };
+        if entry_size < 16 {
+            continue;
+        }
+        let Some(entries) = entries_lba
+            .checked_mul(sector_size)
+            .zip(entries_count.checked_mul(entry_size))
+            .filter(|(_, size)| *size <= MAX_PARTITION_ENTRIES_SIZE)
+            .and_then(|(start, size)| read_at(start, size))
+        else {
+            continue;
+        };
+
+        if entries
+            .chunks_exact(entry_size)
+            .map(|entry| Guid::from_bytes(entry[..16].try_into().unwrap()))
+            .any(|type_guid| VERITY_SIGNATURE_PARTITION_TYPES.contains(&type_guid))
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Read exactly `len` bytes at `offset` of `file`.
+fn read_file_at(file: &mut RegularFile, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buffer = vec![0; len];
+    file.set_position(offset).ok()?;
+    let read = file.read(&mut buffer).ok()?;
+    (read == len).then_some(buffer)
+}
+
 /// Discover any system image extension, i.e. files ending by .raw
-/// They must be present inside $path_to_image.extra/*.raw, specific to this image.
+/// They must be present inside the drop-in directories specific to this image, by default only
+/// $path_to_image.extra/*.raw.
+///
+/// If `secure_boot` is active, only system extensions with a verity signature partition are
+/// picked up, see [`has_verity_signature_partition`]. The others are skipped, so that they are
+/// neither measured nor passed to the kernel. `volume` is the root directory of `fs`, through
+/// which only the partition tables of the images are read for this.
 ///
 /// Those will be unmeasured, you are responsible for measuring them or not.
-/// But CPIOs are guaranteed to be stable and independent of file discovery order.
+/// But CPIOs are guaranteed to be stable and independent of file discovery order: there is one
+/// per drop-in directory, in the order of `dropin_dirs`.
 pub fn discover_system_extensions(
     fs: &mut uefi::fs::FileSystem,
-    default_dropin_dir: &Path,
+    volume: &mut Directory,
+    dropin_dirs: &[PathBuf],
+    secure_boot: bool,
 ) -> uefi::Result<Vec<CompanionInitrd>> {
     let mut companions = Vec::new();
-    let sysexts = find_files(fs, default_dropin_dir, ".raw")?;
 
-    if !sysexts.is_empty() {
-        companions.push(CompanionInitrd {
-            r#type: CompanionInitrdType::SystemExtension,
-            cpio: pack_cpio(fs, sysexts, ".extra/sysext", 0o555, 0o444)
-                .map_err(|_err| uefi::Status::LOAD_ERROR)?,
-        });
+    for dropin_dir in dropin_dirs {
+        let mut sysexts = find_files(fs, dropin_dir, ".raw")?;
+
+        if secure_boot {
+            sysexts.retain(|path| {
+                let Some(mut image) = volume
+                    .open(path.to_cstr16(), FileMode::Read, FileAttribute::empty())
+                    .ok()
+                    .and_then(FileHandle::into_regular_file)
+                else {
+                    log::warn!("Ignoring the system extension {path} because it cannot be read.");
+                    return false;
+                };
+                let signed = has_verity_signature_partition(|offset, len| {
+                    read_file_at(&mut image, offset, len)
+                });
+                if !signed {
+                    log::warn!("Ignoring the system extension {path} without a verity signature partition because Secure Boot is active.");
+                }
+                signed
+            });
+        } else if !sysexts.is_empty() {
+            log::info!("Secure Boot is not active, skipping the signature verification of the system extensions in {dropin_dir}.");
+        }
+
+        if !sysexts.is_empty() {
+            companions.push(CompanionInitrd {
+                r#type: CompanionInitrdType::SystemExtension,
+                files: sysexts.iter().map(ToString::to_string).collect(),
+                contents: pack_cpio(fs, sysexts, ".extra/sysext", 0o555, 0o444)?,
+            });
+        }
     }
 
     Ok(companions)
 }
+
+/// A signed PE binary that extends the configuration of the image, see [`discover_addons`].
+pub struct Addon {
+    /// Kernel command line parameters from the `.cmdline` section, to be appended to the command line.
+    pub cmdline: Option<String>,
+    /// The initrd from the `.initrd` section, passed as-is to the kernel.
+    pub initrd: Option<CompanionInitrd>,
+    /// The path of the addon file.
+    pub file: String,
+}
+
+/// Whether the command lines of addons may be appended to the kernel command line.
+///
+/// Without Secure Boot, the firmware does not verify addons, so anyone with access to the ESP
+/// could drop one in. If editing the command line is forbidden as well, their command lines
+/// would get around that and are ignored.
+pub fn addon_cmdlines_allowed(secure_boot_enabled: bool, editing_allowed: bool) -> bool {
+    secure_boot_enabled || editing_allowed
+}
+
+/// Discover addons, i.e. files ending by .addon.efi, in the same places as systemd-stub.
+///
+/// There are two variants of addons:
+///   - global: `$ESP/loader/addons/*.addon.efi`
+///   - image-specific: `$path_to_image.extra/*.addon.efi`
+///
+/// Every addon is loaded with `LoadImage`, so the firmware checks its signature against the
+/// Secure Boot databases, and only read if this succeeds. If shim loaded us, shim verifies the
+/// addons instead, see [`shim_verify`]. Without Secure Boot, nothing is verified.
+///
+/// Global addons come first, and each variant is sorted by path, so the order is stable.
+/// The addons are unmeasured.
+pub fn discover_addons(
+    fs: &mut uefi::fs::FileSystem,
+    default_dropin_dir: Option<&Path>,
+) -> uefi::Result<Vec<Addon>> {
+    let mut addon_paths = Vec::new();
+
+    let default_global_dropin_dir = cstr16!("\\loader\\addons");
+    if fs
+        .metadata(default_global_dropin_dir)
+        .map_or(false, |metadata| metadata.is_directory())
+    {
+        let mut global_addons = find_files(fs, default_global_dropin_dir.as_ref(), ".addon.efi")?;
+        global_addons.sort();
+        addon_paths.append(&mut global_addons);
+    }
+
+    if let Some(default_dropin_dir) = default_dropin_dir {
+        let mut local_addons = find_files(fs, default_dropin_dir, ".addon.efi")?;
+        local_addons.sort();
+        addon_paths.append(&mut local_addons);
+    }
+
+    if addon_paths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let device_path = image_device_path()?;
+    let mut addons = Vec::new();
+
+    for path in addon_paths {
+        match load_addon(fs, &device_path, &path) {
+            Ok(addon) => addons.push(addon),
+            Err(err) => log::warn!("Ignoring addon {path}: {err}"),
+        }
+    }
+
+    Ok(addons)
+}
+
+/// Open the device path of the device the currently executing image was loaded from.
+fn image_device_path() -> uefi::Result<boot::ScopedProtocol<DevicePath>> {
+    let open_params = |handle: Handle| OpenProtocolParams {
+        handle,
+        agent: boot::image_handle(),
+        controller: None,
+    };
+
+    // SAFETY: Opening the protocols with `GetProtocol` neither disconnects drivers nor conflicts
+    // with the exclusive uses of them elsewhere, and we only read the device path.
+    let loaded_image = unsafe {
+        boot::open_protocol::<LoadedImage>(
+            open_params(boot::image_handle()),
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    let device = loaded_image.device().ok_or(uefi::Status::NOT_FOUND)?;
+    unsafe {
+        boot::open_protocol::<DevicePath>(open_params(device), OpenProtocolAttributes::GetProtocol)
+    }
+}
+
+/// Verify the signature of an addon and extract its sections.
+fn load_addon(
+    fs: &mut uefi::fs::FileSystem,
+    device_path: &DevicePath,
+    path: &Path,
+) -> uefi::Result<Addon> {
+    let data = fs.read(path).map_err(|_err| uefi::Status::LOAD_ERROR)?;
+
+    let mut addon = match shim_verify(&data) {
+        Some(verified) => {
+            verified?;
+            addon_from_sections(
+                pe_file_section(&data, ".cmdline"),
+                pe_file_section(&data, ".initrd"),
+            )?
+        }
+        None => load_addon_with_firmware(device_path, path, &data)?,
+    };
+    addon.file = path.to_string();
+    if let Some(initrd) = &mut addon.initrd {
+        initrd.files.push(addon.file.clone());
+    }
+    Ok(addon)
+}
+
+/// Load an addon with `LoadImage` to have its signature verified by the firmware and extract its
+/// sections.
+fn load_addon_with_firmware(
+    device_path: &DevicePath,
+    path: &Path,
+    data: &[u8],
+) -> uefi::Result<Addon> {
+    // Firmware refuses to verify images without a device path, so point it at the addon file.
+    let mut file_path_buf = Vec::new();
+    let mut builder = DevicePathBuilder::with_vec(&mut file_path_buf);
+    for node in device_path.node_iter() {
+        builder = builder
+            .push(&node)
+            .map_err(|_err| uefi::Status::BUFFER_TOO_SMALL)?;
+    }
+    let file_path = builder
+        .push(&build::media::FilePath {
+            path_name: path.to_cstr16(),
+        })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_err| uefi::Status::BUFFER_TOO_SMALL)?;
+
+    let handle = boot::load_image(
+        boot::image_handle(),
+        LoadImageSource::FromBuffer {
+            buffer: data,
+            file_path: Some(file_path),
+        },
+    )?;
+
+    let addon = read_addon_sections(handle);
+    boot::unload_image(handle)?;
+    addon
+}
+
+/// Copy the `.cmdline` and `.initrd` sections out of a loaded addon.
+///
+/// The sections are read from the image in memory, i.e. exactly what the firmware verified.
+fn read_addon_sections(handle: Handle) -> uefi::Result<Addon> {
+    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(handle)?;
+    let (image_base, image_size) = loaded_image.info();
+    let image_size = usize::try_from(image_size).map_err(|_err| uefi::Status::LOAD_ERROR)?;
+
+    // SAFETY: The firmware just loaded the addon at this place, and it is never started, so
+    // nothing modifies it until it is unloaded.
+    let pe_data = unsafe { core::slice::from_raw_parts(image_base as *const u8, image_size) };
+
+    addon_from_sections(
+        pe_section(pe_data, ".cmdline"),
+        pe_section(pe_data, ".initrd"),
+    )
+}
+
+/// Build an addon from the data of its `.cmdline` and `.initrd` sections.
+fn addon_from_sections(cmdline: Option<&[u8]>, initrd: Option<&[u8]>) -> uefi::Result<Addon> {
+    let cmdline = cmdline
+        .map(|data| {
+            core::str::from_utf8(data)
+                .map(|cmdline| cmdline.trim_end_matches(['\0', '\n', ' ']).to_string())
+                .map_err(|_err| uefi::Status::INVALID_PARAMETER)
+        })
+        .transpose()?;
+    let initrd = initrd.map(|data| CompanionInitrd {
+        r#type: CompanionInitrdType::Addon,
+        contents: data.to_vec(),
+        files: Vec::new(),
+    });
+
+    Ok(Addon {
+        cmdline,
+        initrd,
+        file: String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check an in-memory disk image, see [`has_verity_signature_partition`].
+    fn has_verity_signature_partition_in(image: &[u8]) -> bool {
+        has_verity_signature_partition(|offset, len| {
+            let start = usize::try_from(offset).ok()?;
+            image
+                .get(start..start.checked_add(len)?)
+                .map(<[u8]>::to_vec)
+        })
+    }
+
+    /// Build a disk image with 512-byte sectors and a GPT whose partitions have `types`.
+    fn gpt_image(types: &[Guid]) -> Vec<u8> {
+        let mut image = vec![0u8; 4 * 512];
+        image[512..520].copy_from_slice(b"EFI PART");
+        image[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        image[512 + 80..512 + 84].copy_from_slice(&(types.len() as u32).to_le_bytes());
+        image[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        for (index, type_guid) in types.iter().enumerate() {
+            let start = 2 * 512 + index * 128;
+            image[start..start + 16].copy_from_slice(&type_guid.to_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn parse_extra_dropin_directories() {
+        let dirs = parse_dropin_directories("\\loader\\credentials\n\n  /EFI/extra  \n");
+        let dirs: Vec<String> = dirs.iter().map(ToString::to_string).collect();
+        assert_eq!(dirs, ["\\loader\\credentials", "\\EFI\\extra"]);
+
+        assert!(parse_dropin_directories("").is_empty());
+        // Characters outside of UCS-2 cannot be part of a UEFI path.
+        assert!(parse_dropin_directories("\\loader\\😀").is_empty());
+    }
+
+    #[test]
+    fn ignore_unverified_addon_cmdlines_if_editing_is_forbidden() {
+        assert!(addon_cmdlines_allowed(true, true));
+        assert!(addon_cmdlines_allowed(true, false));
+        assert!(addon_cmdlines_allowed(false, true));
+        assert!(!addon_cmdlines_allowed(false, false));
+    }
+
+    #[test]
+    fn detect_verity_signature_partitions() {
+        let usr = guid!("8484680c-9521-48c6-9c11-b0720656f69e");
+        let usr_verity = guid!("77ff5f63-e7b6-4633-acf4-1565b864c0e6");
+
+        assert!(has_verity_signature_partition_in(&gpt_image(&[
+            usr,
+            usr_verity,
+            VERITY_SIGNATURE_PARTITION_TYPES[1],
+        ])));
+        assert!(!has_verity_signature_partition_in(&gpt_image(&[
+            usr, usr_verity
+        ])));
+        assert!(!has_verity_signature_partition_in(b"squashfs"));
+
+        // Partition entries beyond the end of the image are ignored.
+        let mut truncated = gpt_image(&[usr, VERITY_SIGNATURE_PARTITION_TYPES[1]]);
+        truncated.truncate(2 * 512 + 128);
+        assert!(!has_verity_signature_partition_in(&truncated));
+
+        // So are partition entries at offsets that overflow.
+        let mut overflowing = gpt_image(&[VERITY_SIGNATURE_PARTITION_TYPES[1]]);
+        overflowing[512 + 72..512 + 80].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(!has_verity_signature_partition_in(&overflowing));
+    }
+}
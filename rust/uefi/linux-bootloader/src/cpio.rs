@@ -1,7 +1,9 @@
 use core::convert::Infallible;
 
-use alloc::{string::String, vec::Vec};
-use pio::errors::CPIOError;
+use alloc::{format, string::String, vec::Vec};
+use pio::errors::{CPIOError, CPIOReadError};
+use pio::reader::CpioReader;
+use pio::writer::{entry_size, CpioWriter, TRAILER_NAME};
 use uefi::fs::{Path, PathBuf};
 
 pub type Cpio = pio::writer::Cpio<Infallible>;
@@ -42,30 +44,80 @@ pub fn pack_cpio_literal(
 ///
 /// All prefixes of the target directory prefix excluding itself will be created with 555
 /// permission bits.
+///
+/// A file that cannot be read fails the whole archive.
+///
+/// The archive is allocated once from the sizes of the files, so that packing large files, e.g.
+/// system extensions, does not hold several copies of the archive in memory while it grows.
 pub fn pack_cpio(
     fs: &mut uefi::fs::FileSystem,
     mut files: Vec<PathBuf>,
     target_dir_prefix: &str,
     dir_mode: u32,
     access_mode: u32,
-) -> Result {
-    let mut cpio = Cpio::new();
+) -> uefi::Result<Vec<u8>> {
+    let pack_error = |_err: CPIOError<Infallible>| uefi::Status::LOAD_ERROR;
+    let read_error = |file: &PathBuf, err| {
+        log::warn!("Failed to read {file}: {err}");
+        uefi::Status::LOAD_ERROR
+    };
 
     // Ensure consistency of the CPIO archive layout for future potential measurements via TPM2.
     files.sort();
 
-    cpio.pack_prefix(target_dir_prefix, dir_mode)?;
-    for file in files {
+    let mut filenames = Vec::with_capacity(files.len());
+    let mut ancestor = String::new();
+    let mut size = entry_size(TRAILER_NAME, 0);
+    for component in target_dir_prefix.split('/') {
+        ancestor = ancestor + "/" + component;
+        size = size.saturating_add(entry_size(&ancestor, 0));
+    }
+    for file in &files {
         let utf8_filename = String::from(
             &file
                 .components()
                 .last()
-                .expect("Expected the filename to possess a file name!"),
+                .ok_or(uefi::Status::INVALID_PARAMETER)?,
         );
-        let contents = fs.read(file).expect("failed to read");
-        cpio.pack_one(&utf8_filename, &contents, target_dir_prefix, access_mode)?;
+        let file_size = fs
+            .metadata(file)
+            .map_err(|err| read_error(file, err))?
+            .file_size();
+        let path = format!("{target_dir_prefix}/{utf8_filename}");
+        size = size.saturating_add(entry_size(
+            &path,
+            usize::try_from(file_size).unwrap_or(usize::MAX),
+        ));
+        filenames.push(utf8_filename);
     }
-    cpio.pack_trailer()?;
 
-    Ok(cpio)
+    let mut archive = Vec::new();
+    archive
+        .try_reserve_exact(size)
+        .map_err(|_err| uefi::Status::OUT_OF_RESOURCES)?;
+    let mut cpio = CpioWriter::new(archive);
+
+    cpio.pack_prefix(target_dir_prefix, dir_mode)
+        .map_err(pack_error)?;
+    for (file, utf8_filename) in files.iter().zip(filenames) {
+        let contents = fs.read(file).map_err(|err| read_error(file, err))?;
+        cpio.pack_one(&utf8_filename, &contents, target_dir_prefix, access_mode)
+            .map_err(pack_error)?;
+    }
+    cpio.pack_trailer().map_err(pack_error)?;
+
+    Ok(cpio.into_inner())
+}
+
+/// Check that an initrd consisting of uncompressed CPIO archives is well-formed, i.e. that every
+/// entry has a valid header, name and padding and that every archive ends with a trailer.
+///
+/// Compressed initrds cannot be checked without decompressing them, so anything that does not
+/// start with a CPIO magic number is accepted as it is and left to the kernel. The same holds for
+/// a compressed archive after the trailer of an uncompressed one, e.g. early microcode.
+pub fn validate_cpio(initrd: &[u8]) -> core::result::Result<(), CPIOReadError> {
+    if !initrd.starts_with(b"0707") {
+        return Ok(());
+    }
+    CpioReader::new(initrd).try_for_each(|entry| entry.map(drop))
 }
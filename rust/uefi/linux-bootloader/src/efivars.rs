@@ -1,4 +1,8 @@
-use alloc::{format, string::ToString, vec::Vec};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::mem::size_of;
 use uefi::{
     boot, cstr16, guid,
@@ -16,7 +20,9 @@ use uefi::{
 
 use bitflags::bitflags;
 
-fn disk_get_part_uuid(disk_handle: Handle) -> Result<Guid> {
+use crate::companions::{CompanionInitrd, CompanionInitrdType};
+
+pub(crate) fn disk_get_part_uuid(disk_handle: Handle) -> Result<Guid> {
     let dp = boot::open_protocol_exclusive::<DevicePath>(disk_handle)?;
 
     for node in dp.node_iter() {
@@ -159,8 +165,48 @@ where
     Ok(())
 }
 
+/// Read a variable and delete it, returning its data if it was set.
+fn take_efi_variable(name: &CStr16, vendor: &VariableVendor) -> Result<Option<Vec<u8>>> {
+    let data = match runtime::get_variable_boxed(name, vendor) {
+        Ok((data, _)) => data,
+        Err(err) if err.status() == Status::NOT_FOUND => return Ok(None),
+        Err(err) => return Err(err.status().into()),
+    };
+    runtime::delete_variable(name, vendor)?;
+    Ok(Some(data.into_vec()))
+}
+
+/// Read and clear `LoaderEntryOneShot`, the entry to boot only on the next boot.
+///
+/// The variable is only cleared if the boot loader advertises `EntryOneshot` in `features`,
+/// otherwise it is not the one expected to consume it.
+pub fn take_loader_entry_oneshot(features: EfiLoaderFeatures) -> Result<Option<Vec<u8>>> {
+    if !features.contains(EfiLoaderFeatures::EntryOneshot) {
+        return Ok(None);
+    }
+    take_efi_variable(cstr16!("LoaderEntryOneShot"), &BOOT_LOADER_VENDOR_UUID)
+}
+
+/// Read and clear `LoaderConfigTimeoutOneShot`, the menu timeout of the next boot only.
+///
+/// Like [`take_loader_entry_oneshot`], this requires `ConfigTimeoutOneShot` in `features`.
+pub fn take_loader_config_timeout_oneshot(features: EfiLoaderFeatures) -> Result<Option<Vec<u8>>> {
+    if !features.contains(EfiLoaderFeatures::ConfigTimeoutOneShot) {
+        return Ok(None);
+    }
+    take_efi_variable(
+        cstr16!("LoaderConfigTimeoutOneShot"),
+        &BOOT_LOADER_VENDOR_UUID,
+    )
+}
+
 /// Exports systemd-stub style EFI variables
-pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
+///
+/// If the booted entry is a specialisation, its name is exported as `LanzabooteSpecialisation`.
+///
+/// The one-shot variables of the boot loader interface are cleared, so that they do not stick
+/// to later boots if the stub is entered again without the boot loader consuming them.
+pub fn export_efi_variables(stub_info_name: &str, specialisation: Option<&str>) -> Result<()> {
     let stub_features: EfiStubFeatures = EfiStubFeatures::ReportBootPartition;
 
     let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
@@ -263,5 +309,128 @@ pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
     )
     .ok();
 
+    // LoaderEntryOneShot, LoaderConfigTimeoutOneShot
+    // They only apply to a single boot, which is this one.
+    let loader_features = get_loader_features().unwrap_or_default();
+    take_loader_entry_oneshot(loader_features).ok();
+    take_loader_config_timeout_oneshot(loader_features).ok();
+
+    // LanzabooteSpecialisation
+    // The variable is volatile, so it is not set at all when booting the base generation.
+    if let Some(specialisation) = specialisation {
+        runtime::set_variable(
+            cstr16!("LanzabooteSpecialisation"),
+            &BOOT_LOADER_VENDOR_UUID,
+            default_attributes,
+            &specialisation
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes())
+                .collect::<Vec<u8>>(),
+        )
+        .ok();
+    }
+
     Ok(())
 }
+
+/// Maximum size of `LanzabooteCompanions` in bytes, well below the variable size limits of
+/// common firmware.
+const MAX_COMPANIONS_VARIABLE_SIZE: usize = 4096;
+
+/// Exports the discovered companions as `LanzabooteCompanions`, so that userspace can tell
+/// which credentials, system extensions and addons the stub picked up.
+///
+/// `cmdline_addons` are the files of the addons without an initrd, which are not part of any
+/// companion. The variable holds one line per file, with the type of the companion and the path
+/// of the file separated by a tab, see [`companions_summary`]. Without any companion, the
+/// variable is not set.
+pub fn export_companions(companions: &[CompanionInitrd], cmdline_addons: &[String]) -> Result<()> {
+    let entries: Vec<(&str, &str)> = companions
+        .iter()
+        .flat_map(|companion| {
+            companion
+                .files
+                .iter()
+                .map(|file| (companion.r#type.name(), file.as_str()))
+        })
+        .chain(
+            cmdline_addons
+                .iter()
+                .map(|file| (CompanionInitrdType::Addon.name(), file.as_str())),
+        )
+        .collect();
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    runtime::set_variable(
+        cstr16!("LanzabooteCompanions"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &companions_summary(entries, MAX_COMPANIONS_VARIABLE_SIZE)
+            .iter()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<u8>>(),
+    )
+}
+
+/// Summarizes companions as UTF-16 lines of their type and file, separated by a tab.
+///
+/// If the lines do not fit into `max_size` bytes, the summary ends with a `...` line instead of
+/// the lines that do not fit.
+fn companions_summary<'a>(
+    entries: impl IntoIterator<Item = (&'a str, &'a str)>,
+    max_size: usize,
+) -> Vec<u16> {
+    let truncated: Vec<u16> = "...\n".encode_utf16().collect();
+    let lines: Vec<Vec<u16>> = entries
+        .into_iter()
+        .map(|(kind, file)| format!("{kind}\t{file}\n").encode_utf16().collect())
+        .collect();
+
+    let mut summary = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        // Leave room for the `...` line, unless this is the last line.
+        let reserved = if index + 1 < lines.len() {
+            truncated.len()
+        } else {
+            0
+        };
+        if (summary.len() + line.len() + reserved) * 2 > max_size {
+            summary.extend(&truncated);
+            break;
+        }
+        summary.extend(line);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    fn summary(entries: &[(&str, &str)], max_size: usize) -> String {
+        String::from_utf16(&companions_summary(entries.iter().copied(), max_size)).unwrap()
+    }
+
+    #[test]
+    fn summarize_companions() {
+        let entries = [
+            ("credentials", "\\EFI\\Linux\\nixos.efi.extra\\a.cred"),
+            ("sysext", "\\EFI\\Linux\\nixos.efi.extra\\b.raw"),
+        ];
+        let full = "credentials\t\\EFI\\Linux\\nixos.efi.extra\\a.cred\n\
+                    sysext\t\\EFI\\Linux\\nixos.efi.extra\\b.raw\n";
+        assert_eq!(summary(&entries, MAX_COMPANIONS_VARIABLE_SIZE), full);
+        assert_eq!(summary(&entries, full.len() * 2), full);
+        assert_eq!(summary(&[], MAX_COMPANIONS_VARIABLE_SIZE), "");
+    }
+
+    #[test]
+    fn truncate_companions() {
+        let entries = [("addon", "\\a.addon.efi"), ("addon", "\\b.addon.efi")];
+        assert_eq!(summary(&entries, 60), "addon\t\\a.addon.efi\n...\n");
+        assert_eq!(summary(&entries, 40), "...\n");
+    }
+}
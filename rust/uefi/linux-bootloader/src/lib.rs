@@ -9,6 +9,8 @@ pub mod linux_loader;
 pub mod measure;
 pub mod pe_loader;
 pub mod pe_section;
+pub mod shim;
+pub mod splash;
 pub mod tpm;
 pub mod uefi_helpers;
 pub mod unified_sections;
@@ -1,16 +1,36 @@
-use alloc::{string::ToString, vec::Vec};
+//! Measurements into the TPM.
+//!
+//! The PCRs follow the split of systemd-stub, so that policies sealed against a UKI booted by
+//! systemd-stub carry over:
+//!
+//! - PCR 4: the Authenticode hash of the stub, with the `pcr4` feature.
+//! - PCR 11: the unified sections `.linux`, `.osrel`, `.cmdline`, `.initrd`, `.splash`, `.dtb`
+//!   and `.pcrpkey` in this order, see [`UnifiedSection::pcr_index`], and the kernel
+//!   and initrd read by a measured-only stub.
+//! - PCR 12: the command line passed from the bootloader if it replaces `.cmdline`, the
+//!   `.rollback` counter, credentials, and the command lines and initrds of addons.
+//! - PCR 13: system extensions.
+//!
+//! The names of the measured unified sections are exported as `LanzabooteMeasuredSections`, see
+//! [`measure_image`].
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
 use log::info;
 use uefi::{
     cstr16,
     proto::tcg::PcrIndex,
     runtime::{self, VariableAttributes},
+    Status,
 };
 
 use crate::{
     companions::{CompanionInitrd, CompanionInitrdType},
     efivars::BOOT_LOADER_VENDOR_UUID,
-    pe_section::pe_section_data,
-    tpm::tpm_log_event_ascii,
+    pe_section::{pe_section, pe_section_data},
+    tpm::{pcr_bank_names, tpm_active_pcr_banks, tpm_log_event_ascii, tpm_log_pe_image},
     uefi_helpers::PeInMemory,
     unified_sections::UnifiedSection,
 };
@@ -18,12 +38,24 @@ use crate::{
 /// This is where any stub payloads are extended, e.g. kernel ELF image, embedded initrd
 /// and so on.
 /// Compared to PCR4, this contains only the unified sections rather than the whole PE image as-is.
-const TPM_PCR_INDEX_KERNEL_IMAGE: PcrIndex = PcrIndex(11);
-/// This is where lanzastub extends the kernel command line and any passed credentials into
+pub(crate) const TPM_PCR_INDEX_KERNEL_IMAGE: PcrIndex = PcrIndex(11);
+/// This is where lanzastub extends the kernel command line, any passed credentials and addons into
 const TPM_PCR_INDEX_KERNEL_CONFIG: PcrIndex = PcrIndex(12);
 /// This is where we extend the initrd sysext images into which we pass to the booted kernel
 const TPM_PCR_INDEX_SYSEXTS: PcrIndex = PcrIndex(13);
+/// This is where the firmware measures the PE images it loads, identified by their Authenticode
+/// hash. Policies written for shim and systemd-boot are often sealed against it.
+const TPM_PCR_INDEX_BOOT_APPLICATIONS: PcrIndex = PcrIndex(4);
+/// This is where lanzastub extends the anti-rollback counter into.
+/// It is part of the boot configuration, hence it shares the PCR with the kernel configuration.
+const TPM_PCR_INDEX_ROLLBACK_COUNTER: PcrIndex = TPM_PCR_INDEX_KERNEL_CONFIG;
 
+/// Measures the unified sections of the running image into the PCR of each section, see
+/// [`UnifiedSection::pcr_index`], in the canonical order of [`UnifiedSection`] like systemd-stub,
+/// whatever the order of the sections in the image.
+///
+/// The names of the measured sections are exported in this order as `LanzabooteMeasuredSections`,
+/// separated by spaces, so that userspace can compute the expected PCR 11 value for sealing.
 pub fn measure_image(image: &PeInMemory) -> uefi::Result<u32> {
     // SAFETY: We get a slice that represents our currently running
     // image and then parse the PE data structures from it. This is
@@ -33,25 +65,28 @@ pub fn measure_image(image: &PeInMemory) -> uefi::Result<u32> {
     let pe_binary = unsafe { image.as_slice() };
     let pe = goblin::pe::PE::parse(pe_binary).map_err(|_err| uefi::Status::LOAD_ERROR)?;
 
-    let mut measurements = 0;
-    for section in pe.sections {
-        let section_name = section.name().map_err(|_err| uefi::Status::UNSUPPORTED)?;
-        if let Ok(unified_section) = UnifiedSection::try_from(section_name) {
-            // UNSTABLE: && in the previous if is an unstable feature
-            // https://github.com/rust-lang/rust/issues/53667
-            if unified_section.should_be_measured() {
-                // Here, perform the TPM log event in ASCII.
-                if let Some(data) = pe_section_data(pe_binary, &section) {
-                    info!("Measuring section `{}`...", section_name);
-                    if tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_IMAGE, data, section_name)? {
-                        measurements += 1;
-                    }
-                }
+    let sections = pe
+        .sections
+        .iter()
+        .map(|section| section.name().map(|name| (name, section)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_err| uefi::Status::UNSUPPORTED)?;
+
+    let mut measured_sections = Vec::new();
+    for (unified_section, section_name, section) in UnifiedSection::in_canonical_order(sections) {
+        let Some(pcr_index) = unified_section.pcr_index() else {
+            continue;
+        };
+        // Here, perform the TPM log event in ASCII.
+        if let Some(data) = pe_section_data(pe_binary, section) {
+            info!("Measuring section `{}`...", section_name);
+            if tpm_log_event_ascii(pcr_index, data, section_name)? {
+                measured_sections.push(section_name.to_string());
             }
         }
     }
 
-    if measurements > 0 {
+    if !measured_sections.is_empty() {
         let pcr_index_encoded = TPM_PCR_INDEX_KERNEL_IMAGE
             .0
             .to_string()
@@ -67,11 +102,167 @@ pub fn measure_image(image: &PeInMemory) -> uefi::Result<u32> {
             VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
             &pcr_index_encoded,
         )?;
+
+        let measured_sections_encoded = measured_sections
+            .join(" ")
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<u8>>();
+        runtime::set_variable(
+            cstr16!("LanzabooteMeasuredSections"),
+            &BOOT_LOADER_VENDOR_UUID,
+            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+            &measured_sections_encoded,
+        )?;
+    }
+
+    Ok(measured_sections.len() as u32)
+}
+
+/// Exports the PCR banks that the measurements are extended into as `LanzabooteMeasuredPcrBanks`,
+/// separated by spaces, e.g. `sha256 sha384`.
+///
+/// The firmware chooses the active banks, so sealing policies must use one of these.
+pub fn export_measured_pcr_banks() -> uefi::Result<()> {
+    let banks = pcr_bank_names(tpm_active_pcr_banks()?).join(" ");
+    info!("Measuring into the PCR banks {banks}");
+
+    runtime::set_variable(
+        cstr16!("LanzabooteMeasuredPcrBanks"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &banks
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<u8>>(),
+    )
+}
+
+/// Measures the Authenticode hash of the stub file `pe_file` into PCR 4.
+///
+/// This is independent of [`measure_image`]: PCR 11 covers the unified sections one by one, while
+/// PCR 4 covers the whole image, including the stub code and all sections, as a single
+/// Authenticode hash. Signatures are not part of the hash, so resigning the stub with another key
+/// does not change PCR 4, but changes anything else in the image. Firmware that measures the
+/// images it loads already extends PCR 4 with the same hash, so the stub's hash then shows up
+/// twice in the event log.
+pub fn measure_authenticode(pe_file: &[u8]) -> uefi::Result<u32> {
+    info!("Measuring the Authenticode hash of the stub...");
+    Ok(tpm_log_pe_image(TPM_PCR_INDEX_BOOT_APPLICATIONS, pe_file, "Lanzaboote stub")?.into())
+}
+
+/// Measures the contents of a kernel and initrd that are read from the ESP.
+///
+/// Stubs that do not check the kernel and initrd against the hashes embedded in their sections
+/// measure them instead, so that secrets sealed against PCR 11 are only released for the kernel
+/// and initrd they were sealed with.
+pub fn measure_kernel_and_initrd(kernel: &[u8], initrd: &[u8]) -> uefi::Result<u32> {
+    let mut measurements = 0;
+
+    info!("Measuring the kernel and initrd...");
+    for (data, description) in [(kernel, "Kernel"), (initrd, "Initrd")] {
+        if tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_IMAGE, data, description)? {
+            measurements += 1;
+        }
     }
 
     Ok(measurements)
 }
 
+/// Measures the anti-rollback counter embedded in the `.rollback` section, if any.
+///
+/// The counter increases with every generation, so sealing policies can refuse to unseal secrets
+/// for generations older than the one they were sealed against.
+pub fn measure_rollback_counter(image: &PeInMemory) -> uefi::Result<u32> {
+    // SAFETY: See `measure_image`.
+    let pe_binary = unsafe { image.as_slice() };
+
+    match pe_section(pe_binary, ".rollback") {
+        Some(counter) => {
+            info!("Measuring the rollback counter...");
+            Ok(
+                tpm_log_event_ascii(TPM_PCR_INDEX_ROLLBACK_COUNTER, counter, "Rollback counter")?
+                    .into(),
+            )
+        }
+        None => Ok(0),
+    }
+}
+
+/// Measures the command line passed from the bootloader into PCR 12, if it replaces the embedded
+/// one.
+///
+/// The embedded command line is already part of PCR 11 as `.cmdline` section. Like systemd-stub,
+/// the passed one is measured as it is passed, i.e. UCS-2 encoded.
+pub fn measure_cmdline(cmdline: &[u8]) -> uefi::Result<u32> {
+    info!("Measuring the command line passed from the bootloader...");
+    if !tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_CONFIG, cmdline, "Kernel command line")? {
+        return Ok(0);
+    }
+    export_kernel_config_pcr()?;
+    Ok(1)
+}
+
+/// Measures the command line parameters of addons, in the order they are appended to the
+/// command line.
+pub fn measure_addon_cmdlines(cmdlines: &[String]) -> uefi::Result<u32> {
+    let mut measurements = 0;
+
+    for cmdline in cmdlines {
+        if tpm_log_event_ascii(
+            TPM_PCR_INDEX_KERNEL_CONFIG,
+            cmdline.as_bytes(),
+            "Addon command line",
+        )? {
+            measurements += 1;
+        }
+    }
+
+    if measurements > 0 {
+        export_kernel_config_pcr()?;
+    }
+
+    Ok(measurements)
+}
+
+/// Expose a variable encoding the PCR where the kernel configuration is measured.
+fn export_kernel_config_pcr() -> uefi::Result<()> {
+    runtime::set_variable(
+        cstr16!("StubPcrKernelParameters"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &TPM_PCR_INDEX_KERNEL_CONFIG.0.to_le_bytes(),
+    )
+}
+
+/// Records a measurement that failed although a TPM is available in
+/// `LanzabooteMeasurementFailed`.
+///
+/// The PCRs are then extended only partially, so secrets sealed against them do not unseal. The
+/// variable lets userspace tell this apart from a changed boot configuration. It holds one line
+/// per failed measurement, e.g. `kernel image`, and is not persisted across reboots.
+pub fn record_measurement_failure(measurement: &str) -> uefi::Result<()> {
+    let name = cstr16!("LanzabooteMeasurementFailed");
+    let mut failures = match runtime::get_variable_boxed(name, &BOOT_LOADER_VENDOR_UUID) {
+        Ok((data, _)) => data.into_vec(),
+        Err(err) if err.status() == Status::NOT_FOUND => Vec::new(),
+        Err(err) => return Err(err.status().into()),
+    };
+    failures.extend(
+        measurement
+            .encode_utf16()
+            .chain("\n".encode_utf16())
+            .flat_map(|c| c.to_le_bytes()),
+    );
+
+    runtime::set_variable(
+        name,
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &failures,
+    )
+}
+
 /// Performs all the expected measurements for any list of
 /// companion initrds of any form.
 ///
@@ -79,7 +270,7 @@ pub fn measure_image(image: &PeInMemory) -> uefi::Result<u32> {
 /// A stable order is expected for measurement stability.
 pub fn measure_companion_initrds(companions: &[CompanionInitrd]) -> uefi::Result<u32> {
     let mut measurements = 0;
-    let mut credentials_measured = 0;
+    let mut kernel_config_measured = 0;
     let mut sysext_measured = false;
 
     for initrd in companions {
@@ -90,27 +281,37 @@ pub fn measure_companion_initrds(companions: &[CompanionInitrd]) -> uefi::Result
             CompanionInitrdType::Credentials => {
                 if tpm_log_event_ascii(
                     TPM_PCR_INDEX_KERNEL_CONFIG,
-                    initrd.cpio.as_ref(),
+                    &initrd.contents,
                     "Credentials initrd",
                 )? {
                     measurements += 1;
-                    credentials_measured += 1;
+                    kernel_config_measured += 1;
                 }
             }
             CompanionInitrdType::GlobalCredentials => {
                 if tpm_log_event_ascii(
                     TPM_PCR_INDEX_KERNEL_CONFIG,
-                    initrd.cpio.as_ref(),
+                    &initrd.contents,
                     "Global credentials initrd",
                 )? {
                     measurements += 1;
-                    credentials_measured += 1;
+                    kernel_config_measured += 1;
+                }
+            }
+            CompanionInitrdType::Addon => {
+                if tpm_log_event_ascii(
+                    TPM_PCR_INDEX_KERNEL_CONFIG,
+                    &initrd.contents,
+                    "Addon initrd",
+                )? {
+                    measurements += 1;
+                    kernel_config_measured += 1;
                 }
             }
             CompanionInitrdType::SystemExtension => {
                 if tpm_log_event_ascii(
                     TPM_PCR_INDEX_SYSEXTS,
-                    initrd.cpio.as_ref(),
+                    &initrd.contents,
                     "System extension initrd",
                 )? {
                     measurements += 1;
@@ -120,13 +321,8 @@ pub fn measure_companion_initrds(companions: &[CompanionInitrd]) -> uefi::Result
         }
     }
 
-    if credentials_measured > 0 {
-        runtime::set_variable(
-            cstr16!("StubPcrKernelParameters"),
-            &BOOT_LOADER_VENDOR_UUID,
-            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
-            &TPM_PCR_INDEX_KERNEL_CONFIG.0.to_le_bytes(),
-        )?;
+    if kernel_config_measured > 0 {
+        export_kernel_config_pcr()?;
     }
 
     if sysext_measured {
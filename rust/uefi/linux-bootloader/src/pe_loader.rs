@@ -13,6 +13,20 @@ use uefi::{
 const UEFI_PAGE_BITS: usize = 12;
 const UEFI_PAGE_MASK: usize = (1 << UEFI_PAGE_BITS) - 1;
 
+/// Base relocation types, see the "Base Relocation Types" of the PE format specification.
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// How to handle an image that contains base relocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relocations {
+    /// Refuse to load the image.
+    Reject,
+    /// Relocate the image to the address it is loaded at.
+    Apply,
+}
+
 #[cfg(target_arch = "aarch64")]
 fn make_instruction_cache_coherent(memory: &[u8]) {
     use core::arch::asm;
@@ -78,13 +92,85 @@ fn bytes_to_pages(bytes: usize) -> usize {
         .unwrap_or(1 << (usize::try_from(usize::BITS).unwrap() - UEFI_PAGE_BITS))
 }
 
+/// Apply the base relocations in the table at `table_rva` to a loaded image.
+///
+/// `delta` is the difference between the address the image is loaded at and its preferred
+/// image base. Only `IMAGE_REL_BASED_HIGHLOW` and `IMAGE_REL_BASED_DIR64` relocations are
+/// supported, `IMAGE_REL_BASED_ABSOLUTE` entries are padding.
+fn apply_base_relocations(
+    image: &mut [u8],
+    table_rva: u32,
+    table_size: u32,
+    delta: u64,
+) -> Result<(), Status> {
+    let table_start = usize::try_from(table_rva).map_err(|_| Status::LOAD_ERROR)?;
+    let table_end = usize::try_from(table_size)
+        .ok()
+        .and_then(|size| table_start.checked_add(size))
+        .ok_or(Status::LOAD_ERROR)?;
+    // The table is part of the image, so copy it before patching the image.
+    let table = image
+        .get(table_start..table_end)
+        .ok_or(Status::LOAD_ERROR)?
+        .to_vec();
+
+    let mut blocks = table.as_slice();
+    while blocks.len() >= 8 {
+        let page_rva = u32::from_le_bytes(blocks[0..4].try_into().unwrap());
+        let block_size = usize::try_from(u32::from_le_bytes(blocks[4..8].try_into().unwrap()))
+            .map_err(|_| Status::LOAD_ERROR)?;
+        if block_size < 8 || block_size > blocks.len() {
+            return Err(Status::LOAD_ERROR);
+        }
+
+        for entry in blocks[8..block_size].chunks_exact(2) {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            let offset = usize::try_from(page_rva)
+                .ok()
+                .and_then(|page| page.checked_add(usize::from(entry & 0xfff)))
+                .ok_or(Status::LOAD_ERROR)?;
+
+            match entry >> 12 {
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_HIGHLOW => {
+                    let target = offset
+                        .checked_add(4)
+                        .and_then(|end| image.get_mut(offset..end))
+                        .ok_or(Status::LOAD_ERROR)?;
+                    // Only the low 32 bits of the delta apply to 32-bit addresses.
+                    let value = u32::from_le_bytes((&*target).try_into().unwrap())
+                        .wrapping_add(delta as u32);
+                    target.copy_from_slice(&value.to_le_bytes());
+                }
+                IMAGE_REL_BASED_DIR64 => {
+                    let target = offset
+                        .checked_add(8)
+                        .and_then(|end| image.get_mut(offset..end))
+                        .ok_or(Status::LOAD_ERROR)?;
+                    let value =
+                        u64::from_le_bytes((&*target).try_into().unwrap()).wrapping_add(delta);
+                    target.copy_from_slice(&value.to_le_bytes());
+                }
+                _ => return Err(Status::UNSUPPORTED),
+            }
+        }
+
+        blocks = &blocks[block_size..];
+    }
+
+    Ok(())
+}
+
 impl Image {
     /// Loads and relocates a PE file.
     ///
+    /// Images with base relocations are only loaded if `relocations`
+    /// is [`Relocations::Apply`].
+    ///
     /// The image must be handed to [`start`] later. If this does not
     /// happen, the memory allocated for the unpacked PE binary will
     /// leak.
-    pub fn load(file_data: &[u8]) -> uefi::Result<Image> {
+    pub fn load(file_data: &[u8], relocations: Relocations) -> uefi::Result<Image> {
         let pe = PE::parse(file_data).map_err(|_| Status::LOAD_ERROR)?;
 
         // Allocate all memory the image will need in virtual memory.
@@ -132,14 +218,20 @@ impl Image {
             image[virt_start..virt_end].copy_from_slice(&file_data[raw_start..raw_end]);
         }
 
-        // Image base relocations are not supported.
-        if pe
+        // An empty relocation table does not need to be applied.
+        let relocation_table = pe
             .header
             .optional_header
             .and_then(|h| *h.data_directories.get_base_relocation_table())
-            .is_some()
-        {
-            return Err(Status::INCOMPATIBLE_VERSION.into());
+            .filter(|table| table.size > 0);
+        if let Some(table) = relocation_table {
+            match relocations {
+                Relocations::Reject => return Err(Status::INCOMPATIBLE_VERSION.into()),
+                Relocations::Apply => {
+                    let delta = (image.as_ptr() as u64).wrapping_sub(pe.image_base as u64);
+                    apply_base_relocations(image, table.virtual_address, table.size, delta)?;
+                }
+            }
         }
 
         // On some platforms, the instruction cache is not coherent with the data cache.
@@ -168,8 +260,10 @@ impl Image {
     ///   * Memory it has not allocated should not have been freed.
     ///   * Boot services must not have been exited.
     pub unsafe fn start(self, handle: Handle, load_options: &[u8]) -> Status {
-        let mut loaded_image = boot::open_protocol_exclusive::<LoadedImage>(handle)
-            .expect("Failed to open the LoadedImage protocol");
+        let mut loaded_image = match boot::open_protocol_exclusive::<LoadedImage>(handle) {
+            Ok(loaded_image) => loaded_image,
+            Err(err) => return err.status(),
+        };
 
         let (our_data, our_size) = loaded_image.info();
         let our_load_options = loaded_image
@@ -213,3 +307,80 @@ impl Image {
         status
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const PREFERRED_BASE: u64 = 0x1000_0000;
+    const TABLE_RVA: u32 = 0x1800;
+
+    /// Build an image with a 64-bit and a 32-bit pointer into itself and a relocation table
+    /// containing `entries` for the page at 0x1000.
+    fn relocatable_image(entries: &[u16]) -> Vec<u8> {
+        let mut image = vec![0u8; 0x2000];
+        image[0x1008..0x1010].copy_from_slice(&(PREFERRED_BASE + 0x1100).to_le_bytes());
+        image[0x1010..0x1014].copy_from_slice(&(PREFERRED_BASE as u32 + 0x1200).to_le_bytes());
+
+        let mut table = Vec::new();
+        table.extend_from_slice(&0x1000u32.to_le_bytes());
+        table.extend_from_slice(&(8 + 2 * entries.len() as u32).to_le_bytes());
+        for entry in entries {
+            table.extend_from_slice(&entry.to_le_bytes());
+        }
+        let start = TABLE_RVA as usize;
+        image[start..start + table.len()].copy_from_slice(&table);
+        image
+    }
+
+    fn table_size(entries: &[u16]) -> u32 {
+        8 + 2 * entries.len() as u32
+    }
+
+    #[test]
+    fn apply_dir64_and_highlow_relocations() {
+        let entries = [
+            IMAGE_REL_BASED_DIR64 << 12 | 0x008,
+            IMAGE_REL_BASED_HIGHLOW << 12 | 0x010,
+            IMAGE_REL_BASED_ABSOLUTE << 12,
+            IMAGE_REL_BASED_ABSOLUTE << 12,
+        ];
+        let mut image = relocatable_image(&entries);
+
+        apply_base_relocations(&mut image, TABLE_RVA, table_size(&entries), 0x5000).unwrap();
+
+        assert_eq!(
+            u64::from_le_bytes(image[0x1008..0x1010].try_into().unwrap()),
+            PREFERRED_BASE + 0x6100
+        );
+        assert_eq!(
+            u32::from_le_bytes(image[0x1010..0x1014].try_into().unwrap()),
+            PREFERRED_BASE as u32 + 0x6200
+        );
+    }
+
+    #[test]
+    fn reject_relocation_outside_of_image() {
+        // The 64-bit target would end 4 bytes after the end of the image.
+        let entries = [IMAGE_REL_BASED_DIR64 << 12 | 0xffc];
+        let mut image = relocatable_image(&entries);
+
+        assert_eq!(
+            apply_base_relocations(&mut image, TABLE_RVA, table_size(&entries), 0x5000),
+            Err(Status::LOAD_ERROR)
+        );
+    }
+
+    #[test]
+    fn reject_unsupported_relocation_type() {
+        // IMAGE_REL_BASED_HIGH
+        let entries = [1 << 12 | 0x008];
+        let mut image = relocatable_image(&entries);
+
+        assert_eq!(
+            apply_base_relocations(&mut image, TABLE_RVA, table_size(&entries), 0x5000),
+            Err(Status::UNSUPPORTED)
+        );
+    }
+}
@@ -4,18 +4,25 @@
 // and_then below and this can't be expressed with map.
 #![allow(clippy::bind_instead_of_map)]
 
+#[cfg(feature = "zstd")]
+use alloc::vec::Vec;
 use alloc::{borrow::ToOwned, string::String};
 use goblin::pe::section_table::SectionTable;
 
 /// Extracts the data of a section in a loaded PE file
 /// based on the section table.
+///
+/// Returns `None` if the section is not within `pe_data`, e.g.
+/// because the image size reported by the firmware is wrong.
 pub fn pe_section_data<'a>(pe_data: &'a [u8], section: &SectionTable) -> Option<&'a [u8]> {
-    let section_start: usize = section.virtual_address.try_into().ok()?;
+    if section.virtual_size > section.size_of_raw_data {
+        return None;
+    }
 
-    assert!(section.virtual_size <= section.size_of_raw_data);
-    let section_end: usize = section_start + usize::try_from(section.virtual_size).ok()?;
+    let section_start: usize = section.virtual_address.try_into().ok()?;
+    let section_end = section_start.checked_add(usize::try_from(section.virtual_size).ok()?)?;
 
-    Some(&pe_data[section_start..section_end])
+    pe_data.get(section_start..section_end)
 }
 
 /// Extracts the data of a section of a loaded PE file
@@ -30,7 +37,101 @@ pub fn pe_section<'a>(pe_data: &'a [u8], section_name: &str) -> Option<&'a [u8]>
         .and_then(|s| pe_section_data(pe_data, s))
 }
 
+/// Extracts the data of a section of a PE file as it is stored on disk, i.e. not loaded into
+/// memory, based on the section name.
+pub fn pe_file_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+    let section = pe_binary
+        .sections
+        .iter()
+        .find(|s| s.name().map(|n| n == section_name).unwrap_or(false))?;
+
+    let section_start: usize = section.pointer_to_raw_data.try_into().ok()?;
+    let section_size = u32::min(section.virtual_size, section.size_of_raw_data);
+    let section_end = section_start.checked_add(usize::try_from(section_size).ok()?)?;
+
+    file_data.get(section_start..section_end)
+}
+
 /// Extracts the data of a section of a loaded PE image and returns it as a string.
+///
+/// Returns `None` if the section is not valid UTF-8.
 pub fn pe_section_as_string<'a>(pe_data: &'a [u8], section_name: &str) -> Option<String> {
-    pe_section(pe_data, section_name).map(|data| core::str::from_utf8(data).unwrap().to_owned())
+    pe_section(pe_data, section_name)
+        .and_then(|data| core::str::from_utf8(data).ok())
+        .map(ToOwned::to_owned)
+}
+
+/// Decompresses the data of a section that consists of a single zstd frame.
+///
+/// Returns `None` if the data is not a valid zstd frame.
+#[cfg(feature = "zstd")]
+pub fn zstd_decompress(data: &[u8]) -> Option<Vec<u8>> {
+    use ruzstd::io::Read;
+
+    let mut decoder = ruzstd::decoding::StreamingDecoder::new(data).ok()?;
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed).ok()?;
+    Some(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    // Shared with the unit tests of the tool.
+    include!("../../../tool/shared/src/pe/fixtures.rs");
+
+    #[test]
+    fn read_section_of_truncated_image() {
+        let pe = pe_with_section(b".osrel\0\0", b"ID=nixos\n");
+        assert_eq!(pe_section(&pe, ".osrel"), Some(&b"ID=nixos\n"[..]));
+
+        // An image size that is too small must never lead to reads out of bounds.
+        for len in 0..pe.len() {
+            assert_eq!(pe_section(&pe[..len], ".osrel"), None, "length {len}");
+        }
+    }
+
+    #[test]
+    fn read_section_without_alignment_padding() {
+        let initrd = b"initrd";
+        let mut pe = pe_with_section(b".initrd\0", initrd);
+        // Pad the raw data of the section to the file alignment, like
+        // objcopy does. Only the virtual size is the size of the initrd.
+        pe[0x158..0x15c].copy_from_slice(&0x200u32.to_le_bytes());
+        pe.resize(0x400, 0);
+        assert_eq!(pe_section(&pe, ".initrd"), Some(&initrd[..]));
+    }
+
+    #[test]
+    fn read_section_of_file() {
+        let mut pe = pe_with_section(b".cmdline", b"quiet");
+        // Move the section in memory, so that only its position in the file is valid.
+        pe[0x154..0x158].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(pe_file_section(&pe, ".cmdline"), Some(&b"quiet"[..]));
+        assert_eq!(pe_section(&pe, ".cmdline"), None);
+    }
+
+    #[test]
+    fn reject_section_that_is_not_utf8() {
+        let pe = pe_with_section(b".special", &[0xff, 0xfe]);
+        assert_eq!(pe_section_as_string(&pe, ".special"), None);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn decompress_zstd_section() {
+        let kernel = b"kernel ".repeat(100);
+        let compressed = ruzstd::encoding::compress_to_vec(
+            kernel.as_slice(),
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        let pe = pe_with_section(b".linux\0\0", &compressed);
+        let section = pe_section(&pe, ".linux").expect("The section exists");
+        assert_eq!(zstd_decompress(section), Some(kernel));
+        assert_eq!(zstd_decompress(b"kernel"), None);
+    }
 }
@@ -0,0 +1,62 @@
+//! Verification of PE images through shim.
+//!
+//! On machines whose firmware only trusts Microsoft's keys, the stub is booted through shim,
+//! which also trusts the keys in the MOK list. The firmware's `LoadImage` does not know about
+//! these keys, so images that the stub would verify with `LoadImage`, i.e. addons, are verified
+//! through the shim lock protocol instead if shim loaded us.
+//!
+//! The kernel is not affected: the stub loads it with its own PE loader, like shim does, and it
+//! is covered by the signature of the stub, either embedded or by its hash.
+
+use log::info;
+use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
+    proto::unsafe_protocol,
+    Status, StatusExt,
+};
+
+/// Shim declares its functions with the System V calling convention on x86_64, and with the
+/// UEFI calling convention everywhere else.
+#[cfg(target_arch = "x86_64")]
+type ShimVerify = unsafe extern "sysv64" fn(buffer: *const u8, size: u32) -> Status;
+#[cfg(not(target_arch = "x86_64"))]
+type ShimVerify = unsafe extern "efiapi" fn(buffer: *const u8, size: u32) -> Status;
+
+/// The shim lock protocol, installed by shim for the images it loads.
+///
+/// Only `Verify` is used. The protocol continues with `Hash` and `Context`.
+#[repr(C)]
+#[unsafe_protocol("605dab50-e046-4300-abb6-3dd810dd8b23")]
+struct ShimLock {
+    verify: ShimVerify,
+}
+
+/// Verify the signature of the PE image `data` with shim against `db`, `dbx`, shim's vendor
+/// certificate and the MOK list.
+///
+/// Returns `None` if shim is not present, e.g. because the firmware booted us directly. Shim
+/// accepts every image if Secure Boot is not active.
+pub fn shim_verify(data: &[u8]) -> Option<uefi::Result<()>> {
+    let handle = boot::get_handle_for_protocol::<ShimLock>().ok()?;
+
+    // SAFETY: Opening the protocol with `GetProtocol` does not conflict with shim, which keeps
+    // using it, and we only call `Verify`.
+    let shim_lock = unsafe {
+        boot::open_protocol::<ShimLock>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+
+    info!("Verifying with shim.");
+    let Ok(size) = u32::try_from(data.len()) else {
+        return Some(Err(Status::BAD_BUFFER_SIZE.into()));
+    };
+    // SAFETY: Shim only reads `size` bytes from `buffer`.
+    Some(unsafe { (shim_lock.verify)(data.as_ptr(), size) }.to_result())
+}
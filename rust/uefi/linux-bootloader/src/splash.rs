@@ -0,0 +1,153 @@
+//! Drawing of the splash image embedded as `.splash` section, like systemd-stub does.
+
+use alloc::vec::Vec;
+use uefi::boot::{self, OpenProtocolAttributes, OpenProtocolParams};
+use uefi::proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput};
+use uefi::Status;
+
+/// An image decoded from an uncompressed BMP file, with its rows from top to bottom.
+struct Bitmap {
+    width: usize,
+    height: usize,
+    pixels: Vec<BltPixel>,
+}
+
+/// Decode an uncompressed BMP file with 24 or 32 bits per pixel.
+///
+/// Returns `None` for any other format or if the pixel data is truncated.
+fn parse_bmp(bmp: &[u8]) -> Option<Bitmap> {
+    let u16_at = |offset: usize| {
+        bmp.get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let u32_at = |offset: usize| {
+        bmp.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    if bmp.get(0..2)? != b"BM" || u32_at(14)? < 40 || u16_at(26)? != 1 {
+        return None;
+    }
+    let data_offset = usize::try_from(u32_at(10)?).ok()?;
+    let width = i32::from_le_bytes(u32_at(18)?.to_le_bytes());
+    let height = i32::from_le_bytes(u32_at(22)?.to_le_bytes());
+    let bytes_per_pixel = match u16_at(28)? {
+        24 => 3,
+        32 => 4,
+        _ => return None,
+    };
+    // Only BI_RGB, i.e. uncompressed pixels in BGR order.
+    if u32_at(30)? != 0 {
+        return None;
+    }
+
+    let width = usize::try_from(width).ok().filter(|&width| width > 0)?;
+    // Positive heights store the rows from bottom to top.
+    let bottom_up = height > 0;
+    let height = usize::try_from(height.unsigned_abs())
+        .ok()
+        .filter(|&h| h > 0)?;
+    let stride = (width.checked_mul(bytes_per_pixel)? + 3) & !3;
+    let data = bmp.get(data_offset..data_offset.checked_add(stride.checked_mul(height)?)?)?;
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in 0..height {
+        let row = if bottom_up { height - 1 - row } else { row };
+        let row = &data[row * stride..][..width * bytes_per_pixel];
+        pixels.extend(
+            row.chunks_exact(bytes_per_pixel)
+                .map(|pixel| BltPixel::new(pixel[2], pixel[1], pixel[0])),
+        );
+    }
+
+    Some(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Draw a BMP image centered on the screen with the Graphics Output Protocol.
+///
+/// Fails if the image cannot be decoded, there is no Graphics Output Protocol or the image does
+/// not fit on the screen.
+pub fn draw_splash(bmp: &[u8]) -> uefi::Result<()> {
+    let bitmap = parse_bmp(bmp).ok_or(Status::INVALID_PARAMETER)?;
+
+    let handle = boot::get_handle_for_protocol::<GraphicsOutput>()?;
+    // SAFETY: Opening the protocol with `GetProtocol` keeps the console drivers connected, so
+    // that the log is still printed on top of the splash.
+    let mut gop = unsafe {
+        boot::open_protocol::<GraphicsOutput>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+
+    let (screen_width, screen_height) = gop.current_mode_info().resolution();
+    if bitmap.width > screen_width || bitmap.height > screen_height {
+        return Err(Status::BAD_BUFFER_SIZE.into());
+    }
+
+    gop.blt(BltOp::BufferToVideo {
+        buffer: &bitmap.pixels,
+        src: BltRegion::Full,
+        dest: (
+            (screen_width - bitmap.width) / 2,
+            (screen_height - bitmap.height) / 2,
+        ),
+        dims: (bitmap.width, bitmap.height),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a BMP file with the given header fields and pixel data.
+    fn bmp(width: i32, height: i32, bits_per_pixel: u16, data: &[u8]) -> Vec<u8> {
+        let mut bmp = Vec::new();
+        bmp.extend(b"BM");
+        bmp.extend((54 + data.len() as u32).to_le_bytes());
+        bmp.extend([0; 4]);
+        bmp.extend(54u32.to_le_bytes());
+        bmp.extend(40u32.to_le_bytes());
+        bmp.extend(width.to_le_bytes());
+        bmp.extend(height.to_le_bytes());
+        bmp.extend(1u16.to_le_bytes());
+        bmp.extend(bits_per_pixel.to_le_bytes());
+        bmp.extend([0; 24]);
+        bmp.extend(data);
+        bmp
+    }
+
+    fn rgb(pixel: &BltPixel) -> (u8, u8, u8) {
+        (pixel.red, pixel.green, pixel.blue)
+    }
+
+    #[test]
+    fn decode_bottom_up_rows_with_padding() {
+        // Two rows of a single BGR pixel each, padded to four bytes.
+        let data = [3, 2, 1, 0, 6, 5, 4, 0];
+        let bitmap = parse_bmp(&bmp(1, 2, 24, &data)).unwrap();
+        assert_eq!((bitmap.width, bitmap.height), (1, 2));
+        let pixels: Vec<_> = bitmap.pixels.iter().map(rgb).collect();
+        assert_eq!(pixels, [(4, 5, 6), (1, 2, 3)]);
+
+        let bitmap = parse_bmp(&bmp(1, -2, 32, &[3, 2, 1, 0, 6, 5, 4, 0])).unwrap();
+        let pixels: Vec<_> = bitmap.pixels.iter().map(rgb).collect();
+        assert_eq!(pixels, [(1, 2, 3), (4, 5, 6)]);
+    }
+
+    #[test]
+    fn reject_unsupported_bmp() {
+        assert!(parse_bmp(&bmp(1, 2, 24, &[3, 2, 1, 0])).is_none());
+        assert!(parse_bmp(&bmp(1, 1, 8, &[0, 0, 0, 0])).is_none());
+        assert!(parse_bmp(&bmp(0, 1, 24, &[])).is_none());
+        assert!(parse_bmp(b"BM").is_none());
+    }
+}
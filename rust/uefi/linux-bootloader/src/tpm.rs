@@ -2,7 +2,7 @@ use alloc::vec::Vec;
 use log::warn;
 use uefi::{
     boot::{self, ScopedProtocol},
-    proto::tcg::{v2, EventType, PcrIndex},
+    proto::tcg::{v2, EventType, HashAlgorithm, PcrIndex},
     ResultExt,
 };
 
@@ -32,6 +32,34 @@ pub fn tpm_available() -> bool {
     open_capable_tpm2().is_ok()
 }
 
+/// The PCR banks, i.e. hash algorithms, that are currently active in the TPM.
+///
+/// The firmware extends every active bank on each measurement, so this is also the set of banks
+/// that the measurements of the stub end up in.
+pub fn tpm_active_pcr_banks() -> uefi::Result<HashAlgorithm> {
+    let mut tpm2 = open_capable_tpm2()?;
+    // Older firmware does not implement the call, but reports the banks in its capabilities.
+    match tpm2.get_active_pcr_banks() {
+        Ok(banks) => Ok(banks),
+        Err(_) => Ok(tpm2.get_capability()?.active_pcr_banks),
+    }
+}
+
+/// The names of the PCR banks in `banks`, as used by systemd, e.g. `sha256`.
+pub fn pcr_bank_names(banks: HashAlgorithm) -> Vec<&'static str> {
+    [
+        (HashAlgorithm::SHA1, "sha1"),
+        (HashAlgorithm::SHA256, "sha256"),
+        (HashAlgorithm::SHA384, "sha384"),
+        (HashAlgorithm::SHA512, "sha512"),
+        (HashAlgorithm::SM3_256, "sm3_256"),
+    ]
+    .into_iter()
+    .filter(|(bank, _)| banks.contains(*bank))
+    .map(|(_, name)| name)
+    .collect()
+}
+
 /// Log an event in the TPM with `buffer` as data.
 /// Returns a boolean whether the measurement has been done or not in case of success.
 pub fn tpm_log_event_ascii(
@@ -51,8 +79,54 @@ pub fn tpm_log_event_ascii(
         let event = v2::PcrEventInputs::new_in_box(pcr_index, EventType::IPL, &description_encoded)
             .discard_errdata()?;
         // FIXME: what do we want as flags here?
+        // The firmware hashes the data for and extends each active PCR bank, see
+        // `tpm_active_pcr_banks`.
         tpm2.hash_log_extend_event(Default::default(), buffer, &event)?;
     }
 
     Ok(true)
 }
+
+/// Log the loading of the PE image `pe_file` in the TPM, like the firmware does for the images
+/// it loads.
+///
+/// The firmware computes the Authenticode hash of the image, i.e. the same digest the Secure
+/// Boot databases refer to, instead of hashing `pe_file` as a whole.
+/// Returns a boolean whether the measurement has been done or not in case of success.
+pub fn tpm_log_pe_image(
+    pcr_index: PcrIndex,
+    pe_file: &[u8],
+    description: &str,
+) -> uefi::Result<bool> {
+    if let Ok(mut tpm2) = open_capable_tpm2() {
+        let description_encoded = description
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let event = v2::PcrEventInputs::new_in_box(
+            pcr_index,
+            EventType::EFI_BOOT_SERVICES_APPLICATION,
+            &description_encoded,
+        )
+        .discard_errdata()?;
+        tpm2.hash_log_extend_event(v2::HashLogExtendEventFlags::PE_COFF_IMAGE, pe_file, &event)?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_pcr_banks() {
+        assert_eq!(pcr_bank_names(HashAlgorithm::SHA256), ["sha256"]);
+        assert_eq!(
+            pcr_bank_names(HashAlgorithm::SHA256 | HashAlgorithm::SHA384),
+            ["sha256", "sha384"]
+        );
+        assert!(pcr_bank_names(HashAlgorithm::empty()).is_empty());
+    }
+}
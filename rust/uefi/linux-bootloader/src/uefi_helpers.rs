@@ -1,14 +1,20 @@
+use alloc::vec::Vec;
 use core::ffi::c_void;
 
 use uefi::{
-    boot,
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType},
+    guid,
     proto::{
+        console::serial::Serial,
         device_path::{DevicePath, FfiDevicePath},
         loaded_image::LoadedImage,
+        media::{fs::SimpleFileSystem, partition::PartitionInfo},
     },
-    Result,
+    system, Guid, Handle, Result, ResultExt, Status,
 };
 
+use crate::efivars::disk_get_part_uuid;
+
 #[derive(Debug, Clone, Copy)]
 pub struct PeInMemory {
     image_device_path: Option<*const FfiDevicePath>,
@@ -60,3 +66,89 @@ pub fn booted_image_file() -> Result<PeInMemory> {
         image_size: usize::try_from(image_size).map_err(|_| uefi::Status::INVALID_PARAMETER)?,
     })
 }
+
+/// Open the file system on the GPT partition with the unique partition GUID `part_uuid`, e.g. an
+/// XBOOTLDR partition.
+pub fn partition_file_system(part_uuid: Guid) -> Result<ScopedProtocol<SimpleFileSystem>> {
+    let handles = boot::locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>())?;
+    let handle = handles
+        .iter()
+        .find(|handle| matches!(disk_get_part_uuid(**handle), Ok(uuid) if uuid == part_uuid))
+        .ok_or(Status::NOT_FOUND)?;
+
+    boot::open_protocol_exclusive::<SimpleFileSystem>(*handle)
+}
+
+/// Partition type GUID of EFI system partitions.
+pub const ESP_PARTITION_TYPE_GUID: Guid = guid!("c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+
+/// Partition type GUID of extended boot loader (XBOOTLDR) partitions, see
+/// <https://uapi-group.org/specifications/specs/discoverable_partitions_specification/>.
+pub const XBOOTLDR_PARTITION_TYPE_GUID: Guid = guid!("bc13c2ff-59e6-4262-a352-b275fd6f7172");
+
+/// Find the handles of all GPT partitions with the partition type GUID `type_guid`, e.g.
+/// [`XBOOTLDR_PARTITION_TYPE_GUID`].
+///
+/// The `HardDrive` nodes of the device paths only carry the unique partition GUID, not the type.
+/// The type is read from the partition info protocol instead, which firmware provides since
+/// UEFI 2.7. On older firmware, no partition is found.
+pub fn find_partition_by_type(type_guid: Guid) -> Result<Vec<Handle>> {
+    let handles = boot::locate_handle_buffer(SearchType::from_proto::<PartitionInfo>())?;
+    Ok(handles
+        .iter()
+        .copied()
+        .filter(|handle| gpt_partition_type(*handle) == Some(type_guid))
+        .collect())
+}
+
+/// The partition type GUID of a GPT partition, `None` for other partitions.
+fn gpt_partition_type(handle: Handle) -> Option<Guid> {
+    // SAFETY: Opening the protocol with `GetProtocol` does not disconnect the drivers of the
+    // partition, and the partition info is only read.
+    let info = unsafe {
+        boot::open_protocol::<PartitionInfo>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?
+    };
+    let entry = info.gpt_partition_entry()?;
+    Some(entry.partition_type_guid.0)
+}
+
+/// Write a message to the first serial device, if there is one.
+///
+/// Firmware often mirrors the console to the serial port, but not always, and the serial log
+/// may be all that is left of a failed boot on a headless machine.
+pub fn write_to_serial(message: &str) -> Result<()> {
+    let handle = boot::get_handle_for_protocol::<Serial>()?;
+
+    // SAFETY: Opening the protocol with `GetProtocol` does not disconnect the console drivers
+    // that may also use the serial device, and we only use it for a single write.
+    let mut serial = unsafe {
+        boot::open_protocol::<Serial>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+
+    serial.write(message.as_bytes()).discard_errdata()
+}
+
+/// Block until a key is pressed on the console.
+pub fn wait_for_keypress() -> Result<()> {
+    system::with_stdin(|stdin| {
+        stdin.reset(false)?;
+        let event = stdin.wait_for_key_event().ok_or(Status::UNSUPPORTED)?;
+        boot::wait_for_event(&mut [event]).discard_errdata()?;
+        Ok(())
+    })
+}
@@ -1,9 +1,15 @@
+use alloc::vec::Vec;
+use uefi::proto::tcg::PcrIndex;
+
+use crate::measure::TPM_PCR_INDEX_KERNEL_IMAGE;
+
 /// List of PE sections that have a special meaning with respect to
 /// UKI specification.
 /// This is the canonical order in which they are measured into TPM
 /// PCR 11.
 /// !!! DO NOT REORDER !!!
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum UnifiedSection {
     Linux = 0,
     OsRel = 1,
@@ -33,8 +39,84 @@ impl TryFrom<&str> for UnifiedSection {
 }
 
 impl UnifiedSection {
-    /// Whether this section should be measured into TPM.
-    pub fn should_be_measured(&self) -> bool {
-        !matches!(self, UnifiedSection::PcrSig)
+    /// The PCR this section is measured into, `None` if it is not measured.
+    ///
+    /// Like systemd-stub, all sections that make up the image are measured into PCR 11,
+    /// including `.osrel` and the embedded `.cmdline`. The signature of the expected PCR values
+    /// cannot be part of them.
+    pub fn pcr_index(&self) -> Option<PcrIndex> {
+        match self {
+            UnifiedSection::PcrSig => None,
+            _ => Some(TPM_PCR_INDEX_KERNEL_IMAGE),
+        }
+    }
+
+    /// Picks the unified sections out of `sections` and sorts them in the canonical order.
+    ///
+    /// `sections` are pairs of a section name and a value that is passed along, e.g. the section
+    /// header. Sections with the same name keep their order.
+    pub fn in_canonical_order<'a, T>(
+        sections: impl IntoIterator<Item = (&'a str, T)>,
+    ) -> Vec<(Self, &'a str, T)> {
+        let mut unified_sections: Vec<_> = sections
+            .into_iter()
+            .filter_map(|(name, value)| {
+                Self::try_from(name)
+                    .ok()
+                    .map(|unified_section| (unified_section, name, value))
+            })
+            .collect();
+        unified_sections.sort_by_key(|(unified_section, ..)| *unified_section);
+        unified_sections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measure_sections_into_expected_pcrs() {
+        for (name, pcr_index) in [
+            (".linux", Some(11)),
+            (".osrel", Some(11)),
+            (".cmdline", Some(11)),
+            (".initrd", Some(11)),
+            (".splash", Some(11)),
+            (".dtb", Some(11)),
+            (".pcrsig", None),
+            (".pcrpkey", Some(11)),
+        ] {
+            let section = UnifiedSection::try_from(name).unwrap();
+            assert_eq!(section.pcr_index().map(|pcr| pcr.0), pcr_index, "{name}");
+        }
+        assert!(UnifiedSection::try_from(".rollback").is_err());
+    }
+
+    #[test]
+    fn sort_sections_in_canonical_order() {
+        let sections = [
+            (".text", 0),
+            (".initrd", 1),
+            (".pcrpkey", 2),
+            (".rollback", 3),
+            (".osrel", 4),
+            (".linux", 5),
+            (".osrel", 6),
+        ];
+        let names_and_values: Vec<_> = UnifiedSection::in_canonical_order(sections)
+            .into_iter()
+            .map(|(_, name, value)| (name, value))
+            .collect();
+        assert_eq!(
+            names_and_values,
+            [
+                (".linux", 5),
+                (".osrel", 4),
+                (".osrel", 6),
+                (".initrd", 1),
+                (".pcrpkey", 2),
+            ]
+        );
     }
 }
@@ -17,3 +17,21 @@ pub enum CPIOError<IOError: embedded_io::Error + core::fmt::Debug> {
     #[snafu(display("An IO error was encountered: {src:?}"))]
     IOError { src: IOError },
 }
+
+#[derive(Debug, Snafu, Clone, PartialEq, Eq)]
+pub enum CPIOReadError {
+    #[snafu(display("Entry at offset {offset} does not start with a newc or crc magic number"))]
+    InvalidMagic { offset: usize },
+    #[snafu(display("Entry at offset {offset} has a malformed header field"))]
+    InvalidHeaderField { offset: usize },
+    #[snafu(display("Entry at offset {offset} has a name that is empty or not NUL-terminated"))]
+    InvalidNameSize { offset: usize },
+    #[snafu(display("Entry at offset {offset} is not padded to a 4-bytes boundary with zeros"))]
+    InvalidPadding { offset: usize },
+    #[snafu(display("Entry at offset {offset} does not match its checksum"))]
+    ChecksumMismatch { offset: usize },
+    #[snafu(display("Entry at offset {offset} is truncated"))]
+    Truncated { offset: usize },
+    #[snafu(display("The CPIO archive ends without a trailer"))]
+    MissingTrailer,
+}
@@ -1,7 +1,7 @@
 #![no_std]
 extern crate alloc;
 
-pub mod cursor;
 pub mod errors;
+pub mod reader;
 pub mod writer;
 // pub mod packer;
@@ -0,0 +1,201 @@
+use crate::errors::CPIOReadError;
+use crate::writer::{
+    align, checksum, compute_pad4, MAGIC_NUMBER, MAGIC_NUMBER_CRC, STATIC_HEADER_LEN, TRAILER_NAME,
+};
+
+pub type Result<V> = core::result::Result<V, CPIOReadError>;
+
+/// An entry of a CPIO archive, borrowing its name and contents from the archive.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CpioEntry<'a> {
+    /// The name without its terminating NUL byte.
+    pub name: &'a [u8],
+    pub ino: u32,
+    pub mode: u32,
+    pub data: &'a [u8],
+    /// The checksum of the contents in the "crc" format, `None` in the "newc" format.
+    pub check: Option<u32>,
+}
+
+/// A reader iterating over the entries of an in-memory "newc" (070701) or "crc" (070702) CPIO
+/// archive.
+///
+/// Like the Linux initramfs unpacker, this accepts several archives concatenated with zero
+/// padding in between. The trailers separating them are not returned. Also like the unpacker,
+/// the iteration ends at the first segment after a trailer that is not a CPIO archive, e.g. a
+/// compressed initrd after an uncompressed early microcode archive.
+///
+/// Every entry is validated before it is returned. After the first error, the iteration stops.
+pub struct CpioReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    /// Whether the last entry read was a trailer, i.e. the input may end here.
+    at_trailer: bool,
+    /// Whether a trailer was read, i.e. anything that follows may be a compressed archive.
+    read_trailer: bool,
+    failed: bool,
+}
+
+impl<'a> CpioReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            at_trailer: true,
+            read_trailer: false,
+            failed: false,
+        }
+    }
+
+    /// Parse a header field, i.e. 8 hexadecimal digits.
+    fn word(&self, header: &[u8], index: usize) -> Result<u32> {
+        let field = &header[6 + 8 * index..][..8];
+        if !field.iter().all(u8::is_ascii_hexdigit) {
+            return Err(CPIOReadError::InvalidHeaderField {
+                offset: self.offset,
+            });
+        }
+        // The digits were checked to be ASCII.
+        u32::from_str_radix(core::str::from_utf8(field).unwrap(), 16).map_err(|_err| {
+            CPIOReadError::InvalidHeaderField {
+                offset: self.offset,
+            }
+        })
+    }
+
+    /// Check that `end` is followed by the zero padding to the next 4-bytes boundary and return
+    /// the offset after it.
+    fn skip_padding(&self, end: usize) -> Result<usize> {
+        let padded_end = align::<4>(end);
+        let padding = self
+            .data
+            .get(end..padded_end)
+            .ok_or(CPIOReadError::Truncated {
+                offset: self.offset,
+            })?;
+        if padding != compute_pad4(end).unwrap_or_default() {
+            return Err(CPIOReadError::InvalidPadding {
+                offset: self.offset,
+            });
+        }
+        Ok(padded_end)
+    }
+
+    /// Read the entry at the current offset and advance past it.
+    fn read_entry(&mut self) -> Result<CpioEntry<'a>> {
+        let truncated = CPIOReadError::Truncated {
+            offset: self.offset,
+        };
+        let header = self
+            .data
+            .get(self.offset..)
+            .and_then(|data| data.get(..STATIC_HEADER_LEN))
+            .ok_or(truncated.clone())?;
+
+        let crc = match header[..6].try_into() {
+            Ok(MAGIC_NUMBER) => false,
+            Ok(MAGIC_NUMBER_CRC) => true,
+            _ => {
+                return Err(CPIOReadError::InvalidMagic {
+                    offset: self.offset,
+                })
+            }
+        };
+
+        let ino = self.word(header, 0)?;
+        let mode = self.word(header, 1)?;
+        let file_size = self.word(header, 6)? as usize;
+        let name_size = self.word(header, 11)? as usize;
+        let check = self.word(header, 12)?;
+
+        let name_start = self.offset + STATIC_HEADER_LEN;
+        let name_end = name_start.checked_add(name_size).ok_or(truncated.clone())?;
+        let name = match self
+            .data
+            .get(name_start..name_end)
+            .ok_or(truncated.clone())?
+        {
+            [name @ .., 0] if !name.contains(&0) => name,
+            _ => {
+                return Err(CPIOReadError::InvalidNameSize {
+                    offset: self.offset,
+                })
+            }
+        };
+
+        let data_start = self.skip_padding(name_end)?;
+        let data_end = data_start.checked_add(file_size).ok_or(truncated.clone())?;
+        let data = self.data.get(data_start..data_end).ok_or(truncated)?;
+        if crc && checksum(data) != check {
+            return Err(CPIOReadError::ChecksumMismatch {
+                offset: self.offset,
+            });
+        }
+
+        self.offset = self.skip_padding(data_end)?;
+
+        Ok(CpioEntry {
+            name,
+            ino,
+            mode,
+            data,
+            check: crc.then_some(check),
+        })
+    }
+
+    /// Skip the zero padding between two concatenated archives.
+    ///
+    /// Returns whether there is another CPIO archive.
+    fn skip_archive_padding(&mut self) -> Result<bool> {
+        let zeros = self.data[self.offset..]
+            .iter()
+            .take_while(|byte| **byte == 0)
+            .count();
+        self.offset += zeros;
+        if self.offset == self.data.len() {
+            return Ok(false);
+        }
+        // The kernel decompresses everything that is not a CPIO archive, which we cannot check.
+        if self.read_trailer && !self.data[self.offset..].starts_with(b"0707") {
+            return Ok(false);
+        }
+        if self.offset % 4 != 0 {
+            return Err(CPIOReadError::InvalidPadding {
+                offset: self.offset,
+            });
+        }
+        Ok(true)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<CpioEntry<'a>>> {
+        loop {
+            if self.at_trailer {
+                if !self.skip_archive_padding()? {
+                    return Ok(None);
+                }
+            } else if self.offset == self.data.len() {
+                return Err(CPIOReadError::MissingTrailer);
+            }
+
+            let entry = self.read_entry()?;
+            self.at_trailer = entry.name == TRAILER_NAME.as_bytes();
+            self.read_trailer |= self.at_trailer;
+            if !self.at_trailer {
+                return Ok(Some(entry));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for CpioReader<'a> {
+    type Item = Result<CpioEntry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.next_entry();
+        self.failed = entry.is_err();
+        entry.transpose()
+    }
+}
@@ -1,6 +1,7 @@
-use core::marker::PhantomData;
+use core::{convert::Infallible, marker::PhantomData};
 
 use alloc::{
+    collections::BTreeSet,
     format,
     string::{String, ToString},
     vec,
@@ -8,10 +9,11 @@ use alloc::{
 };
 use embedded_io::Write;
 
-use crate::{cursor::Cursor, errors::CPIOError};
+use crate::errors::CPIOError;
 
-const MAGIC_NUMBER: &[u8; 6] = b"070701";
-const TRAILER_NAME: &str = "TRAILER!!!";
+pub(crate) const MAGIC_NUMBER: &[u8; 6] = b"070701";
+pub(crate) const MAGIC_NUMBER_CRC: &[u8; 6] = b"070702";
+pub const TRAILER_NAME: &str = "TRAILER!!!";
 
 pub type Result<V, IOError> = core::result::Result<V, CPIOError<IOError>>;
 
@@ -28,15 +30,17 @@ struct Entry {
     dev_minor: u32,
     rdev_major: u32,
     rdev_minor: u32,
+    /// The checksum of the file contents in the "crc" format, `None` in the "newc" format.
+    check: Option<u32>,
 }
 
-const STATIC_HEADER_LEN: usize = 6 // c_magic[6]
+pub(crate) const STATIC_HEADER_LEN: usize = 6 // c_magic[6]
     + (8 * 13); // c_ino, c_mode, c_uid, c_gid, c_nlink, c_mtime, c_filesize, c_devmajor,
                 // c_devminor, c_rdevmajor, c_rdevminor, c_namesize, c_check, all of them being &[u8; 8].
 
 /// Compute the necessary padding based on the provided length
 /// It returns None if no padding is necessary.
-fn compute_pad4(len: usize) -> Option<Vec<u8>> {
+pub(crate) fn compute_pad4(len: usize) -> Option<Vec<u8>> {
     let overhang = len % 4;
     if overhang != 0 {
         let repeat = 4 - overhang;
@@ -47,7 +51,7 @@ fn compute_pad4(len: usize) -> Option<Vec<u8>> {
 }
 
 /// Align on N-byte boundary a value.
-fn align<const A: usize>(value: usize) -> usize {
+pub(crate) fn align<const A: usize>(value: usize) -> usize {
     // Assert if A is a power of 2.
     // assert!(A & (A - 1) == 0);
 
@@ -58,6 +62,12 @@ fn align<const A: usize>(value: usize) -> usize {
     }
 }
 
+/// Size of the entry of a file at `path` with `contents_len` bytes of contents in an archive,
+/// e.g. to allocate the sink of a [`CpioWriter`] once.
+pub fn entry_size(path: &str, contents_len: usize) -> usize {
+    align::<4>(STATIC_HEADER_LEN + path.len() + 1).saturating_add(align::<4>(contents_len))
+}
+
 trait WriteBytesExt: Write {
     fn write_cpio_word(&mut self, word: u32) -> core::result::Result<(), Self::Error> {
         // A CPIO word is the hex(word) written as chars.
@@ -66,7 +76,11 @@ trait WriteBytesExt: Write {
 
     fn write_cpio_header(&mut self, entry: Entry) -> core::result::Result<usize, Self::Error> {
         let mut header_size = STATIC_HEADER_LEN;
-        self.write_all(MAGIC_NUMBER)?;
+        self.write_all(if entry.check.is_some() {
+            MAGIC_NUMBER_CRC
+        } else {
+            MAGIC_NUMBER
+        })?;
         self.write_cpio_word(entry.ino)?;
         self.write_cpio_word(entry.mode)?;
         self.write_cpio_word(entry.uid)?;
@@ -83,7 +97,7 @@ trait WriteBytesExt: Write {
                 .try_into()
                 .expect("Filename cannot be longer than a 32-bits size"),
         )?;
-        self.write_cpio_word(0u32)?; // CRC
+        self.write_cpio_word(entry.check.unwrap_or(0))?; // CRC
         self.write_all(entry.name.as_bytes())?;
         header_size += entry.name.len();
         self.write(&[0u8])?; // Write \0 for the string.
@@ -131,43 +145,68 @@ trait WriteBytesExt: Write {
 
 impl<W: Write + ?Sized> WriteBytesExt for W {}
 
-/// A CPIO archive with convenience methods
-/// to pack a file hierarchy inside.
-pub struct Cpio<IOError: embedded_io::Error + core::fmt::Debug> {
-    buffer: Vec<u8>,
+/// Compute the checksum of the "crc" CPIO format.
+///
+/// Despite its name, this format does not use a CRC but the sum of all bytes of the file
+/// contents, truncated to 32 bits. This is also what the Linux initramfs unpacker verifies.
+pub(crate) fn checksum(contents: &[u8]) -> u32 {
+    contents
+        .iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(u32::from(*byte)))
+}
+
+/// A CPIO archive writer streaming the entries into a sink
+/// with convenience methods to pack a file hierarchy inside.
+///
+/// Nothing is buffered: every entry is written to the sink as soon as it is packed.
+pub struct CpioWriter<W: Write> {
+    sink: W,
+    /// Number of bytes written to the sink so far.
+    written: usize,
     inode_counter: u32,
-    _error: PhantomData<IOError>,
+    /// Paths of the directories already packed in the archive.
+    packed_dirs: BTreeSet<String>,
+    /// Whether to produce a "crc" (070702) archive instead of a "newc" (070701) archive.
+    crc: bool,
 }
 
-impl<I: embedded_io::Error + core::fmt::Debug> From<Cpio<I>> for Vec<u8> {
-    fn from(value: Cpio<I>) -> Self {
-        value.into_inner()
+impl<W: Write> CpioWriter<W>
+where
+    W::Error: core::fmt::Debug,
+{
+    pub fn new(sink: W) -> Self {
+        Self {
+            sink,
+            written: 0,
+            inode_counter: 0,
+            packed_dirs: BTreeSet::new(),
+            crc: false,
+        }
     }
-}
 
-impl<I: embedded_io::Error + core::fmt::Debug> AsRef<[u8]> for Cpio<I> {
-    fn as_ref(&self) -> &[u8] {
-        self.buffer.as_ref()
+    /// Create a writer producing an archive in the "crc" (070702) format.
+    ///
+    /// Every file entry carries a checksum of its contents, which the Linux kernel verifies
+    /// when unpacking the archive.
+    pub fn new_with_crc(sink: W) -> Self {
+        Self {
+            crc: true,
+            ..Self::new(sink)
+        }
     }
-}
 
-impl<IOError: embedded_io::Error + core::fmt::Debug> Default for Cpio<IOError> {
-    fn default() -> Self {
-        Self::new()
+    /// The checksum to store for an entry with `contents`.
+    fn check(&self, contents: &[u8]) -> Option<u32> {
+        self.crc.then(|| checksum(contents))
     }
-}
 
-impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
-    pub fn new() -> Self {
-        Self {
-            buffer: Vec::new(),
-            inode_counter: 0,
-            _error: PhantomData,
-        }
+    /// Number of bytes written to the sink so far.
+    pub fn written(&self) -> usize {
+        self.written
     }
 
-    pub fn into_inner(self) -> Vec<u8> {
-        self.buffer
+    pub fn into_inner(self) -> W {
+        self.sink
     }
 
     /// Pack inside the archive a file named `fname` containing `contents` under
@@ -179,7 +218,7 @@ impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
         contents: &[u8],
         target_dir_prefix: &str,
         access_mode: u32,
-    ) -> Result<usize, IOError> {
+    ) -> Result<usize, W::Error> {
         // cpio cannot deal with > 32 bits file sizes
         // SAFETY: u32::MAX as usize can wrap if usize < u32.
         // hopefully, I will never encounter a usize = u16 in the wild.
@@ -231,17 +270,16 @@ impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
 
         current_len += aligned_contents_len;
 
-        if self.buffer.len() > usize::MAX - current_len {
+        if self.written > usize::MAX - current_len {
             return Err(CPIOError::MaximumArchiveReached);
         }
 
-        // Perform re-allocation now.
-        let mut cur = Cursor::new(Vec::with_capacity(current_len));
-
         self.inode_counter += 1;
         // TODO: perform the concat properly
         // transform fname to string
-        let written = cur
+        let check = self.check(contents);
+        let written = self
+            .sink
             .write_cpio_entry(
                 Entry {
                     name: if !target_dir_prefix.is_empty() {
@@ -261,17 +299,23 @@ impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
                     dev_minor: 0,
                     rdev_major: 0,
                     rdev_minor: 0,
+                    check,
                 },
                 contents,
             )
-            .unwrap(); // This is infallible as long as allocation is not failible.
-
-        // Concat the element buffer.
-        self.buffer.append(cur.get_mut());
+            .map_err(|src| CPIOError::IOError { src })?;
+        self.written += written;
 
         Ok(written)
     }
-    pub fn pack_dir(&mut self, path: &str, access_mode: u32) -> Result<(), IOError> {
+
+    /// Pack inside the archive a directory at `path` with access mode specified by `access_mode`.
+    /// Directories that were already packed are skipped, so this is idempotent.
+    pub fn pack_dir(&mut self, path: &str, access_mode: u32) -> Result<(), W::Error> {
+        if self.packed_dirs.contains(path) {
+            return Ok(());
+        }
+
         // cpio cannot deal with > 2^32 - 1 inodes neither
         if self.inode_counter == u32::MAX {
             return Err(CPIOError::MaximumInodesReached);
@@ -286,36 +330,39 @@ impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
 
         // Align the whole header
         current_len = align::<4>(current_len);
-        if self.buffer.len() == usize::MAX || self.buffer.len() > usize::MAX - current_len {
+        if self.written == usize::MAX || self.written > usize::MAX - current_len {
             return Err(CPIOError::MaximumArchiveReached);
         }
 
-        let mut cur = Cursor::new(Vec::with_capacity(current_len));
-
         self.inode_counter += 1;
-        cur.write_cpio_header(Entry {
-            name: path.into(),
-            ino: self.inode_counter,
-            mode: access_mode | 0o040000, // S_IFDIR
-            uid: 0,
-            gid: 0,
-            nlink: 1,
-            mtime: 0,
-            file_size: 0,
-            dev_major: 0,
-            dev_minor: 0,
-            rdev_major: 0,
-            rdev_minor: 0,
-        })
-        .unwrap(); // This is infallible as long as allocation is not failible.
-
-        // Concat the element buffer.
-        self.buffer.append(cur.get_mut());
+        let check = self.check(&[]);
+        let written = self
+            .sink
+            .write_cpio_header(Entry {
+                name: path.into(),
+                ino: self.inode_counter,
+                mode: access_mode | 0o040000, // S_IFDIR
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                mtime: 0,
+                file_size: 0,
+                dev_major: 0,
+                dev_minor: 0,
+                rdev_major: 0,
+                rdev_minor: 0,
+                check,
+            })
+            .map_err(|src| CPIOError::IOError { src })?;
+        self.written += written;
+        self.packed_dirs.insert(path.into());
 
         Ok(())
     }
 
-    pub fn pack_prefix(&mut self, path: &str, dir_mode: u32) -> Result<(), IOError> {
+    /// Pack all directories leading to `path`, including itself.
+    /// Directories already present in the archive are not packed again.
+    pub fn pack_prefix(&mut self, path: &str, dir_mode: u32) -> Result<(), W::Error> {
         // TODO: bring Unix paths inside this crate?
         // and just reuse &Path there and iterate over ancestors().rev()?
         let mut ancestor = String::new();
@@ -340,7 +387,108 @@ impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
         self.pack_dir(&(ancestor + "/" + last), dir_mode)
     }
 
-    pub fn pack_trailer(&mut self) -> Result<usize, IOError> {
+    pub fn pack_trailer(&mut self) -> Result<usize, W::Error> {
         self.pack_one(TRAILER_NAME, b"", "", 0)
     }
 }
+
+/// Convert the error of an in-memory archive, which cannot contain IO errors.
+fn from_infallible<IOError: embedded_io::Error + core::fmt::Debug>(
+    error: CPIOError<Infallible>,
+) -> CPIOError<IOError> {
+    match error {
+        CPIOError::TooLargeFileSize { got } => CPIOError::TooLargeFileSize { got },
+        CPIOError::MaximumInodesReached => CPIOError::MaximumInodesReached,
+        CPIOError::MaximumArchiveReached => CPIOError::MaximumArchiveReached,
+        CPIOError::InsufficientBufferSize { expected, got } => {
+            CPIOError::InsufficientBufferSize { expected, got }
+        }
+        CPIOError::IOError { src } => match src {},
+    }
+}
+
+/// An in-memory CPIO archive with convenience methods
+/// to pack a file hierarchy inside.
+///
+/// This is a `CpioWriter` writing into a `Vec`.
+pub struct Cpio<IOError: embedded_io::Error + core::fmt::Debug> {
+    writer: CpioWriter<Vec<u8>>,
+    _error: PhantomData<IOError>,
+}
+
+impl<I: embedded_io::Error + core::fmt::Debug> From<Cpio<I>> for Vec<u8> {
+    fn from(value: Cpio<I>) -> Self {
+        value.into_inner()
+    }
+}
+
+impl<I: embedded_io::Error + core::fmt::Debug> AsRef<[u8]> for Cpio<I> {
+    fn as_ref(&self) -> &[u8] {
+        self.writer.sink.as_ref()
+    }
+}
+
+impl<IOError: embedded_io::Error + core::fmt::Debug> Default for Cpio<IOError> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<IOError: embedded_io::Error + core::fmt::Debug> Cpio<IOError> {
+    pub fn new() -> Self {
+        Self {
+            writer: CpioWriter::new(Vec::new()),
+            _error: PhantomData,
+        }
+    }
+
+    /// Create an archive in the "crc" (070702) format.
+    ///
+    /// Every file entry carries a checksum of its contents, which the Linux kernel verifies
+    /// when unpacking the archive.
+    pub fn new_with_crc() -> Self {
+        Self {
+            writer: CpioWriter::new_with_crc(Vec::new()),
+            _error: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.writer.into_inner()
+    }
+
+    /// Pack inside the archive a file named `fname` containing `contents` under
+    /// `target_dir_prefix` hierarchy of files with access mode specified by `access_mode`.
+    /// It may return IO errors or error specific to the CPIO archives.
+    pub fn pack_one(
+        &mut self,
+        fname: &str,
+        contents: &[u8],
+        target_dir_prefix: &str,
+        access_mode: u32,
+    ) -> Result<usize, IOError> {
+        self.writer
+            .pack_one(fname, contents, target_dir_prefix, access_mode)
+            .map_err(from_infallible)
+    }
+
+    /// Pack inside the archive a directory at `path` with access mode specified by `access_mode`.
+    /// Directories that were already packed are skipped, so this is idempotent.
+    pub fn pack_dir(&mut self, path: &str, access_mode: u32) -> Result<(), IOError> {
+        self.writer
+            .pack_dir(path, access_mode)
+            .map_err(from_infallible)
+    }
+
+    /// Pack all directories leading to `path`, including itself.
+    /// Directories already present in the archive are not packed again.
+    pub fn pack_prefix(&mut self, path: &str, dir_mode: u32) -> Result<(), IOError> {
+        self.writer
+            .pack_prefix(path, dir_mode)
+            .map_err(from_infallible)
+    }
+
+    pub fn pack_trailer(&mut self) -> Result<usize, IOError> {
+        self.writer.pack_trailer().map_err(from_infallible)
+    }
+}
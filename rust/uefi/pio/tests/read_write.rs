@@ -3,8 +3,12 @@ use std::{
     io::{stdout, Cursor, Write},
 };
 
-use cpio::NewcReader;
-use pio::writer::Cpio;
+use cpio::{NewcBuilder, NewcReader};
+use pio::{
+    errors::{CPIOError, CPIOReadError},
+    reader::CpioReader,
+    writer::{entry_size, Cpio, CpioWriter, TRAILER_NAME},
+};
 
 /*
  * This test is not used in practice,
@@ -91,3 +95,304 @@ fn write_read_basic() {
         "CPIO is not aligned on a 4 bytes boundary!"
     );
 }
+
+#[test]
+fn write_crc_matches_reference() {
+    let mut cpio = Cpio::<Infallible>::new_with_crc();
+    let contents = vec![0xAA; 10];
+    cpio.pack_one("test.txt", &contents, "", 0o644)
+        .expect("Failed to pack a file at the root directory");
+    cpio.pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+
+    // Build the same archive with the reference implementation.
+    let mut reference = NewcBuilder::new("test.txt")
+        .ino(1)
+        .mode(0o100644)
+        .write_crc(Vec::new(), 10, 0xAA * 10);
+    reference
+        .write_all(&contents)
+        .expect("Failed to write the reference contents");
+    let reference = reference
+        .finish()
+        .expect("Failed to write the reference entry");
+    let reference = NewcBuilder::new("TRAILER!!!")
+        .ino(2)
+        .mode(0o100000)
+        .write_crc(reference, 0, 0)
+        .finish()
+        .expect("Failed to write the reference trailer");
+
+    let data = cpio.into_inner();
+    assert_eq!(data, reference);
+
+    let reader = NewcReader::new(Cursor::new(data)).expect("Failed to read the first entry");
+    assert_eq!(reader.entry().checksum(), Some(0xAA * 10));
+}
+
+#[test]
+fn pack_prefix_dedupes_directories() {
+    let mut cpio = Cpio::<Infallible>::new();
+    let contents = vec![0xAA; 10];
+    for fname in ["c.cred", "d.cred"] {
+        cpio.pack_prefix("a/b", 0o500)
+            .expect("Failed to pack prefixes of a directory, including itself");
+        cpio.pack_one(fname, &contents, "/a/b", 0o400)
+            .expect("Failed to pack a file inside the prefix");
+    }
+    cpio.pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+
+    let mut names = Vec::new();
+    let mut inodes = Vec::new();
+    let mut data = Cursor::new(cpio.into_inner());
+    loop {
+        let reader = NewcReader::new(data).expect("Failed to read an entry");
+        if reader.entry().is_trailer() {
+            break;
+        }
+        names.push(reader.entry().name().to_string());
+        inodes.push(reader.entry().ino());
+        data = reader.finish().expect("To finish reading");
+    }
+
+    assert_eq!(
+        names,
+        ["/a", "/a/b", "/a/b/c.cred", "/a/b/d.cred"],
+        "Directories must be packed exactly once"
+    );
+    assert_eq!(inodes, [1, 2, 3, 4]);
+}
+
+#[test]
+fn stream_into_fixed_buffer() {
+    let contents = vec![0xAA; 10];
+
+    let mut cpio = Cpio::<Infallible>::new();
+    cpio.pack_prefix("a/b", 0o500)
+        .expect("Failed to pack prefixes of a directory, including itself");
+    cpio.pack_one("c.cred", &contents, "/a/b", 0o400)
+        .expect("Failed to pack a file inside the prefix");
+    cpio.pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+    let expected = cpio.into_inner();
+
+    let mut buffer = vec![0u8; expected.len()];
+    let mut writer = CpioWriter::new(buffer.as_mut_slice());
+    writer
+        .pack_prefix("a/b", 0o500)
+        .expect("Failed to pack prefixes of a directory, including itself");
+    writer
+        .pack_one("c.cred", &contents, "/a/b", 0o400)
+        .expect("Failed to pack a file inside the prefix");
+    writer
+        .pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+    assert_eq!(writer.written(), expected.len());
+    assert_eq!(buffer, expected);
+
+    // A sink that is too small surfaces its IO error.
+    let mut buffer = vec![0u8; expected.len() - 1];
+    let mut writer = CpioWriter::new(buffer.as_mut_slice());
+    writer
+        .pack_prefix("a/b", 0o500)
+        .expect("Failed to pack prefixes of a directory, including itself");
+    writer
+        .pack_one("c.cred", &contents, "/a/b", 0o400)
+        .expect("Failed to pack a file inside the prefix");
+    assert!(matches!(
+        writer.pack_trailer(),
+        Err(CPIOError::IOError { .. })
+    ));
+}
+
+#[test]
+fn compute_entry_sizes() {
+    let contents = b"system extension";
+
+    let mut cpio = Cpio::<Infallible>::new();
+    cpio.pack_prefix(".extra/sysext", 0o555)
+        .expect("Failed to pack prefixes of a directory, including itself");
+    cpio.pack_one("a.raw", contents, ".extra/sysext", 0o444)
+        .expect("Failed to pack a file inside the prefix");
+    cpio.pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+
+    let size = entry_size("/.extra", 0)
+        + entry_size("/.extra/sysext", 0)
+        + entry_size(".extra/sysext/a.raw", contents.len())
+        + entry_size(TRAILER_NAME, 0);
+    assert_eq!(cpio.into_inner().len(), size);
+}
+
+#[test]
+fn pack_prefix_sets_directory_type() {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFDIR: u32 = 0o040000;
+
+    let mut cpio = Cpio::<Infallible>::new();
+    cpio.pack_prefix("a/b/c", 0o700)
+        .expect("Failed to pack prefixes of a directory, including itself");
+    cpio.pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+
+    let mut modes = Vec::new();
+    let mut data = Cursor::new(cpio.into_inner());
+    loop {
+        let reader = NewcReader::new(data).expect("Failed to read an entry");
+        if reader.entry().is_trailer() {
+            break;
+        }
+        modes.push((reader.entry().name().to_string(), reader.entry().mode()));
+        data = reader.finish().expect("To finish reading");
+    }
+
+    for (name, mode) in &modes {
+        assert_eq!(mode & S_IFMT, S_IFDIR, "{name} is not a directory");
+    }
+    // Intermediate directories are read-only, the final one has the requested permissions.
+    let permissions: Vec<u32> = modes.iter().map(|(_, mode)| mode & 0o7777).collect();
+    assert_eq!(permissions, [0o555, 0o555, 0o700]);
+}
+
+/// Pack two files under a prefix, the way the stub packs companion files.
+fn companion_archive(crc: bool) -> Vec<u8> {
+    let mut cpio = if crc {
+        Cpio::<Infallible>::new_with_crc()
+    } else {
+        Cpio::<Infallible>::new()
+    };
+    cpio.pack_prefix(".extra/credentials", 0o500)
+        .expect("Failed to pack prefixes of a directory, including itself");
+    cpio.pack_one("a.cred", b"secret", ".extra/credentials", 0o400)
+        .expect("Failed to pack a file inside the prefix");
+    cpio.pack_one("b.cred", b"", ".extra/credentials", 0o400)
+        .expect("Failed to pack an empty file inside the prefix");
+    cpio.pack_trailer()
+        .expect("Failed to pack the trailer of the CPIO archive");
+    cpio.into_inner()
+}
+
+#[test]
+fn read_written_archive() {
+    for crc in [false, true] {
+        let data = companion_archive(crc);
+        let entries = CpioReader::new(&data)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to read the archive");
+
+        let names: Vec<_> = entries.iter().map(|entry| entry.name).collect();
+        assert_eq!(
+            names,
+            [
+                b"/.extra".as_slice(),
+                b"/.extra/credentials",
+                b".extra/credentials/a.cred",
+                b".extra/credentials/b.cred"
+            ]
+        );
+        assert_eq!(entries[1].mode, 0o040500);
+        assert_eq!(entries[2].mode, 0o100400);
+        assert_eq!(entries[2].data, b"secret");
+        assert_eq!(entries[3].data, b"");
+        assert_eq!(entries[2].check.is_some(), crc);
+    }
+}
+
+#[test]
+fn read_concatenated_archives() {
+    let mut data = companion_archive(false);
+    data.extend([0; 8]);
+    data.extend(companion_archive(true));
+
+    let entries = CpioReader::new(&data)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to read the concatenated archives");
+    assert_eq!(entries.len(), 8);
+}
+
+#[test]
+fn stop_at_compressed_archive_after_trailer() {
+    let mut data = companion_archive(false);
+    // The magic number and some bytes of a gzip-compressed archive, which the kernel decompresses
+    // after the uncompressed one, e.g. an initrd after early microcode.
+    data.extend([0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03]);
+
+    let entries = CpioReader::new(&data)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to read the archive before the compressed one");
+    assert_eq!(entries.len(), 4);
+
+    // Without a trailer before it, it is not a CPIO archive at all.
+    let compressed = &data[data.len() - 10..];
+    assert!(matches!(CpioReader::new(compressed).next(), Some(Err(_))));
+}
+
+#[test]
+fn reject_malformed_archives() {
+    let read = |data: &[u8]| CpioReader::new(data).try_for_each(|entry| entry.map(drop));
+    let data = companion_archive(true);
+    // The offset of the first file, after the two directories.
+    let file_offset = 120 + 132;
+
+    let mut bad_magic = data.clone();
+    bad_magic[file_offset..][..6].copy_from_slice(b"070707");
+    assert_eq!(
+        read(&bad_magic),
+        Err(CPIOReadError::InvalidMagic {
+            offset: file_offset
+        })
+    );
+
+    // A name size of 0 leaves no room for the terminating NUL byte.
+    let mut bad_name_size = data.clone();
+    bad_name_size[file_offset + 94..][..8].copy_from_slice(b"00000000");
+    assert_eq!(
+        read(&bad_name_size),
+        Err(CPIOReadError::InvalidNameSize {
+            offset: file_offset
+        })
+    );
+
+    let mut bad_padding = data.clone();
+    // The header of the first file is 136 bytes long, followed by its 6 bytes of contents and 2
+    // bytes of padding.
+    bad_padding[file_offset + 136 + 6] = 1;
+    assert_eq!(
+        read(&bad_padding),
+        Err(CPIOReadError::InvalidPadding {
+            offset: file_offset
+        })
+    );
+
+    let mut bad_checksum = data.clone();
+    bad_checksum[file_offset + 136] = b'S';
+    assert_eq!(
+        read(&bad_checksum),
+        Err(CPIOReadError::ChecksumMismatch {
+            offset: file_offset
+        })
+    );
+
+    assert_eq!(
+        read(&data[..file_offset + 4]),
+        Err(CPIOReadError::Truncated {
+            offset: file_offset
+        })
+    );
+    assert_eq!(
+        read(&data[..data.len() - 124]),
+        Err(CPIOReadError::MissingTrailer)
+    );
+
+    // Concatenated archives must start on a 4-bytes boundary as well.
+    let mut misaligned = data.clone();
+    misaligned.push(0);
+    misaligned.extend(companion_archive(false));
+    assert_eq!(
+        read(&misaligned),
+        Err(CPIOReadError::InvalidPadding {
+            offset: data.len() + 1
+        })
+    );
+}
@@ -1,13 +1,17 @@
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use log::warn;
+use log::{error, warn};
 use uefi::{
     boot, guid, prelude::*, proto::loaded_image::LoadedImage, runtime, runtime::VariableVendor,
     CStr16, CString16, Result,
 };
 
+use linux_bootloader::companions::addon_cmdlines_allowed;
 use linux_bootloader::linux_loader::InitrdLoader;
-use linux_bootloader::pe_loader::Image;
-use linux_bootloader::pe_section::pe_section_as_string;
+use linux_bootloader::measure::{measure_cmdline, record_measurement_failure};
+use linux_bootloader::pe_loader::{Image, Relocations};
+use linux_bootloader::pe_section::{pe_section, pe_section_as_string};
+use linux_bootloader::tpm::tpm_available;
 
 /// Extract a string, stored as UTF-8, from a PE section.
 pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
@@ -16,23 +20,143 @@ pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
     Ok(CString16::try_from(string.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
 }
 
+/// Handle a measurement that failed although a TPM is available.
+///
+/// Built with the `strict-measurements` feature, the boot fails, because secrets sealed against
+/// the partially extended PCRs would not unseal anyway. Otherwise, the boot continues and the
+/// failure is recorded, see [`record_measurement_failure`].
+pub fn handle_measurement_failure(measurement: &str, err: uefi::Error) -> Result<()> {
+    error!("Failed to measure the {measurement}, the PCRs are inconsistently extended: {err}");
+    if cfg!(feature = "strict-measurements") {
+        return Err(err);
+    }
+    if let Err(err) = record_measurement_failure(measurement) {
+        warn!("Failed to record the measurement failure: {err}");
+    }
+    Ok(())
+}
+
+/// Flag of the `.cmdflags` section that forbids using the command line passed from the bootloader.
+const CMDLINE_FORBID_EDITING: u32 = 1 << 0;
+
+/// Check whether the command line passed from the bootloader may be used instead of the embedded one.
+///
+/// lzbt can forbid this in the `.cmdflags` section, which contains a bitfield as decimal ASCII.
+/// Without the section, editing is allowed. A malformed section forbids editing to be on the safe side.
+pub fn cmdline_editing_allowed(pe_data: &[u8]) -> bool {
+    let Some(flags) = pe_section(pe_data, ".cmdflags") else {
+        return true;
+    };
+
+    match core::str::from_utf8(flags)
+        .ok()
+        .and_then(|f| f.parse::<u32>().ok())
+    {
+        Some(flags) => flags & CMDLINE_FORBID_EDITING == 0,
+        None => {
+            warn!("Malformed .cmdflags section. Forbidding command line editing.");
+            false
+        }
+    }
+}
+
 /// Obtain the kernel command line that should be used for booting.
 ///
 /// If Secure Boot is active, this is always the embedded one (since the one passed from the bootloader may come from a malicious type 1 entry).
-/// If Secure Boot is not active, the command line passed from the bootloader is used, falling back to the embedded one.
-pub fn get_cmdline(embedded: &CStr16, secure_boot_enabled: bool) -> Vec<u8> {
-    if secure_boot_enabled {
-        // The command line passed from the bootloader cannot be trusted, so it is not used when Secure Boot is active.
-        embedded.as_bytes().to_vec()
-    } else {
-        let passed = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
-            .map(|loaded_image| loaded_image.load_options_as_bytes().map(|b| b.to_vec()));
-        match passed {
-            Ok(Some(passed)) => passed,
-            // If anything went wrong, fall back to the embedded command line.
-            _ => embedded.as_bytes().to_vec(),
+/// The same holds if editing is not allowed, see [`cmdline_editing_allowed`].
+/// Otherwise, the command line passed from the bootloader is used, falling back to the embedded one.
+///
+/// A passed command line that differs from the embedded one and is ignored is logged.
+///
+/// The command lines of addons are appended, as the firmware verified their signatures. Without
+/// Secure Boot, nothing is verified, so they are ignored if editing is not allowed, see
+/// [`addon_cmdlines_allowed`].
+///
+/// A passed command line that is used is measured, see [`measure_cmdline`].
+pub fn get_cmdline(
+    embedded: &CStr16,
+    secure_boot_enabled: bool,
+    editing_allowed: bool,
+    addon_cmdlines: &[String],
+) -> Result<Vec<u8>> {
+    let cmdline = select_cmdline(embedded, secure_boot_enabled, editing_allowed)?;
+
+    if addon_cmdlines.is_empty() {
+        return Ok(cmdline);
+    }
+    if !addon_cmdlines_allowed(secure_boot_enabled, editing_allowed) {
+        warn!("Ignoring the command lines of addons because Secure Boot is not active and editing the command line is forbidden.");
+        return Ok(cmdline);
+    }
+
+    let mut cmdline = ucs2_cmdline(&cmdline);
+    for addon_cmdline in addon_cmdlines {
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
         }
+        cmdline.push_str(addon_cmdline);
     }
+
+    Ok(cmdline
+        .encode_utf16()
+        .chain([0])
+        .flat_map(|c| c.to_le_bytes())
+        .collect())
+}
+
+/// Choose between the embedded command line and the one passed from the bootloader, see [`get_cmdline`].
+fn select_cmdline(
+    embedded: &CStr16,
+    secure_boot_enabled: bool,
+    editing_allowed: bool,
+) -> Result<Vec<u8>> {
+    let passed = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+        .ok()
+        .and_then(|loaded_image| loaded_image.load_options_as_bytes().map(|b| b.to_vec()));
+
+    match passed {
+        // If anything went wrong, fall back to the embedded command line.
+        None => Ok(embedded.as_bytes().to_vec()),
+        Some(passed) if secure_boot_enabled || !editing_allowed => {
+            // systemd-boot passes the embedded command line of a type 2 entry as load options
+            // itself, so only a different command line is worth mentioning.
+            if ucs2_cmdline(&passed) != ucs2_cmdline(embedded.as_bytes()) {
+                if secure_boot_enabled {
+                    // The command line passed from the bootloader cannot be trusted, so it is not used when Secure Boot is active.
+                    warn!("Ignoring the command line passed from the bootloader because Secure Boot is active.");
+                } else {
+                    // Without Secure Boot, anyone with physical access could change the command
+                    // line. This is explicitly forbidden for this stub.
+                    warn!("Ignoring the command line passed from the bootloader because editing it is forbidden.");
+                }
+            }
+            Ok(embedded.as_bytes().to_vec())
+        }
+        // systemd-boot passes the embedded command line itself, which is already measured as
+        // part of the image.
+        Some(passed) if ucs2_cmdline(&passed) == ucs2_cmdline(embedded.as_bytes()) => {
+            Ok(embedded.as_bytes().to_vec())
+        }
+        Some(passed) => {
+            if tpm_available() {
+                if let Err(err) = measure_cmdline(&passed) {
+                    handle_measurement_failure("command line", err)?;
+                }
+            }
+            Ok(passed)
+        }
+    }
+}
+
+/// Decode a UCS-2 command line for comparison, without trailing NUL characters and whitespace.
+fn ucs2_cmdline(bytes: &[u8]) -> String {
+    let chars = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<u16>>();
+    String::from_utf16_lossy(&chars)
+        .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
 }
 
 /// Check whether Secure Boot is active, and we should be enforcing integrity checks.
@@ -77,18 +201,28 @@ pub fn get_secure_boot_status() -> bool {
 ///
 /// We assume that the caller has made sure that the image is safe to
 /// be loaded using other means.
+///
+/// An empty `initrd_data` boots the kernel without initrd, i.e. the initrd loader is not
+/// installed at all.
 pub fn boot_linux_unchecked(
     handle: Handle,
     kernel_data: Vec<u8>,
     kernel_cmdline: &[u8],
     initrd_data: Vec<u8>,
 ) -> uefi::Result<()> {
-    let kernel = Image::load(&kernel_data).expect("Failed to load the kernel");
+    let kernel = Image::load(&kernel_data, Relocations::Apply)
+        .inspect_err(|_| error!("Failed to load the kernel"))?;
 
-    let mut initrd_loader = InitrdLoader::new(handle, initrd_data)?;
+    let mut initrd_loader = if initrd_data.is_empty() {
+        None
+    } else {
+        Some(InitrdLoader::new(handle, initrd_data)?)
+    };
 
     let status = unsafe { kernel.start(handle, kernel_cmdline) };
 
-    initrd_loader.uninstall()?;
+    if let Some(initrd_loader) = &mut initrd_loader {
+        initrd_loader.uninstall()?;
+    }
     status.to_result()
 }
@@ -1,8 +1,12 @@
+use alloc::string::String;
 use alloc::vec::Vec;
+use log::error;
 use uefi::{prelude::*, CString16, Result};
 
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
+use crate::common::{boot_linux_unchecked, cmdline_editing_allowed, extract_string, get_cmdline};
 use linux_bootloader::pe_section::pe_section;
+#[cfg(feature = "compressed-sections")]
+use linux_bootloader::pe_section::zstd_decompress;
 use linux_bootloader::uefi_helpers::booted_image_file;
 
 /// Extract bytes from a PE section.
@@ -14,6 +18,36 @@ pub fn extract_bytes(pe_data: &[u8], section: &str) -> Result<Vec<u8>> {
     Ok(bytes)
 }
 
+/// Extract the kernel or initrd from a PE section.
+///
+/// If the image has a `.compressed` section, the kernel and initrd are embedded compressed with
+/// the algorithm it names and are decompressed in memory.
+fn extract_payload(pe_data: &[u8], section: &str) -> Result<Vec<u8>> {
+    let Some(compression) = pe_section(pe_data, ".compressed") else {
+        return extract_bytes(pe_data, section);
+    };
+    let data = pe_section(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+    match compression {
+        b"zstd" => decompress_zstd(data)
+            .inspect_err(|_| error!("Failed to decompress the {section} section.")),
+        _ => {
+            error!("The embedded kernel and initrd are compressed with an unknown algorithm.");
+            Err(Status::UNSUPPORTED.into())
+        }
+    }
+}
+
+#[cfg(feature = "compressed-sections")]
+fn decompress_zstd(data: &[u8]) -> Result<Vec<u8>> {
+    zstd_decompress(data).ok_or(Status::LOAD_ERROR.into())
+}
+
+#[cfg(not(feature = "compressed-sections"))]
+fn decompress_zstd(_data: &[u8]) -> Result<Vec<u8>> {
+    error!("This stub is built without support for compressed sections.");
+    Err(Status::UNSUPPORTED.into())
+}
+
 /// The configuration that is embedded at build time.
 ///
 /// After this stub is built, configuration need to be embedded into the binary by adding PE
@@ -22,35 +56,49 @@ struct EmbeddedConfiguration {
     /// The kernel command-line.
     cmdline: CString16,
 
+    /// Whether the command line passed from the bootloader may replace `cmdline`.
+    cmdline_editing_allowed: bool,
+
     /// The kernel as raw bytes.
     kernel: Vec<u8>,
 
-    /// The initrd as raw bytes.
+    /// The initrd as raw bytes, empty if the image has no `.initrd` section.
     initrd: Vec<u8>,
 }
 
 impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
         Ok(Self {
-            kernel: extract_bytes(file_data, ".linux")?,
-            initrd: extract_bytes(file_data, ".initrd")?,
+            kernel: extract_payload(file_data, ".linux")?,
+            initrd: match pe_section(file_data, ".initrd") {
+                Some(_) => extract_payload(file_data, ".initrd")?,
+                None => Vec::new(),
+            },
             cmdline: extract_string(file_data, ".cmdline")?,
+            cmdline_editing_allowed: cmdline_editing_allowed(file_data),
         })
     }
 }
 
-pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> Status {
+pub fn boot_linux(
+    handle: Handle,
+    secure_boot_enabled: bool,
+    dynamic_initrds: Vec<Vec<u8>>,
+    addon_cmdlines: &[String],
+) -> Result<()> {
     // SAFETY: We get a slice that represents our currently running
     // image and then parse the PE data structures from it. This is
     // safe, because we don't touch any data in the data sections that
     // might conceivably change while we look at the slice.
-    let mut config = unsafe {
-        EmbeddedConfiguration::new(booted_image_file().unwrap().as_slice())
-            .expect("Failed to extract configuration from binary.")
-    };
+    let mut config = unsafe { EmbeddedConfiguration::new(booted_image_file()?.as_slice()) }
+        .inspect_err(|_| error!("Failed to extract configuration from binary."))?;
 
-    let secure_boot_enabled = get_secure_boot_status();
-    let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
+    let cmdline = get_cmdline(
+        &config.cmdline,
+        secure_boot_enabled,
+        config.cmdline_editing_allowed,
+        addon_cmdlines,
+    )?;
 
     let mut final_initrd = Vec::new();
     final_initrd.append(&mut config.initrd);
@@ -63,5 +111,5 @@ pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> Status {
         final_initrd.append(&mut extra_initrd);
     }
 
-    boot_linux_unchecked(handle, config.kernel, &cmdline, final_initrd).status()
+    boot_linux_unchecked(handle, config.kernel, &cmdline, final_initrd)
 }
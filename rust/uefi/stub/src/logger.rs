@@ -0,0 +1,69 @@
+use core::cell::Cell;
+use core::fmt::Write;
+
+use alloc::format;
+use log::{LevelFilter, Log, Metadata, Record};
+use uefi::system;
+
+use linux_bootloader::uefi_helpers::write_to_serial;
+
+/// The level up to which records are written to the console.
+const CONSOLE_LEVEL: LevelFilter = if cfg!(debug_assertions) {
+    LevelFilter::Info
+} else {
+    LevelFilter::Warn
+};
+
+static LOGGER: StubLogger = StubLogger {
+    serial_level: Cell::new(CONSOLE_LEVEL),
+};
+
+/// Logger that writes to the console and mirrors to the serial port.
+///
+/// Headless machines often only have a serial console, and the firmware does not necessarily
+/// mirror the UEFI console there.
+struct StubLogger {
+    serial_level: Cell<LevelFilter>,
+}
+
+// The logger is not thread-safe, but the UEFI boot environment only uses one processor.
+unsafe impl Sync for StubLogger {}
+
+impl Log for StubLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= CONSOLE_LEVEL.max(self.serial_level.get())
+    }
+
+    fn log(&self, record: &Record) {
+        let message = format!("[{:>5}]: {}", record.level(), record.args());
+
+        // Ignore all errors, logging must never stop the boot.
+        if record.level() <= CONSOLE_LEVEL {
+            system::with_stdout(|stdout| {
+                let _ = writeln!(stdout, "{message}");
+            });
+        }
+
+        // There may be no serial device at all.
+        if record.level() <= self.serial_level.get() {
+            let _ = write_to_serial(&format!("{message}\r\n"));
+        }
+    }
+
+    fn flush(&self) {
+        // Neither sink is buffered.
+    }
+}
+
+/// Set up logging to the console and, with the same level, to the serial port.
+pub fn init() {
+    // This only fails if a logger is already set, which then keeps being used.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(CONSOLE_LEVEL);
+}
+
+/// Change the level up to which records are mirrored to the serial port.
+pub fn set_serial_level(level: LevelFilter) {
+    LOGGER.serial_level.set(level);
+    log::set_max_level(CONSOLE_LEVEL.max(level));
+}
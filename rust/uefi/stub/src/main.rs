@@ -5,31 +5,77 @@
 extern crate alloc;
 
 mod common;
+mod logger;
 
 #[cfg(feature = "fat")]
 mod fat;
+#[cfg(feature = "fat")]
+use fat::boot_linux;
 
-#[cfg(feature = "thin")]
+#[cfg(any(feature = "thin", feature = "measured-only"))]
 mod thin;
+#[cfg(any(feature = "thin", feature = "measured-only"))]
+use thin::boot_linux;
 
 #[cfg(all(feature = "fat", feature = "thin"))]
 compile_error!("A thin and fat stub cannot be produced at the same time, disable either `thin` or `fat` feature");
 
+#[cfg(all(feature = "measured-only", any(feature = "thin", feature = "fat")))]
+compile_error!("A measured-only stub does not verify the kernel and initrd for Secure Boot, disable the `thin` and `fat` features");
+
+use alloc::string::String;
 use alloc::vec::Vec;
+use common::{cmdline_editing_allowed, get_secure_boot_status, handle_measurement_failure};
+#[cfg(feature = "zstd")]
+use linux_bootloader::companions::CompanionInitrd;
 use linux_bootloader::companions::{
-    discover_credentials, discover_system_extensions, get_default_dropin_directory,
+    addon_cmdlines_allowed, discover_addons, discover_credentials, discover_system_extensions,
+    extra_dropin_directories, get_default_dropin_directory,
+};
+use linux_bootloader::cpio::validate_cpio;
+use linux_bootloader::efivars::{
+    export_companions, export_efi_variables, get_loader_features, EfiLoaderFeatures,
+};
+#[cfg(feature = "pcr4")]
+use linux_bootloader::measure::measure_authenticode;
+use linux_bootloader::measure::{
+    export_measured_pcr_banks, measure_addon_cmdlines, measure_companion_initrds, measure_image,
+    measure_rollback_counter,
 };
-use linux_bootloader::efivars::{export_efi_variables, get_loader_features, EfiLoaderFeatures};
-use linux_bootloader::measure::{measure_companion_initrds, measure_image};
+use linux_bootloader::pe_section::{pe_section, pe_section_as_string};
+use linux_bootloader::splash::draw_splash;
 use linux_bootloader::tpm::tpm_available;
-use linux_bootloader::uefi_helpers::booted_image_file;
-use log::{info, warn};
+use linux_bootloader::uefi_helpers::{booted_image_file, wait_for_keypress};
+use log::{error, info, warn, LevelFilter};
 use uefi::boot;
 use uefi::prelude::*;
 
 /// Lanzaboote stub name
 pub static STUB_NAME: &str = concat!("lanzastub ", env!("CARGO_PKG_VERSION"));
 
+/// SBAT metadata of the stub in the CSV format described by shim's SBAT.md.
+///
+/// Shim refuses to boot images whose generation is revoked. lzbt can replace the section when it
+/// assembles an image.
+const SBAT_CSV: &str = concat!(
+    "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n",
+    "lanzaboote,1,Lanzaboote,lanzaboote,",
+    env!("CARGO_PKG_VERSION"),
+    ",https://github.com/nix-community/lanzaboote\n"
+);
+
+#[used]
+#[link_section = ".sbat"]
+static SBAT: [u8; SBAT_CSV.len()] = {
+    let mut sbat = [0; SBAT_CSV.len()];
+    let mut i = 0;
+    while i < sbat.len() {
+        sbat[i] = SBAT_CSV.as_bytes()[i];
+        i += 1;
+    }
+    sbat
+};
+
 /// Print the startup logo on boot.
 fn print_logo() {
     info!(
@@ -45,23 +91,89 @@ fn print_logo() {
     );
 }
 
+/// Tell the user why booting failed and wait until they acknowledge it.
+///
+/// Otherwise, the firmware or the boot loader takes over the screen right away and the error is
+/// gone before anyone could read it.
+fn report_boot_failure(err: &uefi::Error) {
+    error!("Failed to boot: {err}");
+    warn!("Press any key to continue.");
+    let _ = wait_for_keypress();
+}
+
+/// Measure the Authenticode hash of the stub file, see [`measure_authenticode`].
+///
+/// The hash is computed over the file, not the image in memory, because the firmware loads the
+/// sections to other offsets.
+#[cfg(feature = "pcr4")]
+fn measure_stub_file(
+    filesystem: &mut uefi::fs::FileSystem,
+    file_path: &uefi::proto::device_path::DevicePath,
+) -> uefi::Result<u32> {
+    use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+
+    let path = file_path
+        .to_string(DisplayOnly(false), AllowShortcuts(false))
+        .map_err(|_err| Status::NOT_FOUND)?;
+    let stub = filesystem
+        .read(path.as_ref())
+        .map_err(|_err| Status::LOAD_ERROR)?;
+    measure_authenticode(&stub)
+}
+
 #[entry]
 fn main() -> Status {
-    uefi::helpers::init().unwrap();
+    if let Err(err) = uefi::helpers::init() {
+        return err.status();
+    }
+    logger::init();
 
-    print_logo();
+    match boot() {
+        Ok(()) => Status::SUCCESS,
+        Err(err) => {
+            report_boot_failure(&err);
+            err.status()
+        }
+    }
+}
+
+fn boot() -> uefi::Result<()> {
+    let pe_in_memory = booted_image_file().inspect_err(|_| {
+        error!("Failed to extract the in-memory information about our own image");
+    })?;
+
+    // SAFETY: We only read from our own image, see `PeInMemory::as_slice`.
+    if let Some(level) = pe_section_as_string(unsafe { pe_in_memory.as_slice() }, ".loglevel") {
+        match level.parse::<LevelFilter>() {
+            Ok(level) => logger::set_serial_level(level),
+            Err(_) => warn!("Ignoring invalid serial log level: {level}"),
+        }
+    }
+
+    // SAFETY: We only read from our own image, see `PeInMemory::as_slice`.
+    match pe_section(unsafe { pe_in_memory.as_slice() }, ".splash").map(draw_splash) {
+        Some(Ok(())) => {}
+        Some(Err(err)) => {
+            warn!("Failed to draw the splash image: {err}");
+            print_logo();
+        }
+        None => print_logo(),
+    }
 
     let is_tpm_available = tpm_available();
-    let pe_in_memory = booted_image_file()
-        .expect("Failed to extract the in-memory information about our own image");
 
     if is_tpm_available {
         info!("TPM available, will proceed to measurements.");
+        if let Err(err) = export_measured_pcr_banks() {
+            warn!("Failed to export the active PCR banks: {err}");
+        }
         // Iterate over unified sections and measure them
-        // For now, ignore failures during measurements.
-        // TODO: in the future, devise a threat model where this can fail
-        // and ensure this hard-fail correctly.
-        let _ = measure_image(&pe_in_memory);
+        if let Err(err) = measure_image(&pe_in_memory) {
+            handle_measurement_failure("unified sections", err)?;
+        }
+        if let Err(err) = measure_rollback_counter(&pe_in_memory) {
+            handle_measurement_failure("rollback counter", err)?;
+        }
     }
 
     if let Ok(features) = get_loader_features() {
@@ -71,14 +183,19 @@ fn main() -> Status {
         }
     }
 
-    if export_efi_variables(STUB_NAME).is_err() {
+    // SAFETY: We only read from our own image, see `PeInMemory::as_slice`.
+    let specialisation = pe_section_as_string(unsafe { pe_in_memory.as_slice() }, ".special");
+    if export_efi_variables(STUB_NAME, specialisation.as_deref()).is_err() {
         warn!("Failed to export stub EFI variables, some features related to measured boot will not be available");
     }
 
-    let status;
+    let secure_boot_enabled = get_secure_boot_status();
+
     // A list of dynamically assembled initrds, e.g. credential initrds or system extension
     // initrds.
     let mut dynamic_initrds: Vec<Vec<u8>> = Vec::new();
+    // The command line parameters of signed addons, appended to the kernel command line.
+    let mut addon_cmdlines: Vec<String> = Vec::new();
 
     {
         // This is a block for doing filesystem operations once and for all, related to companion
@@ -87,7 +204,9 @@ fn main() -> Status {
         let mut companions = Vec::new();
         let image_fs = uefi::boot::get_image_file_system(boot::image_handle());
 
-        if let Ok(image_fs) = image_fs {
+        if let Ok(mut image_fs) = image_fs {
+            // Opened before `image_fs` is moved into `filesystem`, for reading parts of files.
+            let volume = image_fs.open_volume();
             let mut filesystem = uefi::fs::FileSystem::new(image_fs);
             let default_dropin_directory;
 
@@ -99,41 +218,105 @@ fn main() -> Status {
                     warn!("Failed to discover the default drop-in directory for companion files");
                 }
 
+                #[cfg(feature = "pcr4")]
+                if is_tpm_available {
+                    if let Err(err) = measure_stub_file(&mut filesystem, loaded_image_path) {
+                        handle_measurement_failure("stub", err)?;
+                    }
+                }
+
                 default_dropin_directory = discovered_default_dropin_dir.unwrap_or(None);
             } else {
                 default_dropin_directory = None;
             }
 
-            // TODO: how to do the proper .as_ref()? Should I take AsRef in the call definition… ?
-            if let Ok(mut system_credentials) = discover_credentials(
-                &mut filesystem,
-                default_dropin_directory.as_ref().map(|x| x.as_ref()),
-            ) {
+            // Credentials and system extensions are looked up in the default drop-in directory
+            // first and then in the extra ones listed in the `.dropins` section.
+            let mut dropin_directories: Vec<_> = default_dropin_directory.iter().cloned().collect();
+            // SAFETY: We only read from our own image, see `PeInMemory::as_slice`.
+            if let Some(dropins) =
+                pe_section_as_string(unsafe { pe_in_memory.as_slice() }, ".dropins")
+            {
+                dropin_directories.extend(extra_dropin_directories(&mut filesystem, &dropins));
+            }
+
+            if let Ok(mut system_credentials) =
+                discover_credentials(&mut filesystem, &dropin_directories)
+            {
                 companions.append(&mut system_credentials);
             } else {
                 warn!("Failed to discover any system credential");
             }
 
-            if let Some(default_dropin_dir) = default_dropin_directory {
-                if let Ok(mut system_extensions) =
-                    discover_system_extensions(&mut filesystem, &default_dropin_dir)
-                {
-                    companions.append(&mut system_extensions);
-                } else {
-                    warn!("Failed to discover any system extension");
+            let mut addon_initrds = Vec::new();
+            // Addons with an initrd are listed with the other companions.
+            let mut cmdline_addons = Vec::new();
+            if let Ok(addons) = discover_addons(
+                &mut filesystem,
+                default_dropin_directory.as_ref().map(|x| x.as_ref()),
+            ) {
+                for addon in addons {
+                    if addon.initrd.is_none() && addon.cmdline.is_some() {
+                        cmdline_addons.push(addon.file);
+                    }
+                    addon_cmdlines.extend(addon.cmdline);
+                    addon_initrds.extend(addon.initrd);
                 }
+                // Unlike the other companions, addon initrds are not assembled by us, so a
+                // corrupt one is dropped here instead of being measured and passed to the kernel.
+                addon_initrds.retain(|initrd| {
+                    validate_cpio(&initrd.contents)
+                        .map_err(|err| warn!("Ignoring malformed addon initrd: {err}"))
+                        .is_ok()
+                });
+            } else {
+                warn!("Failed to discover any addon");
+            }
+
+            if let Ok(mut system_extensions) = volume.and_then(|mut volume| {
+                discover_system_extensions(
+                    &mut filesystem,
+                    &mut volume,
+                    &dropin_directories,
+                    secure_boot_enabled,
+                )
+            }) {
+                companions.append(&mut system_extensions);
+            } else {
+                warn!("Failed to discover any system extension");
+            }
+
+            // Compress before measuring, so that the measurements cover exactly what the kernel
+            // receives.
+            #[cfg(feature = "zstd")]
+            companions.iter_mut().for_each(CompanionInitrd::compress);
+
+            // Addon initrds are signed as they are and may be compressed already, so they are
+            // passed on untouched.
+            companions.append(&mut addon_initrds);
+
+            if let Err(err) = export_companions(&companions, &cmdline_addons) {
+                warn!("Failed to export the discovered companions: {err}");
             }
 
             if is_tpm_available {
-                // TODO: in the future, devise a threat model where this can fail, see above
-                // measurements to understand the context.
-                let _ = measure_companion_initrds(&companions);
+                if let Err(err) = measure_companion_initrds(&companions) {
+                    handle_measurement_failure("companion initrds", err)?;
+                }
+                // Addon command lines that are not used, see `get_cmdline`, are not measured either.
+                // SAFETY: We only read from our own image, see `PeInMemory::as_slice`.
+                let editing_allowed = cmdline_editing_allowed(unsafe { pe_in_memory.as_slice() });
+                if addon_cmdlines_allowed(secure_boot_enabled, editing_allowed) {
+                    if let Err(err) = measure_addon_cmdlines(&addon_cmdlines) {
+                        handle_measurement_failure("addon command lines", err)?;
+                    }
+                }
             }
 
             dynamic_initrds.append(
                 &mut companions
                     .into_iter()
-                    .map(|initrd| initrd.cpio.into_inner())
+                    .map(|initrd| initrd.contents)
                     .collect(),
             );
         } else {
@@ -141,15 +324,10 @@ fn main() -> Status {
         }
     }
 
-    #[cfg(feature = "fat")]
-    {
-        status = fat::boot_linux(boot::image_handle(), dynamic_initrds)
-    }
-
-    #[cfg(feature = "thin")]
-    {
-        status = thin::boot_linux(boot::image_handle(), dynamic_initrds).status()
-    }
-
-    status
+    boot_linux(
+        boot::image_handle(),
+        secure_boot_enabled,
+        dynamic_initrds,
+        &addon_cmdlines,
+    )
 }
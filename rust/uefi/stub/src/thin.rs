@@ -1,15 +1,41 @@
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use log::{error, warn};
+#[cfg(feature = "thin")]
 use sha2::{Digest, Sha256};
-use uefi::{fs::FileSystem, prelude::*, CString16, Result};
+use uefi::{
+    boot,
+    fs::{FileSystem, FileSystemResult},
+    prelude::*,
+    CStr16, CString16, Guid, Result,
+};
 
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
+#[cfg(feature = "measured-only")]
+use crate::common::handle_measurement_failure;
+use crate::common::{boot_linux_unchecked, cmdline_editing_allowed, extract_string, get_cmdline};
+#[cfg(feature = "measured-only")]
+use linux_bootloader::measure::measure_kernel_and_initrd;
+#[cfg(feature = "thin")]
 use linux_bootloader::pe_section::pe_section;
-use linux_bootloader::uefi_helpers::booted_image_file;
+use linux_bootloader::pe_section::pe_section_as_string;
+#[cfg(feature = "measured-only")]
+use linux_bootloader::tpm::tpm_available;
+use linux_bootloader::uefi_helpers::{booted_image_file, partition_file_system};
 
+#[cfg(feature = "thin")]
 type Hash = sha2::digest::Output<Sha256>;
 
+/// The algorithms the embedded hashes can be computed with.
+#[cfg(feature = "thin")]
+#[derive(Clone, Copy)]
+enum HashAlgorithm {
+    Sha256,
+}
+
+/// How long to wait before retrying to read a file, in microseconds.
+const READ_RETRY_DELAY: usize = 500_000;
+
 /// The configuration that is embedded at build time.
 ///
 /// After this stub is built, lzbt needs to embed configuration into the binary by adding PE
@@ -20,22 +46,52 @@ struct EmbeddedConfiguration {
     /// lanzaboote binary.
     kernel_filename: CString16,
 
+    /// The algorithm of the embedded hashes, `None` if this stub does not support it.
+    #[cfg(feature = "thin")]
+    hash_algorithm: Option<HashAlgorithm>,
+
     /// The cryptographic hash of the kernel.
+    #[cfg(feature = "thin")]
     kernel_hash: Hash,
 
     /// The filename of the initrd to be passed to the kernel. See
     /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
+    /// `None` if the generation boots without initrd.
+    initrd_filename: Option<CString16>,
 
     /// The cryptographic hash of the initrd. This hash is computed
     /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
+    #[cfg(feature = "thin")]
+    initrd_hash: Option<Hash>,
+
+    /// The unique partition GUID of the file system that holds the kernel and initrd, e.g. an
+    /// XBOOTLDR partition. Without it, they are read from the file system of this image.
+    boot_partition: Option<Guid>,
 
     /// The kernel command-line.
     cmdline: CString16,
+
+    /// Whether the command line passed from the bootloader may replace `cmdline`.
+    cmdline_editing_allowed: bool,
+
+    /// The kernel and initrd of the previous generation, used if the
+    /// kernel or initrd of this generation cannot be read.
+    fallback: Option<FallbackConfiguration>,
+}
+
+/// The kernel and initrd to boot instead of the ones of the
+/// `EmbeddedConfiguration`. Their hashes are checked the same way.
+struct FallbackConfiguration {
+    kernel_filename: CString16,
+    #[cfg(feature = "thin")]
+    kernel_hash: Hash,
+    initrd_filename: CString16,
+    #[cfg(feature = "thin")]
+    initrd_hash: Hash,
 }
 
 /// Extract a SHA256 hash from a PE section.
+#[cfg(feature = "thin")]
 fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
     let array: [u8; 32] = pe_section(pe_data, section)
         .ok_or(Status::INVALID_PARAMETER)?
@@ -45,25 +101,138 @@ fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
     Ok(array.into())
 }
 
+/// Extract the algorithm of the hashes from the optional `.hashalg` section.
+///
+/// Stubs built before the section was introduced only have SHA256 hashes, so that is the
+/// algorithm without the section. `None` means the algorithm is not supported.
+#[cfg(feature = "thin")]
+fn extract_hash_algorithm(pe_data: &[u8]) -> Option<HashAlgorithm> {
+    match pe_section(pe_data, ".hashalg") {
+        None | Some(b"sha256") => Some(HashAlgorithm::Sha256),
+        Some(_) => None,
+    }
+}
+
+/// Extract a hash computed with `hash_algorithm`.
+///
+/// Hashes of unsupported algorithms are never checked, so they are not parsed and left zero.
+#[cfg(feature = "thin")]
+fn extract_hash_with(
+    pe_data: &[u8],
+    section: &str,
+    hash_algorithm: Option<HashAlgorithm>,
+) -> Result<Hash> {
+    match hash_algorithm {
+        Some(HashAlgorithm::Sha256) => extract_hash(pe_data, section),
+        None => Ok(Hash::default()),
+    }
+}
+
+/// Extract a string like [`extract_string`], `None` if the section does not exist.
+fn extract_optional_string(pe_data: &[u8], section: &str) -> Result<Option<CString16>> {
+    pe_section_as_string(pe_data, section)
+        .map(|_| extract_string(pe_data, section))
+        .transpose()
+}
+
+/// Extract the partition GUID from the optional `.bootprt` section.
+fn extract_boot_partition(pe_data: &[u8]) -> Result<Option<Guid>> {
+    let Some(part_uuid) = pe_section_as_string(pe_data, ".bootprt") else {
+        return Ok(None);
+    };
+    Guid::try_parse(part_uuid.trim())
+        .map(Some)
+        .map_err(|_| Status::INVALID_PARAMETER.into())
+}
+
 impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
+        #[cfg(feature = "thin")]
+        let hash_algorithm = extract_hash_algorithm(file_data);
+        let initrd_filename = extract_optional_string(file_data, ".initrd")?;
+        #[cfg(feature = "thin")]
+        let initrd_hash = match initrd_filename {
+            Some(_) => Some(extract_hash_with(file_data, ".initrdh", hash_algorithm)?),
+            None => None,
+        };
+
         Ok(Self {
             kernel_filename: extract_string(file_data, ".linux")?,
-            kernel_hash: extract_hash(file_data, ".linuxh")?,
+            #[cfg(feature = "thin")]
+            hash_algorithm,
+            #[cfg(feature = "thin")]
+            kernel_hash: extract_hash_with(file_data, ".linuxh", hash_algorithm)?,
+
+            initrd_filename,
+            #[cfg(feature = "thin")]
+            initrd_hash,
 
-            initrd_filename: extract_string(file_data, ".initrd")?,
-            initrd_hash: extract_hash(file_data, ".initrdh")?,
+            boot_partition: extract_boot_partition(file_data)?,
 
             cmdline: extract_string(file_data, ".cmdline")?,
+            cmdline_editing_allowed: cmdline_editing_allowed(file_data),
+
+            fallback: FallbackConfiguration::new(
+                file_data,
+                #[cfg(feature = "thin")]
+                hash_algorithm,
+            )
+            .ok(),
+        })
+    }
+}
+
+impl FallbackConfiguration {
+    /// The fallback hashes are computed with the same algorithm as the hashes of this generation.
+    fn new(
+        file_data: &[u8],
+        #[cfg(feature = "thin")] hash_algorithm: Option<HashAlgorithm>,
+    ) -> Result<Self> {
+        Ok(Self {
+            kernel_filename: extract_string(file_data, ".linux2")?,
+            #[cfg(feature = "thin")]
+            kernel_hash: extract_hash_with(file_data, ".linuxh2", hash_algorithm)?,
+
+            initrd_filename: extract_string(file_data, ".initrd2")?,
+            #[cfg(feature = "thin")]
+            initrd_hash: extract_hash_with(file_data, ".initrh2", hash_algorithm)?,
         })
     }
 }
 
+/// Read a file into memory, retrying once after a short delay.
+///
+/// This papers over transient failures, e.g. of slow or flaky storage.
+fn read_with_retry(file_system: &mut FileSystem, path: &CStr16) -> FileSystemResult<Vec<u8>> {
+    file_system.read(path).or_else(|err| {
+        warn!("Failed to read {path}: {err}. Retrying...");
+        boot::stall(READ_RETRY_DELAY);
+        file_system.read(path)
+    })
+}
+
+/// Read the kernel and the initrd into memory.
+///
+/// Without `initrd_filename`, the initrd is empty.
+fn read_kernel_and_initrd(
+    file_system: &mut FileSystem,
+    kernel_filename: &CStr16,
+    initrd_filename: Option<&CStr16>,
+) -> FileSystemResult<(Vec<u8>, Vec<u8>)> {
+    let kernel = read_with_retry(file_system, kernel_filename)?;
+    let initrd = match initrd_filename {
+        Some(initrd_filename) => read_with_retry(file_system, initrd_filename)?,
+        None => Vec::new(),
+    };
+    Ok((kernel, initrd))
+}
+
 /// Verify some data against its expected hash.
 ///
 /// In case of a mismatch:
 /// * If Secure Boot is active, an error message is logged, and the SECURITY_VIOLATION error is returned to stop the boot.
 /// * If Secure Boot is not active, only a warning is logged, and the boot process is allowed to continue.
+#[cfg(feature = "thin")]
 fn check_hash(data: &[u8], expected_hash: Hash, name: &str, secure_boot: bool) -> uefi::Result<()> {
     let hash_correct = Sha256::digest(data) == expected_hash;
     if !hash_correct {
@@ -77,49 +246,115 @@ fn check_hash(data: &[u8], expected_hash: Hash, name: &str, secure_boot: bool) -
     Ok(())
 }
 
-pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result<()> {
+pub fn boot_linux(
+    handle: Handle,
+    secure_boot_enabled: bool,
+    dynamic_initrds: Vec<Vec<u8>>,
+    addon_cmdlines: &[String],
+) -> uefi::Result<()> {
     // SAFETY: We get a slice that represents our currently running
     // image and then parse the PE data structures from it. This is
     // safe, because we don't touch any data in the data sections that
     // might conceivably change while we look at the slice.
-    let config = unsafe {
-        EmbeddedConfiguration::new(booted_image_file().unwrap().as_slice())
-            .expect("Failed to extract configuration from binary. Did you run lzbt?")
-    };
+    let config = unsafe { EmbeddedConfiguration::new(booted_image_file()?.as_slice()) }
+        .inspect_err(|_| {
+            error!("Failed to extract configuration from binary. Did you run lzbt?")
+        })?;
 
-    let secure_boot_enabled = get_secure_boot_status();
+    // Without the hash checks, Secure Boot would only cover the stub, but not the kernel and
+    // initrd it boots.
+    if cfg!(feature = "measured-only") && secure_boot_enabled {
+        error!("This stub does not verify the kernel and initrd and refuses to boot with Secure Boot enabled.");
+        return Err(Status::SECURITY_VIOLATION.into());
+    }
 
     let kernel_data;
     let mut initrd_data;
+    #[cfg(feature = "thin")]
+    let kernel_hash;
+    #[cfg(feature = "thin")]
+    let initrd_hash;
 
     {
-        let file_system =
-            uefi::boot::get_image_file_system(handle).expect("Failed to get file system handle");
+        let file_system = match config.boot_partition {
+            Some(part_uuid) => partition_file_system(part_uuid)
+                .inspect_err(|_| error!("Failed to find the boot partition {part_uuid}"))?,
+            None => uefi::boot::get_image_file_system(handle)
+                .inspect_err(|_| error!("Failed to get file system handle"))?,
+        };
         let mut file_system = FileSystem::new(file_system);
 
-        kernel_data = file_system
-            .read(&*config.kernel_filename)
-            .expect("Failed to read kernel file into memory");
-        initrd_data = file_system
-            .read(&*config.initrd_filename)
-            .expect("Failed to read initrd file into memory");
-    }
+        match read_kernel_and_initrd(
+            &mut file_system,
+            &config.kernel_filename,
+            config.initrd_filename.as_deref(),
+        ) {
+            Ok((kernel, initrd)) => {
+                (kernel_data, initrd_data) = (kernel, initrd);
+                #[cfg(feature = "thin")]
+                {
+                    (kernel_hash, initrd_hash) = (config.kernel_hash, config.initrd_hash);
+                }
+            }
+            Err(err) => {
+                error!("Failed to read the kernel and initrd into memory: {err}");
+                let Some(fallback) = &config.fallback else {
+                    return Err(Status::LOAD_ERROR.into());
+                };
 
-    let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
+                warn!("Falling back to the kernel and initrd of the previous generation.");
+                (kernel_data, initrd_data) = read_kernel_and_initrd(
+                    &mut file_system,
+                    &fallback.kernel_filename,
+                    Some(&fallback.initrd_filename),
+                )
+                .map_err(|err| {
+                    error!("Failed to read the fallback kernel and initrd into memory: {err}");
+                    Status::LOAD_ERROR
+                })?;
+                #[cfg(feature = "thin")]
+                {
+                    (kernel_hash, initrd_hash) = (fallback.kernel_hash, Some(fallback.initrd_hash));
+                }
+            }
+        }
+    }
 
-    check_hash(
-        &kernel_data,
-        config.kernel_hash,
-        "Kernel",
-        secure_boot_enabled,
-    )?;
-    check_hash(
-        &initrd_data,
-        config.initrd_hash,
-        "Initrd",
+    let cmdline = get_cmdline(
+        &config.cmdline,
         secure_boot_enabled,
+        config.cmdline_editing_allowed,
+        addon_cmdlines,
     )?;
 
+    #[cfg(feature = "thin")]
+    match config.hash_algorithm {
+        Some(HashAlgorithm::Sha256) => {
+            check_hash(&kernel_data, kernel_hash, "Kernel", secure_boot_enabled)?;
+            // Without initrd, there is nothing that could be tampered with.
+            if let Some(initrd_hash) = initrd_hash {
+                check_hash(&initrd_data, initrd_hash, "Initrd", secure_boot_enabled)?;
+            }
+        }
+        // Comparing the hashes with SHA256 would reject correct files or, worse, accept wrong
+        // ones.
+        None if secure_boot_enabled => {
+            error!("The kernel and initrd hashes use an unsupported algorithm!");
+            return Err(Status::SECURITY_VIOLATION.into());
+        }
+        None => {
+            warn!("The kernel and initrd hashes use an unsupported algorithm! Continuing without checking them.");
+        }
+    }
+
+    // Instead of checking them, bind the kernel and initrd to the TPM measurements.
+    #[cfg(feature = "measured-only")]
+    if !tpm_available() {
+        warn!("Failed to measure the kernel and initrd. Nothing protects them from tampering.");
+    } else if let Err(err) = measure_kernel_and_initrd(&kernel_data, &initrd_data) {
+        handle_measurement_failure("kernel and initrd", err)?;
+    }
+
     // Correctness: dynamic initrds are supposed to be validated by caller,
     // i.e. they are system extension images or credentials
     // that are supposedly measured in TPM2.